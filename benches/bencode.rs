@@ -0,0 +1,57 @@
+// Timings for torrent decoding and info hashing, run with `cargo bench --bench bencode`.
+use std::collections::BTreeMap;
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use bittorrent_starter_rust::bencode::{self, BencodeValue};
+use bittorrent_starter_rust::torrent::Torrent;
+
+const PIECE_LENGTH: i64 = 256 * 1024;
+
+// a single-file torrent with `num_pieces` pieces, encoded the same way a .torrent file is
+fn torrent_bytes(num_pieces: usize) -> Vec<u8> {
+    let bytes = |s: &str| BencodeValue::Bytes(s.as_bytes().to_vec());
+    let pieces: Vec<u8> = (0..num_pieces * 20).map(|i| (i % 251) as u8).collect();
+    let info = BTreeMap::from([
+        (
+            b"length".to_vec(),
+            BencodeValue::Int(PIECE_LENGTH * num_pieces as i64),
+        ),
+        (b"name".to_vec(), bytes("bench.bin")),
+        (b"piece length".to_vec(), BencodeValue::Int(PIECE_LENGTH)),
+        (b"pieces".to_vec(), BencodeValue::Bytes(pieces)),
+    ]);
+    let torrent = BTreeMap::from([
+        (
+            b"announce".to_vec(),
+            bytes("http://127.0.0.1:8080/announce"),
+        ),
+        (b"info".to_vec(), BencodeValue::Dict(info)),
+    ]);
+    bencode::encode(&BencodeValue::Dict(torrent))
+}
+
+fn bench_torrents(c: &mut Criterion) {
+    for num_pieces in [100, 10_000, 100_000] {
+        let data = torrent_bytes(num_pieces);
+        let torrent = Torrent::from_bytes(&data).expect("bench torrent is valid");
+
+        let mut group = c.benchmark_group(format!("{num_pieces} pieces"));
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_function("bencode::decode", |b| {
+            b.iter(|| bencode::decode(black_box(&data)).unwrap())
+        });
+        group.bench_function("Torrent::from_bytes", |b| {
+            b.iter(|| Torrent::from_bytes(black_box(&data)).unwrap())
+        });
+        group.finish();
+        // cached after the first call, so this is the cost of handing out the cached hash
+        c.bench_function(&format!("{num_pieces} pieces/Info::info_hash_bytes"), |b| {
+            b.iter(|| black_box(&torrent.info).info_hash_bytes())
+        });
+    }
+}
+
+criterion_group!(benches, bench_torrents);
+criterion_main!(benches);
@@ -0,0 +1,47 @@
+// Rough SHA1 throughput of whichever backend src/hash.rs was built with, run with
+// `cargo bench --bench sha1` and again with `--features sha1-asm` or `--features sha1-smol` to
+// compare. Checks the FIPS 180 test vectors first so a faster backend can't be a wrong one.
+use std::hint::black_box;
+use std::time::Instant;
+
+use bittorrent_starter_rust::hash::{sha1, SHA1_BACKEND};
+
+const VECTORS: [(&[u8], &str); 3] = [
+    (b"", "da39a3ee5e6b4b0d3255bfef95601890afd80709"),
+    (b"abc", "a9993e364706816aba3e25717850c26c9cd0d89d"),
+    (
+        b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+        "84983e441c3bd26ebaae4aa1f95129e5e54670f1",
+    ),
+];
+
+fn main() {
+    for (input, expected) in VECTORS {
+        assert_eq!(hex::encode(sha1(input)), expected, "SHA1 of {input:?}");
+    }
+    assert_eq!(
+        hex::encode(sha1(&[b'a'; 1_000_000])),
+        "34aa973cd4c4daa4f61eeb2bdbad27316534016f",
+        "SHA1 of a million 'a's"
+    );
+
+    println!("backend: {SHA1_BACKEND}");
+    // a typical piece, and the size of a large torrent's info dict
+    for (name, len, iterations) in [
+        ("piece/256KiB", 256 * 1024, 2_000),
+        ("info/2MiB", 2 << 20, 200),
+    ] {
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        black_box(sha1(&data)); // warm up
+        let started = Instant::now();
+        for _ in 0..iterations {
+            black_box(sha1(black_box(&data)));
+        }
+        let elapsed = started.elapsed();
+        println!(
+            "{name:<40} {:>12.2?}/iter {:>8.0} MiB/s",
+            elapsed / iterations,
+            (len as f64 * iterations as f64) / elapsed.as_secs_f64() / (1 << 20) as f64
+        );
+    }
+}
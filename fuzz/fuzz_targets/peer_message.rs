@@ -0,0 +1,20 @@
+// Feeds arbitrary bytes to the peer message reader, run with `cargo fuzz run peer_message`.
+// Reading must end in an error once the input runs out, never in a panic or a huge allocation.
+#![no_main]
+
+use bittorrent_starter_rust::peer::Stream;
+use libfuzzer_sys::fuzz_target;
+use tokio::io::AsyncWriteExt;
+
+fuzz_target!(|data: &[u8]| {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("build runtime");
+    runtime.block_on(async {
+        let (mut peer, ours) = tokio::io::duplex(data.len().max(1));
+        peer.write_all(data).await.expect("write fuzz input");
+        drop(peer); // the reader sees eof after the input
+        let mut stream = Stream::new(ours);
+        while stream.read_message().await.is_ok() {}
+    });
+});
@@ -0,0 +1,363 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+
+/// A decoded bencode value. Byte strings are kept as raw bytes since they are not
+/// guaranteed to be valid UTF-8 (e.g. the `pieces` hashes).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BencodeValue {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<BencodeValue>),
+    Dict(BTreeMap<Vec<u8>, BencodeValue>),
+}
+
+/// Encodes `value`, dictionary keys come out sorted as the spec requires.
+pub fn encode(value: &BencodeValue) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_into(value, &mut out);
+    out
+}
+
+fn encode_into(value: &BencodeValue, out: &mut Vec<u8>) {
+    match value {
+        BencodeValue::Int(n) => out.extend_from_slice(format!("i{n}e").as_bytes()),
+        BencodeValue::Bytes(bytes) => {
+            out.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+            out.extend_from_slice(bytes);
+        }
+        BencodeValue::List(values) => {
+            out.push(b'l');
+            for value in values {
+                encode_into(value, out);
+            }
+            out.push(b'e');
+        }
+        BencodeValue::Dict(map) => {
+            out.push(b'd');
+            // BTreeMap iterates in key order
+            for (key, value) in map {
+                encode_into(&BencodeValue::Bytes(key.clone()), out);
+                encode_into(value, out);
+            }
+            out.push(b'e');
+        }
+    }
+}
+
+/// Finds the value of `key` in the bencoded dict at the start of `input` and returns it as the
+/// raw bytes it occupies in `input`, without decoding and re-encoding it.
+pub fn dict_value_bytes<'a>(input: &'a [u8], key: &[u8]) -> Result<Option<&'a [u8]>> {
+    if input.first() != Some(&b'd') {
+        return Err(anyhow!("Expected a dict"));
+    }
+    let mut remainder = &input[1..];
+    while !remainder.starts_with(b"e") {
+        let (current, value_start) = decode_nested(remainder, 1)?;
+        let (_, rest) = decode_nested(value_start, 1)?;
+        if matches!(&current, BencodeValue::Bytes(current) if current == key) {
+            return Ok(Some(&value_start[..value_start.len() - rest.len()]));
+        }
+        remainder = rest;
+    }
+    Ok(None)
+}
+
+/// How deeply lists and dicts may nest before `decode` gives up. Decoding recurses, so without
+/// a limit a few hundred thousand `l`s overflow the stack, while real torrents and tracker
+/// responses stay in the single digits.
+pub const MAX_DEPTH: usize = 256;
+
+/// Checks that lists and dicts in the first value of `input` nest no deeper than `MAX_DEPTH`,
+/// without decoding anything. serde_bencode recurses just like `decode`, so untrusted input goes
+/// through this before `serde_bencode::from_bytes`. Anything else that is wrong with the input is
+/// left for the decoder to report.
+pub fn check_depth(input: &[u8]) -> Result<()> {
+    let mut depth = 0;
+    let mut at = 0;
+    while let Some(&byte) = input.get(at) {
+        match byte {
+            b'l' | b'd' => {
+                depth += 1;
+                if depth > MAX_DEPTH {
+                    return Err(anyhow!(
+                        "Lists and dicts nested deeper than {MAX_DEPTH} levels"
+                    ));
+                }
+                at += 1;
+            }
+            b'e' if depth > 0 => {
+                depth -= 1;
+                at += 1;
+            }
+            b'i' => match input[at..].iter().position(|&b| b == b'e') {
+                Some(end) => at += end + 1,
+                None => return Ok(()),
+            },
+            b'0'..=b'9' => {
+                let Some(colon) = input[at..].iter().position(|&b| b == b':') else {
+                    return Ok(());
+                };
+                let length = std::str::from_utf8(&input[at..at + colon])
+                    .ok()
+                    .and_then(|digits| digits.parse::<usize>().ok());
+                match length {
+                    Some(length) => at = (at + colon + 1).saturating_add(length),
+                    None => return Ok(()),
+                }
+            }
+            _ => return Ok(()),
+        }
+        if depth == 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Decodes the first bencoded value in `input` and returns it together with the remaining bytes.
+pub fn decode(input: &[u8]) -> Result<(BencodeValue, &[u8])> {
+    decode_nested(input, 0)
+}
+
+// `depth` is the number of lists and dicts around `input`
+fn decode_nested(input: &[u8], depth: usize) -> Result<(BencodeValue, &[u8])> {
+    if matches!(input.first(), Some(b'l' | b'd')) && depth >= MAX_DEPTH {
+        return Err(anyhow!(
+            "Lists and dicts nested deeper than {MAX_DEPTH} levels"
+        ));
+    }
+    // we return a tuple so we can always return the remainder of the input after recursive parsing
+    match input.first() {
+        Some(b'i') => {
+            // integer encoded values look like i25e
+            if let Some(end) = input.iter().position(|&b| b == b'e') {
+                let digits = std::str::from_utf8(&input[1..end])?;
+                // the spec only allows one way to write each number: no i03e, no i-0e, no i+3e
+                let unsigned = digits.strip_prefix('-').unwrap_or(digits);
+                let problem = match unsigned.as_bytes() {
+                    [b'0'] if digits == "-0" => Some("negative zero"),
+                    [b'0', _, ..] => Some("leading zero"),
+                    [b'+', ..] => Some("plus sign"),
+                    _ => None,
+                };
+                if let Some(problem) = problem {
+                    return Err(anyhow!("Invalid integer {digits:?}: {problem}"));
+                }
+                let n = digits
+                    .parse::<i64>()
+                    .map_err(|e| anyhow!("Invalid integer {digits:?}: {e}"))?;
+                return Ok((BencodeValue::Int(n), &input[end + 1..]));
+            }
+        }
+        Some(b'l') => {
+            let mut values = Vec::new();
+            let mut remainder = &input[1..]; // lists look like l5:helloi52ee
+            while !remainder.starts_with(b"e") {
+                // e character is the terminator
+                let (value, rest) = decode_nested(remainder, depth + 1)?;
+                values.push(value);
+                remainder = rest;
+            }
+            // skip the e terminating the list
+            return Ok((BencodeValue::List(values), &remainder[1..]));
+        }
+        Some(b'd') => {
+            let mut map = BTreeMap::new();
+            let mut remainder = &input[1..]; // dictionaries look like d3:foo3:bar5:helloi52ee
+            while !remainder.starts_with(b"e") {
+                let key = match decode_nested(remainder, depth + 1)? {
+                    (BencodeValue::Bytes(key), rest) => {
+                        remainder = rest;
+                        key
+                    }
+                    (k, _) => return Err(anyhow!("Dict keys must be strings, not {k:?}")),
+                };
+                let (value, rest) = decode_nested(remainder, depth + 1)?;
+                map.insert(key, value);
+                remainder = rest;
+            }
+            // skip the e terminating the dict
+            return Ok((BencodeValue::Dict(map), &remainder[1..]));
+        }
+        Some(b'0'..=b'9') => {
+            // string encoded values look like 5:hello
+            if let Some(colon) = input.iter().position(|&b| b == b':') {
+                if let Ok(length) = std::str::from_utf8(&input[..colon])?.parse::<usize>() {
+                    let rest = &input[colon + 1..];
+                    if length > rest.len() {
+                        return Err(anyhow!(
+                            "String declares {length} bytes but only {} are left",
+                            rest.len()
+                        ));
+                    }
+                    return Ok((
+                        BencodeValue::Bytes(rest[..length].to_vec()),
+                        &rest[length..],
+                    ));
+                }
+            }
+        }
+        // lists and dicts missing their terminating e end up here too
+        None => return Err(anyhow!("Unexpected end of input")),
+        _ => {}
+    }
+
+    Err(anyhow!(
+        "Unhandled encoded value: {}",
+        String::from_utf8_lossy(input)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_every_variant() {
+        assert_eq!(
+            decode(b"i-42e").unwrap(),
+            (BencodeValue::Int(-42), &b""[..])
+        );
+        assert_eq!(
+            decode(b"4:spam3:egg").unwrap(),
+            (BencodeValue::Bytes(b"spam".to_vec()), &b"3:egg"[..])
+        );
+        // byte strings don't have to be utf8
+        assert_eq!(
+            decode(b"2:\xff\x00").unwrap().0,
+            BencodeValue::Bytes(vec![0xff, 0])
+        );
+        assert_eq!(
+            decode(b"l4:spami7ee").unwrap().0,
+            BencodeValue::List(vec![
+                BencodeValue::Bytes(b"spam".to_vec()),
+                BencodeValue::Int(7)
+            ])
+        );
+        let (dict, rest) = decode(b"d3:cow3:moo4:listli1eee").unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(
+            dict,
+            BencodeValue::Dict(BTreeMap::from([
+                (b"cow".to_vec(), BencodeValue::Bytes(b"moo".to_vec())),
+                (
+                    b"list".to_vec(),
+                    BencodeValue::List(vec![BencodeValue::Int(1)])
+                ),
+            ]))
+        );
+        assert_eq!(encode(&dict), b"d3:cow3:moo4:listli1eee");
+    }
+
+    #[test]
+    fn dict_keys_must_be_strings() {
+        assert!(decode(b"di1e3:mooe").is_err());
+    }
+
+    #[test]
+    fn truncated_input_is_an_error() {
+        let valid = b"d3:cow3:moo4:spaml1:a1:bi42eee";
+        assert!(decode(valid).is_ok());
+        // every strict prefix of a valid value is missing something
+        for end in 0..valid.len() {
+            assert!(decode(&valid[..end]).is_err(), "{:?}", &valid[..end]);
+        }
+    }
+
+    #[test]
+    fn string_longer_than_input_is_an_error() {
+        let error = decode(b"10:short").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "String declares 10 bytes but only 5 are left"
+        );
+        assert!(decode(b"99999999999999999999999:x").is_err());
+        assert!(decode(b"5").is_err());
+    }
+
+    #[test]
+    fn non_numeric_integer_is_an_error() {
+        for input in [&b"iabce"[..], b"i12", b"i1.5e", b"i--1e", b"ie"] {
+            assert!(decode(input).is_err(), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn empty_input_and_empty_containers() {
+        assert_eq!(
+            decode(b"").unwrap_err().to_string(),
+            "Unexpected end of input"
+        );
+        assert_eq!(
+            decode(b"le").unwrap(),
+            (BencodeValue::List(vec![]), &b""[..])
+        );
+        assert_eq!(
+            decode(b"de").unwrap(),
+            (BencodeValue::Dict(BTreeMap::new()), &b""[..])
+        );
+        assert_eq!(
+            decode(b"0:").unwrap(),
+            (BencodeValue::Bytes(vec![]), &b""[..])
+        );
+        assert_eq!(encode(&BencodeValue::List(vec![])), b"le");
+        assert_eq!(encode(&BencodeValue::Dict(BTreeMap::new())), b"de");
+    }
+
+    #[test]
+    fn integers_have_one_spelling() {
+        assert_eq!(decode(b"i0e").unwrap().0, BencodeValue::Int(0));
+        assert_eq!(decode(b"i-10e").unwrap().0, BencodeValue::Int(-10));
+        for (input, problem) in [
+            (&b"i03e"[..], "leading zero"),
+            (b"i00e", "leading zero"),
+            (b"i-03e", "leading zero"),
+            (b"i-0e", "negative zero"),
+            (b"i+3e", "plus sign"),
+        ] {
+            let error = decode(input).unwrap_err().to_string();
+            assert!(error.ends_with(problem), "{input:?}: {error}");
+        }
+        // past i64
+        assert!(decode(b"i9223372036854775808e").is_err());
+    }
+
+    #[test]
+    fn nesting_up_to_max_depth_decodes() {
+        let input = [vec![b'l'; MAX_DEPTH], vec![b'e'; MAX_DEPTH]].concat();
+        let (mut value, rest) = decode(&input).unwrap();
+        assert!(rest.is_empty());
+        let mut depth = 0;
+        while let BencodeValue::List(mut values) = value {
+            depth += 1;
+            value = match values.pop() {
+                Some(inner) => inner,
+                None => break,
+            };
+        }
+        assert_eq!(depth, MAX_DEPTH);
+        assert!(check_depth(&input).is_ok());
+    }
+
+    #[test]
+    fn nesting_past_max_depth_is_an_error() {
+        let input = [vec![b'l'; MAX_DEPTH + 1], vec![b'e'; MAX_DEPTH + 1]].concat();
+        assert!(decode(&input).is_err());
+        assert!(check_depth(&input).is_err());
+        // used to overflow the stack
+        let input = vec![b'l'; 100_000];
+        assert!(decode(&input).is_err());
+        assert!(check_depth(&input).is_err());
+        let input = [&b"d1:a"[..], &vec![b'd'; 100_000]].concat();
+        assert!(decode(&input).is_err());
+        assert!(check_depth(&input).is_err());
+    }
+
+    #[test]
+    fn check_depth_skips_string_contents() {
+        // the l's and d's inside strings and integers are not nesting
+        let input = [&b"l"[..], b"400:", &[b'l'; 400], b"i1e", b"e"].concat();
+        assert!(check_depth(&input).is_ok());
+        assert!(decode(&input).is_ok());
+    }
+}
@@ -0,0 +1,3032 @@
+use anyhow::{anyhow, Context, Result};
+use md5::{Digest, Md5};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch, Notify, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, info_span, trace, warn, Instrument};
+
+use self::ip_filter::IpFilter;
+use self::selector::PieceSelector;
+use self::writer::PieceWriter;
+use crate::peer::{
+    bitfield::BitField,
+    block_cache::BlockCache,
+    choke::{Choker, CHOKE_INTERVAL, DEFAULT_UNCHOKE_SLOTS},
+    handshake::{Handshake, DEFAULT_PEER_ID},
+    message::MessageType,
+    queue::{RequestQueue, DEFAULT_MAX_QUEUE_DEPTH, MIN_QUEUE_DEPTH},
+    rate_limit::RateLimiter,
+    PeerConnection, Stream, DEFAULT_BLOCK_SIZE, DEFAULT_PIECE_TIMEOUT, DEFAULT_TIMEOUT,
+    KEEP_ALIVE_INTERVAL,
+};
+use crate::torrent::{Keys, Torrent};
+
+/// Largest block request we answer when uploading, most clients never ask for more than 16KiB.
+pub const MAX_UPLOAD_BLOCK: u32 = 128 * 1024;
+/// Once fewer than this many pieces are left, idle peers start downloading pieces that are
+/// already in flight on other peers (endgame mode) so one slow peer can't stall the finish.
+pub const ENDGAME_THRESHOLD: usize = 5;
+pub const DEFAULT_MAX_PEERS: usize = 30;
+/// How many times a piece may fail verification before the download gives up on it.
+pub const DEFAULT_MAX_PIECE_FAILURES: u32 = 5;
+/// Pieces a peer may start in a row before the next one goes to an idle peer, if there is one.
+pub const DEFAULT_MAX_CONSECUTIVE_PIECES: u32 = 8;
+/// How often a peer whose connection broke is reconnected before it is given up on.
+pub const PEER_RECONNECTS: u32 = 3;
+// doubled after every reconnect to the same peer, up to MAX_RECONNECT_BACKOFF
+const RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(2);
+/// How long `availability` waits for a peer to connect and send its bitfield.
+pub const BITFIELD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Settings that apply to every peer connection of a download.
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Our peer id, sent in every handshake. Must be exactly 20 bytes.
+    pub peer_id: String,
+    /// Shared by every peer connection so the cap applies to the download as a whole.
+    pub download_limiter: RateLimiter,
+    /// Same for the blocks we send to peers.
+    pub upload_limiter: RateLimiter,
+    /// Bytes requested per block message.
+    pub block_size: u32,
+    /// Most block requests in flight per peer, the actual number adapts to the peer's speed.
+    pub max_requests: usize,
+    /// Most peer connections open at the same time, the rest wait for a free slot.
+    pub max_peers: usize,
+    /// Connection slots shared with other downloads, see `session::Session`. Each download gets
+    /// its own `max_peers` slots when None.
+    pub connections: Option<Arc<Semaphore>>,
+    /// How long connecting to a peer, the handshake, waiting to be unchoked and each block may
+    /// take. A whole piece still gets `DEFAULT_PIECE_TIMEOUT`.
+    pub timeout: Duration,
+    /// Check every piece against its SHA1 from the torrent. Only turn off for peers you trust.
+    pub verify: bool,
+    /// Hash failures after which a piece is given up on, see `DownloadError::PiecesFailed`.
+    /// Pieces lost to broken connections don't count, those are retried as long as there are peers.
+    pub max_piece_failures: u32,
+    /// Pieces a peer may start in a row while other peers sit idle. Once it has, the next piece
+    /// is kept for one of the idle peers, which also gets a chance to show it got faster. 0 for
+    /// no limit.
+    pub max_consecutive_pieces: u32,
+    /// Cancelling it stops the download, `download_all` then fails with `DownloadError::Cancelled`.
+    pub cancel: CancellationToken,
+    /// Where the download reports its `ClientState`, shared by all clones. Follow it with `state()`.
+    pub state: Arc<watch::Sender<ClientState>>,
+    /// Whether peers should hold off on new pieces, shared by all clones. Use `handle()` to flip it.
+    pub paused: Arc<watch::Sender<bool>>,
+    /// Connected peers and their piece throughput in bytes/sec, shared by all clones. Follow it
+    /// with `ranking()`.
+    pub ranking: Arc<watch::Sender<Vec<(SocketAddrV4, f64)>>>,
+    /// Peers learned after the download started, e.g. from re-announces, shared by all clones.
+    /// Unknown ones get connected to. Add to it with `DownloadHandle::add_peers`.
+    pub announced: Arc<watch::Sender<Vec<SocketAddrV4>>>,
+    /// Pieces downloaded so far and pieces wanted in total, shared by all clones. Follow it
+    /// with `progress()`.
+    pub progress: Arc<watch::Sender<(usize, usize)>>,
+    /// Totals of the last download started with these options, shared by all clones. Read it
+    /// with `summary()`.
+    pub summary: Arc<watch::Sender<DownloadSummary>>,
+    /// Peers whose address it doesn't allow are never connected to.
+    pub ip_filter: IpFilter,
+    /// Only these pieces are downloaded, every piece when None. See `Torrent::file_pieces`.
+    pub pieces: Option<BTreeSet<u32>>,
+    /// Also accept peers connecting to us on this address and upload the pieces we already have.
+    /// They take connection slots from `max_peers` like the peers we connect to.
+    pub listen: Option<SocketAddr>,
+    /// How many of the peers connected to us through `listen` may download at once, the ones
+    /// that took blocks from us fastest over the last `CHOKE_INTERVAL`. One more gets picked at
+    /// random, see `Choker`.
+    pub unchoke_slots: usize,
+    /// Every verified piece is written here by the peer that downloaded it and then dropped, so
+    /// the files fill up while the download runs and the torrent never has to fit in memory.
+    /// Uploads read the pieces back from it.
+    pub writer: Option<Arc<PieceWriter>>,
+    /// Pieces `writer`'s files already hold from an earlier run, see `read_resume`. They count
+    /// as downloaded and get uploaded, but are never requested. Only used together with `writer`.
+    pub resumed: BTreeSet<u32>,
+}
+
+/// Controls a running download from the outside. Get one from the `DownloadOptions` the download
+/// was started with, it stays connected to them across clones.
+#[derive(Debug, Clone)]
+pub struct DownloadHandle {
+    cancel: CancellationToken,
+    paused: Arc<watch::Sender<bool>>,
+    announced: Arc<watch::Sender<Vec<SocketAddrV4>>>,
+}
+
+impl DownloadHandle {
+    /// Peers finish the piece they are on and then stop requesting, their connections stay open.
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Connects to the peers the download doesn't know yet, e.g. the ones from a re-announce.
+    pub fn add_peers(&self, peers: &[SocketAddrV4]) {
+        self.announced
+            .send_modify(|announced| announced.extend_from_slice(peers));
+    }
+
+    /// Stops the download for good, it then fails with `DownloadError::Cancelled` which tells
+    /// which pieces were downloaded so far, see `write_partial`.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+}
+
+/// The phase a download is in, for showing a status line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientState {
+    Connecting,
+    Handshaking,
+    Downloading,
+    Seeding,
+    Verifying,
+    Done,
+    Error,
+}
+
+impl ClientState {
+    // with many peers at once a late peer still handshaking must not put the whole download back
+    // a step, only downloading and verifying alternate
+    fn stage(self) -> u8 {
+        match self {
+            ClientState::Connecting => 0,
+            ClientState::Handshaking => 1,
+            ClientState::Downloading | ClientState::Verifying => 2,
+            ClientState::Seeding => 3,
+            ClientState::Done | ClientState::Error => 4,
+        }
+    }
+}
+
+impl DownloadOptions {
+    /// Follows the state of downloads started with these options.
+    pub fn state(&self) -> watch::Receiver<ClientState> {
+        self.state.subscribe()
+    }
+
+    /// Peers of downloads started with these options, fastest first. Peers that haven't
+    /// delivered a piece yet aren't ranked.
+    pub fn ranking(&self) -> watch::Receiver<Vec<(SocketAddrV4, f64)>> {
+        self.ranking.subscribe()
+    }
+
+    /// Follows how many pieces downloads started with these options have.
+    pub fn progress(&self) -> watch::Receiver<(usize, usize)> {
+        self.progress.subscribe()
+    }
+
+    /// What the last `download_all` with these options did, all zero before one finished.
+    pub fn summary(&self) -> DownloadSummary {
+        self.summary.borrow().clone()
+    }
+
+    pub fn handle(&self) -> DownloadHandle {
+        DownloadHandle {
+            cancel: self.cancel.clone(),
+            paused: self.paused.clone(),
+            announced: self.announced.clone(),
+        }
+    }
+
+    fn set_state(&self, state: ClientState) {
+        self.state.send_if_modified(|current| {
+            if *current == state || state.stage() < current.stage() {
+                return false;
+            }
+            *current = state;
+            true
+        });
+    }
+}
+
+/// What a download did, for telling the user once it is over.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DownloadSummary {
+    /// Pieces we downloaded and their size in bytes. Unwanted and resumed pieces aren't counted.
+    pub pieces: usize,
+    pub bytes: u64,
+    /// Everything received from peers, including protocol overhead and discarded data.
+    pub bytes_read: u64,
+    pub elapsed: Duration,
+    /// Peers that delivered at least one piece.
+    pub peers: usize,
+    /// Pieces that had to be downloaded again because a peer dropped or sent corrupt data.
+    pub retried: usize,
+    /// Whether every piece was checked against its hash, false with `verify` off.
+    pub verified: bool,
+}
+
+impl DownloadSummary {
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl fmt::Display for DownloadSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Downloaded {} pieces ({} bytes) in {:.2?}, {} bytes/sec",
+            self.pieces,
+            self.bytes,
+            self.elapsed,
+            self.bytes_per_sec() as u64
+        )?;
+        writeln!(f, "Peers used: {}", self.peers)?;
+        writeln!(f, "Pieces retried: {}", self.retried)?;
+        if self.verified {
+            write!(f, "All pieces matched their hashes")
+        } else {
+            write!(f, "Pieces were not checked against their hashes")
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DownloadError {
+    /// `completed[i]` tells whether piece `i` was verified before the cancel. `pieces[i]` holds
+    /// its data, unless the pieces went to `DownloadOptions::writer` and `pieces` is empty.
+    #[error("Download cancelled")]
+    Cancelled {
+        completed: Vec<bool>,
+        pieces: Vec<Option<Vec<u8>>>,
+    },
+    #[error("Hashes for piece {piece} do NOT match!")]
+    HashMismatch { piece: u32 },
+    /// Every other piece was downloaded, these kept failing verification.
+    #[error("Gave up on pieces {pieces:?}, they failed verification too often")]
+    PiecesFailed { pieces: Vec<u32> },
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            peer_id: String::from(DEFAULT_PEER_ID),
+            download_limiter: RateLimiter::default(),
+            upload_limiter: RateLimiter::default(),
+            block_size: DEFAULT_BLOCK_SIZE,
+            max_requests: DEFAULT_MAX_QUEUE_DEPTH,
+            max_peers: DEFAULT_MAX_PEERS,
+            connections: None,
+            timeout: DEFAULT_TIMEOUT,
+            verify: true,
+            max_piece_failures: DEFAULT_MAX_PIECE_FAILURES,
+            max_consecutive_pieces: DEFAULT_MAX_CONSECUTIVE_PIECES,
+            cancel: CancellationToken::new(),
+            state: Arc::new(watch::channel(ClientState::Connecting).0),
+            paused: Arc::new(watch::channel(false).0),
+            ranking: Arc::new(watch::channel(Vec::new()).0),
+            announced: Arc::new(watch::channel(Vec::new()).0),
+            progress: Arc::new(watch::channel((0, 0)).0),
+            summary: Arc::new(watch::channel(DownloadSummary::default()).0),
+            ip_filter: IpFilter::default(),
+            pieces: None,
+            listen: None,
+            unchoke_slots: DEFAULT_UNCHOKE_SLOTS,
+            writer: None,
+            resumed: BTreeSet::new(),
+        }
+    }
+}
+
+// everything the peer workers of a single download share
+struct Shared {
+    torrent: Torrent,
+    options: DownloadOptions,
+    scheduler: Mutex<Scheduler>,
+    notify: Notify,
+    // one permit per open connection, so large swarms can't exhaust file descriptors
+    connections: Arc<Semaphore>,
+    // bytes received over all connections, for the throughput readout at the end
+    bytes_read: AtomicU64,
+    // peers learned from other peers (ut_pex), download_all decides whether to connect
+    new_peers: mpsc::UnboundedSender<SocketAddrV4>,
+    // blocks of pieces whose peer dropped mid-piece, for the peer that takes the piece over
+    blocks: BlockCache,
+    // decides which of the peers connected to us may download, rechoked every CHOKE_INTERVAL
+    choker: Mutex<Choker>,
+    // the peers allowed to download right now, serve_peer follows it
+    unchoked: watch::Sender<Vec<SocketAddr>>,
+}
+
+/// Downloads every piece of the torrent from all `peers` concurrently and returns the assembled file bytes.
+/// `selector` decides which piece each peer works on next. Pieces left out of `options.pieces`
+/// come back as zeroes. With `options.writer` every piece goes to disk as soon as it is verified
+/// and isn't kept in memory, the returned bytes are empty then.
+pub async fn download_all(
+    torrent: &Torrent,
+    peers: &[SocketAddrV4],
+    selector: Box<dyn PieceSelector>,
+    options: &DownloadOptions,
+) -> Result<Vec<u8>> {
+    options.set_state(ClientState::Connecting);
+    let started = Instant::now();
+    let (new_peers, mut discovered) = mpsc::unbounded_channel();
+    let shared = Arc::new(Shared {
+        torrent: torrent.clone(),
+        options: options.clone(),
+        scheduler: Mutex::new(
+            Scheduler::new(
+                torrent.num_pieces(),
+                options.pieces.as_ref(),
+                options.max_piece_failures,
+                selector,
+            )
+            .with_max_streak(options.max_consecutive_pieces)
+            .with_resumed(&options.resumed),
+        ),
+        notify: Notify::new(),
+        connections: options
+            .connections
+            .clone()
+            .unwrap_or_else(|| Arc::new(Semaphore::new(options.max_peers))),
+        bytes_read: AtomicU64::new(0),
+        new_peers,
+        blocks: BlockCache::default(),
+        choker: Mutex::new(Choker::new(options.unchoke_slots)),
+        unchoked: watch::channel(Vec::new()).0,
+    });
+    options.progress.send_replace(shared.scheduler().progress());
+
+    let listener = match options.listen {
+        Some(address) => Some(
+            TcpListener::bind(address)
+                .await
+                .context(format!("CTX: listen on {address}"))?,
+        ),
+        None => None,
+    };
+    // peers that connected to us, they only download so they don't decide when we are done
+    let mut uploads = JoinSet::new();
+
+    let mut workers = JoinSet::new();
+    let mut known = HashSet::new();
+    for &peer in peers {
+        if known.insert(peer) && is_allowed(&peer, options) {
+            workers.spawn(peer_worker(peer, shared.clone()).instrument(info_span!("peer", %peer)));
+        }
+    }
+
+    let mut announced = options.announced.subscribe();
+    let mut last_error = anyhow!("No peers to download from");
+    let mut rechoke = tokio::time::interval(CHOKE_INTERVAL);
+    rechoke.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_rechoke = Instant::now();
+    loop {
+        tokio::select! {
+            // a worker may hand over peers right before it exits, pick those up first
+            biased;
+            Some(peer) = discovered.recv() => {
+                if known.insert(peer) && is_allowed(&peer, options) {
+                    debug!(%peer, "connecting to peer found through peer exchange");
+                    workers.spawn(peer_worker(peer, shared.clone()).instrument(info_span!("peer", %peer)));
+                }
+            }
+            Ok(()) = announced.changed() => {
+                let peers = announced.borrow_and_update().clone();
+                for peer in peers {
+                    if known.insert(peer) && is_allowed(&peer, options) {
+                        debug!(%peer, "connecting to peer from a later announce");
+                        workers.spawn(peer_worker(peer, shared.clone()).instrument(info_span!("peer", %peer)));
+                    }
+                }
+            }
+            _ = rechoke.tick(), if listener.is_some() => {
+                let unchoked = shared.choker().rechoke(last_rechoke.elapsed());
+                last_rechoke = Instant::now();
+                debug!(?unchoked, "rechoked");
+                shared.unchoked.send_replace(unchoked);
+            }
+            Ok((socket, peer)) = accept(listener.as_ref()) => {
+                if options.ip_filter.allows(peer.ip()) {
+                    let shared = shared.clone();
+                    uploads.spawn(
+                        async move {
+                            if let Err(e) = serve_peer(socket, peer, &shared).await {
+                                debug!(error = format!("{e:#}"), "incoming peer dropped");
+                            }
+                        }
+                        .instrument(info_span!("incoming", %peer)),
+                    );
+                } else {
+                    debug!(%peer, "turning away peer blocked by the ip filter");
+                }
+            }
+            _ = shared.options.cancel.cancelled() => {
+                info!("download cancelled");
+                break;
+            }
+            joined = workers.join_next() => {
+                let Some(joined) = joined else {
+                    break;
+                };
+                if let Err(e) = joined.context("CTX: peer task panicked")? {
+                    warn!(error = format!("{e:#}"), "peer dropped");
+                    last_error = e;
+                }
+                if shared.scheduler().is_finished() {
+                    break;
+                }
+            }
+        }
+    }
+    // peers still busy with endgame duplicates are no longer needed
+    workers.abort_all();
+    uploads.abort_all();
+    let bytes_read = shared.bytes_read.load(Ordering::Relaxed);
+    let elapsed = started.elapsed();
+    info!(
+        bytes_read,
+        ?elapsed,
+        bytes_per_sec = (bytes_read as f64 / elapsed.as_secs_f64()) as u64,
+        "transfer finished"
+    );
+
+    let mut scheduler = shared.scheduler();
+    let downloaded: Vec<u32> = (0..torrent.num_pieces() as u32)
+        .filter(|&piece| scheduler.completed[piece as usize] && !options.resumed.contains(&piece))
+        .collect();
+    options.summary.send_replace(DownloadSummary {
+        pieces: downloaded.len(),
+        bytes: downloaded
+            .iter()
+            .map(|&piece| torrent.piece_size(piece))
+            .sum(),
+        bytes_read,
+        elapsed,
+        peers: scheduler.contributors.len(),
+        retried: scheduler.retried.len(),
+        verified: options.verify,
+    });
+    if !scheduler.corrupt.is_empty() {
+        warn!(pieces = ?scheduler.corrupt, "peers sent corrupt data for these pieces, they were fetched again");
+    }
+    options.set_state(if scheduler.is_done() {
+        ClientState::Done
+    } else {
+        ClientState::Error
+    });
+    if !scheduler.is_done() && shared.options.cancel.is_cancelled() {
+        return Err(DownloadError::Cancelled {
+            completed: std::mem::take(&mut scheduler.completed),
+            pieces: if options.writer.is_some() {
+                Vec::new()
+            } else {
+                std::mem::take(&mut scheduler.data)
+            },
+        }
+        .into());
+    }
+    if !scheduler.failed.is_empty() && scheduler.is_finished() {
+        return Err(DownloadError::PiecesFailed {
+            pieces: scheduler.failed.iter().copied().collect(),
+        }
+        .into());
+    }
+    if !scheduler.is_done() {
+        return Err(last_error.context(format!(
+            "CTX: {} pieces could not be downloaded",
+            scheduler.remaining
+        )));
+    }
+    if options.writer.is_some() {
+        return Ok(Vec::new());
+    }
+    let data: Vec<u8> = scheduler
+        .data
+        .drain(..)
+        .enumerate()
+        .flat_map(|(index, piece)| {
+            piece.unwrap_or_else(|| vec![0; torrent.piece_size(index as u32) as usize])
+        })
+        .collect();
+    check_length(torrent, &data).context("CTX: assemble pieces")?;
+    Ok(data)
+}
+
+/// Makes sure the assembled download is exactly as long as the torrent's files together, a short
+/// or overlong buffer means pieces went missing or got the wrong size somewhere.
+pub fn check_length(torrent: &Torrent, data: &[u8]) -> Result<()> {
+    if data.len() as u64 != torrent.total_length() {
+        return Err(anyhow!(
+            "Downloaded {} bytes but the torrent has {}",
+            data.len(),
+            torrent.total_length()
+        ));
+    }
+    Ok(())
+}
+
+/// Writes the downloaded bytes to `output`. For multi-file torrents `output` is the base
+/// directory and every file is written to its `path` below it.
+pub fn write_output(torrent: &Torrent, output: &Path, data: &[u8]) -> Result<()> {
+    let all = (0..torrent.files().len()).collect();
+    write_files(torrent, output, data, &all)
+}
+
+/// Like `write_output` but only creates the files at the given indices of `Torrent::files`,
+/// the bytes of every other file are skipped.
+pub fn write_files(
+    torrent: &Torrent,
+    output: &Path,
+    data: &[u8],
+    selected: &BTreeSet<usize>,
+) -> Result<()> {
+    check_length(torrent, data)?;
+    let writer = PieceWriter::create(torrent, output, selected)?;
+    for (index, piece) in data.chunks(torrent.info.piece_length as usize).enumerate() {
+        writer.write_piece(index as u32, piece)?;
+    }
+    Ok(())
+}
+
+/// Checks what `write_files` left on disk: every selected file has to have its length from the
+/// torrent, and files the torrent gives an `md5sum` for have to match it.
+pub fn verify_files(torrent: &Torrent, output: &Path, selected: &BTreeSet<usize>) -> Result<()> {
+    let files = match &torrent.info.keys {
+        Keys::SingleFile { length } => {
+            vec![(
+                output.to_path_buf(),
+                *length,
+                torrent.info.md5sum.as_deref(),
+            )]
+        }
+        Keys::MultiFile { files } => selected
+            .iter()
+            .filter_map(|&index| files.get(index))
+            .map(|file| {
+                let path = sanitize_path(output, file.display_path())?;
+                Ok((path, file.length, file.md5sum.as_deref()))
+            })
+            .collect::<Result<_>>()?,
+    };
+    for (path, length, md5sum) in files {
+        let size = fs::metadata(&path)
+            .context(format!("CTX: stat {}", path.display()))?
+            .len();
+        if size != length {
+            return Err(anyhow!(
+                "{} is {size} bytes but the torrent has {length}",
+                path.display()
+            ));
+        }
+        let Some(expected) = md5sum else {
+            continue;
+        };
+        let contents = fs::read(&path).context(format!("CTX: read {}", path.display()))?;
+        let actual = hex::encode(Md5::digest(contents));
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            return Err(anyhow!(
+                "MD5 of {} is {actual} but the torrent says {expected}",
+                path.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Where the resume file of `output` goes: `<output>.resume`, with the suffix added to the whole
+/// file name so `a.iso` and `a.img` don't share one.
+pub fn resume_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".resume");
+    PathBuf::from(path)
+}
+
+/// Saves which pieces of `output` a cancelled download got, as a bitfield in `<output>.resume`.
+pub fn write_resume(torrent: &Torrent, output: &Path, completed: &[bool]) -> Result<()> {
+    let mut resume = BitField::empty(torrent.num_pieces());
+    for (index, _) in completed.iter().enumerate().filter(|(_, &done)| done) {
+        resume.set_piece(index);
+    }
+    let resume_path = resume_path(output);
+    fs::write(&resume_path, resume.0)
+        .context(format!("CTX: write resume file {}", resume_path.display()))?;
+    Ok(())
+}
+
+/// Saves what we have of an unfinished download where it belongs: every piece goes into the
+/// output files like `write_output` would write it and a bitfield of the pieces we have goes to
+/// `<output>.resume`, for `read_resume` to pick up.
+pub fn write_partial(torrent: &Torrent, output: &Path, pieces: &[Option<Vec<u8>>]) -> Result<()> {
+    let all = (0..torrent.files().len()).collect();
+    let writer = PieceWriter::create(torrent, output, &all)?;
+    for (index, piece) in pieces.iter().enumerate() {
+        if let Some(piece) = piece {
+            writer.write_piece(index as u32, piece)?;
+        }
+    }
+    writer.sync()?;
+    let completed: Vec<bool> = pieces.iter().map(Option::is_some).collect();
+    write_resume(torrent, output, &completed)
+}
+
+/// The pieces `<output>.resume` says an earlier run saved that still match their hashes in
+/// `writer`'s files, empty without a resume file. Pieces that changed on disk since are left
+/// out and get downloaded again.
+pub fn read_resume(
+    torrent: &Torrent,
+    output: &Path,
+    writer: &PieceWriter,
+) -> Result<BTreeSet<u32>> {
+    let resume_path = resume_path(output);
+    let resume = match fs::read(&resume_path) {
+        Ok(resume) => BitField(resume),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        Err(e) => {
+            return Err(e).context(format!("CTX: read resume file {}", resume_path.display()))
+        }
+    };
+    let mut resumed = BTreeSet::new();
+    for piece in resume
+        .pieces()
+        .filter(|&piece| piece < torrent.num_pieces())
+    {
+        match writer.read_piece(piece as u32)? {
+            Some(data) if torrent.verify_piece(piece, &data) => {
+                resumed.insert(piece as u32);
+            }
+            _ => debug!(piece, "saved piece doesn't match its hash anymore"),
+        }
+    }
+    Ok(resumed)
+}
+
+/// Joins the path segments of a torrent file onto `base`. Torrents are untrusted input, so empty
+/// segments, `.`/`..`, absolute paths and segments hiding separators are all rejected to make sure
+/// the result can't escape `base`.
+pub fn sanitize_path(base: &Path, segments: &[String]) -> Result<PathBuf> {
+    if segments.is_empty() {
+        return Err(anyhow!("File path in torrent is empty"));
+    }
+    let mut path = base.to_path_buf();
+    for segment in segments {
+        let mut components = Path::new(segment).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) if name == segment.as_str() => path.push(name),
+            _ => {
+                return Err(anyhow!(
+                    "Refusing unsafe path segment {segment:?} in torrent"
+                ))
+            }
+        }
+    }
+    if !path.starts_with(base) {
+        return Err(anyhow!(
+            "Path {} escapes {}",
+            path.display(),
+            base.display()
+        ));
+    }
+    Ok(path)
+}
+
+/// Downloads and verifies a single piece, moving on to the next peer whenever one fails
+/// (connection error, bad message or a hash mismatch).
+pub async fn download_piece(
+    torrent: &Torrent,
+    peers: &[SocketAddrV4],
+    piece: u32,
+    options: &DownloadOptions,
+) -> Result<Vec<u8>> {
+    let mut pieces = download_pieces(torrent, peers, &[piece], options).await?;
+    Ok(pieces.remove(0))
+}
+
+/// Downloads and verifies `pieces` one after the other, in the order given. The connection to a
+/// peer is kept for the next piece until it fails, then that piece moves on to the next peer
+/// like in `download_piece`.
+pub async fn download_pieces(
+    torrent: &Torrent,
+    peers: &[SocketAddrV4],
+    pieces: &[u32],
+    options: &DownloadOptions,
+) -> Result<Vec<Vec<u8>>> {
+    options.set_state(ClientState::Connecting);
+    let peers: Vec<&SocketAddrV4> = peers
+        .iter()
+        .filter(|peer| is_allowed(peer, options))
+        .collect();
+    // the next peer picks up where a broken off one left the piece
+    let blocks = BlockCache::default();
+    let mut connection: Option<PeerConnection> = None;
+    let mut current = 0; // the peer `connection` is to, or the next one to connect to
+    let mut downloaded = Vec::with_capacity(pieces.len());
+    for &piece in pieces {
+        let mut last_error = anyhow!("No peers to download piece {piece} from");
+        let mut failed = 0;
+        let piece_data = loop {
+            if failed == peers.len() {
+                options.set_state(ClientState::Error);
+                return Err(last_error.context(format!("CTX: all peers failed for piece {piece}")));
+            }
+            let peer = peers[current];
+            let result = match &mut connection {
+                Some(connection) => fetch_piece(connection, torrent, piece, options).await,
+                None => match prepare_connection(torrent, peer, options, &blocks).await {
+                    Ok(prepared) => {
+                        let connection = connection.insert(prepared);
+                        fetch_piece(connection, torrent, piece, options).await
+                    }
+                    Err(e) => Err(e),
+                },
+            };
+            match result {
+                Ok(piece_data) => break piece_data,
+                Err(e) => {
+                    warn!(piece, %peer, error = format!("{e:#}"), "retrying piece with another peer");
+                    last_error = e;
+                    connection = None;
+                    current = (current + 1) % peers.len();
+                    failed += 1;
+                }
+            }
+        };
+        downloaded.push(piece_data);
+    }
+    options.set_state(ClientState::Done);
+    Ok(downloaded)
+}
+
+async fn fetch_piece(
+    connection: &mut PeerConnection,
+    torrent: &Torrent,
+    piece: u32,
+    options: &DownloadOptions,
+) -> Result<Vec<u8>> {
+    options.set_state(ClientState::Downloading);
+    let piece_data: Vec<u8> = connection
+        .download_piece(piece, torrent, DEFAULT_PIECE_TIMEOUT)
+        .await
+        .context("CTX: Get piece data failed")?;
+    if options.verify {
+        options.set_state(ClientState::Verifying);
+        verify_piece(torrent, piece, &piece_data)?;
+    }
+    Ok(piece_data)
+}
+
+/// How many of the peers we asked have each piece, see `availability`.
+#[derive(Debug, Clone, Default)]
+pub struct Availability {
+    /// Peers holding each piece, indexed by piece.
+    pub counts: Vec<usize>,
+    /// Peers whose bitfield went into `counts`.
+    pub peers: usize,
+}
+
+impl Availability {
+    pub fn new(num_pieces: usize) -> Self {
+        Self {
+            counts: vec![0; num_pieces],
+            peers: 0,
+        }
+    }
+
+    /// Counts the pieces of one more peer, bits past the last piece are ignored.
+    pub fn add(&mut self, bitfield: &BitField) {
+        for piece in bitfield.pieces() {
+            if let Some(count) = self.counts.get_mut(piece) {
+                *count += 1;
+            }
+        }
+        self.peers += 1;
+    }
+
+    /// Pieces held by the fewest peers, leaving out the ones nobody has.
+    pub fn rarest(&self) -> Vec<u32> {
+        let fewest = self.counts.iter().copied().filter(|&count| count > 0).min();
+        self.pieces_with(|count| Some(count) == fewest)
+    }
+
+    /// Pieces held by the most peers.
+    pub fn most_common(&self) -> Vec<u32> {
+        let most = self.counts.iter().copied().max().filter(|&count| count > 0);
+        self.pieces_with(|count| Some(count) == most)
+    }
+
+    /// Pieces no peer has, the download can't finish until someone with them shows up.
+    pub fn missing(&self) -> Vec<u32> {
+        self.pieces_with(|count| count == 0)
+    }
+
+    fn pieces_with(&self, matches: impl Fn(usize) -> bool) -> Vec<u32> {
+        (0..self.counts.len() as u32)
+            .filter(|&piece| matches(self.counts[piece as usize]))
+            .collect()
+    }
+}
+
+/// Handshakes with every peer and adds up the bitfields they send, without downloading anything.
+/// Peers that can't be reached or don't send a bitfield within `BITFIELD_TIMEOUT` are skipped.
+pub async fn availability(
+    torrent: &Torrent,
+    peers: &[SocketAddrV4],
+    options: &DownloadOptions,
+) -> Result<Availability> {
+    let connections = Arc::new(Semaphore::new(options.max_peers));
+    let mut bitfields = JoinSet::new();
+    for &peer in peers.iter().filter(|peer| is_allowed(peer, options)) {
+        let (torrent, options, connections) =
+            (torrent.clone(), options.clone(), connections.clone());
+        bitfields.spawn(async move {
+            let _slot = connections.acquire_owned().await?;
+            let bitfield =
+                tokio::time::timeout(BITFIELD_TIMEOUT, read_bitfield(&torrent, &peer, &options))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|bitfield| bitfield);
+            anyhow::Ok((peer, bitfield))
+        });
+    }
+
+    let mut availability = Availability::new(torrent.num_pieces());
+    while let Some(joined) = bitfields.join_next().await {
+        let (peer, bitfield) = joined.context("CTX: peer task panicked")??;
+        match bitfield {
+            Ok(bitfield) => {
+                debug!(%peer, pieces = bitfield.pieces().count(), "got bitfield");
+                availability.add(&bitfield);
+            }
+            Err(e) => warn!(%peer, error = format!("{e:#}"), "skipping peer"),
+        }
+    }
+    if availability.peers == 0 && !peers.is_empty() {
+        return Err(anyhow!("None of the {} peers sent a bitfield", peers.len()));
+    }
+    Ok(availability)
+}
+
+async fn read_bitfield(
+    torrent: &Torrent,
+    peer: &SocketAddrV4,
+    options: &DownloadOptions,
+) -> Result<BitField> {
+    let handshake = Handshake::new(torrent.info.info_hash_bytes())
+        .with_peer_id(options.peer_id.clone())?
+        .with_fast();
+    let (mut stream, _) =
+        Stream::connect_and_handshake_within(peer, handshake, options.timeout).await?;
+    stream
+        .bitfield(torrent.num_pieces())
+        .await
+        .context("CTX: bitfield")
+}
+
+// connects and walks the peer through handshake -> bitfield -> interested -> unchoke
+async fn prepare_connection(
+    torrent: &Torrent,
+    peer: &SocketAddrV4,
+    options: &DownloadOptions,
+    blocks: &BlockCache,
+) -> Result<PeerConnection> {
+    let mut handshake = Handshake::new(torrent.info.info_hash_bytes())
+        .with_peer_id(options.peer_id.clone())?
+        .with_fast();
+    // extensions are only there for ut_pex, which private torrents can't use
+    if !torrent.is_private() {
+        handshake = handshake.with_extensions();
+    }
+    options.set_state(ClientState::Handshaking);
+    let stream = Stream::connect_within(peer, options.timeout)
+        .await?
+        .with_download_limiter(options.download_limiter.clone())
+        .with_block_size(options.block_size)
+        .with_block_cache(blocks.clone())
+        .with_request_queue(RequestQueue::new(MIN_QUEUE_DEPTH, options.max_requests));
+    let mut connection = PeerConnection::new(stream);
+    connection.prepare(handshake, torrent.num_pieces()).await?;
+    Ok(connection)
+}
+
+fn is_allowed(peer: &SocketAddrV4, options: &DownloadOptions) -> bool {
+    let allowed = options.ip_filter.allows((*peer.ip()).into());
+    if !allowed {
+        debug!(%peer, "skipping peer blocked by the ip filter");
+    }
+    allowed
+}
+
+fn verify_piece(torrent: &Torrent, piece: u32, piece_data: &[u8]) -> Result<()> {
+    if !torrent.verify_piece(piece as usize, piece_data) {
+        return Err(DownloadError::HashMismatch { piece }.into());
+    }
+    Ok(())
+}
+
+// keeps a single connection to `peer` and downloads pieces handed out by the scheduler until
+// everything is done; any failure puts the piece back in the queue and drops the peer
+async fn peer_worker(peer: SocketAddrV4, shared: Arc<Shared>) -> Result<()> {
+    let _permit = shared
+        .connections
+        .acquire()
+        .await
+        .context("CTX: connection limit closed")?;
+    let mut reconnects = 0;
+    loop {
+        let (mut stream, mut bitfield) = reconnect(&peer, &shared, &mut reconnects).await?;
+        shared.scheduler().add_peer(peer, &bitfield);
+        let result = download_scheduled_pieces(peer, &mut stream, &mut bitfield, &shared).await;
+        {
+            let mut scheduler = shared.scheduler();
+            scheduler.remove_peer(peer, &bitfield);
+            shared.options.ranking.send_replace(scheduler.ranking());
+        }
+        // peers that left pieces to this one have to pick them up now
+        shared.notify.notify_waiters();
+        match result {
+            Err(e) if is_io_error(&e) && reconnects < PEER_RECONNECTS => {
+                warn!(
+                    error = format!("{e:#}"),
+                    "lost connection to peer, reconnecting"
+                );
+                reconnects += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// connects to `peer`, backing off and trying again as long as the failures look like network
+// trouble and the peer has reconnects left. `reconnects` counts over the whole life of the worker
+// so a peer that keeps dropping us eventually gets dropped for good
+async fn reconnect(
+    peer: &SocketAddrV4,
+    shared: &Shared,
+    reconnects: &mut u32,
+) -> Result<(Stream, BitField)> {
+    loop {
+        if *reconnects > 0 {
+            tokio::time::sleep(reconnect_backoff(*reconnects)).await;
+        }
+        match prepare_connection(&shared.torrent, peer, &shared.options, &shared.blocks)
+            .await
+            .map(PeerConnection::into_parts)
+        {
+            Err(e) if is_io_error(&e) && *reconnects < PEER_RECONNECTS => {
+                debug!(error = format!("{e:#}"), "connecting to peer failed");
+                *reconnects += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// 100ms, 200ms, 400ms, ... for the 1st, 2nd, 3rd, ... reconnect
+fn reconnect_backoff(reconnect: u32) -> Duration {
+    RECONNECT_BACKOFF
+        .saturating_mul(2u32.saturating_pow(reconnect.saturating_sub(1)))
+        .min(MAX_RECONNECT_BACKOFF)
+}
+
+fn is_io_error(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| cause.is::<std::io::Error>())
+}
+
+async fn download_scheduled_pieces(
+    peer: SocketAddrV4,
+    stream: &mut Stream,
+    bitfield: &mut BitField,
+    shared: &Shared,
+) -> Result<()> {
+    let torrent = &shared.torrent;
+    let mut announced = 0; // how much of the completion log this peer has been told about
+    let mut counted = 0; // how much of stream.bytes_read() went into shared.bytes_read
+    loop {
+        wait_while_paused(stream, &shared.options).await?;
+        let bytes_read = stream.bytes_read();
+        shared
+            .bytes_read
+            .fetch_add(bytes_read - counted, Ordering::Relaxed);
+        counted = bytes_read;
+        // register for wake ups before looking at the state so we can't miss one
+        let notified = shared.notify.notified();
+        let (next, haves) = {
+            let mut scheduler = shared.scheduler();
+            for piece in stream.peer_haves.drain(..) {
+                if (piece as usize) < torrent.num_pieces() && !bitfield.has_piece(piece as usize) {
+                    bitfield.set_piece(piece as usize);
+                    scheduler.peer_has(peer, piece);
+                }
+            }
+            if scheduler.is_finished() {
+                return Ok(());
+            }
+            if !scheduler.is_useful(bitfield) {
+                return Err(anyhow!("Peer has none of the remaining pieces"));
+            }
+            let haves = scheduler.completion_log[announced..].to_vec();
+            announced = scheduler.completion_log.len();
+            let handoff = scheduler.handoff;
+            let next = scheduler.next_piece(peer, bitfield);
+            if scheduler.handoff.is_some() && scheduler.handoff != handoff {
+                // the idle peers wait for a wake up
+                shared.notify.notify_waiters();
+            }
+            (next, haves)
+        };
+        for piece in haves {
+            stream.have(piece).await?;
+        }
+        // peers sending ut_pex without being asked don't get to add to a private torrent's swarm
+        if shared.torrent.is_private() {
+            stream.discovered_peers.clear();
+        }
+        for peer in stream.discovered_peers.drain(..) {
+            // only fails once download_all stopped listening, at which point nobody needs new peers
+            let _ = shared.new_peers.send(peer);
+        }
+        let Some((piece, done)) = next else {
+            // everything left is in flight on other peers, wait until that changes
+            notified.await;
+            continue;
+        };
+
+        info!(piece, "starting piece");
+        shared.options.set_state(ClientState::Downloading);
+        let result = match stream
+            .get_piece_data_cancellable(piece, torrent, DEFAULT_PIECE_TIMEOUT, &done)
+            .await
+        {
+            Ok(piece_data) if shared.options.verify => {
+                shared.options.set_state(ClientState::Verifying);
+                verify_piece(torrent, piece, &piece_data).map(|()| piece_data)
+            }
+            result => result,
+        };
+        // another peer may have written the same piece in endgame, that's the same bytes twice
+        let result = match (result, &shared.options.writer) {
+            (Ok(piece_data), Some(writer)) => writer
+                .clone()
+                .write_piece_async(piece, piece_data)
+                .await
+                .map(|_| None),
+            (result, _) => result.map(Some),
+        };
+
+        let mut scheduler = shared.scheduler();
+        match result {
+            Ok(piece_data) => {
+                scheduler.contributors.insert(peer);
+                scheduler.record_rate(peer, stream.throughput());
+                shared.options.ranking.send_replace(scheduler.ranking());
+                if scheduler.complete(piece, piece_data) {
+                    shared.options.progress.send_replace(scheduler.progress());
+                    info!(
+                        piece,
+                        bytes_per_sec = stream.throughput() as u64,
+                        queue_depth = stream.request_queue.depth(),
+                        "piece complete"
+                    );
+                }
+                shared.notify.notify_waiters();
+            }
+            Err(_) if done.load(Ordering::Acquire) => {
+                debug!(piece, "piece was completed by another peer");
+                scheduler.release(piece);
+            }
+            Err(e) => {
+                if let Some(DownloadError::HashMismatch { .. }) = e.downcast_ref() {
+                    scheduler.corrupt.insert(piece);
+                    if scheduler.fail(piece) {
+                        warn!(
+                            piece,
+                            "piece failed verification too often, giving up on it"
+                        );
+                        shared.notify.notify_waiters();
+                        return Err(e.context(format!("CTX: piece {piece} failed")));
+                    }
+                }
+                warn!(piece, "retrying piece with another peer");
+                scheduler.requeue(piece);
+                shared.notify.notify_waiters();
+                return Err(e.context(format!("CTX: piece {piece} failed")));
+            }
+        }
+    }
+}
+
+// keeps the connection alive while the download is paused, cancelling is taken care of by
+// download_all aborting the worker
+async fn wait_while_paused(stream: &mut Stream, options: &DownloadOptions) -> Result<()> {
+    let mut paused = options.paused.subscribe();
+    if !*paused.borrow_and_update() {
+        return Ok(());
+    }
+    debug!("paused");
+    while *paused.borrow_and_update() {
+        tokio::select! {
+            changed = paused.changed() => changed.context("CTX: pause state dropped")?,
+            _ = tokio::time::sleep(KEEP_ALIVE_INTERVAL) => stream.keep_alive().await?,
+        }
+    }
+    debug!("resumed");
+    Ok(())
+}
+
+// never resolves when we aren't listening, so the select in download_all can always poll it
+async fn accept(listener: Option<&TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+// uploads the pieces we have to a peer that connected to us, while the choker lets it
+async fn serve_peer(socket: TcpStream, peer: SocketAddr, shared: &Shared) -> Result<()> {
+    let Ok(_permit) = shared.connections.try_acquire() else {
+        debug!("no free connection slot, turning peer away");
+        return Ok(());
+    };
+    shared.choker().add_peer(peer);
+    let stream = Stream::new(socket).with_upload_limiter(shared.options.upload_limiter.clone());
+    let result = upload(stream, peer, shared).await;
+    shared.choker().remove_peer(&peer);
+    shared.unchoked.send_if_modified(|unchoked| {
+        match unchoked.iter().position(|&other| other == peer) {
+            Some(position) => {
+                unchoked.swap_remove(position);
+                true
+            }
+            None => false,
+        }
+    });
+    result
+}
+
+async fn upload(mut stream: Stream, peer: SocketAddr, shared: &Shared) -> Result<()> {
+    let handshake = Handshake::new(shared.torrent.info.info_hash_bytes())
+        .with_peer_id(shared.options.peer_id.clone())?;
+    stream.accept_handshake(handshake).await?;
+    let (bitfield, mut announced) = {
+        let scheduler = shared.scheduler();
+        let mut bitfield = BitField::empty(shared.torrent.num_pieces());
+        for &piece in &scheduler.completion_log {
+            bitfield.set_piece(piece as usize);
+        }
+        (bitfield, scheduler.completion_log.len())
+    };
+    stream.send_bitfield(&bitfield).await?;
+    info!("peer connected to us");
+
+    let mut unchoked_peers = shared.unchoked.subscribe();
+    let mut unchoked = false;
+    let mut peek = [0; 1];
+    loop {
+        // peeking can be given up without losing any bytes, unlike read_message, so a rechoke
+        // doesn't have to wait for the peer's next message. Not `readable`, its readiness stays
+        // set after a read that didn't drain the socket and read_message would then block
+        tokio::select! {
+            peeked = stream.connection.peek(&mut peek) => {
+                peeked.context("CTX: wait for peer")?;
+            }
+            Ok(()) = unchoked_peers.changed() => {
+                let unchoke = unchoked_peers.borrow_and_update().contains(&peer);
+                if unchoke && !unchoked {
+                    stream.unchoke().await?;
+                } else if !unchoke && unchoked {
+                    stream.choke().await?;
+                }
+                unchoked = unchoke;
+                continue;
+            }
+        }
+        // haves only go out when the peer sends something, which it does at least every couple
+        // of minutes to keep the connection alive
+        let (id, payload) = match stream.read_message().await {
+            Ok(message) => message,
+            Err(e) if is_io_error(&e) => {
+                info!(uploaded = stream.bytes_written(), "peer disconnected");
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let haves = shared.scheduler().completion_log[announced..].to_vec();
+        announced += haves.len();
+        for piece in haves {
+            stream.have(piece).await?;
+        }
+        match MessageType::from_id(id) {
+            Some(MessageType::Interested) => {
+                shared.choker().set_interested(peer, true);
+                // a free slot doesn't have to wait for the next rechoke
+                let slots = shared.options.unchoke_slots;
+                shared.unchoked.send_if_modified(|unchoked| {
+                    let free = unchoked.len() < slots && !unchoked.contains(&peer);
+                    if free {
+                        unchoked.push(peer);
+                    }
+                    free
+                });
+            }
+            Some(MessageType::NotInterested) => shared.choker().set_interested(peer, false),
+            Some(MessageType::Request) if unchoked => {
+                let [piece, offset, length] = parse_request(&payload)?;
+                if length > MAX_UPLOAD_BLOCK {
+                    return Err(anyhow!("Peer requested a {length} byte block"));
+                }
+                let completed = shared.scheduler().completed.get(piece as usize) == Some(&true);
+                let block = match &shared.options.writer {
+                    Some(writer) if completed => {
+                        writer
+                            .clone()
+                            .read_block_async(piece, offset, length)
+                            .await?
+                    }
+                    Some(_) => None,
+                    None => shared
+                        .scheduler()
+                        .data
+                        .get(piece as usize)
+                        .and_then(|data| {
+                            let start = offset as usize;
+                            data.as_ref()?
+                                .get(start..start.checked_add(length as usize)?)
+                                .map(<[u8]>::to_vec)
+                        }),
+                };
+                match block {
+                    Some(block) => {
+                        stream.send_block(piece, offset, &block).await?;
+                        shared.choker().record(peer, block.len() as u64);
+                    }
+                    None => debug!(
+                        piece,
+                        offset, length, "ignoring request for data we don't have"
+                    ),
+                }
+            }
+            _ => trace!(id, "ignoring message from incoming peer"),
+        }
+    }
+}
+
+// <index><begin><length>
+fn parse_request(payload: &[u8]) -> Result<[u32; 3]> {
+    if payload.len() != 12 {
+        return Err(anyhow!("Request message of {} bytes", payload.len()));
+    }
+    let field = |at: usize| u32::from_be_bytes(payload[at..at + 4].try_into().expect("4 bytes"));
+    Ok([field(0), field(4), field(8)])
+}
+
+impl Shared {
+    fn scheduler(&self) -> MutexGuard<'_, Scheduler> {
+        self.scheduler.lock().expect("scheduler lock poisoned")
+    }
+
+    fn choker(&self) -> MutexGuard<'_, Choker> {
+        self.choker.lock().expect("choker lock poisoned")
+    }
+}
+
+struct PeerSpeed {
+    bitfield: BitField,
+    // piece throughput in bytes/sec, None until the peer delivered a piece
+    rate: Option<f64>,
+    // pieces started since another peer started one
+    streak: u32,
+    // the last next_piece gave it nothing, it is waiting for a wake up
+    idle: bool,
+}
+
+struct InFlight {
+    done: Arc<AtomicBool>,
+    workers: usize,
+}
+
+// hands out pieces to peer workers and collects the verified results
+struct Scheduler {
+    pending: BTreeSet<u32>,
+    in_flight: HashMap<u32, InFlight>,
+    // verified pieces
+    completed: Vec<bool>,
+    // and their data, only kept in memory when there is no `DownloadOptions::writer` for it
+    data: Vec<Option<Vec<u8>>>,
+    // verified pieces in the order they completed, so every peer can be sent a have for each
+    completion_log: Vec<u32>,
+    // pieces that failed verification at least once, reported when the download ends
+    corrupt: BTreeSet<u32>,
+    // verification failures per piece, at max_failures the piece goes into failed for good
+    failures: HashMap<u32, u32>,
+    max_failures: u32,
+    failed: BTreeSet<u32>,
+    // pieces that went back into pending after a peer dropped or sent corrupt data
+    retried: BTreeSet<u32>,
+    // peers that delivered at least one piece, connected or not
+    contributors: HashSet<SocketAddrV4>,
+    remaining: usize,
+    // connected peers, for keeping the last pieces away from slow ones
+    peers: HashMap<SocketAddrV4, PeerSpeed>,
+    // pieces a peer may start in a row while others are idle, 0 for no limit
+    max_streak: u32,
+    // a pending piece kept for an idle peer and the peer that passed it on
+    handoff: Option<(u32, SocketAddrV4)>,
+    selector: Box<dyn PieceSelector>,
+}
+
+impl Scheduler {
+    fn new(
+        num_pieces: usize,
+        wanted: Option<&BTreeSet<u32>>,
+        max_failures: u32,
+        selector: Box<dyn PieceSelector>,
+    ) -> Self {
+        let pending: BTreeSet<u32> = match wanted {
+            Some(wanted) => wanted
+                .iter()
+                .copied()
+                .filter(|&piece| (piece as usize) < num_pieces)
+                .collect(),
+            None => (0..num_pieces as u32).collect(),
+        };
+        Self {
+            remaining: pending.len(),
+            pending,
+            in_flight: HashMap::new(),
+            completed: vec![false; num_pieces],
+            data: vec![None; num_pieces],
+            completion_log: Vec::new(),
+            corrupt: BTreeSet::new(),
+            failures: HashMap::new(),
+            max_failures,
+            failed: BTreeSet::new(),
+            retried: BTreeSet::new(),
+            contributors: HashSet::new(),
+            peers: HashMap::new(),
+            max_streak: 0,
+            handoff: None,
+            selector,
+        }
+    }
+
+    fn with_max_streak(mut self, max_streak: u32) -> Self {
+        self.max_streak = max_streak;
+        self
+    }
+
+    // pieces we have from an earlier run are done before anything starts
+    fn with_resumed(mut self, resumed: &BTreeSet<u32>) -> Self {
+        for &piece in resumed {
+            if (piece as usize) < self.completed.len() && !self.completed[piece as usize] {
+                self.completed[piece as usize] = true;
+                self.completion_log.push(piece);
+                if self.pending.remove(&piece) {
+                    self.remaining -= 1;
+                }
+            }
+        }
+        self
+    }
+
+    // (downloaded, wanted), pieces we gave up on still count as wanted
+    fn progress(&self) -> (usize, usize) {
+        let downloaded = self.completion_log.len();
+        (downloaded, downloaded + self.remaining)
+    }
+
+    fn is_done(&self) -> bool {
+        self.remaining == 0
+    }
+
+    // nothing left to download, though pieces we gave up on may be missing
+    fn is_finished(&self) -> bool {
+        self.remaining == self.failed.len()
+    }
+
+    fn add_peer(&mut self, peer: SocketAddrV4, bitfield: &BitField) {
+        self.selector.add_peer(bitfield);
+        self.peers.insert(
+            peer,
+            PeerSpeed {
+                bitfield: bitfield.clone(),
+                rate: None,
+                streak: 0,
+                idle: false,
+            },
+        );
+    }
+
+    // a Have from `peer`, which has to be applied to its `bitfield` too
+    fn peer_has(&mut self, peer: SocketAddrV4, piece: u32) {
+        self.selector.add_piece(piece);
+        if let Some(speed) = self.peers.get_mut(&peer) {
+            speed.bitfield.set_piece(piece as usize);
+        }
+    }
+
+    fn remove_peer(&mut self, peer: SocketAddrV4, bitfield: &BitField) {
+        self.selector.remove_peer(bitfield);
+        self.peers.remove(&peer);
+        // nobody left to take the kept piece, anyone may have it again
+        if let Some((piece, _)) = self.handoff {
+            if !self.has_idle_peer(peer, piece) {
+                self.handoff = None;
+            }
+        }
+    }
+
+    // whether a peer other than `peer` that has `piece` is waiting for work
+    fn has_idle_peer(&self, peer: SocketAddrV4, piece: u32) -> bool {
+        self.peers.iter().any(|(&other, speed)| {
+            other != peer && speed.idle && speed.bitfield.has_piece(piece as usize)
+        })
+    }
+
+    // whether `peer` had enough pieces in a row to leave `piece` to an idle peer
+    fn should_hand_off(&self, peer: SocketAddrV4, piece: u32) -> bool {
+        self.max_streak > 0
+            && self.handoff.is_none()
+            && self
+                .peers
+                .get(&peer)
+                .is_some_and(|speed| speed.streak >= self.max_streak)
+            && self.has_idle_peer(peer, piece)
+    }
+
+    fn record_rate(&mut self, peer: SocketAddrV4, rate: f64) {
+        if let Some(speed) = self.peers.get_mut(&peer) {
+            speed.rate = Some(rate);
+        }
+    }
+
+    fn ranking(&self) -> Vec<(SocketAddrV4, f64)> {
+        let mut ranking: Vec<_> = self
+            .peers
+            .iter()
+            .filter_map(|(&peer, speed)| Some((peer, speed.rate?)))
+            .collect();
+        ranking.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranking
+    }
+
+    // whether the faster peers that have `piece` would get through the pending pieces, plus the
+    // ones they are busy with, before this peer managed a single piece. Keeps the last pieces of
+    // a download from getting stuck on a slow peer
+    fn leave_to_faster_peers(&self, peer: SocketAddrV4, piece: u32) -> bool {
+        let Some(rate) = self.peers.get(&peer).and_then(|speed| speed.rate) else {
+            return false;
+        };
+        let faster: f64 = self
+            .peers
+            .values()
+            .filter(|other| other.bitfield.has_piece(piece as usize))
+            .filter_map(|other| other.rate.filter(|&other| other > rate))
+            .sum();
+        (self.pending.len() + 1) as f64 * rate < faster
+    }
+
+    // whether the peer has any piece we still need
+    fn is_useful(&self, bitfield: &BitField) -> bool {
+        self.pending
+            .iter()
+            .chain(self.in_flight.keys())
+            .any(|&piece| !self.completed[piece as usize] && bitfield.has_piece(piece as usize))
+    }
+
+    fn next_piece(
+        &mut self,
+        peer: SocketAddrV4,
+        bitfield: &BitField,
+    ) -> Option<(u32, Arc<AtomicBool>)> {
+        let next = self.pick_piece(peer, bitfield);
+        for (&other, speed) in &mut self.peers {
+            if other == peer {
+                speed.idle = next.is_none();
+                speed.streak += u32::from(next.is_some());
+            } else if next.is_some() {
+                speed.streak = 0;
+            }
+        }
+        next
+    }
+
+    fn pick_piece(
+        &mut self,
+        peer: SocketAddrV4,
+        bitfield: &BitField,
+    ) -> Option<(u32, Arc<AtomicBool>)> {
+        // the kept piece goes to the first other peer that can take it, even a slow one
+        if let Some((piece, from)) = self.handoff {
+            if from != peer && bitfield.has_piece(piece as usize) {
+                self.handoff = None;
+                if self.pending.remove(&piece) {
+                    debug!(piece, "taking over a piece another peer passed on");
+                    return Some(self.start(piece));
+                }
+            }
+        }
+        // and nobody else gets it in the meantime
+        let kept = self
+            .handoff
+            .map(|(piece, _)| piece)
+            .filter(|piece| self.pending.remove(piece));
+        let next = self.select_piece(peer, bitfield);
+        if let Some(piece) = kept {
+            self.pending.insert(piece);
+        }
+        next
+    }
+
+    fn select_piece(
+        &mut self,
+        peer: SocketAddrV4,
+        bitfield: &BitField,
+    ) -> Option<(u32, Arc<AtomicBool>)> {
+        if let Some(mut piece) = self.selector.select(&self.pending, bitfield) {
+            if self.should_hand_off(peer, piece) {
+                debug!(
+                    piece,
+                    "peer had many pieces in a row, keeping this one for an idle peer"
+                );
+                self.handoff = Some((piece, peer));
+                if let Some(speed) = self.peers.get_mut(&peer) {
+                    speed.streak = 0;
+                }
+                self.pending.remove(&piece);
+                let other = self.selector.select(&self.pending, bitfield);
+                self.pending.insert(piece);
+                piece = other?;
+            }
+            if self.leave_to_faster_peers(peer, piece) {
+                debug!(piece, "leaving piece to faster peers");
+                return None;
+            }
+            self.pending.remove(&piece);
+            return Some(self.start(piece));
+        }
+        if self.remaining >= ENDGAME_THRESHOLD {
+            return None;
+        }
+        // endgame: double up on the in-flight piece with the fewest peers working on it
+        let (&piece, in_flight) = self
+            .in_flight
+            .iter_mut()
+            .filter(|(&piece, in_flight)| {
+                bitfield.has_piece(piece as usize) && !in_flight.done.load(Ordering::Acquire)
+            })
+            .min_by_key(|(_, in_flight)| in_flight.workers)?;
+        debug!(
+            piece,
+            "endgame: requesting in-flight piece from another peer"
+        );
+        in_flight.workers += 1;
+        Some((piece, in_flight.done.clone()))
+    }
+
+    // marks a pending piece as in flight for one more peer
+    fn start(&mut self, piece: u32) -> (u32, Arc<AtomicBool>) {
+        let in_flight = self.in_flight.entry(piece).or_insert_with(|| InFlight {
+            done: Arc::default(),
+            workers: 0,
+        });
+        in_flight.workers += 1;
+        (piece, in_flight.done.clone())
+    }
+
+    // returns false if another peer already delivered this piece. `piece_data` is None once it
+    // was written out
+    fn complete(&mut self, piece: u32, piece_data: Option<Vec<u8>>) -> bool {
+        let newly_completed = !self.completed[piece as usize];
+        if newly_completed {
+            self.completed[piece as usize] = true;
+            self.data[piece as usize] = piece_data;
+            self.completion_log.push(piece);
+            self.remaining -= 1;
+            self.failed.remove(&piece);
+            if let Some(in_flight) = self.in_flight.get(&piece) {
+                in_flight.done.store(true, Ordering::Release);
+            }
+        }
+        self.release(piece);
+        newly_completed
+    }
+
+    fn release(&mut self, piece: u32) {
+        if let Some(in_flight) = self.in_flight.get_mut(&piece) {
+            in_flight.workers -= 1;
+            if in_flight.workers == 0 {
+                self.in_flight.remove(&piece);
+            }
+        }
+    }
+
+    // counts a verification failure, returns true if that was the last one the piece gets. It
+    // then stays out of the queue, unless a peer already working on it still delivers it
+    fn fail(&mut self, piece: u32) -> bool {
+        let failures = self.failures.entry(piece).or_default();
+        *failures += 1;
+        if *failures < self.max_failures {
+            return false;
+        }
+        self.release(piece);
+        if !self.completed[piece as usize] {
+            self.failed.insert(piece);
+        }
+        true
+    }
+
+    fn requeue(&mut self, piece: u32) {
+        self.release(piece);
+        // only put it back if no other peer is still working on it
+        if !self.in_flight.contains_key(&piece) && !self.completed[piece as usize] {
+            self.pending.insert(piece);
+            self.retried.insert(piece);
+        }
+    }
+}
+
+pub mod ip_filter {
+    use anyhow::{anyhow, Context, Result};
+    use std::fs;
+    use std::net::IpAddr;
+    use std::path::Path;
+
+    /// Decides which peer addresses we may connect to. Rules are CIDR ranges, the most specific
+    /// range containing an address decides and addresses no rule covers are allowed, so an allow
+    /// rule can open up part of a larger blocked range.
+    #[derive(Debug, Clone, Default)]
+    pub struct IpFilter {
+        rules: Vec<Rule>,
+    }
+
+    #[derive(Debug, Clone)]
+    struct Rule {
+        network: IpAddr,
+        prefix: u8,
+        allow: bool,
+    }
+
+    impl IpFilter {
+        /// Reads one rule per line: `block <cidr>`, `allow <cidr>` or just `<cidr>` which blocks.
+        /// Empty lines and lines starting with `#` are skipped.
+        pub fn from_file(path: &Path) -> Result<Self> {
+            let rules = fs::read_to_string(path)
+                .context(format!("CTX: read ip filter {}", path.display()))?;
+            Self::parse(&rules).context(format!("CTX: parse ip filter {}", path.display()))
+        }
+
+        pub fn parse(rules: &str) -> Result<Self> {
+            let mut filter = Self::default();
+            for (number, line) in rules.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let (allow, cidr) = match line.split_once(char::is_whitespace) {
+                    Some(("allow", cidr)) => (true, cidr),
+                    Some(("block", cidr)) => (false, cidr),
+                    None => (false, line),
+                    Some((action, _)) => {
+                        return Err(anyhow!(
+                            "Line {}: unknown action {action:?}, expected allow or block",
+                            number + 1
+                        ))
+                    }
+                };
+                filter = filter
+                    .with_rule(cidr.trim(), allow)
+                    .context(format!("CTX: line {}", number + 1))?;
+            }
+            Ok(filter)
+        }
+
+        /// Adds a rule for `cidr`, e.g. `10.0.0.0/8` or `2001:db8::/32`. A plain address covers
+        /// only itself.
+        pub fn with_rule(mut self, cidr: &str, allow: bool) -> Result<Self> {
+            let (address, prefix) = match cidr.split_once('/') {
+                Some((address, prefix)) => (address, Some(prefix)),
+                None => (cidr, None),
+            };
+            let network: IpAddr = address
+                .parse()
+                .context(format!("CTX: invalid address in {cidr:?}"))?;
+            let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                Some(prefix) => prefix
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|&prefix| prefix <= max_prefix)
+                    .ok_or_else(|| anyhow!("Invalid prefix length in {cidr:?}"))?,
+                None => max_prefix,
+            };
+            self.rules.push(Rule {
+                network,
+                prefix,
+                allow,
+            });
+            Ok(self)
+        }
+
+        pub fn allows(&self, address: IpAddr) -> bool {
+            self.rules
+                .iter()
+                .filter(|rule| rule.contains(address))
+                .max_by_key(|rule| rule.prefix)
+                .is_none_or(|rule| rule.allow)
+        }
+    }
+
+    impl Rule {
+        fn contains(&self, address: IpAddr) -> bool {
+            // compare the leading `prefix` bits, both families as 128 bit numbers
+            let (network, address, bits) = match (self.network, address) {
+                (IpAddr::V4(network), IpAddr::V4(address)) => {
+                    (u32::from(network) as u128, u32::from(address) as u128, 32)
+                }
+                (IpAddr::V6(network), IpAddr::V6(address)) => {
+                    (u128::from(network), u128::from(address), 128)
+                }
+                _ => return false,
+            };
+            let shift = bits - u32::from(self.prefix);
+            network.checked_shr(shift).unwrap_or(0) == address.checked_shr(shift).unwrap_or(0)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn allows(filter: &IpFilter, address: &str) -> bool {
+            filter.allows(address.parse().unwrap())
+        }
+
+        #[test]
+        fn the_most_specific_rule_decides() {
+            let filter = IpFilter::parse(
+                "# a comment\n\n10.0.0.0/8\nallow 10.1.0.0/16\nblock 10.1.2.3\nblock 2001:db8::/32\n",
+            )
+            .unwrap();
+            assert!(!allows(&filter, "10.0.0.1"));
+            assert!(!allows(&filter, "10.255.255.255"));
+            assert!(allows(&filter, "10.1.0.1"));
+            assert!(!allows(&filter, "10.1.2.3"));
+            assert!(allows(&filter, "11.0.0.0"));
+            assert!(!allows(&filter, "2001:db8::1"));
+            assert!(allows(&filter, "2001:db9::1"));
+            // a v4 rule says nothing about v6 addresses
+            assert!(allows(&filter, "::ffff:10.0.0.1"));
+
+            let everything = IpFilter::default().with_rule("0.0.0.0/0", false).unwrap();
+            assert!(!allows(&everything, "1.2.3.4"));
+            assert!(allows(&IpFilter::default(), "1.2.3.4"));
+        }
+
+        #[test]
+        fn broken_rules_are_rejected() {
+            for rules in [
+                "10.0.0.0/33",
+                "10.0.0/8",
+                "deny 10.0.0.0/8",
+                "::/129",
+                "10.0.0.0/x",
+            ] {
+                assert!(IpFilter::parse(rules).is_err(), "{rules}");
+            }
+        }
+    }
+}
+
+pub mod writer {
+    use anyhow::{anyhow, Context, Result};
+    use std::collections::BTreeSet;
+    use std::fs::{self, File, OpenOptions};
+    use std::io;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use super::sanitize_path;
+    use crate::torrent::{Keys, Torrent};
+
+    /// The output files of a download, open for writing pieces at their offsets and reading them
+    /// back for uploads. Neither seeks or shares a cursor, so peer tasks can write different
+    /// pieces at the same time without a lock.
+    #[derive(Debug)]
+    pub struct PieceWriter {
+        piece_length: u64,
+        total_length: u64,
+        // every file of the torrent in order with where it starts in the concatenated data,
+        // None for files that weren't selected
+        files: Vec<(u64, u64, Option<File>)>,
+    }
+
+    impl PieceWriter {
+        /// Creates the output files at their full length, empty ones too. `output` is the file
+        /// for single-file torrents and the base directory for multi-file ones, where only the
+        /// files at the `selected` indices of `Torrent::files` get created. Files that are there
+        /// already keep their contents, see `read_resume`.
+        pub fn create(
+            torrent: &Torrent,
+            output: &Path,
+            selected: &BTreeSet<usize>,
+        ) -> Result<Self> {
+            let mut files = Vec::new();
+            match &torrent.info.keys {
+                Keys::SingleFile { length } => {
+                    let file = create_file(output, *length).context("CTX: create output file")?;
+                    files.push((0, *length, Some(file)));
+                }
+                Keys::MultiFile {
+                    files: torrent_files,
+                } => {
+                    let mut start = 0;
+                    for (index, file) in torrent_files.iter().enumerate() {
+                        let created = if selected.contains(&index) {
+                            // checked before anything gets joined onto `output`
+                            let path = sanitize_path(output, file.display_path())?;
+                            if let Some(parent) = path.parent() {
+                                fs::create_dir_all(parent).context(format!(
+                                    "CTX: create directory {}",
+                                    parent.display()
+                                ))?;
+                            }
+                            let created = create_file(&path, file.length)
+                                .context(format!("CTX: create file {}", path.display()))?;
+                            Some(created)
+                        } else {
+                            None
+                        };
+                        files.push((start, file.length, created));
+                        start += file.length;
+                    }
+                }
+            }
+            Ok(Self {
+                piece_length: torrent.info.piece_length,
+                total_length: torrent.total_length(),
+                files,
+            })
+        }
+
+        /// Writes `data` as piece `piece`, the parts falling into files that weren't selected
+        /// are dropped.
+        pub fn write_piece(&self, piece: u32, data: &[u8]) -> Result<()> {
+            let piece_start = piece as u64 * self.piece_length;
+            let piece_end = piece_start + data.len() as u64;
+            if piece_end > self.total_length {
+                return Err(anyhow!(
+                    "Piece {piece} of {} bytes runs past the end of the torrent",
+                    data.len()
+                ));
+            }
+            for (start, length, file) in &self.files {
+                let (from, to) = (piece_start.max(*start), piece_end.min(start + length));
+                let Some(file) = file.as_ref().filter(|_| from < to) else {
+                    continue;
+                };
+                let (from_index, to_index) =
+                    ((from - piece_start) as usize, (to - piece_start) as usize);
+                write_all_at(file, &data[from_index..to_index], from - start)
+                    .context(format!("CTX: write piece {piece}"))?;
+            }
+            Ok(())
+        }
+
+        /// `write_piece` on the blocking thread pool, hands the data back once it is written.
+        pub async fn write_piece_async(
+            self: Arc<Self>,
+            piece: u32,
+            data: Vec<u8>,
+        ) -> Result<Vec<u8>> {
+            tokio::task::spawn_blocking(move || self.write_piece(piece, &data).map(|()| data))
+                .await
+                .context("CTX: piece writer task")?
+        }
+
+        /// Reads `length` bytes at `offset` of piece `piece` back, None if part of them lies in
+        /// a file that wasn't selected or past the end of the piece.
+        pub fn read_block(&self, piece: u32, offset: u32, length: u32) -> Result<Option<Vec<u8>>> {
+            let piece_start = piece as u64 * self.piece_length;
+            let block_start = piece_start + offset as u64;
+            let block_end = block_start + length as u64;
+            let piece_end = (piece_start + self.piece_length).min(self.total_length);
+            if offset as u64 >= self.piece_length || block_end > piece_end {
+                return Ok(None);
+            }
+            let mut block = vec![0; length as usize];
+            for (start, file_length, file) in &self.files {
+                let (from, to) = (block_start.max(*start), block_end.min(start + file_length));
+                if from >= to {
+                    continue;
+                }
+                let Some(file) = file else {
+                    return Ok(None);
+                };
+                let (from_index, to_index) =
+                    ((from - block_start) as usize, (to - block_start) as usize);
+                read_exact_at(file, &mut block[from_index..to_index], from - start)
+                    .context(format!("CTX: read piece {piece}"))?;
+            }
+            Ok(Some(block))
+        }
+
+        /// Reads all of piece `piece` back, None like for `read_block`.
+        pub fn read_piece(&self, piece: u32) -> Result<Option<Vec<u8>>> {
+            let piece_start = piece as u64 * self.piece_length;
+            let length = self
+                .piece_length
+                .min(self.total_length.saturating_sub(piece_start));
+            match u32::try_from(length) {
+                Ok(length) if length > 0 => self.read_block(piece, 0, length),
+                _ => Ok(None),
+            }
+        }
+
+        /// `read_block` on the blocking thread pool.
+        pub async fn read_block_async(
+            self: Arc<Self>,
+            piece: u32,
+            offset: u32,
+            length: u32,
+        ) -> Result<Option<Vec<u8>>> {
+            tokio::task::spawn_blocking(move || self.read_block(piece, offset, length))
+                .await
+                .context("CTX: piece reader task")?
+        }
+
+        /// Flushes everything written so far to disk.
+        pub fn sync(&self) -> Result<()> {
+            for file in self.files.iter().filter_map(|(_, _, file)| file.as_ref()) {
+                file.sync_all().context("CTX: sync output file")?;
+            }
+            Ok(())
+        }
+    }
+
+    // readable too, uploads read the pieces back
+    fn create_file(path: &Path, length: u64) -> io::Result<File> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        file.set_len(length)?;
+        Ok(file)
+    }
+
+    #[cfg(unix)]
+    fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        use std::os::windows::fs::FileExt;
+        while !buf.is_empty() {
+            match file.seek_read(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(read) => {
+                    buf = &mut buf[read..];
+                    offset += read as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+    }
+
+    // seek_write moves the cursor, but nothing here reads it
+    #[cfg(windows)]
+    fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+        use std::os::windows::fs::FileExt;
+        while !buf.is_empty() {
+            match file.seek_write(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    buf = &buf[written..];
+                    offset += written as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::testing::{multi_file_torrent, pattern};
+
+        #[test]
+        fn blocks_are_read_back_across_files() {
+            let torrent = multi_file_torrent(&[("a", 100), ("b/c", 50), ("d", 70)], 64);
+            let data = pattern(220);
+            let dir = tempfile::tempdir().unwrap();
+            let writer =
+                PieceWriter::create(&torrent, dir.path(), &BTreeSet::from([0, 1])).unwrap();
+            for (index, piece) in data.chunks(64).enumerate() {
+                writer.write_piece(index as u32, piece).unwrap();
+            }
+            assert_eq!(fs::read(dir.path().join("b/c")).unwrap(), data[100..150]);
+            assert!(!dir.path().join("d").exists());
+
+            // piece 1 spans a and b/c
+            let block = writer.read_block(1, 10, 40).unwrap();
+            assert_eq!(block.as_deref(), Some(&data[74..114]));
+            // piece 2 reaches into d, which wasn't written
+            assert_eq!(writer.read_block(2, 0, 64).unwrap(), None);
+            // the last piece is only 28 bytes
+            assert_eq!(writer.read_block(3, 0, 29).unwrap(), None);
+        }
+    }
+}
+
+pub mod selector {
+    use std::collections::BTreeSet;
+
+    use crate::peer::bitfield::BitField;
+
+    /// Decides which piece a peer downloads next.
+    pub trait PieceSelector: Send {
+        /// Called for every peer we connect to, with the pieces it announced.
+        fn add_peer(&mut self, _bitfield: &BitField) {}
+
+        /// Called when a peer we added disconnects, with the pieces it had by then.
+        fn remove_peer(&mut self, _bitfield: &BitField) {}
+
+        /// Called when a peer we added announces a piece it didn't have before.
+        fn add_piece(&mut self, _piece: u32) {}
+
+        /// Picks one of the `needed` pieces that `peer` has, or None if it has none of them.
+        fn select(&mut self, needed: &BTreeSet<u32>, peer: &BitField) -> Option<u32>;
+    }
+
+    /// Downloads pieces in index order.
+    #[derive(Debug, Default)]
+    pub struct Sequential;
+
+    impl PieceSelector for Sequential {
+        fn select(&mut self, needed: &BTreeSet<u32>, peer: &BitField) -> Option<u32> {
+            needed
+                .iter()
+                .copied()
+                .find(|&piece| peer.has_piece(piece as usize))
+        }
+    }
+
+    /// For playback while downloading: the first and last pieces come first since players read
+    /// headers and indexes from there, then everything else in index order.
+    #[derive(Debug)]
+    pub struct Streaming {
+        last_piece: u32,
+    }
+
+    impl Streaming {
+        pub fn new(num_pieces: usize) -> Self {
+            Self {
+                last_piece: num_pieces.saturating_sub(1) as u32,
+            }
+        }
+    }
+
+    impl PieceSelector for Streaming {
+        fn select(&mut self, needed: &BTreeSet<u32>, peer: &BitField) -> Option<u32> {
+            [0, self.last_piece]
+                .iter()
+                .chain(needed.iter())
+                .copied()
+                .find(|&piece| needed.contains(&piece) && peer.has_piece(piece as usize))
+        }
+    }
+
+    /// Downloads the piece that the fewest connected peers have first, so rare pieces
+    /// get replicated before the peers holding them leave. Ties go to the lowest index.
+    #[derive(Debug)]
+    pub struct RarestFirst {
+        availability: Vec<usize>,
+    }
+
+    impl RarestFirst {
+        pub fn new(num_pieces: usize) -> Self {
+            Self {
+                availability: vec![0; num_pieces],
+            }
+        }
+    }
+
+    impl PieceSelector for RarestFirst {
+        fn add_peer(&mut self, bitfield: &BitField) {
+            for piece in bitfield.pieces() {
+                if let Some(count) = self.availability.get_mut(piece) {
+                    *count += 1;
+                }
+            }
+        }
+
+        fn remove_peer(&mut self, bitfield: &BitField) {
+            for piece in bitfield.pieces() {
+                if let Some(count) = self.availability.get_mut(piece) {
+                    *count = count.saturating_sub(1);
+                }
+            }
+        }
+
+        fn add_piece(&mut self, piece: u32) {
+            if let Some(count) = self.availability.get_mut(piece as usize) {
+                *count += 1;
+            }
+        }
+
+        fn select(&mut self, needed: &BTreeSet<u32>, peer: &BitField) -> Option<u32> {
+            needed
+                .iter()
+                .copied()
+                .filter(|&piece| peer.has_piece(piece as usize))
+                .min_by_key(|&piece| self.availability.get(piece as usize))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn bitfield(num_pieces: usize, pieces: &[usize]) -> BitField {
+            let mut bitfield = BitField::empty(num_pieces);
+            for &piece in pieces {
+                bitfield.set_piece(piece);
+            }
+            bitfield
+        }
+
+        #[test]
+        fn streaming_takes_the_first_and_last_piece_then_goes_in_order() {
+            let mut selector = Streaming::new(6);
+            let seeder = BitField::full(6);
+            let mut needed: BTreeSet<u32> = (0..6).collect();
+            let mut order = Vec::new();
+            while let Some(piece) = selector.select(&needed, &seeder) {
+                needed.remove(&piece);
+                order.push(piece);
+            }
+            assert_eq!(order, [0, 5, 1, 2, 3, 4]);
+
+            // pieces the peer lacks are skipped, not waited for
+            let needed = BTreeSet::from([0, 2, 5]);
+            assert_eq!(selector.select(&needed, &bitfield(6, &[2, 5])), Some(5));
+            assert_eq!(selector.select(&needed, &bitfield(6, &[2])), Some(2));
+        }
+
+        #[test]
+        fn rarest_first_picks_the_least_replicated_piece() {
+            // piece 3 is on one peer, 1 and 2 on two, 0 on all three
+            let peers = [
+                bitfield(4, &[0, 1, 2, 3]),
+                bitfield(4, &[0, 1]),
+                bitfield(4, &[0, 2]),
+            ];
+            let mut selector = RarestFirst::new(4);
+            for peer in &peers {
+                selector.add_peer(peer);
+            }
+            let mut needed = BTreeSet::from([0, 1, 2, 3]);
+            assert_eq!(selector.select(&needed, &peers[0]), Some(3));
+            // a peer only gets pieces it has, ties go to the lowest index
+            assert_eq!(selector.select(&needed, &peers[1]), Some(1));
+            assert_eq!(selector.select(&needed, &peers[2]), Some(2));
+            needed.remove(&3);
+            assert_eq!(selector.select(&needed, &peers[0]), Some(1));
+
+            // once the third peer leaves, 2 is the rarest
+            selector.remove_peer(&peers[2]);
+            assert_eq!(selector.select(&needed, &peers[0]), Some(2));
+            assert_eq!(selector.select(&BTreeSet::from([3]), &peers[1]), None);
+        }
+
+        #[test]
+        fn sequential_goes_in_index_order() {
+            let needed = BTreeSet::from([1, 2, 3]);
+            assert_eq!(Sequential.select(&needed, &BitField::full(4)), Some(1));
+            assert_eq!(Sequential.select(&needed, &bitfield(4, &[0, 3])), Some(3));
+        }
+
+        #[test]
+        fn rarest_first_counts_haves() {
+            let mut selector = RarestFirst::new(3);
+            let mut first_only = BitField::empty(3);
+            first_only.set_piece(0);
+            selector.add_peer(&BitField::full(3));
+            selector.add_peer(&first_only);
+            let needed = BTreeSet::from([0, 1, 2]);
+            let seeder = BitField::full(3);
+            assert_eq!(selector.select(&needed, &seeder), Some(1));
+            selector.add_piece(1);
+            assert_eq!(selector.select(&needed, &seeder), Some(2));
+            selector.add_piece(2);
+            selector.add_piece(2);
+            assert_eq!(selector.select(&needed, &seeder), Some(0));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::selector::Sequential;
+    use crate::peer::handshake::Capabilities;
+    use crate::testing::{
+        multi_file_torrent, pattern, serve, serve_handshaken, torrent, Connections, Seeder,
+    };
+    use tokio::io::AsyncWriteExt;
+
+    fn peer(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, port)
+    }
+
+    // what a fmt subscriber writes, so tests can look at the log
+    #[derive(Clone, Default)]
+    struct Log(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Log {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn a_download_walks_through_the_states() {
+        let data = pattern(50_000);
+        let torrent = torrent("http://tracker/announce", &data, 16 * 1024);
+        let seeder = Seeder::start(&torrent, data.clone()).await;
+        let options = DownloadOptions::default();
+        let mut state = options.state();
+        let watcher = tokio::spawn(async move {
+            let mut seen = vec![*state.borrow_and_update()];
+            while *seen.last().unwrap() != ClientState::Done {
+                state.changed().await.unwrap();
+                seen.push(*state.borrow_and_update());
+            }
+            seen
+        });
+        download_all(&torrent, &[seeder.address], Box::new(Sequential), &options)
+            .await
+            .unwrap();
+        let mut seen = watcher.await.unwrap();
+        // the watcher may miss short lived states, downloading and verifying alternate per piece
+        seen.dedup();
+        assert_eq!(
+            seen[..3],
+            [
+                ClientState::Connecting,
+                ClientState::Handshaking,
+                ClientState::Downloading
+            ]
+        );
+        assert_eq!(seen.last(), Some(&ClientState::Done));
+        assert!(seen.iter().all(|state| matches!(
+            state,
+            ClientState::Connecting
+                | ClientState::Handshaking
+                | ClientState::Downloading
+                | ClientState::Verifying
+                | ClientState::Done
+        )));
+    }
+
+    #[tokio::test]
+    async fn pieces_that_arent_a_power_of_two_long_download() {
+        // two 20000 byte pieces of a full and a short block each, then a 10000 byte one
+        let data = pattern(50_000);
+        let torrent = torrent("http://tracker/announce", &data, 20_000);
+        assert_eq!(torrent.num_pieces(), 3);
+        let seeder = Seeder::start(&torrent, data.clone()).await;
+        let options = DownloadOptions::default();
+        let downloaded = download_all(&torrent, &[seeder.address], Box::new(Sequential), &options)
+            .await
+            .unwrap();
+        assert_eq!(downloaded, data);
+        assert!(Torrent::from_bytes_strict(&torrent.to_bytes().unwrap()).is_err());
+    }
+
+    #[tokio::test]
+    async fn pieces_in_a_row_share_one_connection() {
+        let data = pattern(3 * 16 * 1024);
+        let torrent = torrent("http://tracker/announce", &data, 16 * 1024);
+        let connections = Arc::new(Connections::default());
+        let seeder = Seeder::start_counting(&torrent, data.clone(), connections.clone()).await;
+        let options = DownloadOptions::default();
+        let pieces = download_pieces(&torrent, &[seeder.address], &[2, 0], &options)
+            .await
+            .unwrap();
+        assert_eq!(pieces, [&data[2 * 16 * 1024..], &data[..16 * 1024]]);
+        assert_eq!(connections.total.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn no_more_than_max_peers_connections_are_open() {
+        let data = pattern(20 * 16 * 1024);
+        let torrent = torrent("http://tracker/announce", &data, 16 * 1024);
+        let connections = Arc::new(Connections::default());
+        let mut peers = Vec::new();
+        for _ in 0..5 {
+            let seeder = Seeder::start_counting(&torrent, data.clone(), connections.clone()).await;
+            peers.push(seeder.address);
+        }
+        let options = DownloadOptions {
+            max_peers: 2,
+            ..Default::default()
+        };
+        let downloaded = download_all(&torrent, &peers, Box::new(Sequential), &options)
+            .await
+            .unwrap();
+        assert_eq!(downloaded, data);
+        assert_eq!(connections.peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn pausing_halts_progress_until_resumed() {
+        let data = pattern(40 * 16 * 1024);
+        let torrent = torrent("http://tracker/announce", &data, 16 * 1024);
+        let seeder = Seeder::start(&torrent, data.clone()).await;
+        let options = DownloadOptions::default();
+        let (handle, mut progress) = (options.handle(), options.progress());
+        let download = tokio::spawn(async move {
+            download_all(&torrent, &[seeder.address], Box::new(Sequential), &options).await
+        });
+        progress.wait_for(|&(done, _)| done > 0).await.unwrap();
+        handle.pause();
+        // the piece in flight may still finish, nothing after it
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let paused_at = progress.borrow().0;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(progress.borrow().0, paused_at);
+        assert!(paused_at < 40, "{paused_at}");
+        assert!(!download.is_finished());
+
+        handle.resume();
+        assert_eq!(download.await.unwrap().unwrap(), data);
+        assert_eq!(*progress.borrow(), (40, 40));
+    }
+
+    #[tokio::test]
+    async fn blocked_peers_are_never_connected_to() {
+        let data = pattern(1000);
+        let torrent = torrent("http://tracker/announce", &data, 1000);
+        let connections = Arc::new(Connections::default());
+        let seeder = Seeder::start_counting(&torrent, data, connections.clone()).await;
+        let options = DownloadOptions {
+            ip_filter: IpFilter::default().with_rule("127.0.0.0/8", false).unwrap(),
+            ..Default::default()
+        };
+        let result =
+            download_all(&torrent, &[seeder.address], Box::new(Sequential), &options).await;
+        assert!(result.is_err());
+        assert_eq!(connections.peak.load(Ordering::SeqCst), 0);
+    }
+
+    // seeds `torrent` to one connection, telling it about `gossip` through ut_pex right after the
+    // handshake. Gives back what the downloader's handshake advertised
+    async fn pex_seeder(
+        torrent: &Torrent,
+        data: Vec<u8>,
+        gossip: SocketAddrV4,
+    ) -> (SocketAddrV4, tokio::task::JoinHandle<Capabilities>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(address) = listener.local_addr().unwrap() else {
+            unreachable!("bound to an ipv4 address");
+        };
+        let torrent = torrent.clone();
+        let seeder = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = Stream::new(socket);
+            let handshake = Handshake::new(torrent.info.info_hash_bytes());
+            let downloader = stream.accept_handshake(handshake).await.unwrap();
+            let mut added = gossip.ip().octets().to_vec();
+            added.extend(gossip.port().to_be_bytes());
+            let payload = [&b"d5:added6:"[..], &added, b"e"].concat();
+            let mut message = (2 + payload.len() as u32).to_be_bytes().to_vec();
+            message.extend([20, crate::peer::extension::UT_PEX_ID]);
+            message.extend(payload);
+            stream.connection.write_all(&message).await.unwrap();
+            tokio::spawn(serve_handshaken(stream, torrent, Arc::new(data)));
+            downloader.capabilities
+        });
+        (address, seeder)
+    }
+
+    #[tokio::test]
+    async fn private_torrents_ignore_peer_exchange() {
+        let data = pattern(8 * 1024);
+        let public = torrent("http://tracker/announce", &data, 1024);
+        let (address, seeder) =
+            pex_seeder(&public, data.clone(), "127.0.0.1:1".parse().unwrap()).await;
+        let options = DownloadOptions::default();
+        download_all(&public, &[address], Box::new(Sequential), &options)
+            .await
+            .unwrap();
+        assert!(seeder.await.unwrap().extensions);
+
+        let mut private = public.clone();
+        private.info.private = Some(1);
+        private.info.forget_original();
+        let connections = Arc::new(Connections::default());
+        let bystander = Seeder::start_counting(&private, data.clone(), connections.clone()).await;
+        let (address, seeder) = pex_seeder(&private, data.clone(), bystander.address).await;
+        let downloaded = download_all(&private, &[address], Box::new(Sequential), &options)
+            .await
+            .unwrap();
+        assert_eq!(downloaded, data);
+        assert!(!seeder.await.unwrap().extensions);
+        assert_eq!(connections.peak.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn completed_pieces_are_uploaded_mid_download() {
+        let data = pattern(40 * 16 * 1024);
+        let torrent = torrent("http://tracker/announce", &data, 16 * 1024);
+        let seeder = Seeder::start(&torrent, data.clone()).await;
+        let listen = {
+            let reserved = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            reserved.local_addr().unwrap()
+        };
+        let options = DownloadOptions {
+            listen: Some(listen),
+            ..Default::default()
+        };
+        let (handle, mut progress) = (options.handle(), options.progress());
+        let download = {
+            let torrent = torrent.clone();
+            tokio::spawn(async move {
+                download_all(&torrent, &[seeder.address], Box::new(Sequential), &options).await
+            })
+        };
+        // hold the download halfway so there is something to upload and it is still listening
+        progress.wait_for(|&(done, _)| done > 0).await.unwrap();
+        handle.pause();
+
+        let stream = Stream::connect(&listen).await.unwrap();
+        let mut connection = PeerConnection::new(stream);
+        let handshake = Handshake::new(torrent.info.info_hash_bytes());
+        connection
+            .prepare(handshake, torrent.num_pieces())
+            .await
+            .unwrap();
+        let bitfield = connection.bitfield().unwrap();
+        assert!(bitfield.has_piece(0) && !bitfield.has_piece(39));
+        let piece = connection
+            .download_piece(0, &torrent, DEFAULT_PIECE_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(piece, data[..16 * 1024]);
+        assert!(!download.is_finished());
+
+        handle.resume();
+        assert_eq!(download.await.unwrap().unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn download_logs_its_progress() {
+        let data = pattern(50_000);
+        let torrent = torrent("http://tracker/announce", &data, 16 * 1024);
+        let seeder = Seeder::start(&torrent, data.clone()).await;
+        let log = Log::default();
+        let writer = log.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::INFO)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        // the test runtime is single threaded, so the spawned peer workers log here too
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let downloaded = download_all(
+            &torrent,
+            &[seeder.address],
+            Box::new(Sequential),
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(downloaded, data);
+
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        for event in [
+            "connected to peer",
+            "starting piece",
+            "piece complete",
+            "transfer finished",
+        ] {
+            assert!(log.contains(event), "no {event:?} in\n{log}");
+        }
+        assert_eq!(log.matches("piece complete").count(), 4);
+        assert!(log.contains(&format!("peer{{peer={}}}", seeder.address)));
+    }
+
+    #[tokio::test]
+    async fn corrupt_pieces_are_fetched_from_another_peer() {
+        let data = pattern(50_000);
+        let torrent = torrent("http://tracker/announce", &data, 16 * 1024);
+        let mut corrupt = data.clone();
+        corrupt[20_000] ^= 0xff;
+        let bad = Seeder::start(&torrent, corrupt).await;
+        let good = Seeder::start(&torrent, data.clone()).await;
+
+        let piece = download_piece(
+            &torrent,
+            &[bad.address, good.address],
+            1,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(piece, data[16 * 1024..32 * 1024]);
+
+        // every piece the bad peer sends is corrupt, so whatever it picks first has to be retried
+        let garbage = data.iter().map(|byte| !byte).collect();
+        let bad = Seeder::start(&torrent, garbage).await;
+        let options = DownloadOptions::default();
+        let downloaded = download_all(
+            &torrent,
+            &[bad.address, good.address],
+            Box::new(Sequential),
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(downloaded, data);
+        let summary = options.summary();
+        assert_eq!((summary.pieces, summary.peers), (4, 1));
+        assert!(summary.retried >= 1, "{summary:?}");
+    }
+
+    #[tokio::test]
+    async fn the_summary_adds_up_a_download() {
+        let data = pattern(50_000);
+        let torrent = torrent("http://tracker/announce", &data, 16 * 1024);
+        let options = DownloadOptions::default();
+        assert_eq!(options.summary().pieces, 0);
+        let seeder = Seeder::start(&torrent, data.clone()).await;
+        download_all(&torrent, &[seeder.address], Box::new(Sequential), &options)
+            .await
+            .unwrap();
+        let summary = options.summary();
+        assert_eq!((summary.pieces, summary.bytes), (4, 50_000));
+        assert_eq!((summary.peers, summary.retried), (1, 0));
+        assert!(summary.verified);
+        // the bitfield, message headers and handshake come on top
+        assert!(summary.bytes_read > summary.bytes, "{summary:?}");
+        let report = summary.to_string();
+        assert!(
+            report.starts_with("Downloaded 4 pieces (50000 bytes) in "),
+            "{report}"
+        );
+        assert!(
+            report.ends_with("Peers used: 1\nPieces retried: 0\nAll pieces matched their hashes")
+        );
+    }
+
+    #[tokio::test]
+    async fn without_verification_corrupt_pieces_are_kept() {
+        let data = pattern(50_000);
+        let torrent = torrent("http://tracker/announce", &data, 16 * 1024);
+        let mut corrupt = data.clone();
+        corrupt[20_000] ^= 0xff;
+        let bad = Seeder::start(&torrent, corrupt.clone()).await;
+
+        let options = DownloadOptions {
+            verify: false,
+            ..Default::default()
+        };
+        let downloaded = download_all(&torrent, &[bad.address], Box::new(Sequential), &options)
+            .await
+            .unwrap();
+        assert_eq!(downloaded, corrupt);
+        assert_eq!(options.summary().retried, 0);
+
+        // verified, the corrupt piece is refused and there is nobody else to get it from
+        let e = download_all(
+            &torrent,
+            &[bad.address],
+            Box::new(Sequential),
+            &Default::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            e.chain().any(|cause| matches!(
+                cause.downcast_ref(),
+                Some(DownloadError::HashMismatch { piece: 1 })
+            )),
+            "{e:#}"
+        );
+    }
+
+    #[test]
+    fn reconnect_backoff_doubles_up_to_the_cap() {
+        let schedule: Vec<u64> = (1..=7)
+            .map(|reconnect| reconnect_backoff(reconnect).as_millis() as u64)
+            .collect();
+        assert_eq!(schedule, [100, 200, 400, 800, 1600, 2000, 2000]);
+        assert_eq!(reconnect_backoff(u32::MAX), MAX_RECONNECT_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn a_peer_that_comes_up_late_is_reconnected_to() {
+        let data = pattern(50_000);
+        let torrent = torrent("http://tracker/announce", &data, 16 * 1024);
+        let address = {
+            let reserved = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            match reserved.local_addr().unwrap() {
+                SocketAddr::V4(address) => address,
+                address => panic!("bound to {address}"),
+            }
+        };
+        // refuses the first connects, the backoff gives it 700ms in total
+        let (late, served) = (torrent.clone(), Arc::new(data.clone()));
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            let listener = tokio::net::TcpListener::bind(address).await.unwrap();
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve(Stream::new(socket), late.clone(), served.clone()));
+            }
+        });
+        let downloaded = download_all(
+            &torrent,
+            &[address],
+            Box::new(Sequential),
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(downloaded, data);
+    }
+
+    #[test]
+    fn endgame_doubles_up_on_the_last_pieces() {
+        let mut scheduler = Scheduler::new(2, None, 3, Box::new(Sequential));
+        let bitfield = BitField::full(2);
+        scheduler.add_peer(peer(1), &bitfield);
+        scheduler.add_peer(peer(2), &bitfield);
+        let (first, _) = scheduler.next_piece(peer(1), &bitfield).unwrap();
+        let (stalled, stalled_done) = scheduler.next_piece(peer(2), &bitfield).unwrap();
+        assert!(scheduler.complete(first, None));
+
+        // peer 1 is idle and takes over the piece peer 2 is stuck on
+        let (piece, done) = scheduler.next_piece(peer(1), &bitfield).unwrap();
+        assert_eq!(piece, stalled);
+        assert!(scheduler.complete(piece, None));
+        assert!(done.load(Ordering::Acquire) && stalled_done.load(Ordering::Acquire));
+        assert!(scheduler.is_done());
+        // peer 2 gives up on it once it notices, nothing goes back into the queue
+        scheduler.release(stalled);
+        assert!(scheduler.in_flight.is_empty() && scheduler.pending.is_empty());
+    }
+
+    #[test]
+    fn a_streak_on_one_peer_hands_a_piece_to_the_idle_one() {
+        let assignments = |max_streak| {
+            let mut scheduler =
+                Scheduler::new(8, None, 3, Box::new(Sequential)).with_max_streak(max_streak);
+            let bitfield = BitField::full(8);
+            scheduler.add_peer(peer(1), &bitfield);
+            scheduler.add_peer(peer(2), &bitfield);
+            // peer 2 is slow enough that it would leave every piece to peer 1
+            scheduler.record_rate(peer(1), 1e6);
+            scheduler.record_rate(peer(2), 1.0);
+            let mut assigned = Vec::new();
+            while !scheduler.is_done() {
+                for port in [2, 1] {
+                    if let Some((piece, _)) = scheduler.next_piece(peer(port), &bitfield) {
+                        assigned.push((piece, port));
+                        assert!(scheduler.complete(piece, None));
+                    }
+                }
+            }
+            assigned.sort();
+            assigned
+                .into_iter()
+                .map(|(_, port)| port)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(assignments(0), [1; 8]);
+        // peer 1 starts a new streak once peer 2 takes the piece it passed on
+        assert_eq!(assignments(2), [1, 1, 2, 1, 1, 1, 2, 1]);
+    }
+
+    #[test]
+    fn a_piece_failing_verification_is_given_up_after_max_failures() {
+        let mut scheduler = Scheduler::new(2, None, 3, Box::new(Sequential));
+        let bitfield = BitField::full(2);
+        scheduler.add_peer(peer(1), &bitfield);
+        // broken connections don't count
+        for _ in 0..5 {
+            let (piece, _) = scheduler.next_piece(peer(1), &bitfield).unwrap();
+            assert_eq!(piece, 0);
+            scheduler.requeue(piece);
+        }
+        // the way a worker handles a hash mismatch
+        for attempt in 1..=3 {
+            let (piece, _) = scheduler.next_piece(peer(1), &bitfield).unwrap();
+            assert_eq!(piece, 0);
+            let given_up = scheduler.fail(piece);
+            assert_eq!(given_up, attempt == 3);
+            if !given_up {
+                scheduler.requeue(piece);
+            }
+        }
+        assert_eq!(scheduler.failed, BTreeSet::from([0]));
+        let (piece, _) = scheduler.next_piece(peer(1), &bitfield).unwrap();
+        assert_eq!(piece, 1);
+        assert!(scheduler.complete(piece, None));
+        assert!(scheduler.next_piece(peer(1), &bitfield).is_none());
+        assert!(scheduler.is_finished() && !scheduler.is_done());
+    }
+
+    #[tokio::test]
+    async fn selecting_one_file_downloads_only_its_pieces() {
+        let torrent =
+            multi_file_torrent(&[("a.txt", 100), ("sub/b.txt", 50), ("sub/deep/c", 70)], 64);
+        let data = pattern(220);
+        // b.txt is bytes 100..150, the tail of piece 1 and the head of piece 2
+        assert_eq!(torrent.file_pieces(1), 1..3);
+        let seeder = Seeder::start(&torrent, data.clone()).await;
+        let options = DownloadOptions {
+            pieces: Some(torrent.file_pieces(1).collect()),
+            ..Default::default()
+        };
+        let downloaded = download_all(&torrent, &[seeder.address], Box::new(Sequential), &options)
+            .await
+            .unwrap();
+        assert_eq!(downloaded[64..192], data[64..192]);
+        assert!(downloaded[..64]
+            .iter()
+            .chain(&downloaded[192..])
+            .all(|&byte| byte == 0));
+        let summary = options.summary();
+        assert_eq!((summary.pieces, summary.bytes), (2, 128));
+    }
+
+    #[tokio::test]
+    async fn the_faster_peer_gets_more_pieces() {
+        let data = pattern(16384 * 24);
+        let torrent = torrent("http://127.0.0.1:1/announce", &data, 16384);
+        let fast = Seeder::start(&torrent, data.clone()).await;
+        let slow = Seeder::start_slow(&torrent, data.clone(), Duration::from_millis(40)).await;
+        let options = DownloadOptions::default();
+        let mut ranking = options.ranking();
+        // peers leave the ranking once they disconnect, look at it once both are in
+        let ranked_together = tokio::spawn(async move {
+            loop {
+                ranking.changed().await.unwrap();
+                let ranked = ranking.borrow_and_update().clone();
+                if ranked.len() == 2 {
+                    return ranked;
+                }
+            }
+        });
+        let downloaded = download_all(
+            &torrent,
+            &[fast.address, slow.address],
+            Box::new(Sequential),
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(downloaded, data);
+        let (fast_blocks, slow_blocks) = (
+            fast.blocks.load(Ordering::SeqCst),
+            slow.blocks.load(Ordering::SeqCst),
+        );
+        assert!(
+            fast_blocks > slow_blocks,
+            "fast peer sent {fast_blocks} blocks, slow peer {slow_blocks}"
+        );
+        let both = tokio::time::timeout(Duration::from_secs(1), ranked_together)
+            .await
+            .expect("both peers were never ranked at the same time")
+            .unwrap();
+        assert_eq!(both.first().map(|(peer, _)| *peer), Some(fast.address));
+    }
+
+    #[test]
+    fn availability_adds_up_bitfields() {
+        let mut availability = Availability::new(10);
+        assert_eq!(availability.rarest(), Vec::<u32>::new());
+        assert_eq!(availability.missing(), (0..10).collect::<Vec<_>>());
+        // pieces 0-7 and 8, the spare bits past piece 9 are set and must not count
+        availability.add(&BitField(vec![0b1111_1111, 0b1011_1111]));
+        // pieces 0, 1 and 8
+        availability.add(&BitField(vec![0b1100_0000, 0b1000_0000]));
+        // pieces 1 and 3
+        availability.add(&BitField(vec![0b0101_0000, 0b0000_0000]));
+        assert_eq!(availability.peers, 3);
+        assert_eq!(availability.counts, [2, 3, 1, 2, 1, 1, 1, 1, 2, 0]);
+        assert_eq!(availability.most_common(), [1]);
+        assert_eq!(availability.rarest(), [2, 4, 5, 6, 7]);
+        assert_eq!(availability.missing(), [9]);
+    }
+
+    #[test]
+    fn multi_file_torrents_are_written_as_a_tree() {
+        let torrent =
+            multi_file_torrent(&[("a.txt", 100), ("sub/b.txt", 50), ("sub/deep/c", 70)], 64);
+        let data = pattern(220);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out");
+        write_output(&torrent, &output, &data).unwrap();
+        assert_eq!(fs::read(output.join("a.txt")).unwrap(), data[..100]);
+        assert_eq!(fs::read(output.join("sub/b.txt")).unwrap(), data[100..150]);
+        assert_eq!(fs::read(output.join("sub/deep/c")).unwrap(), data[150..]);
+        verify_files(&torrent, &output, &(0..3).collect()).unwrap();
+
+        let evil = multi_file_torrent(&[("a.txt", 100), ("../escaped", 10)], 64);
+        assert!(write_output(&evil, &output, &pattern(110)).is_err());
+        assert!(!dir.path().join("escaped").exists());
+    }
+
+    #[test]
+    fn truncated_downloads_and_files_are_caught() {
+        let multi =
+            multi_file_torrent(&[("a.txt", 100), ("sub/b.txt", 50), ("sub/deep/c", 70)], 64);
+        let data = pattern(220);
+        check_length(&multi, &data).unwrap();
+        let e = check_length(&multi, &data[..219]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Downloaded 219 bytes but the torrent has 220"
+        );
+        assert!(check_length(&multi, &[data.as_slice(), &[0]].concat()).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out");
+        assert!(write_output(&multi, &output, &data[..200]).is_err());
+        write_output(&multi, &output, &data).unwrap();
+        let b = output.join("sub/b.txt");
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&b)
+            .unwrap()
+            .set_len(40)
+            .unwrap();
+        let e = verify_files(&multi, &output, &(0..3).collect()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("{} is 40 bytes but the torrent has 50", b.display())
+        );
+        // files that weren't selected aren't looked at
+        verify_files(&multi, &output, &BTreeSet::from([0, 2])).unwrap();
+
+        // same length, different content: only an md5sum tells
+        let mut single = torrent("http://tracker/announce", &data, 64);
+        single.info.md5sum = Some(hex::encode(Md5::digest(&data)));
+        let output = dir.path().join("single.bin");
+        write_output(&single, &output, &data).unwrap();
+        verify_files(&single, &output, &BTreeSet::new()).unwrap();
+        fs::write(&output, &pattern(221)[1..]).unwrap();
+        let e = verify_files(&single, &output, &BTreeSet::new()).unwrap_err();
+        assert!(e.to_string().starts_with("MD5 of "), "{e}");
+    }
+
+    #[test]
+    fn traversal_payloads_are_rejected() {
+        let base = Path::new("/downloads/torrent");
+        let payloads: &[&[&str]] = &[
+            &[],
+            &[".."],
+            &["sub", "..", "..", "etc"],
+            &["/etc/passwd"],
+            &["sub/../../x"],
+            &["."],
+            &[""],
+            &["a/b"],
+            &["ok", "/abs"],
+        ];
+        for payload in payloads {
+            let segments: Vec<String> = payload.iter().map(|s| s.to_string()).collect();
+            let result = sanitize_path(base, &segments);
+            assert!(result.is_err(), "{payload:?} gave {result:?}");
+        }
+        assert_eq!(
+            sanitize_path(base, &["sub".into(), "file..txt".into()]).unwrap(),
+            base.join("sub").join("file..txt")
+        );
+    }
+
+    #[test]
+    fn a_have_makes_the_piece_available_to_the_peer() {
+        let mut scheduler = Scheduler::new(4, None, 3, Box::new(Sequential));
+        let mut bitfield = BitField::empty(4);
+        scheduler.add_peer(peer(1), &bitfield);
+        assert!(scheduler.next_piece(peer(1), &bitfield).is_none());
+
+        bitfield.set_piece(2);
+        scheduler.peer_has(peer(1), 2);
+        assert!(scheduler.peers[&peer(1)].bitfield.has_piece(2));
+        let (piece, _) = scheduler.next_piece(peer(1), &bitfield).unwrap();
+        assert_eq!(piece, 2);
+    }
+
+    #[test]
+    fn partial_downloads_resume_from_the_files() {
+        let torrent = multi_file_torrent(&[("a", 100), ("b/c", 50), ("d", 70)], 64);
+        let data = pattern(220);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out");
+        let pieces: Vec<Option<Vec<u8>>> = data
+            .chunks(64)
+            .enumerate()
+            .map(|(index, piece)| (index != 1).then(|| piece.to_vec()))
+            .collect();
+        write_partial(&torrent, &output, &pieces).unwrap();
+        assert_eq!(fs::read(output.join("d")).unwrap()[42..], data[192..]);
+
+        let all = (0..3).collect();
+        let writer = PieceWriter::create(&torrent, &output, &all).unwrap();
+        let resumed = read_resume(&torrent, &output, &writer).unwrap();
+        assert_eq!(resumed, BTreeSet::from([0, 2, 3]));
+
+        // piece 2 changed on disk since
+        writer.write_piece(2, &[0; 64]).unwrap();
+        let resumed = read_resume(&torrent, &output, &writer).unwrap();
+        assert_eq!(resumed, BTreeSet::from([0, 3]));
+
+        let scheduler = Scheduler::new(4, None, 3, Box::new(Sequential)).with_resumed(&resumed);
+        assert_eq!(scheduler.pending, BTreeSet::from([1, 2]));
+        assert_eq!(scheduler.completion_log, [0, 3]);
+        assert_eq!(scheduler.progress(), (2, 4));
+    }
+
+    #[test]
+    fn resume_files_go_next_to_the_output() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            resume_path(&dir.path().join("a.iso")),
+            dir.path().join("a.iso.resume")
+        );
+        assert_ne!(
+            resume_path(Path::new("a.iso")),
+            resume_path(Path::new("a.img"))
+        );
+        assert_eq!(resume_path(Path::new("out")), Path::new("out.resume"));
+
+        // an output that itself ends in .resume keeps its data
+        let torrent = torrent("http://tracker/announce", &pattern(100), 64);
+        let output = dir.path().join("x.resume");
+        fs::write(&output, pattern(100)).unwrap();
+        write_resume(&torrent, &output, &[true, false]).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), pattern(100));
+        assert_eq!(
+            fs::read(dir.path().join("x.resume.resume")).unwrap(),
+            [0x80]
+        );
+    }
+
+    #[test]
+    fn no_resume_file_resumes_nothing() {
+        let torrent = multi_file_torrent(&[("a", 100)], 64);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out");
+        let writer = PieceWriter::create(&torrent, &output, &BTreeSet::from([0])).unwrap();
+        assert!(read_resume(&torrent, &output, &writer).unwrap().is_empty());
+    }
+
+    #[test]
+    fn written_pieces_are_not_kept_in_memory() {
+        let mut scheduler = Scheduler::new(2, None, 3, Box::new(Sequential));
+        let bitfield = BitField::full(2);
+        scheduler.add_peer(peer(1), &bitfield);
+        let (first, _) = scheduler.next_piece(peer(1), &bitfield).unwrap();
+        let (second, _) = scheduler.next_piece(peer(1), &bitfield).unwrap();
+        assert!(scheduler.complete(first, None));
+        assert!(scheduler.complete(second, Some(vec![1, 2, 3])));
+        assert_eq!(scheduler.completed, [true, true]);
+        assert_eq!(scheduler.data, [None, Some(vec![1, 2, 3])]);
+        assert!(scheduler.is_done());
+    }
+}
@@ -0,0 +1,113 @@
+use thiserror::Error;
+
+use crate::download::DownloadError;
+use crate::tracker::TrackerError;
+
+/// What went wrong in one of the library's entry points (`TrackerRequest::discover_peers`,
+/// `Stream::handshake`, `Stream::get_piece_data`), for callers that want to react differently
+/// to different failures, e.g. retry a `Timeout` but not a `HashMismatch`.
+///
+/// Apart from `HashMismatch` every variant wraps the error it was sorted by, `source()` walks its
+/// whole chain and the underlying error can be reached with `downcast_ref`.
+#[derive(Debug, Error)]
+pub enum BtError {
+    /// No tracker gave us peers, including trackers refusing us with a `TrackerError`.
+    #[error(transparent)]
+    Tracker(anyhow::Error),
+    /// The handshake could not be exchanged for a reason other than the connection.
+    #[error(transparent)]
+    Handshake(anyhow::Error),
+    /// The connection broke.
+    #[error(transparent)]
+    Io(anyhow::Error),
+    /// Bencoded data (a torrent, a tracker response) did not decode.
+    #[error(transparent)]
+    Bencode(anyhow::Error),
+    #[error("Hashes for piece {piece} do NOT match!")]
+    HashMismatch { piece: u32 },
+    /// The tracker or peer did not answer in time.
+    #[error(transparent)]
+    Timeout(anyhow::Error),
+    /// The peer broke the protocol, e.g. sent an oversized message or rejected our request.
+    #[error(transparent)]
+    Peer(anyhow::Error),
+}
+
+impl BtError {
+    // sorts an internal error into a variant by what is in its chain, `otherwise` picks the
+    // variant for errors that don't give themselves away
+    pub(crate) fn classify(e: anyhow::Error, otherwise: fn(anyhow::Error) -> BtError) -> Self {
+        if let Some(DownloadError::HashMismatch { piece }) = e.downcast_ref() {
+            return BtError::HashMismatch { piece: *piece };
+        }
+        let timed_out = e.chain().any(|cause| {
+            cause.is::<tokio::time::error::Elapsed>()
+                || cause
+                    .downcast_ref::<std::io::Error>()
+                    .is_some_and(|io| io.kind() == std::io::ErrorKind::TimedOut)
+                || cause
+                    .downcast_ref::<reqwest::Error>()
+                    .is_some_and(reqwest::Error::is_timeout)
+        });
+        if timed_out {
+            BtError::Timeout(e)
+        } else if e.chain().any(|cause| cause.is::<TrackerError>()) {
+            BtError::Tracker(e)
+        } else if e.chain().any(|cause| cause.is::<std::io::Error>()) {
+            BtError::Io(e)
+        } else if e.chain().any(|cause| cause.is::<serde_bencode::Error>()) {
+            BtError::Bencode(e)
+        } else {
+            otherwise(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::handshake::DEFAULT_PEER_ID;
+    use crate::testing::{torrent, MockTracker, Reply};
+    use crate::tracker::TrackerRequest;
+    use std::time::Duration;
+
+    #[test]
+    fn classify_looks_through_the_context_chain() {
+        let e = anyhow::Error::from(DownloadError::HashMismatch { piece: 3 }).context("CTX: piece");
+        assert!(matches!(
+            BtError::classify(e, BtError::Peer),
+            BtError::HashMismatch { piece: 3 }
+        ));
+        let e = anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::TimedOut))
+            .context("CTX: read");
+        assert!(matches!(
+            BtError::classify(e, BtError::Peer),
+            BtError::Timeout(_)
+        ));
+        let e = anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::BrokenPipe));
+        assert!(matches!(
+            BtError::classify(e, BtError::Peer),
+            BtError::Io(_)
+        ));
+        let e = anyhow::anyhow!("something else");
+        assert!(matches!(
+            BtError::classify(e, BtError::Handshake),
+            BtError::Handshake(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn discover_peers_reports_timeouts() {
+        let tracker = MockTracker::start(|_, _| Reply {
+            delay: Duration::from_secs(5),
+            ..Default::default()
+        })
+        .await;
+        let torrent = torrent(&tracker.announce_url(), b"data", 16 * 1024);
+        let result = TrackerRequest::new(String::from(DEFAULT_PEER_ID), 6881, 4)
+            .with_timeout(Duration::from_millis(200))
+            .discover_peers(&torrent)
+            .await;
+        assert!(matches!(result, Err(BtError::Timeout(_))), "{result:?}");
+    }
+}
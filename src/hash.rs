@@ -0,0 +1,55 @@
+// Every SHA1 in the crate (info hashes, piece checks, creating torrents) goes through here, so
+// the implementation can be picked with a cargo feature:
+//   sha1-asm   the `sha1` crate with its assembly compression function, needs a C toolchain. Can
+//              help on CPUs without SHA instructions, which the default detects and uses anyway
+//   sha1-smol  the dependency-free `sha1_smol` crate, smaller but about half as fast
+// Without either the plain `sha1` crate is used. All of them give the same bytes, compare their
+// speed with `cargo bench --bench sha1`.
+
+/// SHA1 of `data`.
+#[cfg(not(feature = "sha1-smol"))]
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    use sha1::{Digest, Sha1};
+    Sha1::digest(data).into()
+}
+
+/// SHA1 of `data`.
+#[cfg(feature = "sha1-smol")]
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    sha1_smol::Sha1::from(data).digest().bytes()
+}
+
+/// Which implementation `sha1` uses, for benchmarks and bug reports.
+pub const SHA1_BACKEND: &str = if cfg!(feature = "sha1-smol") {
+    "sha1_smol"
+} else if cfg!(feature = "sha1-asm") {
+    "sha1 (asm)"
+} else {
+    "sha1"
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // FIPS 180 examples, whichever backend is built must give exactly these
+    #[test]
+    fn sha1_matches_the_test_vectors() {
+        let vectors: [(&[u8], &str); 3] = [
+            (b"", "da39a3ee5e6b4b0d3255bfef95601890afd80709"),
+            (b"abc", "a9993e364706816aba3e25717850c26c9cd0d89d"),
+            (
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "84983e441c3bd26ebaae4aa1f95129e5e54670f1",
+            ),
+        ];
+        for (data, expected) in vectors {
+            assert_eq!(hex::encode(sha1(data)), expected, "{SHA1_BACKEND}");
+        }
+        assert_eq!(
+            hex::encode(sha1(&vec![b'a'; 1_000_000])),
+            "34aa973cd4c4daa4f61eeb2bdbad27316534016f",
+            "{SHA1_BACKEND}"
+        );
+    }
+}
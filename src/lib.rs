@@ -1,3 +1,12 @@
+pub mod bencode;
+pub mod download;
+pub mod error;
+pub mod hash;
+pub mod magnet;
+pub mod nat;
+pub mod peer;
+pub mod session;
+#[cfg(test)]
+mod testing;
 pub mod torrent;
 pub mod tracker;
-pub mod peer;
\ No newline at end of file
@@ -0,0 +1,182 @@
+use anyhow::{anyhow, Context, Result};
+use base32::Alphabet;
+use hex::encode;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use crate::torrent::parse_info_hash;
+
+// magnet links look like magnet:?xt=urn:btih:<hash>&dn=<name>&tr=<tracker>&tr=<tracker>...
+const SCHEME: &str = "magnet:?";
+const BTIH_PREFIX: &str = "urn:btih:";
+
+/// What a magnet link (BEP 9) tells us about a torrent before we have its info dict.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    /// `dn`, only good for showing until we have the info dict's name
+    pub name: Option<String>,
+    /// Every `tr` in the order given, duplicates dropped
+    pub trackers: Vec<String>,
+}
+
+impl Magnet {
+    /// Parses a magnet URI. Its info hash may be hex or base32, parameters we don't know are
+    /// ignored.
+    pub fn parse(uri: &str) -> Result<Self> {
+        let uri = uri.trim();
+        let query = match uri.get(..SCHEME.len()) {
+            Some(scheme) if scheme.eq_ignore_ascii_case(SCHEME) => &uri[SCHEME.len()..],
+            _ => {
+                return Err(anyhow!(
+                    "Not a magnet link, expected it to start with {SCHEME}"
+                ))
+            }
+        };
+        let params: Vec<(String, String)> =
+            serde_urlencoded::from_str(query).context("CTX: decoding magnet parameters")?;
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        for (key, value) in params {
+            match key.as_str() {
+                // there can be several, e.g. a btmh next to the btih of a hybrid torrent
+                "xt" => {
+                    let Some(hash) = strip_prefix_ignore_case(&value, BTIH_PREFIX) else {
+                        continue;
+                    };
+                    let hash =
+                        parse_info_hash(hash).context(format!("CTX: info hash of xt={value}"))?;
+                    match info_hash {
+                        None => info_hash = Some(hash),
+                        Some(first) if first != hash => {
+                            return Err(anyhow!("Magnet link has more than one btih info hash"))
+                        }
+                        Some(_) => {}
+                    }
+                }
+                "dn" => name = Some(value),
+                "tr" if !trackers.contains(&value) => trackers.push(value),
+                _ => {}
+            }
+        }
+        let info_hash = info_hash
+            .ok_or_else(|| anyhow!("Magnet link has no xt={BTIH_PREFIX}<info hash> parameter"))?;
+        Ok(Self {
+            info_hash,
+            name,
+            trackers,
+        })
+    }
+
+    pub fn info_hash_str(&self) -> String {
+        encode(self.info_hash)
+    }
+
+    /// Uppercase RFC 4648 base32 without padding, like `Info::info_hash_base32`.
+    pub fn info_hash_base32(&self) -> String {
+        base32::encode(Alphabet::Rfc4648 { padding: false }, &self.info_hash)
+    }
+}
+
+fn strip_prefix_ignore_case<'a>(value: &'a str, prefix: &str) -> Option<&'a str> {
+    match value.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => Some(&value[prefix.len()..]),
+        _ => None,
+    }
+}
+
+impl FromStr for Magnet {
+    type Err = anyhow::Error;
+
+    fn from_str(uri: &str) -> Result<Self> {
+        Self::parse(uri)
+    }
+}
+
+impl Display for Magnet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Info Hash: {}", self.info_hash_str())?;
+        writeln!(f, "Info Hash (base32): {}", self.info_hash_base32())?;
+        writeln!(f, "Name: {}", self.name.as_deref().unwrap_or("none"))?;
+        write!(f, "Trackers:")?;
+        if self.trackers.is_empty() {
+            write!(f, " none")?;
+        }
+        for tracker in &self.trackers {
+            write!(f, "\n  {tracker}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BIG_BUCK_BUNNY: &str = "dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c";
+
+    #[test]
+    fn real_world_magnets() {
+        // as WebTorrent hands it out, with web seeds (ws, xs) we don't use
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c&dn=Big+Buck+Bunny\
+             &tr=udp%3A%2F%2Fexplodie.org%3A6969&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337\
+             &tr=wss%3A%2F%2Ftracker.openwebtorrent.com&tr=udp%3A%2F%2Fexplodie.org%3A6969\
+             &ws=https%3A%2F%2Fwebtorrent.io%2Ftorrents%2F\
+             &xs=https%3A%2F%2Fwebtorrent.io%2Ftorrents%2Fbig-buck-bunny.torrent",
+        )
+        .unwrap();
+        assert_eq!(magnet.info_hash_str(), BIG_BUCK_BUNNY);
+        assert_eq!(magnet.name.as_deref(), Some("Big Buck Bunny"));
+        assert_eq!(
+            magnet.trackers,
+            [
+                "udp://explodie.org:6969",
+                "udp://tracker.opentrackr.org:1337",
+                "wss://tracker.openwebtorrent.com",
+            ]
+        );
+
+        // base32 hash, upper case scheme, no name or trackers
+        let magnet: Magnet = "MAGNET:?xt=URN:BTIH:3WBFL3G4PSSV7MF37AJSHWDQMLNR63I4"
+            .parse()
+            .unwrap();
+        assert_eq!(magnet.info_hash_str(), BIG_BUCK_BUNNY);
+        assert_eq!(
+            magnet.info_hash_base32(),
+            "3WBFL3G4PSSV7MF37AJSHWDQMLNR63I4"
+        );
+        assert_eq!(magnet.name, None);
+        assert!(magnet.trackers.is_empty());
+        assert!(magnet.to_string().ends_with("Name: none\nTrackers: none"));
+
+        // a hybrid v1/v2 torrent lists its v2 hash too
+        let magnet = Magnet::parse(&format!(
+            "magnet:?xt=urn:btmh:1220{}&xt=urn:btih:{BIG_BUCK_BUNNY}&dn=hybrid",
+            "ab".repeat(32)
+        ))
+        .unwrap();
+        assert_eq!(magnet.info_hash_str(), BIG_BUCK_BUNNY);
+    }
+
+    #[test]
+    fn malformed_magnets_are_rejected() {
+        let other = "ab".repeat(20);
+        for uri in [
+            String::from(
+                "http://example.com/?xt=urn:btih:dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c",
+            ),
+            String::from("magnet:?dn=no+hash&tr=udp%3A%2F%2Fexplodie.org%3A6969"),
+            String::from("magnet:?xt=urn:btih:dd8255ecdc"),
+            String::from("magnet:?xt=urn:btih:zz8255ecdc7ca55fb0bbf81323d87062db1f6d1c"),
+            format!("magnet:?xt=urn:btih:{BIG_BUCK_BUNNY}&xt=urn:btih:{other}"),
+            String::from("magnet"),
+        ] {
+            assert!(Magnet::parse(&uri).is_err(), "{uri}");
+        }
+        let error = Magnet::parse("magnet:?dn=x").unwrap_err();
+        assert!(error.to_string().contains("no xt=urn:btih:"), "{error}");
+    }
+}
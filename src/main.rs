@@ -1,279 +1,1095 @@
-use anyhow::{Context, Result};
-use bittorrent_starter_rust::peer::Stream;
-use clap::{Parser, Subcommand};
+use anyhow::{anyhow, Context, Result};
+use bittorrent_starter_rust::bencode::{self, BencodeValue};
+use bittorrent_starter_rust::download::{
+    self,
+    ip_filter::IpFilter,
+    selector::{PieceSelector, RarestFirst, Sequential, Streaming},
+    writer::PieceWriter,
+    DownloadError, DownloadHandle, DownloadOptions, DEFAULT_MAX_CONSECUTIVE_PIECES,
+    DEFAULT_MAX_PEERS, DEFAULT_MAX_PIECE_FAILURES,
+};
+use bittorrent_starter_rust::peer::{
+    choke::DEFAULT_UNCHOKE_SLOTS, queue::DEFAULT_MAX_QUEUE_DEPTH, rate_limit::RateLimiter, Stream,
+    DEFAULT_BLOCK_SIZE, DEFAULT_TIMEOUT,
+};
+use clap::{Parser, Subcommand, ValueEnum};
 use hex::encode;
-use serde_bencode::from_bytes;
-use sha1::{Digest, Sha1};
-use std::net::SocketAddrV4;
-use std::str::FromStr;
-use std::{fs, path::PathBuf};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn, Level};
 
-use bittorrent_starter_rust::peer::handshake::{Handshake, HANDSHAKE_PEER_ID_BYTE_INDEX_START};
-use bittorrent_starter_rust::torrent::Torrent;
-use bittorrent_starter_rust::tracker::TrackerRequest;
+use bittorrent_starter_rust::magnet::Magnet;
+use bittorrent_starter_rust::nat::{self, NatProtocol};
+use bittorrent_starter_rust::peer::handshake::{Handshake, DEFAULT_PEER_ID};
+use bittorrent_starter_rust::session::{Download, Session};
+use bittorrent_starter_rust::torrent::{self, Torrent, DEFAULT_PIECE_LENGTH};
+use bittorrent_starter_rust::tracker::{
+    self, AnnounceSchedule, Event, TrackerRequest, TrackerResponse, DEFAULT_PORT,
+    DEFAULT_TRACKER_RETRIES, DEFAULT_TRACKER_TIMEOUT, DEFAULT_USER_AGENT,
+};
+use reqwest::Client;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// Log more details to stderr (-v info, -vv debug, -vvv trace)
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Refuse torrents that only go against BEP 3's recommendations instead of warning about
+    /// them, e.g. a piece length that isn't a power of two
+    #[arg(long, global = true)]
+    strict: bool,
+    #[command(flatten)]
+    client: ClientArgs,
     #[command(subcommand)]
     command: Command,
 }
 
+// how we present ourselves to trackers and peers
+#[derive(clap::Args, Debug)]
+struct ClientArgs {
+    /// The port we tell the tracker we are listening on
+    #[arg(long, global = true, default_value_t = DEFAULT_PORT)]
+    port: u16,
+    /// Our peer id for the tracker and handshakes, exactly 20 bytes
+    #[arg(long, global = true, default_value = DEFAULT_PEER_ID, value_parser = parse_peer_id)]
+    peer_id: String,
+    /// Ask the tracker for the dictionary peer list, for trackers that reject compact=1
+    #[arg(long, global = true)]
+    no_compact: bool,
+    /// User agent sent to trackers, some private trackers only accept known clients
+    #[arg(long, global = true, default_value = DEFAULT_USER_AGENT)]
+    user_agent: String,
+    /// Seconds any network operation may take: connecting, handshakes, tracker requests and
+    /// each block. Per-operation flags like --tracker-timeout take precedence
+    #[arg(long, global = true)]
+    timeout: Option<u64>,
+    /// Seconds to wait for a tracker to answer an announce [default: --timeout or 15]
+    #[arg(long, global = true)]
+    tracker_timeout: Option<u64>,
+    /// Use https for trackers the torrent lists with http urls
+    #[arg(long, global = true)]
+    force_https: bool,
+    /// How often to retry a tracker that failed before moving on to the next one
+    #[arg(long, global = true, default_value_t = DEFAULT_TRACKER_RETRIES)]
+    tracker_retries: u32,
+    /// Cap on download bandwidth in bytes/sec across all peers, 0 for unlimited
+    #[arg(long, global = true, default_value_t = 0)]
+    max_download_rate: u64,
+    /// Cap on upload bandwidth in bytes/sec across all peers, 0 for unlimited
+    #[arg(long, global = true, default_value_t = 0)]
+    max_upload_rate: u64,
+    /// Bytes to request per block, peers commonly drop connections asking for more than 16KiB
+    #[arg(long, global = true, default_value_t = DEFAULT_BLOCK_SIZE, value_parser = clap::value_parser!(u32).range(1..))]
+    block_size: u32,
+    /// Most block requests to keep in flight per peer, fewer are sent to slow peers
+    #[arg(long, global = true, default_value_t = DEFAULT_MAX_QUEUE_DEPTH, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_requests: usize,
+    /// Most peers to be connected to at the same time
+    #[arg(long, global = true, default_value_t = DEFAULT_MAX_PEERS, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_peers: usize,
+    /// File of CIDR ranges to never connect to, one `block <cidr>` or `allow <cidr>` per line
+    #[arg(long, global = true)]
+    ip_filter: Option<PathBuf>,
+    /// Accept pieces without checking their SHA1, only for peers you trust
+    #[arg(long, global = true)]
+    no_verify: bool,
+    /// Give up on a piece once peers sent data for it that failed verification this many times
+    #[arg(long, global = true, default_value_t = DEFAULT_MAX_PIECE_FAILURES, value_parser = clap::value_parser!(u32).range(1..))]
+    max_piece_failures: u32,
+    /// Pieces one peer may get in a row while others sit idle before an idle one gets the next, 0 for no limit
+    #[arg(long, global = true, default_value_t = DEFAULT_MAX_CONSECUTIVE_PIECES)]
+    max_consecutive_pieces: u32,
+    /// Ask the router to forward --port to us while downloading and announce the port it opened,
+    /// downloads go on if it says no
+    #[arg(long, global = true, value_enum)]
+    nat: Option<NatMethod>,
+    /// The router to ask for --nat, found through the default route or UPnP discovery otherwise
+    #[arg(long, global = true)]
+    nat_gateway: Option<Ipv4Addr>,
+}
+
+impl ClientArgs {
+    fn timeout(&self) -> Duration {
+        self.timeout.map_or(DEFAULT_TIMEOUT, Duration::from_secs)
+    }
+
+    fn tracker_timeout(&self) -> Duration {
+        self.tracker_timeout
+            .or(self.timeout)
+            .map_or(DEFAULT_TRACKER_TIMEOUT, Duration::from_secs)
+    }
+
+    fn tracker_request(&self, torrent: &Torrent, http: &Client) -> TrackerRequest {
+        // the tracker wants to know how many bytes are left, which is everything for us
+        let mut request =
+            TrackerRequest::new(self.peer_id.clone(), self.port, torrent.total_length())
+                .with_client(http.clone())
+                .with_timeout(self.tracker_timeout())
+                .with_retries(self.tracker_retries)
+                .with_force_https(self.force_https);
+        if self.no_compact {
+            request.compact = 0;
+        }
+        request
+    }
+}
+
 #[derive(Subcommand, Debug)]
 enum Command {
     Decode {
+        #[arg(long, value_enum, default_value_t = DecodeFormat::Json)]
+        format: DecodeFormat,
         value: String,
     },
     Info {
-        torrent: PathBuf,
+        /// Path to a .torrent file, `-` for stdin or an http(s) URL
+        torrent: String,
+    },
+    /// Print only the info hash of a torrent
+    InfoHash {
+        #[arg(long, value_enum, default_value_t = HashFormat::Hex)]
+        format: HashFormat,
+        /// Hash the info dict re-encoded with its keys in canonical order instead of the bytes
+        /// in the file. Only differs for torrents that were not encoded canonically
+        #[arg(long)]
+        canonical: bool,
+        /// Path to a .torrent file, `-` for stdin or an http(s) URL
+        torrent: String,
+    },
+    /// Print the info hash, name and trackers of a magnet link, nothing is fetched
+    MagnetInfo {
+        /// magnet:?xt=urn:btih:... URI, quoted so the shell leaves the & alone
+        magnet: String,
+    },
+    /// Write the bencoded info dict that the info hash is computed from
+    DumpInfo {
+        /// Where to write the bytes, stdout if not given
+        #[arg(short)]
+        output: Option<PathBuf>,
+        /// Path to a .torrent file, `-` for stdin or an http(s) URL
+        torrent: String,
     },
     Peers {
-        torrent: PathBuf,
+        /// Print a json array of `{"ip": ..., "port": ...}` objects instead of one peer per line
+        #[arg(long)]
+        json: bool,
+        /// Path to a .torrent file, `-` for stdin or an http(s) URL
+        torrent: String,
+    },
+    /// Announce to the trackers without connecting to any peer, then print the interval they
+    /// asked for and how many peers they returned
+    Announce {
+        #[arg(long, value_enum)]
+        event: Option<AnnounceEvent>,
+        /// Bytes uploaded so far
+        #[arg(long, default_value_t = 0)]
+        uploaded: u64,
+        /// Bytes downloaded so far
+        #[arg(long, default_value_t = 0)]
+        downloaded: u64,
+        /// Bytes still missing, the whole torrent if not given
+        #[arg(long)]
+        left: Option<u64>,
+        /// Print the announce url of every tracker instead of sending it
+        #[arg(long)]
+        dry_run: bool,
+        /// Path to a .torrent file, `-` for stdin or an http(s) URL
+        torrent: String,
+    },
+    /// Print the number of seeders, leechers and completed downloads the tracker knows of
+    Scrape {
+        /// Path to a .torrent file, `-` for stdin or an http(s) URL
+        torrent: String,
     },
     Handshake {
-        torrent: PathBuf,
+        /// Path to a .torrent file, `-` for stdin or an http(s) URL
+        torrent: String,
         peer: String,
+        /// Handshake with the peer right away instead of first checking the tracker lists it
+        #[arg(long, alias = "no-peers-check")]
+        skip_tracker_check: bool,
+    },
+    /// Ask every peer for its bitfield and print how many peers have each piece
+    Availability {
+        /// Path to a .torrent file, `-` for stdin or an http(s) URL
+        torrent: String,
     },
     #[clap(name = "download_piece")]
     DownloadPiece {
+        #[arg(short, required_unless_present_any = ["check_only", "dry_run"])]
+        output: Option<PathBuf>,
+        /// Only download and verify the piece, print OK/FAIL instead of writing it
+        #[arg(long)]
+        check_only: bool,
+        /// Print what would be downloaded and from which trackers, then exit without connecting
+        #[arg(long)]
+        dry_run: bool,
+        /// Only download from this peer (ip:port), the trackers are not asked for peers
+        #[arg(long)]
+        peer: Option<SocketAddrV4>,
+        /// Path to a .torrent file, `-` for stdin or an http(s) URL
+        torrent: String,
+        /// Pieces to download, in this order over as few connections as possible. -o gets them
+        /// one after the other
+        #[arg(required = true)]
+        pieces: Vec<u32>,
+    },
+    /// Build a .torrent for a file or a directory
+    Create {
+        /// Where to write the .torrent file
         #[arg(short)]
         output: PathBuf,
+        /// Tracker announce URL
+        #[arg(long)]
+        announce: String,
+        /// Size of each piece in bytes
+        #[arg(long, default_value_t = DEFAULT_PIECE_LENGTH)]
+        piece_length: u64,
+        /// File or directory to share
+        path: PathBuf,
+    },
+    /// Point a torrent at different trackers without changing its info hash
+    Edit {
+        /// Where to write the edited .torrent file
+        #[arg(short)]
+        output: PathBuf,
+        /// New tracker announce URL
+        #[arg(long)]
+        announce: String,
+        /// A tier of backup trackers as comma separated URLs, repeat for more tiers. Without it
+        /// the torrent's announce-list is dropped
+        #[arg(long = "announce-list")]
+        announce_list: Vec<String>,
+        /// Path to the .torrent file to edit
         torrent: PathBuf,
-        piece: u32,
     },
     Download {
+        /// Output file, or the base directory for multi-file torrents
         #[arg(short)]
         output: PathBuf,
-        torrent: PathBuf,
+        /// The order in which pieces are requested
+        #[arg(long, value_enum, default_value_t = PieceOrder::RarestFirst)]
+        order: PieceOrder,
+        /// Only download these files, given by their position in `info`'s file list (from 0) or
+        /// as globs on their path, e.g. `--files 0,'sub/*.bin'`
+        #[arg(long, value_delimiter = ',')]
+        files: Vec<String>,
+        /// Accept connections from other peers on --port and upload the pieces we have so far
+        #[arg(long)]
+        listen: bool,
+        /// How many of the peers connected through --listen may download at once, plus one picked
+        /// at random
+        #[arg(long, default_value_t = DEFAULT_UNCHOKE_SLOTS, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        unchoke_slots: usize,
+        /// Print what would be downloaded and from which trackers, then exit without connecting
+        #[arg(long)]
+        dry_run: bool,
+        /// Only download from this peer (ip:port), the trackers are not contacted at all
+        #[arg(long)]
+        peer: Option<SocketAddrV4>,
+        /// Paths to .torrent files, `-` for stdin or http(s) URLs. With more than one, -o is a
+        /// directory that gets one file or directory per torrent
+        #[arg(required = true)]
+        torrents: Vec<String>,
     },
 }
 
-// for the actual invocation we will use the serde_bencode::from_str as it is safer and will work with non-utf8 strings
-fn decode_bencoded_value(encoded_value: &str) -> (serde_json::Value, &str) {
-    // we return a tuple so we can always return the remainder of the string after recursive parsing
-    match encoded_value.chars().next() {
-        Some('i') => {
-            if let Some((n, rest)) = encoded_value
-                .split_at(1)
-                .1
-                .split_once('e') // integer encoded strings look like i25e
-                .and_then(|(digits, rest)| {
-                    let n = digits.parse::<i64>().ok()?;
-                    Some((n, rest))
-                })
-            {
-                return (n.into(), rest);
-            }
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum HashFormat {
+    Hex,
+    Base32,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum DecodeFormat {
+    /// Compact json on one line
+    Json,
+    /// Indented json
+    Pretty,
+    /// The decoded Rust value, byte strings as raw bytes
+    Debug,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum AnnounceEvent {
+    Started,
+    Stopped,
+    Completed,
+}
+
+impl From<AnnounceEvent> for Event {
+    fn from(event: AnnounceEvent) -> Self {
+        match event {
+            AnnounceEvent::Started => Event::Started,
+            AnnounceEvent::Stopped => Event::Stopped,
+            AnnounceEvent::Completed => Event::Completed,
         }
-        Some('l') => {
-            let mut values = Vec::new();
-            let mut remainder = encoded_value.split_at(1).1; // lists look like l5:helloi52ee
-            while !remainder.starts_with('e') {
-                // e character is the terminator
-                let (value, rest) = decode_bencoded_value(remainder);
-                values.push(value);
-                remainder = rest;
-            }
-            // return the list with whatever is left after in the encoded string, as the list has been terminated in the while with 'e'
-            return (values.into(), &remainder[1..]); // skip the e terminating the list
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum NatMethod {
+    Upnp,
+    /// NAT-PMP
+    Pmp,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum PieceOrder {
+    Sequential,
+    RarestFirst,
+    /// First and last piece, then the rest in order
+    Streaming,
+}
+
+// the cli prints decoded values as json, byte strings are assumed to be (mostly) utf8
+fn bencode_to_json(value: &BencodeValue) -> serde_json::Value {
+    match value {
+        BencodeValue::Int(n) => (*n).into(),
+        BencodeValue::Bytes(bytes) => String::from_utf8_lossy(bytes).into(),
+        BencodeValue::List(values) => values.iter().map(bencode_to_json).collect(),
+        BencodeValue::Dict(map) => map
+            .iter()
+            .map(|(k, v)| (String::from_utf8_lossy(k).into_owned(), bencode_to_json(v)))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+    }
+}
+
+fn parse_peer_id(peer_id: &str) -> Result<String, String> {
+    if peer_id.len() != 20 {
+        return Err(format!(
+            "peer id must be exactly 20 bytes, got {}",
+            peer_id.len()
+        ));
+    }
+    Ok(peer_id.to_string())
+}
+
+// where a torrent of a session with several goes: its name under `base`, which the torrent can't
+// escape since the name comes from the file. A name that's taken gets " (2)", " (3)"... added so
+// torrents never share files or a resume file
+fn session_output(base: &Path, name: &str, taken: &mut HashSet<PathBuf>) -> Result<PathBuf> {
+    let output = download::sanitize_path(base, &[name.to_string()])
+        .context(format!("CTX: output for torrent {name:?}"))?;
+    let mut candidate = output.clone();
+    for n in 2.. {
+        if taken.insert(candidate.clone()) {
+            break;
         }
-        Some('d') => {
-            let mut map = serde_json::Map::new();
-            let mut remainder = encoded_value.split_at(1).1; // dictionaries look like d3:foo3:bar5:helloi52ee
-            let mut count = 0;
-            let mut key: String = String::new();
-            let mut map_value: serde_json::Value;
-            while !remainder.starts_with('e') {
-                let (value, rest) = decode_bencoded_value(remainder);
-                if count == 0 {
-                    match value {
-                        serde_json::Value::String(k) => key = k,
-                        k => {
-                            panic!("Dict keys must be strings, not {k:?}");
-                        }
-                    };
-                    count += 1;
-                } else {
-                    map_value = value;
-                    map.insert(key.clone(), map_value);
-                    count = 0;
-                }
-                remainder = rest;
+        let mut suffixed = output.clone().into_os_string();
+        suffixed.push(format!(" ({n})"));
+        candidate = PathBuf::from(suffixed);
+    }
+    Ok(candidate)
+}
+
+// the download is complete, nothing left to resume
+fn remove_resume(output: &Path) -> Result<()> {
+    match fs::remove_file(download::resume_path(output)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// what --dry-run prints, everything here is known without touching the network
+// (except for fetching the torrent itself when it was given as a url)
+fn print_plan(torrent: &Torrent, pieces: Option<&[u32]>, output: Option<&Path>) {
+    println!("Info Hash: {}", torrent.info.info_hash_str());
+    println!("Piece Length: {}", torrent.info.piece_length);
+    match pieces {
+        Some(pieces) => {
+            for &piece in pieces {
+                println!("Piece: {piece} ({} bytes)", torrent.piece_size(piece));
             }
-            return (map.into(), &remainder[1..]); // skip the e terminating the dict
         }
-        Some('0'..='9') => {
-            if let Some((length, rest)) = encoded_value.split_once(':') {
-                // string encoded values look like 5:hello
-                if let Ok(length) = length.parse::<usize>() {
-                    return (rest[..length].into(), &rest[length..]);
+        None => println!(
+            "Pieces: {} ({} bytes)",
+            torrent.num_pieces(),
+            torrent.total_length()
+        ),
+    }
+    println!("Trackers:");
+    for tracker in torrent.trackers() {
+        println!("  {tracker}");
+    }
+    match output {
+        Some(output) => println!("Output: {}", output.display()),
+        None => println!("Output: none"),
+    }
+}
+
+// keeps announcing while we download, peers from later announces join the running download
+fn reannounce(
+    request: &TrackerRequest,
+    torrent: &Torrent,
+    response: &TrackerResponse,
+    handle: DownloadHandle,
+) -> JoinHandle<()> {
+    let (request, torrent) = (request.clone(), torrent.clone());
+    let mut schedule = AnnounceSchedule::default();
+    let mut delay = schedule.on_success(response);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(delay).await;
+            delay = match request.announce(&torrent).await {
+                Ok(response) => {
+                    handle.add_peers(&response.peers.addresses);
+                    schedule.on_success(&response)
                 }
-            }
+                Err(e) => {
+                    let delay = schedule.on_failure();
+                    warn!(error = format!("{e:#}"), ?delay, "re-announce failed");
+                    delay
+                }
+            };
+            debug!(?delay, "next announce");
         }
-        _ => {}
-    }
+    })
+}
 
-    panic!("Unhandled encoded value: {}", encoded_value)
+// reads the torrent from a file path, from stdin when given `-`, or fetches it with `http` when
+// given a url, giving up on a server that takes longer than `timeout`
+async fn load_torrent(
+    source: &str,
+    strict: bool,
+    http: &Client,
+    timeout: Duration,
+) -> Result<Torrent> {
+    let bytes = if source == "-" {
+        let mut bytes = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut bytes)
+            .context("CTX: Read torrent from stdin")?;
+        bytes
+    } else if source.starts_with("http://") || source.starts_with("https://") {
+        let response = http
+            .get(source)
+            .timeout(timeout)
+            .send()
+            .await
+            .context("CTX: Fetch torrent url")?
+            .error_for_status()
+            .context("CTX: Torrent url response status")?;
+        response
+            .bytes()
+            .await
+            .context("CTX: Torrent url response to bytes")?
+            .to_vec()
+    } else {
+        fs::read(source).context("CTX: Open torrent file")?
+    };
+    if strict {
+        Torrent::from_bytes_strict(&bytes)
+    } else {
+        Torrent::from_bytes(&bytes)
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
+    let level = match args.verbose {
+        0 => Level::WARN,
+        1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    // logs go to stderr so they never mix with the command output on stdout
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr)
+        .init();
+
+    let http = tracker::http_client(&args.client.user_agent, args.client.tracker_timeout())?;
+    let options = DownloadOptions {
+        peer_id: args.client.peer_id.clone(),
+        download_limiter: RateLimiter::new(args.client.max_download_rate),
+        upload_limiter: RateLimiter::new(args.client.max_upload_rate),
+        block_size: args.client.block_size,
+        max_requests: args.client.max_requests,
+        verify: !args.client.no_verify,
+        max_piece_failures: args.client.max_piece_failures,
+        max_consecutive_pieces: args.client.max_consecutive_pieces,
+        max_peers: args.client.max_peers,
+        timeout: args.client.timeout(),
+        ip_filter: match &args.client.ip_filter {
+            Some(path) => IpFilter::from_file(path)?,
+            None => IpFilter::default(),
+        },
+        ..Default::default()
+    };
+    let mut state = options.state();
+    tokio::spawn(async move {
+        // downloading and verifying flip for every piece, we may only see the latest of several changes
+        let mut last = None;
+        loop {
+            let current = *state.borrow_and_update();
+            if last != Some(current) {
+                info!(state = ?current, "client state");
+                last = Some(current);
+            }
+            if state.changed().await.is_err() {
+                break;
+            }
+        }
+    });
+
     match args.command {
-        Command::Decode { value } => {
-            let decoded_value = decode_bencoded_value(&value).0;
-            println!("{decoded_value}");
+        Command::Decode { format, value } => {
+            let (decoded_value, _) = bencode::decode(value.as_bytes())?;
+            match format {
+                DecodeFormat::Json => println!("{}", bencode_to_json(&decoded_value)),
+                DecodeFormat::Pretty => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&bencode_to_json(&decoded_value))?
+                ),
+                DecodeFormat::Debug => println!("{decoded_value:?}"),
+            }
         }
         Command::Info { torrent } => {
-            let file = fs::read(torrent).context("CTX: Open torrent file")?;
-            let torrent: Torrent = from_bytes(&file).context("CTX: torrent file to bytes")?;
+            let torrent = load_torrent(&torrent, args.strict, &http, args.client.timeout()).await?;
             println!("{torrent}")
         }
-        Command::Peers { torrent } => {
-            let file: Vec<u8> = fs::read(torrent).context("CTX: Open torrent file")?;
-            let torrent: Torrent = from_bytes(&file).context("CTX: torrent file to bytes")?;
-            let request = TrackerRequest::default(torrent.info.length);
+        Command::InfoHash {
+            format,
+            canonical,
+            torrent,
+        } => {
+            let torrent = load_torrent(&torrent, args.strict, &http, args.client.timeout()).await?;
+            let hash = if canonical {
+                torrent.info.canonical_info_hash_bytes()?
+            } else {
+                torrent.info.info_hash_bytes()
+            };
+            match format {
+                HashFormat::Hex => println!("{}", encode(hash)),
+                HashFormat::Base32 => println!(
+                    "{}",
+                    base32::encode(base32::Alphabet::Rfc4648 { padding: false }, &hash)
+                ),
+            }
+        }
+        Command::MagnetInfo { magnet } => {
+            let magnet = Magnet::parse(&magnet).context("CTX: parse magnet link")?;
+            println!("{magnet}");
+        }
+        Command::DumpInfo { output, torrent } => {
+            let torrent = load_torrent(&torrent, args.strict, &http, args.client.timeout()).await?;
+            let bytes = torrent.info.to_bytes()?;
+            match output {
+                Some(output) => fs::write(&output, bytes)
+                    .context(format!("CTX: writing {}", output.display()))?,
+                None => std::io::stdout()
+                    .write_all(&bytes)
+                    .context("CTX: write info dict to stdout")?,
+            }
+        }
+        Command::Peers { json, torrent } => {
+            let torrent = load_torrent(&torrent, args.strict, &http, args.client.timeout()).await?;
+            let request = args.client.tracker_request(&torrent, &http);
             let peers = request
                 .discover_peers(&torrent)
                 .await
                 .context("CTX: discover peers")?;
 
-            peers.addresses.iter().for_each(|peer| println!("{peer}"));
+            if json {
+                let peers: Vec<serde_json::Value> = peers
+                    .addresses
+                    .iter()
+                    .map(|peer| serde_json::json!({ "ip": peer.ip().to_string(), "port": peer.port() }))
+                    .collect();
+                println!("{}", serde_json::Value::Array(peers));
+            } else {
+                peers.addresses.iter().for_each(|peer| println!("{peer}"));
+            }
+        }
+        Command::Announce {
+            event,
+            uploaded,
+            downloaded,
+            left,
+            dry_run,
+            torrent,
+        } => {
+            let torrent = load_torrent(&torrent, args.strict, &http, args.client.timeout()).await?;
+            let mut request = args.client.tracker_request(&torrent, &http);
+            if let Some(event) = event {
+                request = request.with_event(event.into());
+            }
+            request.uploaded = uploaded;
+            request.downloaded = downloaded;
+            if let Some(left) = left {
+                request.left = left;
+            }
+
+            if dry_run {
+                for tracker in torrent.trackers() {
+                    println!("{}", request.announce_url(&torrent, tracker)?);
+                }
+                return Ok(());
+            }
+            let response = request
+                .announce(&torrent)
+                .await
+                .context("CTX: announce to tracker")?;
+            match response.interval {
+                Some(interval) => println!("Interval: {interval}"),
+                None => println!("Interval: unknown"),
+            }
+            println!("Peers: {}", response.peers.addresses.len());
+        }
+        Command::Scrape { torrent } => {
+            let torrent = load_torrent(&torrent, args.strict, &http, args.client.timeout()).await?;
+            let stats = args
+                .client
+                .tracker_request(&torrent, &http)
+                .scrape(&torrent)
+                .await
+                .context("CTX: scrape tracker")?;
+            println!("Seeders: {}", stats.complete);
+            println!("Leechers: {}", stats.incomplete);
+            println!("Downloaded: {}", stats.downloaded);
         }
         Command::Handshake {
             torrent: torrent_path,
             peer,
+            skip_tracker_check,
         } => {
-            let file = fs::read(&torrent_path).context("CTX: Open torrent file")?;
-            let torrent: Torrent = from_bytes(&file).context("CTX: torrent file to bytes")?;
+            let peer_addr = peer
+                .parse::<SocketAddrV4>()
+                .context(format!("CTX: parse peer address {peer}"))?;
+            let torrent =
+                load_torrent(&torrent_path, args.strict, &http, args.client.timeout()).await?;
 
             // check if the peer provided is actually in the list of peers
-            let request = TrackerRequest::default(torrent.info.length);
+            if !skip_tracker_check {
+                let request = args.client.tracker_request(&torrent, &http);
+                let peers = request
+                    .discover_peers(&torrent)
+                    .await
+                    .context("CTX: discover peers")?;
+                if !peers.addresses.contains(&peer_addr) {
+                    return Err(anyhow!(
+                        "The trackers of {torrent_path} don't list peer {peer_addr}"
+                    ));
+                }
+            }
+            // advertise what a download would so the peer answers the same way
+            let mut handshake = Handshake::new(torrent.info.info_hash_bytes())
+                .with_peer_id(args.client.peer_id.clone())?
+                .with_fast();
+            if !torrent.is_private() {
+                handshake = handshake.with_extensions();
+            }
+            let (_, peer_info) =
+                Stream::connect_and_handshake_within(&peer_addr, handshake, options.timeout)
+                    .await
+                    .context("CTX: Handshake failed")?;
+            let yes_no = |supported: bool| if supported { "yes" } else { "no" };
+            println!("Peer ID: {}", encode(peer_info.peer_id));
+            println!(
+                "Extension protocol: {}",
+                yes_no(peer_info.capabilities.extensions)
+            );
+            println!("DHT: {}", yes_no(peer_info.capabilities.dht));
+            println!("Fast extension: {}", yes_no(peer_info.capabilities.fast));
+        }
+        Command::Availability { torrent } => {
+            let torrent = load_torrent(&torrent, args.strict, &http, args.client.timeout()).await?;
+            let request = args.client.tracker_request(&torrent, &http);
             let peers = request
                 .discover_peers(&torrent)
                 .await
                 .context("CTX: discover peers")?;
-
-            if !peers.addresses.iter().any(|&item| {
-                item == SocketAddrV4::from_str(&peer)
-                    .expect("Peer address must be a valid IPv4 address")
-            }) {
-                panic!(
-                    "Torrent file {} does not contain peer address {}",
-                    torrent_path.to_string_lossy(),
-                    peer
-                );
-            }
-            let peer_addr = peer
-                .parse::<SocketAddrV4>()
-                .context("CTX: parse peer address")?;
-            let handshake = Handshake::new(torrent.info.info_hash_bytes());
-            let mut stream = Stream::connect(&peer_addr)
+            let availability = download::availability(&torrent, &peers.addresses, &options)
                 .await
-                .context("CTX: Init TCP stream for handshake failed")?;
-            let handshake_response = stream
-                .handshake(handshake)
-                .await
-                .context("CTX: Handshake failed");
+                .context("CTX: collect bitfields")?;
 
-            match handshake_response {
-                Ok(buffer) => println!(
-                    "Peer ID: {}",
-                    encode(&buffer[HANDSHAKE_PEER_ID_BYTE_INDEX_START..])
-                ),
-                Err(e) => panic!("Could not complete handshake! {}", e),
+            println!("Peers: {}", availability.peers);
+            for (piece, count) in availability.counts.iter().enumerate() {
+                println!("{piece}\t{count}");
             }
+            let list = |pieces: Vec<u32>| {
+                if pieces.is_empty() {
+                    return String::from("none");
+                }
+                pieces
+                    .iter()
+                    .map(u32::to_string)
+                    .collect::<Vec<_>>()
+                    .join(",")
+            };
+            println!("Rarest: {}", list(availability.rarest()));
+            println!("Most common: {}", list(availability.most_common()));
+            println!("Missing: {}", list(availability.missing()));
         }
         Command::DownloadPiece {
             output,
+            check_only,
+            dry_run,
+            peer,
             torrent: torrent_path,
-            piece,
+            pieces,
         } => {
-            let file = fs::read(&torrent_path).context("CTX: Open torrent file")?;
-            let torrent: Torrent = from_bytes(&file).context("CTX: torrent file to bytes")?;
-            println!("{torrent:?}");
-            println!("{:?}", torrent.info.pieces.0.len());
-            let request = TrackerRequest::default(torrent.info.length);
-            let peers = request
-                .discover_peers(&torrent)
-                .await
-                .context("CTX: discover peers")?;
-
-            let mut stream = Stream::connect(&peers.addresses[0]).await?;
+            let torrent =
+                load_torrent(&torrent_path, args.strict, &http, args.client.timeout()).await?;
+            if let Some(piece) = pieces
+                .iter()
+                .find(|&&piece| piece as usize >= torrent.num_pieces())
+            {
+                return Err(anyhow!(
+                    "Piece {piece} is out of range, the torrent has {} pieces",
+                    torrent.num_pieces()
+                ));
+            }
+            if dry_run {
+                print_plan(&torrent, Some(&pieces), output.as_deref());
+                return Ok(());
+            }
+            let peers = match peer {
+                Some(peer) => vec![peer],
+                None => {
+                    let request = args.client.tracker_request(&torrent, &http);
+                    let mut peers = request
+                        .discover_peers(&torrent)
+                        .await
+                        .context("CTX: discover peers")?;
+                    // spread the load instead of everyone starting with the tracker's first peer
+                    peers.shuffle();
+                    peers.addresses
+                }
+            };
 
-            let handshake = Handshake::new(torrent.info.info_hash_bytes());
-            stream.handshake(handshake).await?;
-            stream.bitfield().await.context("CTX: bitfield")?;
-            stream.interested().await.context("CT: interested")?;
-            stream
-                .wait_unchoke()
-                .await
-                .context("CTX: await for unchoke")?;
+            // checking the hash is the whole point of --check-only, --no-verify can't skip it
+            let options = DownloadOptions {
+                verify: options.verify || check_only,
+                ..options
+            };
+            let context = match peer {
+                Some(peer) => format!("CTX: Get piece data from {peer} failed"),
+                None => String::from("CTX: Get piece data failed"),
+            };
+            let piece_data = if check_only {
+                // one download per piece, so a piece that fails doesn't take the others with it.
+                // download_piece only hands back data whose hash matched
+                let mut piece_data = Vec::with_capacity(pieces.len());
+                let mut failed = Vec::new();
+                for &piece in &pieces {
+                    match download::download_piece(&torrent, &peers, piece, &options).await {
+                        Ok(data) => {
+                            println!("OK piece {piece}");
+                            piece_data.push(data);
+                        }
+                        Err(e) => {
+                            println!("FAIL piece {piece}");
+                            warn!(piece, error = format!("{e:#}"), "check failed");
+                            failed.push(piece);
+                        }
+                    }
+                }
+                if !failed.is_empty() {
+                    return Err(anyhow!("Pieces {failed:?} failed the check").context(context));
+                }
+                piece_data
+            } else {
+                download::download_pieces(&torrent, &peers, &pieces, &options)
+                    .await
+                    .context(context)?
+            };
 
-            let piece_data: Vec<u8> = stream
-                .get_piece_data(piece, &torrent)
-                .await
-                .context("CTX: Get piece data failed")?;
-
-            let mut hasher = <Sha1 as Digest>::new();
-            hasher.update(&piece_data);
-            #[allow(clippy::unnecessary_fallible_conversions)]
-            let piece_hash: [u8; 20] = hasher
-                .finalize()
-                .try_into()
-                .expect("Hasher finalize failed");
-            let torrent_hash = &torrent.info.pieces.0[piece as usize];
-            if &piece_hash != torrent_hash {
-                panic!("Hashes do NOT match!");
+            if let Some(output) = output {
+                fs::write(output, piece_data.concat())?;
             }
-
-            fs::write(output, &piece_data)?;
+        }
+        Command::Create {
+            output,
+            announce,
+            piece_length,
+            path,
+        } => {
+            let torrent = Torrent::create(&path, announce, piece_length)?;
+            fs::write(&output, torrent.to_bytes()?)
+                .context(format!("CTX: writing {}", output.display()))?;
+            println!(
+                "Created {} with info hash {}",
+                output.display(),
+                torrent.info.info_hash_str()
+            );
+        }
+        Command::Edit {
+            output,
+            announce,
+            announce_list,
+            torrent,
+        } => {
+            let bytes = fs::read(&torrent).context("CTX: Open torrent file")?;
+            let tiers: Vec<Vec<String>> = announce_list
+                .iter()
+                .map(|tier| tier.split(',').map(String::from).collect())
+                .collect();
+            let announce_list = (!tiers.is_empty()).then_some(tiers);
+            let edited = torrent::edit_trackers(&bytes, &announce, announce_list)?;
+            fs::write(&output, edited).context(format!("CTX: writing {}", output.display()))?;
         }
         Command::Download {
             output,
-            torrent: torrent_path,
+            order,
+            files,
+            listen,
+            unchoke_slots,
+            dry_run,
+            peer,
+            torrents: torrent_paths,
         } => {
-            let file = fs::read(&torrent_path).context("CTX: Open torrent file")?;
-            let torrent: Torrent = from_bytes(&file).context("CTX: torrent file to bytes")?;
+            // with several torrents the output is a directory holding one entry per torrent
+            let many = torrent_paths.len() > 1;
+            if many && (!files.is_empty() || listen) {
+                return Err(anyhow!(
+                    "--files and --listen only work with a single torrent"
+                ));
+            }
+            if many && !dry_run {
+                fs::create_dir_all(&output)
+                    .context(format!("CTX: creating {}", output.display()))?;
+            }
+            // the router may open another port than ours, that one is what peers have to be told
+            let mapping = match args.client.nat {
+                Some(method) if !dry_run => {
+                    let protocol = match method {
+                        NatMethod::Upnp => NatProtocol::Upnp,
+                        NatMethod::Pmp => NatProtocol::Pmp,
+                    };
+                    nat::map_port(protocol, args.client.port, args.client.nat_gateway)
+                        .await
+                        .inspect_err(|e| warn!(error = format!("{e:#}"), "could not map port"))
+                        .ok()
+                }
+                _ => None,
+            };
+            let announced_port = mapping
+                .as_ref()
+                .map_or(args.client.port, |mapping| mapping.external.port());
+            let session = Session::new(options);
+            let work = async {
+                let mut jobs = Vec::new();
+                let mut downloads = Vec::new();
+                let mut outputs = HashSet::new();
+                for torrent_path in &torrent_paths {
+                    let torrent =
+                        load_torrent(torrent_path, args.strict, &http, args.client.timeout())
+                            .await?;
+                    let output = if many {
+                        session_output(&output, torrent.info.display_name(), &mut outputs)?
+                    } else {
+                        output.clone()
+                    };
+                    let selected = if files.is_empty() {
+                        (0..torrent.files().len()).collect()
+                    } else {
+                        torrent.select_files(&files)?
+                    };
+                    // pieces at a file boundary get downloaded whole, the writer drops the other file's part
+                    let options = DownloadOptions {
+                        pieces: (!files.is_empty()).then(|| {
+                            selected
+                                .iter()
+                                .flat_map(|&index| torrent.file_pieces(index))
+                                .collect()
+                        }),
+                        listen: listen.then(|| SocketAddr::from(([0, 0, 0, 0], args.client.port))),
+                        unchoke_slots,
+                        ..session.download_options()
+                    };
+                    if dry_run {
+                        print_plan(&torrent, None, Some(&output));
+                        continue;
+                    }
+                    // every piece goes to disk as soon as it is verified, so the torrent never has to
+                    // fit in memory
+                    let writer = PieceWriter::create(&torrent, &output, &selected)
+                        .context(format!("CTX: create {}", output.display()))?;
+                    // a cancelled earlier run left its pieces in place
+                    let resumed = download::read_resume(&torrent, &output, &writer)?;
+                    if !resumed.is_empty() {
+                        info!(pieces = resumed.len(), "resuming an earlier download");
+                    }
+                    let options = DownloadOptions {
+                        writer: Some(Arc::new(writer)),
+                        resumed,
+                        ..options
+                    };
 
-            let request = TrackerRequest::default(torrent.info.length);
-            let peers = request
-                .discover_peers(&torrent)
-                .await
-                .context("CTX: discover peers")?;
+                    let mut request = args.client.tracker_request(&torrent, &http);
+                    request.port = announced_port;
+                    let (peers, reannounce) = match peer {
+                        Some(peer) => (vec![peer], None),
+                        None => {
+                            let response = request
+                                .announce(&torrent)
+                                .await
+                                .context(format!("CTX: discover peers for {torrent_path}"))?;
+                            let mut peers = response.peers.clone();
+                            // spread the load instead of everyone starting with the tracker's first peer
+                            peers.shuffle();
+                            let reannounce =
+                                reannounce(&request, &torrent, &response, options.handle());
+                            (peers.addresses, Some(reannounce))
+                        }
+                    };
+                    if many {
+                        let mut progress = options.progress();
+                        let name = torrent.info.display_name().to_string();
+                        tokio::spawn(async move {
+                            while progress.changed().await.is_ok() {
+                                let (done, wanted) = *progress.borrow_and_update();
+                                info!(torrent = name, done, wanted, "progress");
+                            }
+                        });
+                    }
 
-            let mut file_data: Vec<u8> = Vec::new();
-            for piece in 0..torrent.info.pieces.0.len() {
-                let mut stream = Stream::connect(&peers.addresses[0]).await?;
-                let handshake = Handshake::new(torrent.info.info_hash_bytes());
-                stream.handshake(handshake).await?;
-                stream.bitfield().await.context("CTX: bitfield")?;
-                stream.interested().await.context("CT: interested")?;
-                stream
-                    .wait_unchoke()
-                    .await
-                    .context("CTX: await for unchoke")?;
+                    let selector: Box<dyn PieceSelector> = match order {
+                        PieceOrder::Sequential => Box::new(Sequential),
+                        PieceOrder::RarestFirst => Box::new(RarestFirst::new(torrent.num_pieces())),
+                        PieceOrder::Streaming => Box::new(Streaming::new(torrent.num_pieces())),
+                    };
+                    downloads.push(Download {
+                        torrent: torrent.clone(),
+                        peers,
+                        selector,
+                        options: options.clone(),
+                    });
+                    jobs.push((torrent, output, selected, request, options, reannounce));
+                }
+                if dry_run {
+                    return Ok(None);
+                }
 
-                let piece_data: Vec<u8> = stream
-                    .get_piece_data(piece as u32, &torrent)
-                    .await
-                    .context("CTX: Get piece data failed")?;
-                let mut hasher = <Sha1 as Digest>::new();
-                hasher.update(&piece_data);
-                #[allow(clippy::unnecessary_fallible_conversions)]
-                let piece_hash: [u8; 20] = hasher
-                    .finalize()
-                    .try_into()
-                    .expect("Hasher finalize failed");
-                let torrent_hash = &torrent.info.pieces.0[piece];
-                if &piece_hash != torrent_hash {
-                    panic!("Hashes for piece {} do NOT match!", piece);
+                // on ctrl-c keep what we have and tell the trackers we are gone instead of just dying
+                let cancel = session.clone();
+                tokio::spawn(async move {
+                    if tokio::signal::ctrl_c().await.is_ok() {
+                        cancel.cancel();
+                    }
+                });
+                let results = session.download_all(downloads).await?;
+                anyhow::Ok(Some((jobs, results)))
+            };
+            // the mapping is removed however the downloads end, not only when they succeed
+            let outcome = match mapping {
+                Some(mapping) => mapping.hold(work).await,
+                None => work.await,
+            };
+            let Some((jobs, results)) = outcome? else {
+                return Ok(());
+            };
+
+            let mut errors = Vec::new();
+            for ((torrent, output, selected, request, options, reannounce), result) in
+                jobs.into_iter().zip(results)
+            {
+                if let Some(reannounce) = reannounce {
+                    reannounce.abort();
+                }
+                let name = torrent.info.display_name();
+                let result = match result {
+                    Ok(_) => options
+                        .writer
+                        .as_ref()
+                        .map_or(Ok(()), |writer| writer.sync())
+                        .and_then(|()| download::verify_files(&torrent, &output, &selected))
+                        .and_then(|()| remove_resume(&output))
+                        .context(format!("CTX: write {}", output.display())),
+                    Err(e) => match e.downcast_ref() {
+                        Some(DownloadError::Cancelled { completed, .. }) => {
+                            // the pieces are in their files already, only which ones is missing
+                            if let Some(writer) = &options.writer {
+                                writer.sync()?;
+                            }
+                            download::write_resume(&torrent, &output, completed)?;
+                            // with --peer the tracker never heard of us
+                            if peer.is_none() {
+                                let stopped = request.with_event(Event::Stopped).with_retries(0);
+                                if let Err(e) = stopped.discover_peers(&torrent).await {
+                                    warn!(
+                                        error = format!("{e:#}"),
+                                        "could not announce stopped event"
+                                    );
+                                }
+                            }
+                            let have = completed.iter().filter(|&&done| done).count();
+                            Err(e.context(format!(
+                                "CTX: saved {have} of {} pieces to {}",
+                                torrent.num_pieces(),
+                                output.display()
+                            )))
+                        }
+                        _ => match peer {
+                            Some(peer) => Err(e.context(format!("CTX: download from {peer}"))),
+                            None => Err(e.context("CTX: download")),
+                        },
+                    },
+                };
+                match result {
+                    Ok(()) if many => println!("{name}:\n{}", options.summary()),
+                    Ok(()) => println!("{}", options.summary()),
+                    Err(e) if many => errors.push(format!("{name}: {e:#}")),
+                    Err(e) => return Err(e),
                 }
-                file_data.extend(piece_data);
             }
-            fs::write(output, &file_data)?;
+            if !errors.is_empty() {
+                return Err(anyhow!(
+                    "{} of {} torrents failed:\n{}",
+                    errors.len(),
+                    torrent_paths.len(),
+                    errors.join("\n")
+                ));
+            }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_args(flags: &[&str]) -> ClientArgs {
+        let args = [
+            &["bittorrent-starter-rust"],
+            flags,
+            &["info", "sample.torrent"],
+        ]
+        .concat();
+        Args::parse_from(args).client
+    }
+
+    #[test]
+    fn timeout_reaches_tracker_requests_and_peers() {
+        let torrent = Torrent::from_bytes(include_bytes!("../sample.torrent")).unwrap();
+        let defaults = client_args(&[]);
+        assert_eq!(defaults.timeout(), DEFAULT_TIMEOUT);
+        assert_eq!(defaults.tracker_timeout(), DEFAULT_TRACKER_TIMEOUT);
+
+        let client = client_args(&["--timeout", "3"]);
+        assert_eq!(client.timeout(), Duration::from_secs(3));
+        let request = client.tracker_request(&torrent, &Client::new());
+        assert_eq!(request.timeout, Duration::from_secs(3));
+
+        // the more specific flag wins, wherever it is given
+        let client = client_args(&["--tracker-timeout", "7", "--timeout", "3"]);
+        assert_eq!(client.timeout(), Duration::from_secs(3));
+        let request = client.tracker_request(&torrent, &Client::new());
+        assert_eq!(request.timeout, Duration::from_secs(7));
+    }
+}
@@ -0,0 +1,541 @@
+use anyhow::{anyhow, Context, Result};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// How long a mapping lives unless it is removed earlier, so a crashed client doesn't leave the
+/// port open for good.
+pub const MAPPING_LIFETIME: Duration = Duration::from_secs(3600);
+// how long to wait for the router to answer a single request
+const GATEWAY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The protocols routers speak for opening a port from the inside.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatProtocol {
+    Upnp,
+    /// NAT-PMP (RFC 6886), mostly found on Apple routers
+    Pmp,
+}
+
+/// A TCP port opened on the router. Call `remove` when done, otherwise it lapses after
+/// `MAPPING_LIFETIME` unless it is renewed, `hold` does both around a task.
+#[derive(Debug, Clone)]
+pub struct PortMapping {
+    pub internal_port: u16,
+    /// Where peers on the internet can reach us.
+    pub external: SocketAddr,
+    gateway: Gateway,
+}
+
+#[derive(Debug, Clone)]
+enum Gateway {
+    Upnp {
+        control_url: String,
+        service: String,
+        // the address the router forwards to
+        local: IpAddr,
+    },
+    Pmp(SocketAddr),
+}
+
+impl Display for NatProtocol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NatProtocol::Upnp => write!(f, "UPnP"),
+            NatProtocol::Pmp => write!(f, "NAT-PMP"),
+        }
+    }
+}
+
+/// Asks the router to forward TCP `port` to us. `gateway` is the router's address, for UPnP it
+/// is only needed when the router doesn't answer multicast discovery. NAT-PMP falls back to
+/// the default route's gateway.
+pub async fn map_port(
+    protocol: NatProtocol,
+    port: u16,
+    gateway: Option<Ipv4Addr>,
+) -> Result<PortMapping> {
+    let mapping = match protocol {
+        NatProtocol::Upnp => upnp::map_port(port, gateway).await?,
+        NatProtocol::Pmp => {
+            let gateway = match gateway {
+                Some(gateway) => gateway,
+                None => default_gateway()?,
+            };
+            pmp::map_port(SocketAddr::from((gateway, pmp::PORT)), port).await?
+        }
+    };
+    info!(%protocol, external = %mapping.external, "mapped port on the router");
+    Ok(mapping)
+}
+
+impl PortMapping {
+    /// Asks the router for another `MAPPING_LIFETIME` of the same mapping.
+    pub async fn renew(&self) -> Result<()> {
+        match &self.gateway {
+            Gateway::Upnp {
+                control_url,
+                service,
+                local,
+            } => upnp::add_port_mapping(control_url, service, *local, self.internal_port).await?,
+            Gateway::Pmp(gateway) => {
+                let external_port =
+                    pmp::renew_port(*gateway, self.internal_port, self.external.port()).await?;
+                // peers that got the old port from the tracker can't reach us anymore
+                if external_port != self.external.port() {
+                    return Err(anyhow!(
+                        "Gateway moved the mapping from port {} to {external_port}",
+                        self.external.port()
+                    ));
+                }
+            }
+        }
+        debug!(port = self.internal_port, "renewed port mapping");
+        Ok(())
+    }
+
+    /// Runs `work` while renewing the mapping halfway through every lifetime, then removes it
+    /// whether `work` succeeded or not.
+    pub async fn hold<T>(self, work: impl Future<Output = T>) -> T {
+        let renewals = async {
+            loop {
+                tokio::time::sleep(MAPPING_LIFETIME / 2).await;
+                if let Err(e) = self.renew().await {
+                    warn!(error = format!("{e:#}"), "could not renew port mapping");
+                }
+            }
+        };
+        let result = tokio::select! {
+            result = work => result,
+            _ = renewals => unreachable!("renewals never end"),
+        };
+        if let Err(e) = self.remove().await {
+            warn!(error = format!("{e:#}"), "could not remove port mapping");
+        }
+        result
+    }
+
+    pub async fn remove(self) -> Result<()> {
+        match &self.gateway {
+            Gateway::Upnp {
+                control_url,
+                service,
+                ..
+            } => upnp::unmap_port(control_url, service, self.internal_port).await?,
+            Gateway::Pmp(gateway) => pmp::unmap_port(*gateway, self.internal_port).await?,
+        }
+        debug!(port = self.internal_port, "removed port mapping");
+        Ok(())
+    }
+}
+
+// the gateway of the default route, from the kernel's routing table
+fn default_gateway() -> Result<Ipv4Addr> {
+    let routes = fs::read_to_string("/proc/net/route")
+        .context("CTX: read routing table, pass the gateway explicitly")?;
+    // Iface Destination Gateway ..., addresses are little endian hex
+    routes
+        .lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| fields.get(1) == Some(&"00000000"))
+        .and_then(|fields| u32::from_str_radix(fields.get(2)?, 16).ok())
+        .map(|gateway| Ipv4Addr::from(gateway.swap_bytes()))
+        .ok_or_else(|| anyhow!("No default route, pass the gateway explicitly"))
+}
+
+// the address we reach `remote` from, which is what the router has to forward to
+fn local_address(remote: SocketAddr) -> Result<IpAddr> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").context("CTX: bind udp socket")?;
+    socket
+        .connect(remote)
+        .context(format!("CTX: route to {remote}"))?;
+    Ok(socket.local_addr()?.ip())
+}
+
+// NAT-PMP talks udp to port 5351 of the gateway. Every request starts with <version=0><opcode>,
+// every response with <version><opcode + 128><result code u16><seconds since epoch u32>
+pub mod pmp {
+    use anyhow::{anyhow, Context, Result};
+    use std::net::{Ipv4Addr, SocketAddr};
+    use tokio::net::UdpSocket;
+    use tokio::time::timeout;
+
+    use super::{Gateway, PortMapping, GATEWAY_TIMEOUT, MAPPING_LIFETIME};
+
+    pub const PORT: u16 = 5351;
+    const OP_EXTERNAL_ADDRESS: u8 = 0;
+    const OP_MAP_TCP: u8 = 2;
+
+    /// `<0><2><reserved u16><internal port><suggested external port><lifetime secs u32>`.
+    /// A lifetime of 0 removes the mapping.
+    pub fn map_request(internal_port: u16, external_port: u16, lifetime: u32) -> [u8; 12] {
+        let mut request = [0u8; 12];
+        request[1] = OP_MAP_TCP;
+        request[4..6].copy_from_slice(&internal_port.to_be_bytes());
+        request[6..8].copy_from_slice(&external_port.to_be_bytes());
+        request[8..12].copy_from_slice(&lifetime.to_be_bytes());
+        request
+    }
+
+    /// Returns the external port the gateway picked and the lifetime it granted.
+    pub fn parse_map_response(response: &[u8], internal_port: u16) -> Result<(u16, u32)> {
+        let body = check_response(response, OP_MAP_TCP, 16)?;
+        let field16 = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+        if field16(0) != internal_port {
+            return Err(anyhow!(
+                "Gateway answered for port {} instead of {internal_port}",
+                field16(0)
+            ));
+        }
+        let lifetime = u32::from_be_bytes(body[4..8].try_into().expect("4 bytes"));
+        Ok((field16(2), lifetime))
+    }
+
+    pub fn parse_external_address_response(response: &[u8]) -> Result<Ipv4Addr> {
+        let body = check_response(response, OP_EXTERNAL_ADDRESS, 12)?;
+        Ok(Ipv4Addr::new(body[0], body[1], body[2], body[3]))
+    }
+
+    // checks the common header and returns what follows it
+    fn check_response(response: &[u8], opcode: u8, length: usize) -> Result<&[u8]> {
+        if response.len() < length {
+            return Err(anyhow!(
+                "NAT-PMP response of {} bytes, expected {length}",
+                response.len()
+            ));
+        }
+        if response[1] != opcode + 128 {
+            return Err(anyhow!(
+                "NAT-PMP response for opcode {}, expected {}",
+                response[1].wrapping_sub(128),
+                opcode
+            ));
+        }
+        match u16::from_be_bytes([response[2], response[3]]) {
+            0 => Ok(&response[8..length]),
+            // 1 unsupported version, 2 not authorized, 3 network failure, 4 out of resources
+            code => Err(anyhow!(
+                "Gateway refused the NAT-PMP request with code {code}"
+            )),
+        }
+    }
+
+    async fn exchange(gateway: SocketAddr, request: &[u8]) -> Result<Vec<u8>> {
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .context("CTX: bind udp socket")?;
+        socket
+            .send_to(request, gateway)
+            .await
+            .context(format!("CTX: send NAT-PMP request to {gateway}"))?;
+        let mut response = [0u8; 16];
+        let (length, _) = timeout(GATEWAY_TIMEOUT, socket.recv_from(&mut response))
+            .await
+            .context(format!("CTX: {gateway} did not answer the NAT-PMP request"))??;
+        Ok(response[..length].to_vec())
+    }
+
+    pub(super) async fn map_port(gateway: SocketAddr, port: u16) -> Result<PortMapping> {
+        let external_port = renew_port(gateway, port, port).await?;
+        let response = exchange(gateway, &[0, OP_EXTERNAL_ADDRESS]).await?;
+        let external_ip = parse_external_address_response(&response)?;
+        Ok(PortMapping {
+            internal_port: port,
+            external: SocketAddr::from((external_ip, external_port)),
+            gateway: Gateway::Pmp(gateway),
+        })
+    }
+
+    // asking for a mapping that exists already extends it, the gateway may still pick another
+    // external port than the suggested one
+    pub(super) async fn renew_port(
+        gateway: SocketAddr,
+        port: u16,
+        external_port: u16,
+    ) -> Result<u16> {
+        let lifetime = MAPPING_LIFETIME.as_secs() as u32;
+        let response = exchange(gateway, &map_request(port, external_port, lifetime)).await?;
+        let (external_port, _) = parse_map_response(&response, port)?;
+        Ok(external_port)
+    }
+
+    pub(super) async fn unmap_port(gateway: SocketAddr, port: u16) -> Result<()> {
+        let response = exchange(gateway, &map_request(port, 0, 0)).await?;
+        parse_map_response(&response, port)?;
+        Ok(())
+    }
+}
+
+// UPnP IGD: find the router with an SSDP search, read its device description for the url of
+// the WAN connection service and call that service's SOAP actions
+pub mod upnp {
+    use anyhow::{anyhow, Context, Result};
+    use regex::Regex;
+    use reqwest::Url;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use tokio::net::UdpSocket;
+    use tokio::time::timeout;
+
+    use super::{local_address, Gateway, PortMapping, GATEWAY_TIMEOUT, MAPPING_LIFETIME};
+
+    const SSDP_ADDRESS: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+    pub const SSDP_PORT: u16 = 1900;
+    const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+    // the router offers one of these depending on how it connects to the internet
+    const WAN_SERVICES: [&str; 2] = [
+        "urn:schemas-upnp-org:service:WANIPConnection:1",
+        "urn:schemas-upnp-org:service:WANPPPConnection:1",
+    ];
+
+    pub fn search_request() -> String {
+        format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP_ADDRESS}:{SSDP_PORT}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {SEARCH_TARGET}\r\n\r\n"
+        )
+    }
+
+    /// The LOCATION header of an SSDP answer, the url of the router's device description.
+    pub fn parse_search_response(response: &str) -> Result<String> {
+        response
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("location")
+                    .then(|| value.trim().to_string())
+            })
+            .ok_or_else(|| anyhow!("SSDP response without a LOCATION header"))
+    }
+
+    /// Finds the WAN connection service in a device description and returns its service type and
+    /// control url, resolved against `location`.
+    pub fn parse_description(description: &str, location: &str) -> Result<(String, String)> {
+        let service = Regex::new(r"(?s)<service>(.*?)</service>").expect("valid regex");
+        let tag = |block: &str, name: &str| {
+            Regex::new(&format!(r"(?s)<{name}>\s*(.*?)\s*</{name}>"))
+                .expect("valid regex")
+                .captures(block)
+                .map(|captures| captures[1].to_string())
+        };
+        for block in service.captures_iter(description) {
+            let Some(service_type) = tag(&block[1], "serviceType") else {
+                continue;
+            };
+            if !WAN_SERVICES.contains(&service_type.as_str()) {
+                continue;
+            }
+            let control_url = tag(&block[1], "controlURL")
+                .ok_or_else(|| anyhow!("{service_type} has no controlURL"))?;
+            let control_url = Url::parse(location)
+                .and_then(|base| base.join(&control_url))
+                .context(format!("CTX: invalid control url {control_url}"))?;
+            return Ok((service_type, control_url.into()));
+        }
+        Err(anyhow!("Router offers no WAN connection service"))
+    }
+
+    /// The SOAP request for `action` of `service` with `arguments` in order.
+    pub fn soap_body(service: &str, action: &str, arguments: &[(&str, String)]) -> String {
+        let arguments: String = arguments
+            .iter()
+            .map(|(name, value)| format!("<{name}>{value}</{name}>"))
+            .collect();
+        format!(
+            "<?xml version=\"1.0\"?>\r\n\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{service}\">{arguments}</u:{action}></s:Body>\
+             </s:Envelope>\r\n"
+        )
+    }
+
+    // finds the router's description url, asking `gateway` directly if given instead of everyone
+    async fn discover(gateway: Option<Ipv4Addr>) -> Result<String> {
+        let target = SocketAddr::from((gateway.unwrap_or(SSDP_ADDRESS), SSDP_PORT));
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .context("CTX: bind udp socket")?;
+        socket
+            .send_to(search_request().as_bytes(), target)
+            .await
+            .context(format!("CTX: send SSDP search to {target}"))?;
+        let mut response = vec![0u8; 2048];
+        let (length, _) = timeout(GATEWAY_TIMEOUT, socket.recv_from(&mut response))
+            .await
+            .context("CTX: no UPnP router answered")??;
+        parse_search_response(&String::from_utf8_lossy(&response[..length]))
+    }
+
+    async fn call(
+        control_url: &str,
+        service: &str,
+        action: &str,
+        arguments: &[(&str, String)],
+    ) -> Result<String> {
+        let response = reqwest::Client::new()
+            .post(control_url)
+            .timeout(GATEWAY_TIMEOUT)
+            .header("Content-Type", "text/xml; charset=\"utf-8\"")
+            .header("SOAPAction", format!("\"{service}#{action}\""))
+            .body(soap_body(service, action, arguments))
+            .send()
+            .await
+            .context(format!("CTX: UPnP {action}"))?;
+        let status = response.status();
+        let body = response.text().await.context("CTX: UPnP response body")?;
+        if !status.is_success() {
+            return Err(anyhow!("Router refused {action} with {status}: {body}"));
+        }
+        Ok(body)
+    }
+
+    pub(super) async fn map_port(port: u16, gateway: Option<Ipv4Addr>) -> Result<PortMapping> {
+        let location = discover(gateway).await?;
+        let description = reqwest::get(&location)
+            .await
+            .and_then(|response| response.error_for_status())
+            .context("CTX: fetch router description")?
+            .text()
+            .await
+            .context("CTX: router description body")?;
+        let (service, control_url) = parse_description(&description, &location)?;
+        let router = Url::parse(&control_url)?
+            .socket_addrs(|| Some(80))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("Cannot resolve {control_url}"))?;
+        let local = local_address(router)?;
+        add_port_mapping(&control_url, &service, local, port).await?;
+        let response = call(&control_url, &service, "GetExternalIPAddress", &[]).await?;
+        let external_ip: IpAddr = Regex::new(r"<NewExternalIPAddress>\s*([^<\s]+)\s*<")
+            .expect("valid regex")
+            .captures(&response)
+            .ok_or_else(|| anyhow!("Router did not tell us its external address"))?[1]
+            .parse()
+            .context("CTX: parse external address")?;
+        Ok(PortMapping {
+            internal_port: port,
+            external: SocketAddr::new(external_ip, port),
+            gateway: Gateway::Upnp {
+                control_url,
+                service,
+                local,
+            },
+        })
+    }
+
+    // adding a mapping that exists already renews its lease
+    pub(super) async fn add_port_mapping(
+        control_url: &str,
+        service: &str,
+        local: IpAddr,
+        port: u16,
+    ) -> Result<()> {
+        call(
+            control_url,
+            service,
+            "AddPortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", port.to_string()),
+                ("NewProtocol", String::from("TCP")),
+                ("NewInternalPort", port.to_string()),
+                ("NewInternalClient", local.to_string()),
+                ("NewEnabled", String::from("1")),
+                ("NewPortMappingDescription", String::from("bittorrent-rust")),
+                ("NewLeaseDuration", MAPPING_LIFETIME.as_secs().to_string()),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub(super) async fn unmap_port(control_url: &str, service: &str, port: u16) -> Result<()> {
+        call(
+            control_url,
+            service,
+            "DeletePortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", port.to_string()),
+                ("NewProtocol", String::from("TCP")),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::UdpSocket;
+
+    // a NAT-PMP gateway on an ephemeral port that maps to the external ports it is given in
+    // turn and records every request
+    async fn mock_gateway(external_ports: Vec<u16>) -> (SocketAddr, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            let mut external_ports = external_ports.into_iter();
+            let mut request = [0u8; 12];
+            loop {
+                let (length, from) = socket.recv_from(&mut request).await.unwrap();
+                let request = request[..length].to_vec();
+                // <version><opcode + 128><result><seconds since epoch>
+                let mut response = vec![0, request[1] + 128, 0, 0, 0, 0, 0, 42];
+                match request[1] {
+                    0 => response.extend([203, 0, 113, 7]),
+                    _ => {
+                        let lifetime = &request[8..12];
+                        let external = match lifetime {
+                            [0, 0, 0, 0] => 0,
+                            _ => external_ports.next().unwrap(),
+                        };
+                        response.extend(&request[4..6]);
+                        response.extend(external.to_be_bytes());
+                        response.extend(lifetime);
+                    }
+                }
+                recorded.lock().unwrap().push(request);
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+        (address, requests)
+    }
+
+    #[tokio::test]
+    async fn pmp_mapping_is_renewed_and_removed() {
+        let (gateway, requests) = mock_gateway(vec![40000, 40000]).await;
+        let mapping = pmp::map_port(gateway, 6881).await.unwrap();
+        assert_eq!(mapping.external, "203.0.113.7:40000".parse().unwrap());
+
+        mapping.renew().await.unwrap();
+        assert_eq!(mapping.hold(async { 7 }).await, 7);
+
+        let lifetime = MAPPING_LIFETIME.as_secs() as u32;
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                pmp::map_request(6881, 6881, lifetime).to_vec(),
+                vec![0, 0],
+                // the renewal asks for the port we announced
+                pmp::map_request(6881, 40000, lifetime).to_vec(),
+                pmp::map_request(6881, 0, 0).to_vec(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn renewal_to_another_port_is_an_error() {
+        let (gateway, _) = mock_gateway(vec![40000, 40001]).await;
+        let mapping = pmp::map_port(gateway, 6881).await.unwrap();
+        let error = mapping.renew().await.unwrap_err();
+        assert!(error.to_string().contains("40001"), "{error:#}");
+    }
+}
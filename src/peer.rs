@@ -1,191 +1,897 @@
 use anyhow::{anyhow, Context, Result};
-use std::{net::SocketAddrV4, time::Duration};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::{SocketAddr, SocketAddrV4},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
     time::timeout,
 };
+use tracing::{debug, info, instrument, trace};
 
+use crate::bencode;
+use crate::error::BtError;
 use crate::torrent::Torrent;
 
+pub const DEFAULT_PIECE_TIMEOUT: Duration = Duration::from_secs(30);
+/// 16KiB, the largest block every client accepts. Some reject anything bigger.
+pub const DEFAULT_BLOCK_SIZE: u32 = 16 * 1024;
+/// Peers usually drop connections that have been silent for two minutes.
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+/// How long connecting, the handshake, waiting to be unchoked and each block may take, unless
+/// the stream is given a different `timeout`.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest message we accept from a peer. The length prefix comes straight off the wire and we
+/// allocate that much, so anything past this is treated as a broken or hostile peer.
+/// Most message types have a much lower limit, see `MessageType::max_length`.
+pub const MAX_MESSAGE_LENGTH: u32 = 2 * 1024 * 1024;
+
 use self::{
-    handshake::{Handshake, HANDSHAKE_BYTE_BUFFER_SIZE},
+    bitfield::BitField,
+    block_cache::BlockCache,
+    extension::{ExtendedHandshake, PexMessage},
+    handshake::{Capabilities, Handshake, PeerInfo, HANDSHAKE_BYTE_BUFFER_SIZE},
     message::MessageType,
+    queue::RequestQueue,
+    rate_limit::RateLimiter,
 };
 
-pub struct Stream {
-    pub connection: TcpStream,
+/// A peer wire connection. Generic over the transport so anything that reads and writes bytes
+/// (e.g. an in-memory `tokio::io::duplex` pipe) can stand in for the tcp socket.
+pub struct Stream<T = TcpStream> {
+    pub connection: T,
+    /// Throttles how fast we pull piece data from this peer, usually shared by all connections.
+    pub download_limiter: RateLimiter,
+    /// Throttles how fast we send blocks to this peer, usually shared by all connections.
+    pub upload_limiter: RateLimiter,
+    /// How many bytes to ask for per request, the last block of a piece may be shorter.
+    pub block_size: u32,
+    /// How many block requests we keep in flight, adapts to how fast the peer answers.
+    pub request_queue: RequestQueue,
+    /// How long the handshake, waiting to be unchoked and each block of a piece may take.
+    pub timeout: Duration,
+    /// What we advertised in our handshake, all false until the handshake is done.
+    pub capabilities: Capabilities,
+    /// What the peer advertised in its handshake, all false until the handshake is done.
+    pub peer_capabilities: Capabilities,
+    /// Extension name to message id, as sent in the peer's extended handshake.
+    pub peer_extensions: BTreeMap<String, u8>,
+    /// Addresses the peer told us about through ut_pex that nobody has picked up yet.
+    pub discovered_peers: Vec<SocketAddrV4>,
+    /// The port the peer's DHT node listens on, from its Port message.
+    pub dht_port: Option<u16>,
+    /// Pieces the peer announced with Have messages that nobody has picked up yet.
+    pub peer_haves: Vec<u32>,
+    /// Where blocks go as they arrive and where a piece that broke off earlier is picked up from.
+    /// Only shared with other connections when given with `with_block_cache`.
+    pub block_cache: BlockCache,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    throughput: f64,
+}
+
+impl Stream<TcpStream> {
+    /// Connects over IPv4 or IPv6, takes a `SocketAddr` as well as a `SocketAddrV4`/`SocketAddrV6`.
+    pub async fn connect(peer_addr: &(impl Into<SocketAddr> + Copy)) -> Result<Self> {
+        Self::connect_addr((*peer_addr).into(), DEFAULT_TIMEOUT).await
+    }
+
+    /// `connect` with `timeout` for connecting and the stream's later reads.
+    pub async fn connect_within(
+        peer_addr: &(impl Into<SocketAddr> + Copy),
+        timeout: Duration,
+    ) -> Result<Self> {
+        Self::connect_addr((*peer_addr).into(), timeout).await
+    }
+
+    #[instrument(level = "info")]
+    async fn connect_addr(peer_addr: SocketAddr, connect_timeout: Duration) -> Result<Self> {
+        let connection = timeout(connect_timeout, TcpStream::connect(peer_addr))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|connected| Ok(connected?))
+            .context(format!(
+                "CTX: Stream connection failed to peer address: {peer_addr}"
+            ))?;
+        info!("connected to peer");
+        Ok(Self::new(connection).with_timeout(connect_timeout))
+    }
+
+    /// Connects, sends `handshake` and checks that the peer answered for the same torrent.
+    pub async fn connect_and_handshake(
+        peer_addr: &(impl Into<SocketAddr> + Copy),
+        handshake: Handshake,
+    ) -> Result<(Self, PeerInfo)> {
+        Self::connect_and_handshake_within(peer_addr, handshake, DEFAULT_TIMEOUT).await
+    }
+
+    /// `connect_and_handshake` with `timeout` for connecting, the handshake and the stream's
+    /// later reads instead of `DEFAULT_TIMEOUT`.
+    pub async fn connect_and_handshake_within(
+        peer_addr: &(impl Into<SocketAddr> + Copy),
+        handshake: Handshake,
+        timeout: Duration,
+    ) -> Result<(Self, PeerInfo)> {
+        let peer_addr: SocketAddr = (*peer_addr).into();
+        let info_hash = handshake.info_hash;
+        let mut stream = Self::connect_addr(peer_addr, timeout).await?;
+        let buf = stream
+            .handshake(handshake)
+            .await
+            .context("CTX: handshake")?;
+        let peer = PeerInfo::from_handshake(&buf);
+        if peer.info_hash != info_hash {
+            return Err(anyhow!(
+                "Peer {peer_addr} answered for info hash {} instead of {}",
+                hex::encode(peer.info_hash),
+                hex::encode(info_hash)
+            ));
+        }
+        Ok((stream, peer))
+    }
 }
 
-impl Stream {
-    pub async fn connect(peer_addr: &SocketAddrV4) -> Result<Self> {
-        let connection = TcpStream::connect(peer_addr).await.context(format!(
-            "CTX: Stream connection failed to peer address: {peer_addr}"
-        ))?;
-        Ok(Self { connection })
+impl<T: AsyncRead + AsyncWrite + Unpin> Stream<T> {
+    pub fn new(connection: T) -> Self {
+        Self {
+            connection,
+            download_limiter: RateLimiter::default(),
+            upload_limiter: RateLimiter::default(),
+            block_size: DEFAULT_BLOCK_SIZE,
+            request_queue: RequestQueue::default(),
+            timeout: DEFAULT_TIMEOUT,
+            capabilities: Capabilities::default(),
+            peer_capabilities: Capabilities::default(),
+            peer_extensions: BTreeMap::new(),
+            discovered_peers: Vec::new(),
+            dht_port: None,
+            peer_haves: Vec::new(),
+            block_cache: BlockCache::default(),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            throughput: 0.0,
+        }
+    }
+
+    /// Piece data received per second, averaged over the last few pieces.
+    pub fn throughput(&self) -> f64 {
+        self.throughput
+    }
+
+    /// Bytes received from the peer so far, protocol overhead included.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
     }
 
+    /// Bytes sent to the peer so far, protocol overhead included.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    pub fn with_download_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.download_limiter = limiter;
+        self
+    }
+
+    pub fn with_upload_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.upload_limiter = limiter;
+        self
+    }
+
+    pub fn with_block_size(mut self, block_size: u32) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn with_request_queue(mut self, request_queue: RequestQueue) -> Self {
+        self.request_queue = request_queue;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_block_cache(mut self, block_cache: BlockCache) -> Self {
+        self.block_cache = block_cache;
+        self
+    }
+
+    /// Sends our handshake and reads the peer's. Fails with `BtError::Io` or `BtError::Timeout`
+    /// if the connection does or the peer doesn't answer within `timeout`.
+    #[instrument(level = "debug", skip_all)]
     pub async fn handshake(
         &mut self,
         handshake: Handshake,
+    ) -> Result<[u8; HANDSHAKE_BYTE_BUFFER_SIZE], BtError> {
+        timeout(self.timeout, self.exchange_handshakes(handshake))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|exchanged| exchanged)
+            .map_err(|e| BtError::classify(e, BtError::Handshake))
+    }
+
+    async fn exchange_handshakes(
+        &mut self,
+        handshake: Handshake,
     ) -> Result<[u8; HANDSHAKE_BYTE_BUFFER_SIZE]> {
-        self.connection
-            .write_all(&handshake.as_bytes())
+        self.write_all(&handshake.as_bytes())
             .await
             .context("CTX: Write handshake bytes failed")?;
         let mut buf = [0u8; HANDSHAKE_BYTE_BUFFER_SIZE];
-        self.connection
-            .read_exact(&mut buf)
+        self.read_exact(&mut buf)
             .await
             .context("CTX: Read handshake bytes failed")?;
+        self.capabilities = Capabilities::from_reserved(handshake.reserved);
+        self.peer_capabilities = Capabilities::from_handshake(&buf);
+        debug!(
+            peer_id = hex::encode(&buf[handshake::HANDSHAKE_PEER_ID_BYTE_INDEX_START..]),
+            capabilities = ?self.peer_capabilities,
+            "handshake complete"
+        );
         Ok(buf)
     }
 
-    pub async fn bitfield(&mut self) -> Result<()> {
-        let length = self.get_message_length().await?;
-        let mut buf = vec![0u8; length as usize];
-        self.connection
-            .read_exact(&mut buf)
+    /// The other side of `handshake` for peers that connected to us: reads their handshake,
+    /// checks it is for `handshake.info_hash` and only then answers with ours.
+    #[instrument(level = "debug", skip_all)]
+    pub async fn accept_handshake(&mut self, handshake: Handshake) -> Result<PeerInfo> {
+        let mut buf = [0u8; HANDSHAKE_BYTE_BUFFER_SIZE];
+        self.read_exact(&mut buf)
+            .await
+            .context("CTX: Read handshake bytes failed")?;
+        let peer = PeerInfo::from_handshake(&buf);
+        if peer.info_hash != handshake.info_hash {
+            return Err(anyhow!(
+                "Peer asked for info hash {}, we serve {}",
+                hex::encode(peer.info_hash),
+                hex::encode(handshake.info_hash)
+            ));
+        }
+        self.write_all(&handshake.as_bytes())
+            .await
+            .context("CTX: Write handshake bytes failed")?;
+        self.capabilities = Capabilities::from_reserved(handshake.reserved);
+        self.peer_capabilities = peer.capabilities;
+        debug!(peer_id = hex::encode(peer.peer_id), "accepted handshake");
+        Ok(peer)
+    }
+
+    /// Reads which of the `num_pieces` pieces the peer has. When both handshakes set the fast
+    /// extension bit (BEP 6) the peer may send HaveAll or HaveNone instead of a bitfield.
+    pub async fn bitfield(&mut self, num_pieces: usize) -> Result<BitField> {
+        let (id, payload) = self
+            .read_message()
             .await
             .context("CTX: Read bitfield buffer failed")?;
 
-        match MessageType::from_id(buf[0]) {
-            Some(MessageType::Bitfield) => Ok(()),
+        let fast = self.capabilities.fast && self.peer_capabilities.fast;
+        match MessageType::from_id(id) {
+            Some(MessageType::Bitfield) => Ok(BitField(payload)),
+            Some(MessageType::HaveAll) if fast => Ok(BitField::full(num_pieces)),
+            Some(MessageType::HaveNone) if fast => Ok(BitField::empty(num_pieces)),
+            Some(MessageType::HaveAll | MessageType::HaveNone) => Err(anyhow!(
+                "Peer sent {id} without negotiating the fast extension"
+            )),
             _ => Err(anyhow!("Expected bitfield")),
         }
     }
 
+    /// Tells the peer which extensions we understand (BEP 10). Only send this to peers that
+    /// set the extension bit in their handshake.
+    pub async fn extended_handshake(&mut self) -> Result<()> {
+        let payload = serde_bencode::to_bytes(&ExtendedHandshake::ours())
+            .context("CTX: encode extended handshake")?;
+        let mut buf = Vec::with_capacity(6 + payload.len());
+        buf.extend_from_slice(&(2 + payload.len() as u32).to_be_bytes());
+        buf.push(MessageType::Extended.id());
+        buf.push(extension::HANDSHAKE_ID);
+        buf.extend_from_slice(&payload);
+        self.write_all(&buf)
+            .await
+            .context("CTX: Write extended handshake failed")?;
+        trace!("sent extended handshake");
+        Ok(())
+    }
+
     pub async fn interested(&mut self) -> Result<()> {
         let mut interested = [0u8; 5];
         interested[3] = 1;
         interested[4] = MessageType::Interested.id();
-        self.connection
-            .write_all(&interested)
+        self.write_all(&interested)
             .await
             .context("CTX: Write interested buffer failed")?;
+        trace!("sent interested");
         Ok(())
     }
 
-    pub async fn get_piece_data(&mut self, piece: u32, torrent: &Torrent) -> Result<Vec<u8>> {
-        let mut data = Vec::new();
-        let mut block_index: u32 = 0;
-        let mut block_size: u32 = 16 * 1024; // 16Kb // 2^14
-        let mut remaining_bytes: u32 = if piece == torrent.info.pieces.0.len() as u32 - 1 {
-            (torrent.info.length as u32) % (torrent.info.piece_length as u32)
-        } else {
-            torrent.info.piece_length as u32
-        };
+    /// Tells the peer we now have `piece`, so it can request it from us.
+    pub async fn have(&mut self, piece: u32) -> Result<()> {
+        let mut have = [0u8; 9];
+        have[0..4].copy_from_slice(&5u32.to_be_bytes()); // Message length: 5
+        have[4] = MessageType::Have.id();
+        have[5..9].copy_from_slice(&piece.to_be_bytes());
+        self.write_all(&have)
+            .await
+            .context("CTX: Write have buffer failed")?;
+        trace!(piece, "sent have");
+        Ok(())
+    }
 
-        while remaining_bytes > 0 {
+    /// Tells the peer which pieces we have, only valid right after the handshake.
+    pub async fn send_bitfield(&mut self, bitfield: &BitField) -> Result<()> {
+        let mut buf = Vec::with_capacity(5 + bitfield.0.len());
+        buf.extend_from_slice(&(1 + bitfield.0.len() as u32).to_be_bytes());
+        buf.push(MessageType::Bitfield.id());
+        buf.extend_from_slice(&bitfield.0);
+        self.write_all(&buf)
+            .await
+            .context("CTX: Write bitfield failed")?;
+        trace!("sent bitfield");
+        Ok(())
+    }
+
+    /// Tells the peer we won't answer its requests anymore, it has to wait for an unchoke.
+    pub async fn choke(&mut self) -> Result<()> {
+        let mut choke = [0u8; 5];
+        choke[3] = 1;
+        choke[4] = MessageType::Choke.id();
+        self.write_all(&choke)
+            .await
+            .context("CTX: Write choke failed")?;
+        trace!("sent choke");
+        Ok(())
+    }
+
+    /// Lets the peer start requesting blocks from us.
+    pub async fn unchoke(&mut self) -> Result<()> {
+        let mut unchoke = [0u8; 5];
+        unchoke[3] = 1;
+        unchoke[4] = MessageType::Unchoke.id();
+        self.write_all(&unchoke)
+            .await
+            .context("CTX: Write unchoke failed")?;
+        trace!("sent unchoke");
+        Ok(())
+    }
+
+    /// Answers a request with `data`, the block at `offset` of `piece`.
+    pub async fn send_block(&mut self, piece: u32, offset: u32, data: &[u8]) -> Result<()> {
+        self.upload_limiter.acquire(data.len() as u64).await;
+        let mut buf = Vec::with_capacity(13 + data.len());
+        buf.extend_from_slice(&(9 + data.len() as u32).to_be_bytes());
+        buf.push(MessageType::Piece.id());
+        buf.extend_from_slice(&piece.to_be_bytes());
+        buf.extend_from_slice(&offset.to_be_bytes());
+        buf.extend_from_slice(data);
+        self.write_all(&buf)
+            .await
+            .context("CTX: Write piece block failed")?;
+        trace!(piece, offset, length = data.len(), "sent block");
+        Ok(())
+    }
 
-            if remaining_bytes < block_size {
-                block_size = remaining_bytes;
+    /// Keeps an otherwise idle connection from being dropped, see `KEEP_ALIVE_INTERVAL`.
+    pub async fn keep_alive(&mut self) -> Result<()> {
+        self.write_all(&[0u8; 4])
+            .await
+            .context("CTX: Write keep-alive failed")?;
+        trace!("sent keep-alive");
+        Ok(())
+    }
+
+    /// Requests every block of `piece` and gives up on this peer if the whole piece
+    /// does not arrive within `piece_timeout`, so a slow peer can't hold a piece hostage.
+    /// The piece is checked against its hash, data that doesn't match is a `BtError::HashMismatch`.
+    #[instrument(level = "debug", skip(self, torrent))]
+    pub async fn get_piece_data(
+        &mut self,
+        piece: u32,
+        torrent: &Torrent,
+        piece_timeout: Duration,
+    ) -> Result<Vec<u8>, BtError> {
+        let data = self
+            .get_piece_data_cancellable(piece, torrent, piece_timeout, &AtomicBool::new(false))
+            .await
+            .map_err(|e| BtError::classify(e, BtError::Peer))?;
+        if !torrent.verify_piece(piece as usize, &data) {
+            return Err(BtError::HashMismatch { piece });
+        }
+        Ok(data)
+    }
+
+    /// Same as `get_piece_data`, but once `done` is set (another peer delivered the piece first)
+    /// the outstanding block requests are cancelled and an error is returned.
+    /// `done` is checked every time a block arrives. The data is not checked against the piece's
+    /// hash, that is left to the caller.
+    #[instrument(level = "debug", skip(self, torrent, done))]
+    pub async fn get_piece_data_cancellable(
+        &mut self,
+        piece: u32,
+        torrent: &Torrent,
+        piece_timeout: Duration,
+        done: &AtomicBool,
+    ) -> Result<Vec<u8>> {
+        // pay for the whole piece up front so waiting on the limiter doesn't eat into the timeout
+        self.download_limiter
+            .acquire(torrent.piece_size(piece))
+            .await;
+        let started = Instant::now();
+        let result =
+            match timeout(piece_timeout, self.read_piece_blocks(piece, torrent, done)).await {
+                Ok(result) => result,
+                Err(elapsed) => {
+                    self.request_queue.on_timeout();
+                    return Err(anyhow::Error::new(elapsed).context(format!(
+                        "Piece {piece} did not complete within {piece_timeout:?}"
+                    )));
+                }
+            };
+        if let Ok(data) = &result {
+            let rate = data.len() as f64 / started.elapsed().as_secs_f64().max(f64::EPSILON);
+            // weigh the latest piece at a quarter so one odd piece doesn't swing it too much
+            self.throughput = if self.throughput == 0.0 {
+                rate
+            } else {
+                0.75 * self.throughput + 0.25 * rate
+            };
+        }
+        result
+    }
+
+    async fn read_piece_blocks(
+        &mut self,
+        piece: u32,
+        torrent: &Torrent,
+        done: &AtomicBool,
+    ) -> Result<Vec<u8>> {
+        let plan = torrent.blocks_for_piece(piece, self.block_size);
+        // request messages only have 32 bits for the offset, so find out before allocating
+        if let Some(&(offset, _)) = plan.last() {
+            if offset > u32::MAX as u64 {
+                return Err(anyhow!(
+                    "Piece {piece} of {} bytes is too large to request in blocks",
+                    torrent.piece_size(piece)
+                ));
+            }
+        }
+        let piece_size = usize::try_from(torrent.piece_size(piece))
+            .map_err(|_| anyhow!("Piece {piece} does not fit in memory"))?;
+        let mut data = vec![0u8; piece_size];
+        // offsets are below piece_size, which fits in usize
+        let block = |offset: u64, length: u32| offset as usize..offset as usize + length as usize;
+        // (offset, length, sent at) of requested blocks
+        let mut outstanding: Vec<(u64, u32, Instant)> = Vec::new();
+
+        // blocks an earlier attempt got before it broke off are reused as long as they line up
+        // with ours, everything else gets requested in order
+        let mut cached = self.block_cache.blocks(piece);
+        let mut to_request = VecDeque::new();
+        for &(offset, length) in &plan {
+            match cached.remove(&offset) {
+                Some(cached) if cached.len() == length as usize => {
+                    data[block(offset, length)].copy_from_slice(&cached)
+                }
+                _ => to_request.push_back((offset, length)),
             }
-            self.send_request_piece(piece, block_index, block_size)
-                .await?;
-            let request_buf = self
-                .read_request_piece()
+        }
+        if to_request.len() < plan.len() {
+            debug!(
+                piece,
+                blocks = plan.len() - to_request.len(),
+                "resuming piece from cached blocks"
+            );
+        }
+        let mut remaining_bytes: u64 = to_request.iter().map(|&(_, length)| length as u64).sum();
+
+        while remaining_bytes > 0 {
+            // keep a few requests in flight so we don't pay a round trip per block
+            while outstanding.len() < self.request_queue.depth() {
+                let Some((offset, length)) = to_request.pop_front() else {
+                    break;
+                };
+                self.send_request_piece(piece, offset, length).await?;
+                outstanding.push((offset, length, Instant::now()));
+            }
+            let (id, payload) = timeout(self.timeout, self.read_message())
                 .await
+                .context(format!("CTX: no block within {:?}", self.timeout))?
                 .context("CTX: Reading request piece")?;
+            match MessageType::from_id(id) {
+                Some(MessageType::Piece) => {}
+                Some(MessageType::RejectRequest) => {
+                    return Err(anyhow!("Peer rejected a request for piece {piece}"));
+                }
+                Some(MessageType::Choke) => {
+                    // the peer drops all our pending requests when it chokes us,
+                    // so ask for them again once we are unchoked
+                    debug!(piece, "choked while downloading, waiting for unchoke");
+                    self.wait_unchoke().await?;
+                    for (offset, length, sent_at) in &mut outstanding {
+                        self.send_request_piece(piece, *offset, *length).await?;
+                        *sent_at = Instant::now();
+                    }
+                    continue;
+                }
+                _ => {
+                    trace!(id, "ignoring message while downloading");
+                    continue;
+                }
+            }
+            if payload.len() < 8 {
+                return Err(anyhow!(
+                    "Piece message of {} bytes is too short",
+                    payload.len()
+                ));
+            }
 
-            let mut piece_data_index = [0u8; 4];
-            piece_data_index.copy_from_slice(&request_buf[1..5]);
-            let mut piece_offset_begin = [0u8; 4];
-            piece_offset_begin.copy_from_slice(&request_buf[5..9]);
-            let data_block = request_buf[9..].to_vec();
-            data.extend(data_block);
-            remaining_bytes -= block_size;
-            block_index += block_size;
+            let piece_data_index = u32::from_be_bytes(payload[0..4].try_into()?);
+            let piece_offset_begin = u32::from_be_bytes(payload[4..8].try_into()?);
+            let data_block = &payload[8..];
+            // blocks of a piece we cancelled earlier may still trickle in, skip those
+            let Some(position) = outstanding.iter().position(|&(offset, _, _)| {
+                piece_data_index == piece && offset == piece_offset_begin as u64
+            }) else {
+                trace!(
+                    piece_data_index,
+                    piece_offset_begin,
+                    "ignoring unrequested block"
+                );
+                continue;
+            };
+            let (offset, length, sent_at) = outstanding.swap_remove(position);
+            self.request_queue.on_block(sent_at.elapsed());
+            if data_block.len() != length as usize {
+                return Err(anyhow!(
+                    "Block at offset {offset} of piece {piece} has {} bytes, expected {length}",
+                    data_block.len()
+                ));
+            }
+            data[block(offset, length)].copy_from_slice(data_block);
+            remaining_bytes -= length as u64;
+            if remaining_bytes > 0 {
+                self.block_cache.insert(piece, offset, data_block.to_vec());
+            }
+
+            if remaining_bytes > 0 && done.load(Ordering::Acquire) {
+                self.block_cache.discard(piece);
+                for &(offset, length, _) in &outstanding {
+                    self.cancel(piece, wire_offset(offset), length).await?;
+                }
+                return Err(anyhow!("Piece {piece} was completed by another peer"));
+            }
         }
+        self.block_cache.discard(piece);
         Ok(data)
     }
 
     async fn send_request_piece(
         &mut self,
         piece: u32,
-        block_index: u32,
+        block_index: u64,
         block_size: u32,
     ) -> Result<()> {
-        let mut request_piece_buf = [0u8; 17];
-        request_piece_buf[0..4].copy_from_slice(&13u32.to_be_bytes()); // Message length: 13
-        request_piece_buf[4] = MessageType::Request.id();
-        request_piece_buf[5..9].copy_from_slice(&piece.to_be_bytes());
-        request_piece_buf[9..13].copy_from_slice(&block_index.to_be_bytes());
-        request_piece_buf[13..17].copy_from_slice(&block_size.to_be_bytes());
-        self.connection
-            .write_all(&request_piece_buf)
+        let offset = wire_offset(block_index);
+        self.send_block_message(MessageType::Request, piece, offset, block_size)
             .await
             .context("CTX: send request piece")?;
+        trace!(piece, block_index, block_size, "sent request");
         Ok(())
     }
 
-    async fn read_request_piece(&mut self) -> Result<Vec<u8>> {
-        let length = self.get_message_length().await?;
-        let mut request_buf = vec![0; length as usize];
-        self.connection
-            .read_exact(&mut request_buf)
+    /// Withdraws a block request that was sent earlier but is no longer needed.
+    pub async fn cancel(&mut self, piece: u32, offset: u32, length: u32) -> Result<()> {
+        self.send_block_message(MessageType::Cancel, piece, offset, length)
             .await
-            .context("CTX: request piece buf")?;
-        if request_buf[0] != MessageType::Piece.id() {
-            panic!("expected request piece");
+            .context("CTX: send cancel")?;
+        debug!(piece, offset, length, "sent cancel");
+        Ok(())
+    }
+
+    // request and cancel share the same layout: <len=13><id><index><begin><length>
+    async fn send_block_message(
+        &mut self,
+        message_type: MessageType,
+        piece: u32,
+        offset: u32,
+        length: u32,
+    ) -> Result<()> {
+        let mut buf = [0u8; 17];
+        buf[0..4].copy_from_slice(&13u32.to_be_bytes()); // Message length: 13
+        buf[4] = message_type.id();
+        buf[5..9].copy_from_slice(&piece.to_be_bytes());
+        buf[9..13].copy_from_slice(&offset.to_be_bytes());
+        buf[13..17].copy_from_slice(&length.to_be_bytes());
+        self.write_all(&buf).await?;
+        Ok(())
+    }
+
+    // every byte on the wire goes through these two so the counters stay exact
+    async fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        self.connection.read_exact(buf).await?;
+        self.bytes_read
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.connection.write_all(buf).await?;
+        self.bytes_written
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Reads the next message and splits it into its id and payload. Keep-alives are skipped,
+    /// extension, port and have messages are handled here so none of the callers have to care
+    /// about them.
+    pub async fn read_message(&mut self) -> Result<(u8, Vec<u8>)> {
+        loop {
+            let length = self.get_message_length().await?;
+            if length == 0 {
+                trace!("received keep-alive");
+                continue;
+            }
+            // the id tells us how long the message may be, so read it before allocating the rest
+            let mut id = [0u8; 1];
+            self.read_exact(&mut id)
+                .await
+                .context("CTX: read message id")?;
+            let [id] = id;
+            let max_length = MessageType::from_id(id).map_or(MAX_MESSAGE_LENGTH, |message| {
+                message.max_length(self.block_size)
+            });
+            if length > max_length {
+                return Err(anyhow!(
+                    "Peer sent a {length} byte message with id {id}, the limit is {max_length}"
+                ));
+            }
+            let mut payload = vec![0u8; length as usize - 1];
+            self.read_exact(&mut payload)
+                .await
+                .context("CTX: read message buf")?;
+            trace!(id, length, "received message");
+            if id == MessageType::Extended.id() {
+                self.handle_extended(&payload);
+                continue;
+            }
+            if id == MessageType::Port.id() {
+                match <[u8; 2]>::try_from(payload.as_slice()) {
+                    Ok(port) => {
+                        self.dht_port = Some(u16::from_be_bytes(port));
+                        debug!(port = self.dht_port, "received dht port");
+                    }
+                    Err(_) => debug!(length, "ignoring malformed port message"),
+                }
+                continue;
+            }
+            if id == MessageType::Have.id() {
+                match <[u8; 4]>::try_from(payload.as_slice()) {
+                    Ok(piece) => {
+                        let piece = u32::from_be_bytes(piece);
+                        trace!(piece, "peer has a new piece");
+                        self.peer_haves.push(piece);
+                    }
+                    Err(_) => debug!(length, "ignoring malformed have message"),
+                }
+                continue;
+            }
+            return Ok((id, payload));
+        }
+    }
+
+    // a peer sending us a broken extension message is not worth dropping the connection over
+    fn handle_extended(&mut self, payload: &[u8]) {
+        let Some((&id, body)) = payload.split_first() else {
+            debug!("ignoring empty extended message");
+            return;
+        };
+        match id {
+            extension::HANDSHAKE_ID => match bencode::check_depth(body)
+                .and_then(|()| Ok(serde_bencode::from_bytes::<ExtendedHandshake>(body)?))
+            {
+                Ok(handshake) => {
+                    debug!(extensions = ?handshake.m, "received extended handshake");
+                    self.peer_extensions = handshake.m;
+                }
+                Err(e) => debug!(error = %e, "ignoring malformed extended handshake"),
+            },
+            extension::UT_PEX_ID => match PexMessage::from_bytes(body) {
+                Ok(pex) => {
+                    debug!(added = pex.added.addresses.len(), "received peer exchange");
+                    for address in pex.added.addresses {
+                        if !self.discovered_peers.contains(&address) {
+                            self.discovered_peers.push(address);
+                        }
+                    }
+                }
+                Err(e) => debug!(error = %e, "ignoring malformed ut_pex message"),
+            },
+            _ => trace!(id, "ignoring unknown extended message"),
         }
-        Ok(request_buf)
     }
 
     async fn get_message_length(&mut self) -> Result<u32> {
         let mut length_buf = [0u8; 4];
-        self.connection
-            .read_exact(&mut length_buf)
+        self.read_exact(&mut length_buf)
             .await
             .context("CTX: read length buffer")?;
         let length = u32::from_be_bytes(length_buf);
         Ok(length)
     }
 
-    async fn read_with_timeout(
-        &mut self,
-        buffer: &mut [u8],
-        timeout_duration: Duration,
-    ) -> Result<()> {
-        timeout(timeout_duration, self.connection.read_exact(buffer))
-            .await
-            .context("CTX: read operation timed out")??;
-        Ok(())
-    }
-
+    /// Waits for the peer to unchoke us, skipping any other messages it sends in the meantime.
+    /// Gives up if the peer goes quiet for longer than `timeout`.
     pub async fn wait_unchoke(&mut self) -> Result<()> {
-        let length = self.get_message_length().await?;
-        let mut unchoke_message_buffer = vec![0; length as usize];
-        // i think this is fundamentally wrong because we will get the first byte anyway
-        // whether or not it is unchoke, and i'm unsure if we should reinitialize the entire connection from the handshake
-        // or when is the peer going to send us another byte? ¯\_(ツ)_/¯ but leave this here for future reference on async + timeout
         loop {
-            self.read_with_timeout(&mut unchoke_message_buffer, Duration::from_secs(10))
-                .await?;
-            if unchoke_message_buffer[0] == MessageType::Unchoke.id() {
+            let (id, _) = timeout(self.timeout, self.read_message())
+                .await
+                .context("CTX: read operation timed out")??;
+            if id == MessageType::Unchoke.id() {
                 break;
             }
         }
+        debug!("unchoked");
         Ok(())
     }
 }
 
+// the `begin` of request and cancel messages is 32 bits, read_piece_blocks refuses pieces
+// with offsets past that before anything gets sent
+fn wire_offset(offset: u64) -> u32 {
+    u32::try_from(offset).expect("block offset must fit in 32 bits")
+}
+
+/// A `Stream` that knows where in the protocol both sides are, so pieces can only be requested
+/// once the handshake is done, the peer said what it has and unchoked us.
+pub struct PeerConnection<T = TcpStream> {
+    pub stream: Stream<T>,
+    bitfield: Option<BitField>,
+    am_choking: bool,
+    am_interested: bool,
+    peer_choking: bool,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> PeerConnection<T> {
+    /// Wraps a stream that has just connected, nothing has been sent yet.
+    pub fn new(stream: Stream<T>) -> Self {
+        Self {
+            stream,
+            bitfield: None,
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+        }
+    }
+
+    /// Pieces the peer has, None until `prepare` read its bitfield.
+    pub fn bitfield(&self) -> Option<&BitField> {
+        self.bitfield.as_ref()
+    }
+
+    /// Hands back the stream and the peer's pieces, which are empty if `prepare` never got to
+    /// the bitfield.
+    pub fn into_parts(self) -> (Stream<T>, BitField) {
+        (self.stream, self.bitfield.unwrap_or(BitField(Vec::new())))
+    }
+
+    pub fn am_choking(&self) -> bool {
+        self.am_choking
+    }
+
+    pub fn am_interested(&self) -> bool {
+        self.am_interested
+    }
+
+    pub fn peer_choking(&self) -> bool {
+        self.peer_choking
+    }
+
+    /// Walks through handshake -> bitfield -> (extended handshake) -> interested -> unchoke.
+    /// Fails if the peer answers for another torrent or the connection was prepared before.
+    pub async fn prepare(&mut self, handshake: Handshake, num_pieces: usize) -> Result<PeerInfo> {
+        if self.bitfield.is_some() {
+            return Err(anyhow!("Connection is already prepared"));
+        }
+        let info_hash = handshake.info_hash;
+        let buf = self
+            .stream
+            .handshake(handshake)
+            .await
+            .context("CTX: handshake")?;
+        let peer = PeerInfo::from_handshake(&buf);
+        if peer.info_hash != info_hash {
+            return Err(anyhow!(
+                "Peer answered for info hash {} instead of {}",
+                hex::encode(peer.info_hash),
+                hex::encode(info_hash)
+            ));
+        }
+        let bitfield = self
+            .stream
+            .bitfield(num_pieces)
+            .await
+            .context("CTX: bitfield")?;
+        self.bitfield = Some(bitfield);
+        if self.stream.peer_capabilities.extensions {
+            self.stream
+                .extended_handshake()
+                .await
+                .context("CTX: extended handshake")?;
+        }
+        self.stream.interested().await.context("CTX: interested")?;
+        self.am_interested = true;
+        self.stream
+            .wait_unchoke()
+            .await
+            .context("CTX: await for unchoke")?;
+        self.peer_choking = false;
+        Ok(peer)
+    }
+
+    /// Lets the peer request blocks from us.
+    pub async fn unchoke(&mut self) -> Result<()> {
+        self.stream.unchoke().await?;
+        self.am_choking = false;
+        Ok(())
+    }
+
+    /// Downloads `piece`, refused without sending anything while the peer is choking us or
+    /// doesn't have the piece.
+    pub async fn download_piece(
+        &mut self,
+        piece: u32,
+        torrent: &Torrent,
+        piece_timeout: Duration,
+    ) -> Result<Vec<u8>, BtError> {
+        if let Some(bitfield) = &mut self.bitfield {
+            for piece in self.stream.peer_haves.drain(..) {
+                bitfield.set_piece(piece as usize);
+            }
+        }
+        if self.peer_choking || !self.am_interested {
+            return Err(BtError::Peer(anyhow!(
+                "Can't request piece {piece} while the peer is choking us"
+            )));
+        }
+        if !self
+            .bitfield
+            .as_ref()
+            .is_some_and(|bitfield| bitfield.has_piece(piece as usize))
+        {
+            return Err(BtError::Peer(anyhow!("Peer does not have piece {piece}")));
+        }
+        self.stream
+            .get_piece_data(piece, torrent, piece_timeout)
+            .await
+    }
+}
+
 // The handshake is a message consisting of the following parts as described in the peer protocol:
 pub mod handshake {
+    use anyhow::{anyhow, Result};
+
     // length of the protocol string (BitTorrent protocol) which is 19 (1 byte)
     // the string BitTorrent protocol (19 bytes)
     // eight reserved bytes, which are all set to zero (8 bytes)
     // sha1 infohash (20 bytes) (NOT the hexadecimal representation, which is 40 bytes long)
     // peer id (20 bytes) (you can use 00112233445566778899 for this challenge)
+    pub const HANDSHAKE_RESERVED_BYTE_INDEX_START: usize = 20;
+    pub const HANDSHAKE_INFO_HASH_BYTE_INDEX_START: usize = 28;
     pub const HANDSHAKE_PEER_ID_BYTE_INDEX_START: usize = 48;
     pub const HANDSHAKE_BYTE_BUFFER_SIZE: usize = 68;
+    pub const DEFAULT_PEER_ID: &str = "00112233445566778899";
 
     pub struct Handshake {
         pub length: u8,
         pub protocol: &'static [u8; 19], // static byte slice (can also write it as &'static [u8])
         pub reserved: [u8; 8],
         pub info_hash: [u8; 20],
-        pub peer_id: String,
+        // only set through with_peer_id, which checks the length
+        peer_id: [u8; 20],
     }
 
     impl Handshake {
@@ -195,20 +901,236 @@ pub mod handshake {
                 protocol: b"BitTorrent protocol", // creates a static byte string slice
                 reserved: [0; 8],
                 info_hash: info_hash_bytes,
-                peer_id: String::from("00112233445566778899"),
+                peer_id: DEFAULT_PEER_ID
+                    .as_bytes()
+                    .try_into()
+                    .expect("DEFAULT_PEER_ID is 20 bytes"),
             }
         }
 
-        pub fn as_bytes(&self) -> Vec<u8> {
-            let mut bytes: Vec<u8> = Vec::new();
-            bytes.push(self.length);
-            bytes.extend_from_slice(self.protocol);
-            bytes.extend_from_slice(&self.reserved);
-            bytes.extend_from_slice(&self.info_hash);
-            bytes.extend_from_slice(self.peer_id.as_bytes());
+        /// Fails unless `peer_id` is exactly 20 bytes, anything else would make a malformed handshake.
+        pub fn with_peer_id(mut self, peer_id: String) -> Result<Self> {
+            self.peer_id = peer_id.as_bytes().try_into().map_err(|_| {
+                anyhow!("Peer id must be 20 bytes, {peer_id:?} is {}", peer_id.len())
+            })?;
+            Ok(self)
+        }
+
+        pub fn peer_id(&self) -> &[u8; 20] {
+            &self.peer_id
+        }
+
+        /// Advertises support for the fast extension (BEP 6). We only make use of HaveAll,
+        /// HaveNone and RejectRequest from it.
+        pub fn with_fast(mut self) -> Self {
+            self.reserved[7] |= 0x04;
+            self
+        }
+
+        /// Advertises support for the extension protocol (BEP 10), needed for PEX and metadata exchange.
+        pub fn with_extensions(mut self) -> Self {
+            self.reserved[5] |= 0x10; // bit 20 counting from the right
+            self
+        }
+
+        pub fn as_bytes(&self) -> [u8; HANDSHAKE_BYTE_BUFFER_SIZE] {
+            let mut bytes = [0u8; HANDSHAKE_BYTE_BUFFER_SIZE];
+            bytes[0] = self.length;
+            bytes[1..HANDSHAKE_RESERVED_BYTE_INDEX_START].copy_from_slice(self.protocol);
+            bytes[HANDSHAKE_RESERVED_BYTE_INDEX_START..HANDSHAKE_INFO_HASH_BYTE_INDEX_START]
+                .copy_from_slice(&self.reserved);
+            bytes[HANDSHAKE_INFO_HASH_BYTE_INDEX_START..HANDSHAKE_PEER_ID_BYTE_INDEX_START]
+                .copy_from_slice(&self.info_hash);
+            bytes[HANDSHAKE_PEER_ID_BYTE_INDEX_START..].copy_from_slice(&self.peer_id);
             bytes
         }
     }
+
+    /// Features a peer announced through the reserved bytes of its handshake.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct Capabilities {
+        /// Extension protocol (BEP 10)
+        pub extensions: bool,
+        /// DHT (BEP 5), the peer also sends its DHT port
+        pub dht: bool,
+        /// Fast extension (BEP 6), only counts when we advertised it too
+        pub fast: bool,
+    }
+
+    /// What we learn about a peer from its handshake.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PeerInfo {
+        pub info_hash: [u8; 20],
+        pub peer_id: [u8; 20],
+        pub capabilities: Capabilities,
+    }
+
+    impl PeerInfo {
+        pub fn from_handshake(buf: &[u8; HANDSHAKE_BYTE_BUFFER_SIZE]) -> Self {
+            let mut info_hash = [0u8; 20];
+            info_hash.copy_from_slice(
+                &buf[HANDSHAKE_INFO_HASH_BYTE_INDEX_START..HANDSHAKE_PEER_ID_BYTE_INDEX_START],
+            );
+            let mut peer_id = [0u8; 20];
+            peer_id.copy_from_slice(&buf[HANDSHAKE_PEER_ID_BYTE_INDEX_START..]);
+            Self {
+                info_hash,
+                peer_id,
+                capabilities: Capabilities::from_handshake(buf),
+            }
+        }
+    }
+
+    impl Capabilities {
+        pub fn from_handshake(buf: &[u8; HANDSHAKE_BYTE_BUFFER_SIZE]) -> Self {
+            let mut reserved = [0u8; 8];
+            reserved.copy_from_slice(
+                &buf[HANDSHAKE_RESERVED_BYTE_INDEX_START..HANDSHAKE_INFO_HASH_BYTE_INDEX_START],
+            );
+            Self::from_reserved(reserved)
+        }
+
+        /// Reads the bits we know of out of the 8 reserved bytes, the rest are ignored.
+        pub fn from_reserved(reserved: [u8; 8]) -> Self {
+            Self {
+                extensions: reserved[5] & 0x10 != 0,
+                dht: reserved[7] & 0x01 != 0,
+                fast: reserved[7] & 0x04 != 0,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn as_bytes_matches_the_spec_layout() {
+            let info_hash: [u8; 20] = std::array::from_fn(|i| i as u8 + 100);
+            let handshake = Handshake::new(info_hash)
+                .with_peer_id(String::from("-RS0001-abcdefghijkl"))
+                .unwrap();
+            let bytes = handshake.as_bytes();
+            let expected = [
+                &[19u8][..],
+                b"BitTorrent protocol",
+                &[0; 8],
+                &info_hash,
+                b"-RS0001-abcdefghijkl",
+            ]
+            .concat();
+            assert_eq!(bytes.as_slice(), expected.as_slice());
+        }
+
+        #[test]
+        fn default_peer_id_is_used_until_set() {
+            let handshake = Handshake::new([0; 20]);
+            assert_eq!(handshake.peer_id(), DEFAULT_PEER_ID.as_bytes());
+            assert_eq!(
+                &handshake.as_bytes()[HANDSHAKE_PEER_ID_BYTE_INDEX_START..],
+                DEFAULT_PEER_ID.as_bytes()
+            );
+        }
+
+        #[test]
+        fn extension_bit_is_set_and_read_back() {
+            let handshake = Handshake::new([0; 20]).with_extensions();
+            assert_eq!(handshake.reserved, [0, 0, 0, 0, 0, 0x10, 0, 0]);
+            let capabilities = Capabilities::from_handshake(&handshake.as_bytes());
+            assert!(capabilities.extensions);
+            assert!(!capabilities.dht && !capabilities.fast);
+            assert!(!Capabilities::from_handshake(&Handshake::new([0; 20]).as_bytes()).extensions);
+        }
+
+        #[test]
+        fn capabilities_of_reserved_byte_combinations() {
+            let caps = |extensions, dht, fast| Capabilities {
+                extensions,
+                dht,
+                fast,
+            };
+            let none = caps(false, false, false);
+            for (reserved, expected) in [
+                ([0; 8], none),
+                ([0, 0, 0, 0, 0, 0x10, 0, 0], caps(true, false, false)),
+                ([0, 0, 0, 0, 0, 0, 0, 0x01], caps(false, true, false)),
+                ([0, 0, 0, 0, 0, 0, 0, 0x04], caps(false, false, true)),
+                ([0, 0, 0, 0, 0, 0x10, 0, 0x05], caps(true, true, true)),
+                ([0xff; 8], caps(true, true, true)),
+                // every bit but ours
+                ([0xff, 0xff, 0xff, 0xff, 0xff, 0xef, 0xff, 0xfa], none),
+                // next to ours, or ours in the wrong byte
+                ([0, 0, 0, 0, 0, 0x28, 0, 0x0a], none),
+                ([0x10, 0, 0, 0, 0x10, 0, 0x05, 0], none),
+                // e.g. Azureus messaging and LT metadata bits of other clients, with DHT
+                ([0x80, 0, 0, 0, 0, 0, 0x08, 0x01], caps(false, true, false)),
+            ] {
+                assert_eq!(
+                    Capabilities::from_reserved(reserved),
+                    expected,
+                    "{reserved:x?}"
+                );
+            }
+        }
+
+        #[test]
+        fn peer_id_must_be_20_bytes() {
+            // the last one is 20 characters but 21 bytes
+            for peer_id in [
+                "",
+                "short",
+                "-RS0001-abcdefghijklm",
+                "-RS0001-abcdefghijk\u{e9}",
+            ] {
+                assert!(
+                    Handshake::new([0; 20])
+                        .with_peer_id(String::from(peer_id))
+                        .is_err(),
+                    "{peer_id:?}"
+                );
+            }
+        }
+    }
+}
+
+pub mod bitfield {
+    /// The pieces a peer has, as sent in its bitfield message.
+    /// The high bit of the first byte is piece 0, spare bits at the end are zero.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct BitField(pub Vec<u8>);
+
+    impl BitField {
+        /// A bitfield with all `num_pieces` pieces set, what HaveAll stands for.
+        pub fn full(num_pieces: usize) -> Self {
+            let mut bytes = vec![0xffu8; num_pieces.div_ceil(8)];
+            let spare_bits = bytes.len() * 8 - num_pieces;
+            if let Some(last) = bytes.last_mut() {
+                *last <<= spare_bits; // spare bits must stay zero
+            }
+            Self(bytes)
+        }
+
+        /// A bitfield with none of the `num_pieces` pieces set, what HaveNone stands for.
+        pub fn empty(num_pieces: usize) -> Self {
+            Self(vec![0; num_pieces.div_ceil(8)])
+        }
+
+        pub fn set_piece(&mut self, index: usize) {
+            if let Some(byte) = self.0.get_mut(index / 8) {
+                *byte |= 0x80 >> (index % 8);
+            }
+        }
+
+        pub fn has_piece(&self, index: usize) -> bool {
+            self.0
+                .get(index / 8)
+                .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+        }
+
+        pub fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
+            (0..self.0.len() * 8).filter(|&index| self.has_piece(index))
+        }
+    }
 }
 
 pub mod message {
@@ -223,6 +1145,13 @@ pub mod message {
         Request,
         Piece,
         Cancel,
+        // DHT (BEP 5)
+        Port,
+        // fast extension (BEP 6)
+        HaveAll,
+        HaveNone,
+        RejectRequest,
+        Extended,
     }
 
     impl MessageType {
@@ -237,6 +1166,31 @@ pub mod message {
                 MessageType::Request => 6,
                 MessageType::Piece => 7,
                 MessageType::Cancel => 8,
+                MessageType::Port => 9,
+                MessageType::HaveAll => 14,
+                MessageType::HaveNone => 15,
+                MessageType::RejectRequest => 16,
+                MessageType::Extended => 20,
+            }
+        }
+
+        /// Longest valid message of this type including the id byte, given that we never
+        /// request blocks larger than `block_size`.
+        pub fn max_length(&self, block_size: u32) -> u32 {
+            match self {
+                MessageType::Choke
+                | MessageType::Unchoke
+                | MessageType::Interested
+                | MessageType::NotInterested
+                | MessageType::HaveAll
+                | MessageType::HaveNone => 1,
+                MessageType::Port => 3,
+                MessageType::Have => 5,
+                MessageType::Request | MessageType::Cancel | MessageType::RejectRequest => 13,
+                // <id><index><begin><block>
+                MessageType::Piece => 9u32.saturating_add(block_size),
+                // grow with the torrent, only the global cap applies
+                MessageType::Bitfield | MessageType::Extended => super::MAX_MESSAGE_LENGTH,
             }
         }
 
@@ -251,6 +1205,11 @@ pub mod message {
                 6 => Some(MessageType::Request),
                 7 => Some(MessageType::Piece),
                 8 => Some(MessageType::Cancel),
+                9 => Some(MessageType::Port),
+                14 => Some(MessageType::HaveAll),
+                15 => Some(MessageType::HaveNone),
+                16 => Some(MessageType::RejectRequest),
+                20 => Some(MessageType::Extended),
                 _ => None,
             }
         }
@@ -281,3 +1240,1030 @@ pub mod message {
         }
     }
 }
+
+// Extension protocol (BEP 10) messages are `<len><20><extended id><bencoded dict>`. Extended id 0 is
+// the handshake, for everything else the sender uses the id the receiver picked in its handshake.
+pub mod extension {
+    use anyhow::{Context, Result};
+    use serde::{Deserialize, Serialize};
+    use serde_bytes::ByteBuf;
+    use std::collections::BTreeMap;
+
+    use crate::tracker::peers::Peers;
+
+    pub const HANDSHAKE_ID: u8 = 0;
+    /// The id we ask peers to use for the ut_pex messages they send us.
+    pub const UT_PEX_ID: u8 = 1;
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    pub struct ExtendedHandshake {
+        /// Supported extensions mapped to their message ids, 0 means disabled.
+        #[serde(default)]
+        pub m: BTreeMap<String, u8>,
+    }
+
+    impl ExtendedHandshake {
+        pub fn ours() -> Self {
+            Self {
+                m: BTreeMap::from([(String::from("ut_pex"), UT_PEX_ID)]),
+            }
+        }
+    }
+
+    /// Peer exchange (ut_pex), a peer telling us about other peers in the swarm.
+    #[derive(Debug, Default, Deserialize)]
+    pub struct PexMessage {
+        /// Compact IPv4 peers the sender connected to since its last message.
+        #[serde(default)]
+        pub added: Peers,
+        /// One byte of flags per added peer (0x01 prefers encryption, 0x02 is a seed, ...).
+        #[serde(default, rename = "added.f")]
+        pub added_flags: ByteBuf,
+    }
+
+    impl PexMessage {
+        pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+            crate::bencode::check_depth(bytes)?;
+            serde_bencode::from_bytes(bytes).context("CTX: decode ut_pex message")
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::net::{Ipv4Addr, SocketAddrV4};
+
+        #[test]
+        fn ut_pex_payload_gives_the_added_addresses() {
+            // 10.0.0.1:6881 and 192.168.1.2:80, dropped peers are ignored
+            let payload = [
+                &b"d5:added12:"[..],
+                &[10, 0, 0, 1, 0x1a, 0xe1, 192, 168, 1, 2, 0, 80],
+                b"7:added.f2:",
+                &[0x02, 0x00],
+                b"7:dropped6:",
+                &[1, 2, 3, 4, 0, 1],
+                b"e",
+            ]
+            .concat();
+            let pex = PexMessage::from_bytes(&payload).unwrap();
+            assert_eq!(
+                pex.added.addresses,
+                [
+                    SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881),
+                    SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 80),
+                ]
+            );
+            assert_eq!(pex.added_flags.as_slice(), [0x02, 0x00]);
+
+            assert!(PexMessage::from_bytes(b"de")
+                .unwrap()
+                .added
+                .addresses
+                .is_empty());
+            // not a multiple of 6 bytes
+            assert!(PexMessage::from_bytes(b"d5:added5:abcdee").is_err());
+        }
+    }
+}
+
+pub mod queue {
+    use std::time::Duration;
+
+    pub const MIN_QUEUE_DEPTH: usize = 1;
+    pub const DEFAULT_MAX_QUEUE_DEPTH: usize = 32;
+    // where every connection starts, the old fixed pipelining depth
+    const INITIAL_QUEUE_DEPTH: usize = 5;
+    // a block answered within this long means the peer could take more requests
+    const PROMPT_BLOCK_LATENCY: Duration = Duration::from_millis(500);
+
+    /// Number of block requests to keep in flight on one connection. Grows by one for every
+    /// block the peer answers promptly and halves when a piece times out, staying within
+    /// `min..=max`, so fast peers get deep pipelines and slow ones aren't flooded.
+    #[derive(Debug, Clone)]
+    pub struct RequestQueue {
+        depth: usize,
+        min: usize,
+        max: usize,
+    }
+
+    impl Default for RequestQueue {
+        fn default() -> Self {
+            Self::new(MIN_QUEUE_DEPTH, DEFAULT_MAX_QUEUE_DEPTH)
+        }
+    }
+
+    impl RequestQueue {
+        pub fn new(min: usize, max: usize) -> Self {
+            let min = min.max(1);
+            let max = max.max(min);
+            Self {
+                depth: INITIAL_QUEUE_DEPTH.clamp(min, max),
+                min,
+                max,
+            }
+        }
+
+        pub fn depth(&self) -> usize {
+            self.depth
+        }
+
+        /// A requested block arrived `latency` after we asked for it.
+        pub fn on_block(&mut self, latency: Duration) {
+            if latency <= PROMPT_BLOCK_LATENCY {
+                self.depth = (self.depth + 1).min(self.max);
+            }
+        }
+
+        /// A piece did not arrive in time.
+        pub fn on_timeout(&mut self) {
+            self.depth = (self.depth / 2).max(self.min);
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn depth_grows_on_prompt_blocks_and_halves_on_timeouts() {
+            let mut queue = RequestQueue::new(MIN_QUEUE_DEPTH, 8);
+            assert_eq!(queue.depth(), INITIAL_QUEUE_DEPTH);
+            queue.on_block(Duration::from_millis(20));
+            assert_eq!(queue.depth(), 6);
+            // slow blocks leave it where it is
+            queue.on_block(Duration::from_secs(2));
+            assert_eq!(queue.depth(), 6);
+            for _ in 0..10 {
+                queue.on_block(PROMPT_BLOCK_LATENCY);
+            }
+            assert_eq!(queue.depth(), 8);
+
+            queue.on_timeout();
+            assert_eq!(queue.depth(), 4);
+            for _ in 0..5 {
+                queue.on_timeout();
+            }
+            assert_eq!(queue.depth(), MIN_QUEUE_DEPTH);
+        }
+
+        #[test]
+        fn bounds_are_kept_consistent() {
+            assert_eq!(RequestQueue::new(0, 0).depth(), 1);
+            assert_eq!(RequestQueue::new(10, 3).depth(), 10);
+            assert_eq!(RequestQueue::new(1, 2).depth(), 2);
+        }
+    }
+}
+
+pub mod rate_limit {
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    /// A token bucket shared by everything that clones it, so a cap applies across all peers.
+    /// The bucket holds at most one second worth of bytes and may go into debt: `acquire`
+    /// takes the tokens right away and then sleeps until the debt would have been paid off.
+    #[derive(Debug, Clone, Default)]
+    pub struct RateLimiter {
+        bucket: Option<Arc<Mutex<Bucket>>>, // None means unlimited
+    }
+
+    #[derive(Debug)]
+    struct Bucket {
+        bytes_per_sec: f64,
+        tokens: f64,
+        last_refill: Instant,
+    }
+
+    impl RateLimiter {
+        /// Limits to `bytes_per_sec`, 0 means unlimited.
+        pub fn new(bytes_per_sec: u64) -> Self {
+            if bytes_per_sec == 0 {
+                return Self::default();
+            }
+            let bytes_per_sec = bytes_per_sec as f64;
+            Self {
+                bucket: Some(Arc::new(Mutex::new(Bucket {
+                    bytes_per_sec,
+                    tokens: bytes_per_sec,
+                    last_refill: Instant::now(),
+                }))),
+            }
+        }
+
+        pub async fn acquire(&self, bytes: u64) {
+            let Some(bucket) = &self.bucket else {
+                return;
+            };
+            let wait = {
+                let mut bucket = bucket.lock().expect("rate limiter lock poisoned");
+                let now = Instant::now();
+                let refill =
+                    now.duration_since(bucket.last_refill).as_secs_f64() * bucket.bytes_per_sec;
+                bucket.tokens = (bucket.tokens + refill).min(bucket.bytes_per_sec);
+                bucket.last_refill = now;
+                bucket.tokens -= bytes as f64;
+                if bucket.tokens < 0.0 {
+                    Duration::from_secs_f64(-bucket.tokens / bucket.bytes_per_sec)
+                } else {
+                    Duration::ZERO
+                }
+            };
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+        }
+    }
+}
+
+pub mod block_cache {
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::{Arc, Mutex, MutexGuard};
+
+    /// Blocks of unfinished pieces that already arrived, by piece and offset, so a piece whose
+    /// connection broke off only needs its missing blocks from the next peer. Shared by everything
+    /// that clones it. A piece's blocks are dropped once it completes: when it then fails
+    /// verification there is no telling which block was bad, so it starts over.
+    #[derive(Debug, Clone, Default)]
+    pub struct BlockCache {
+        pieces: Arc<Mutex<Pieces>>,
+    }
+
+    // piece -> offset -> block
+    type Pieces = HashMap<u32, BTreeMap<u64, Vec<u8>>>;
+
+    impl BlockCache {
+        pub fn insert(&self, piece: u32, offset: u64, block: Vec<u8>) {
+            self.lock().entry(piece).or_default().insert(offset, block);
+        }
+
+        /// The blocks kept for `piece` by offset, they stay in the cache.
+        pub fn blocks(&self, piece: u32) -> BTreeMap<u64, Vec<u8>> {
+            self.lock().get(&piece).cloned().unwrap_or_default()
+        }
+
+        pub fn discard(&self, piece: u32) {
+            self.lock().remove(&piece);
+        }
+
+        /// Bytes kept over all pieces.
+        pub fn bytes(&self) -> usize {
+            self.lock()
+                .values()
+                .flat_map(BTreeMap::values)
+                .map(Vec::len)
+                .sum()
+        }
+
+        fn lock(&self) -> MutexGuard<'_, Pieces> {
+            self.pieces.lock().expect("block cache lock poisoned")
+        }
+    }
+}
+
+pub mod choke {
+    use rand::{seq::IteratorRandom, Rng};
+    use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+    /// How often the unchoke set is recomputed.
+    pub const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+    pub const DEFAULT_UNCHOKE_SLOTS: usize = 4;
+    // the optimistic unchoke moves on every third round, i.e. every 30 seconds
+    const OPTIMISTIC_ROUNDS: u32 = 3;
+
+    /// The standard choking algorithm: every `CHOKE_INTERVAL` the `slots` interested peers with
+    /// the best rate over the last round are unchoked, plus one interested peer picked at random
+    /// among the rest so newcomers get a chance to show what they can do. Drives the uploads of
+    /// `DownloadOptions::listen`, with `DownloadOptions::unchoke_slots` slots.
+    #[derive(Debug)]
+    pub struct Choker {
+        slots: usize,
+        peers: HashMap<SocketAddr, PeerStats>,
+        optimistic: Option<SocketAddr>,
+        round: u32,
+    }
+
+    #[derive(Debug, Default)]
+    struct PeerStats {
+        interested: bool,
+        bytes: u64, // since the last round
+        rate: f64,  // bytes per second over the last round
+    }
+
+    impl Choker {
+        pub fn new(slots: usize) -> Self {
+            Self {
+                slots,
+                peers: HashMap::new(),
+                optimistic: None,
+                round: 0,
+            }
+        }
+
+        pub fn add_peer(&mut self, peer: SocketAddr) {
+            self.peers.entry(peer).or_default();
+        }
+
+        pub fn remove_peer(&mut self, peer: &SocketAddr) {
+            self.peers.remove(peer);
+            if self.optimistic.as_ref() == Some(peer) {
+                self.optimistic = None;
+            }
+        }
+
+        pub fn set_interested(&mut self, peer: SocketAddr, interested: bool) {
+            self.peers.entry(peer).or_default().interested = interested;
+        }
+
+        /// Counts `bytes` transferred with `peer` towards its rate for the current round.
+        pub fn record(&mut self, peer: SocketAddr, bytes: u64) {
+            self.peers.entry(peer).or_default().bytes += bytes;
+        }
+
+        /// Closes the round that lasted `elapsed` and returns the peers that should be
+        /// unchoked from now on, everyone else gets choked.
+        pub fn rechoke(&mut self, elapsed: Duration) -> Vec<SocketAddr> {
+            self.rechoke_with(elapsed, &mut rand::thread_rng())
+        }
+
+        pub fn rechoke_with<R: Rng + ?Sized>(
+            &mut self,
+            elapsed: Duration,
+            rng: &mut R,
+        ) -> Vec<SocketAddr> {
+            let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+            for stats in self.peers.values_mut() {
+                stats.rate = stats.bytes as f64 / secs;
+                stats.bytes = 0;
+            }
+
+            let mut interested: Vec<_> = self
+                .peers
+                .iter()
+                .filter(|(_, stats)| stats.interested)
+                .map(|(peer, stats)| (*peer, stats.rate))
+                .collect();
+            interested.sort_by(|a, b| b.1.total_cmp(&a.1));
+            let mut unchoked: Vec<_> = interested
+                .iter()
+                .take(self.slots)
+                .map(|(peer, _)| *peer)
+                .collect();
+
+            // keep the optimistic peer for a few rounds unless it stopped being a candidate
+            let still_valid = self.optimistic.is_some_and(|peer| {
+                self.peers.get(&peer).is_some_and(|stats| stats.interested)
+                    && !unchoked.contains(&peer)
+            });
+            if !still_valid || self.round.is_multiple_of(OPTIMISTIC_ROUNDS) {
+                self.optimistic = interested
+                    .iter()
+                    .map(|(peer, _)| *peer)
+                    .filter(|peer| !unchoked.contains(peer) && Some(*peer) != self.optimistic)
+                    .choose(rng)
+                    .or(self.optimistic.filter(|_| still_valid));
+            }
+            self.round += 1;
+
+            unchoked.extend(self.optimistic);
+            unchoked
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use rand::{rngs::StdRng, SeedableRng};
+
+        fn peer(port: u16) -> SocketAddr {
+            SocketAddr::from(([127, 0, 0, 1], port))
+        }
+
+        #[test]
+        fn fastest_interested_peers_get_the_slots() {
+            let mut choker = Choker::new(2);
+            for (port, bytes, interested) in [
+                (1, 300, true),
+                (2, 200, true),
+                (3, 100, true),
+                (4, 1000, false),
+            ] {
+                choker.add_peer(peer(port));
+                choker.set_interested(peer(port), interested);
+                choker.record(peer(port), bytes);
+            }
+            let mut rng = StdRng::seed_from_u64(1);
+            let unchoked = choker.rechoke_with(CHOKE_INTERVAL, &mut rng);
+            // the two fastest plus the only other interested one as the optimistic unchoke
+            assert_eq!(unchoked, [peer(1), peer(2), peer(3)]);
+
+            // peer 3 took the most this round, peer 1 nothing
+            choker.record(peer(3), 500);
+            choker.record(peer(2), 100);
+            let unchoked = choker.rechoke_with(CHOKE_INTERVAL, &mut rng);
+            assert_eq!(unchoked[..2], [peer(3), peer(2)]);
+            assert_eq!(unchoked[2], peer(1));
+        }
+
+        #[test]
+        fn peers_that_leave_lose_their_slot() {
+            let mut choker = Choker::new(1);
+            for port in [1, 2] {
+                choker.add_peer(peer(port));
+                choker.set_interested(peer(port), true);
+            }
+            choker.record(peer(1), 10);
+            let mut rng = StdRng::seed_from_u64(1);
+            assert_eq!(
+                choker.rechoke_with(CHOKE_INTERVAL, &mut rng),
+                [peer(1), peer(2)]
+            );
+            choker.remove_peer(&peer(2));
+            choker.set_interested(peer(1), false);
+            assert!(choker.rechoke_with(CHOKE_INTERVAL, &mut rng).is_empty());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::BencodeValue;
+    use crate::testing::{bytes, dict, pattern, seed, serve, torrent, Seeder};
+    use tokio::io::DuplexStream;
+
+    // a peer that sets the fast bit, answers our handshake and then sends HaveAll
+    async fn fast_peer_sending_have_all(mut remote: DuplexStream) {
+        let mut ours = [0; HANDSHAKE_BYTE_BUFFER_SIZE];
+        remote.read_exact(&mut ours).await.unwrap();
+        let info_hash = PeerInfo::from_handshake(&ours).info_hash;
+        let theirs = Handshake::new(info_hash).with_fast().as_bytes();
+        remote.write_all(&theirs).await.unwrap();
+        remote.write_all(&[0, 0, 0, 1, 14]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_whole_download_over_an_in_memory_pipe() {
+        let data = pattern(40000);
+        let torrent = torrent("http://tracker/announce", &data, 16 * 1024);
+        let (local, remote) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(serve(
+            Stream::new(remote),
+            torrent.clone(),
+            std::sync::Arc::new(data.clone()),
+        ));
+        let mut connection = PeerConnection::new(Stream::new(local));
+        let handshake = Handshake::new(torrent.info.info_hash_bytes());
+        connection
+            .prepare(handshake, torrent.num_pieces())
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        for piece in 0..torrent.num_pieces() as u32 {
+            received.extend(
+                connection
+                    .download_piece(piece, &torrent, DEFAULT_PIECE_TIMEOUT)
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert_eq!(received, data);
+    }
+
+    #[tokio::test]
+    async fn connection_states_and_choked_requests() {
+        let data = pattern(40000);
+        let torrent = torrent("http://tracker/announce", &data, 16 * 1024);
+        let (local, remote) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(serve(
+            Stream::new(remote),
+            torrent.clone(),
+            std::sync::Arc::new(data.clone()),
+        ));
+        let mut connection = PeerConnection::new(Stream::new(local));
+        assert!(connection.am_choking() && connection.peer_choking());
+        assert!(!connection.am_interested() && connection.bitfield().is_none());
+
+        // refused without a word to the peer, which would choke on a request instead of a handshake
+        let e = connection
+            .download_piece(0, &torrent, DEFAULT_PIECE_TIMEOUT)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Can't request piece 0 while the peer is choking us"
+        );
+
+        let handshake = || Handshake::new(torrent.info.info_hash_bytes());
+        connection
+            .prepare(handshake(), torrent.num_pieces())
+            .await
+            .unwrap();
+        assert!(connection.am_interested() && !connection.peer_choking());
+        assert_eq!(connection.bitfield(), Some(&BitField::full(3)));
+        // we haven't unchoked the peer yet
+        assert!(connection.am_choking());
+        connection.unchoke().await.unwrap();
+        assert!(!connection.am_choking());
+
+        let piece = connection
+            .download_piece(2, &torrent, DEFAULT_PIECE_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(piece, data[32 * 1024..]);
+        let e = connection
+            .download_piece(3, &torrent, DEFAULT_PIECE_TIMEOUT)
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "Peer does not have piece 3");
+        let e = connection
+            .prepare(handshake(), torrent.num_pieces())
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "Connection is already prepared");
+    }
+
+    #[tokio::test]
+    async fn pieces_are_requested_in_blocks_of_the_configured_size() {
+        // 5000 byte blocks: three full ones and 1384 bytes for the rest of the 16 KiB piece
+        let data = pattern(16 * 1024);
+        let torrent = torrent("http://tracker/announce", &data, 16 * 1024);
+        let (local, remote) = tokio::io::duplex(64 * 1024);
+        let seeder = tokio::spawn(seed(remote, data.clone(), 16 * 1024));
+        let mut stream = Stream::new(local).with_block_size(5000);
+        let received = stream
+            .get_piece_data(0, &torrent, DEFAULT_PIECE_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(received, data);
+        drop(stream);
+        assert_eq!(seeder.await.unwrap(), [6, 6, 6, 6]);
+    }
+
+    #[tokio::test]
+    async fn byte_counters_match_the_bytes_on_the_wire() {
+        let data = pattern(32 * 1024);
+        let torrent = torrent("http://tracker/announce", &data, 32 * 1024);
+        let (local, remote) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(seed(remote, data.clone(), 32 * 1024));
+        let mut stream = Stream::new(local);
+        assert_eq!((stream.bytes_read(), stream.bytes_written()), (0, 0));
+        stream
+            .get_piece_data(0, &torrent, DEFAULT_PIECE_TIMEOUT)
+            .await
+            .unwrap();
+        // two 17 byte requests out, two blocks with a 13 byte header each back
+        assert_eq!(stream.bytes_written(), 2 * 17);
+        assert_eq!(stream.bytes_read(), 2 * (13 + 16 * 1024));
+        stream.have(0).await.unwrap();
+        assert_eq!(stream.bytes_written(), 2 * 17 + 9);
+    }
+
+    #[tokio::test]
+    async fn connect_and_handshake_checks_the_answer() {
+        let data = pattern(1000);
+        let torrent = torrent("http://tracker/announce", &data, 1000);
+        let info_hash = torrent.info.info_hash_bytes();
+        let seeder = Seeder::start(&torrent, data).await;
+        let (mut stream, peer) =
+            Stream::connect_and_handshake(&seeder.address, Handshake::new(info_hash))
+                .await
+                .unwrap();
+        assert_eq!(peer.info_hash, info_hash);
+        assert_eq!(peer.peer_id, handshake::DEFAULT_PEER_ID.as_bytes());
+        assert!(stream.bitfield(1).await.unwrap().has_piece(0));
+
+        // a peer answering for some other torrent
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await?;
+            let mut ours = [0; HANDSHAKE_BYTE_BUFFER_SIZE];
+            socket.read_exact(&mut ours).await?;
+            socket.write_all(&Handshake::new([9; 20]).as_bytes()).await
+        });
+        let e = Stream::connect_and_handshake(&address, Handshake::new(info_hash))
+            .await
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("answered for info hash 0909"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn a_huge_declared_length_is_an_error() {
+        // only the length and the id are sent, a reader trying to allocate and fill 4 GiB would
+        // hang or abort instead of failing
+        let (local, mut remote) = tokio::io::duplex(1024);
+        remote
+            .write_all(&[0xff, 0xff, 0xff, 0xff, 5])
+            .await
+            .unwrap();
+        let e = Stream::new(local).read_message().await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "Peer sent a 4294967295 byte message with id 5, the limit is {MAX_MESSAGE_LENGTH}"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn port_messages_are_kept_and_skipped() {
+        let (local, mut remote) = tokio::io::duplex(1024);
+        // port 6881, a port message one byte short, then an unchoke
+        remote
+            .write_all(&[
+                0, 0, 0, 3, 9, 0x1a, 0xe1, 0, 0, 0, 2, 9, 0x1a, 0, 0, 0, 1, 1,
+            ])
+            .await
+            .unwrap();
+        let mut stream = Stream::new(local);
+        assert_eq!(stream.dht_port, None);
+        assert_eq!(stream.read_message().await.unwrap(), (1, Vec::new()));
+        assert_eq!(stream.dht_port, Some(6881));
+    }
+
+    #[tokio::test]
+    async fn messages_longer_than_their_type_allows_are_rejected() {
+        for (length, id, limit) in [
+            // a have is 5 bytes, a piece the block plus 9
+            (6u32, 4u8, 5),
+            (9 + DEFAULT_BLOCK_SIZE + 1, 7, 9 + DEFAULT_BLOCK_SIZE),
+            (2, 1, 1),
+        ] {
+            let (local, mut remote) = tokio::io::duplex(1024);
+            remote.write_all(&length.to_be_bytes()).await.unwrap();
+            remote.write_all(&[id]).await.unwrap();
+            let e = Stream::new(local).read_message().await.unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                format!("Peer sent a {length} byte message with id {id}, the limit is {limit}")
+            );
+        }
+        // a piece of exactly one block is fine
+        let (local, mut remote) = tokio::io::duplex(64 * 1024);
+        remote
+            .write_all(&(9 + DEFAULT_BLOCK_SIZE).to_be_bytes())
+            .await
+            .unwrap();
+        remote.write_all(&[7]).await.unwrap();
+        remote
+            .write_all(&vec![0; 8 + DEFAULT_BLOCK_SIZE as usize])
+            .await
+            .unwrap();
+        let (id, payload) = Stream::new(local).read_message().await.unwrap();
+        assert_eq!((id, payload.len()), (7, 8 + DEFAULT_BLOCK_SIZE as usize));
+    }
+
+    #[tokio::test]
+    async fn handshake_over_ipv6_loopback() {
+        let listener = tokio::net::TcpListener::bind("[::1]:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await?;
+            Stream::new(socket)
+                .accept_handshake(Handshake::new([7; 20]))
+                .await
+        });
+        let (_, peer) = Stream::connect_and_handshake(&address, Handshake::new([7; 20]))
+            .await
+            .unwrap();
+        assert_eq!(peer.info_hash, [7; 20]);
+    }
+
+    #[tokio::test]
+    async fn have_is_nine_bytes() {
+        let (local, mut remote) = tokio::io::duplex(1024);
+        let mut stream = Stream::new(local);
+        stream.have(0x01020304).await.unwrap();
+        drop(stream);
+        let mut sent = Vec::new();
+        remote.read_to_end(&mut sent).await.unwrap();
+        assert_eq!(sent, [0, 0, 0, 5, 4, 1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn have_messages_are_kept_for_the_caller() {
+        let (local, mut remote) = tokio::io::duplex(1024);
+        // have 3, a malformed have, have 7, unchoke
+        remote
+            .write_all(&[0, 0, 0, 5, 4, 0, 0, 0, 3])
+            .await
+            .unwrap();
+        remote.write_all(&[0, 0, 0, 3, 4, 0, 1]).await.unwrap();
+        remote
+            .write_all(&[0, 0, 0, 5, 4, 0, 0, 0, 7])
+            .await
+            .unwrap();
+        remote.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+        let mut stream = Stream::new(local);
+        stream.wait_unchoke().await.unwrap();
+        assert_eq!(stream.peer_haves, vec![3, 7]);
+    }
+
+    #[tokio::test]
+    async fn have_all_needs_the_fast_bit_on_both_sides() {
+        for (handshake, accepted) in [
+            (Handshake::new([1; 20]).with_fast(), true),
+            (Handshake::new([1; 20]), false),
+        ] {
+            let (local, remote) = tokio::io::duplex(1024);
+            tokio::spawn(fast_peer_sending_have_all(remote));
+            let mut stream = Stream::new(local);
+            stream.handshake(handshake).await.unwrap();
+            assert!(stream.peer_capabilities.fast);
+            let bitfield = stream.bitfield(10).await;
+            assert_eq!(bitfield.is_ok(), accepted, "{bitfield:?}");
+            if let Ok(bitfield) = bitfield {
+                assert!((0..10).all(|piece| bitfield.has_piece(piece)));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn the_extension_bit_is_stored_on_the_stream() {
+        let (local, remote) = tokio::io::duplex(1024);
+        let seeder = tokio::spawn(async move {
+            let mut stream = Stream::new(remote);
+            stream
+                .accept_handshake(Handshake::new([1; 20]))
+                .await
+                .unwrap();
+            stream.peer_capabilities
+        });
+        let mut stream = Stream::new(local);
+        stream
+            .handshake(Handshake::new([1; 20]).with_extensions())
+            .await
+            .unwrap();
+        assert!(seeder.await.unwrap().extensions);
+        assert!(!stream.peer_capabilities.extensions);
+    }
+
+    #[tokio::test]
+    async fn slow_pieces_run_out_of_time() {
+        let data = pattern(16 * 1024);
+        let torrent = torrent("http://tracker/announce", &data, 16 * 1024);
+        let (local, mut remote) = tokio::io::duplex(64 * 1024);
+        // answers the request a kilobyte every 50ms, it never goes quiet for long but the whole
+        // block takes most of a second
+        tokio::spawn(async move {
+            let mut request = [0; 17];
+            remote.read_exact(&mut request).await?;
+            let mut reply = (9 + data.len() as u32).to_be_bytes().to_vec();
+            reply.extend([7, 0, 0, 0, 0, 0, 0, 0, 0]);
+            reply.extend(&data);
+            for chunk in reply.chunks(1024) {
+                remote.write_all(chunk).await?;
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            std::io::Result::Ok(())
+        });
+        let mut stream = Stream::new(local).with_timeout(Duration::from_secs(1));
+        let started = Instant::now();
+        let result = stream
+            .get_piece_data(0, &torrent, Duration::from_millis(300))
+            .await;
+        assert!(matches!(result, Err(BtError::Timeout(_))), "{result:?}");
+        assert!(started.elapsed() < Duration::from_millis(600));
+    }
+
+    #[tokio::test]
+    async fn pieces_finished_elsewhere_cancel_their_requests() {
+        let data = pattern(64 * 1024);
+        let torrent = torrent("http://tracker/announce", &data, 64 * 1024);
+        let (local, remote) = tokio::io::duplex(256 * 1024);
+        let seeder = tokio::spawn(seed(remote, data, 64 * 1024));
+        let mut stream = Stream::new(local);
+        // another peer delivered the piece while the requests were on their way
+        let done = AtomicBool::new(true);
+        let result = stream
+            .get_piece_data_cancellable(0, &torrent, DEFAULT_PIECE_TIMEOUT, &done)
+            .await;
+        assert!(result.is_err());
+        assert!(stream.block_cache.blocks(0).is_empty());
+        drop(stream);
+        // all four blocks were requested, the three still outstanding after the first arrived
+        // got cancelled
+        assert_eq!(seeder.await.unwrap(), [6, 6, 6, 6, 8, 8, 8]);
+    }
+
+    // reads a request message off the wire as (index, begin, length)
+    async fn read_request(remote: &mut DuplexStream) -> (u32, u32, u32) {
+        let mut request = [0; 17];
+        remote.read_exact(&mut request).await.unwrap();
+        assert_eq!(request[..5], [0, 0, 0, 13, 6]);
+        let field = |at: usize| u32::from_be_bytes(request[at..at + 4].try_into().unwrap());
+        (field(5), field(9), field(13))
+    }
+
+    async fn send_block(
+        remote: &mut DuplexStream,
+        piece: &[u8],
+        (index, begin, length): (u32, u32, u32),
+    ) {
+        let mut stream = Stream::new(remote);
+        let block = &piece[begin as usize..(begin + length) as usize];
+        stream.send_block(index, begin, block).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn choke_in_the_middle_of_a_piece_rerequests_after_unchoke() {
+        let data = pattern(64 * 1024);
+        let torrent = torrent("http://tracker/announce", &data, 64 * 1024);
+        let (local, mut remote) = tokio::io::duplex(256 * 1024);
+        let piece = data.clone();
+        let peer = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for _ in 0..4 {
+                requests.push(read_request(&mut remote).await);
+            }
+            // one block gets through, then the peer chokes us and forgets the other requests
+            send_block(&mut remote, &piece, requests[0]).await;
+            remote.write_all(&[0, 0, 0, 1, 0]).await.unwrap();
+            remote.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+            for _ in 0..3 {
+                let request = read_request(&mut remote).await;
+                send_block(&mut remote, &piece, request).await;
+                requests.push(request);
+            }
+            requests
+        });
+        let mut stream = Stream::new(local);
+        let received = stream
+            .get_piece_data(0, &torrent, DEFAULT_PIECE_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(received, data);
+        let offsets: Vec<u32> = peer
+            .await
+            .unwrap()
+            .iter()
+            .map(|&(_, begin, _)| begin)
+            .collect();
+        let block = 16 * 1024;
+        assert_eq!(offsets[..4], [0, block, 2 * block, 3 * block]);
+        let mut rerequested = offsets[4..].to_vec();
+        rerequested.sort();
+        assert_eq!(rerequested, [block, 2 * block, 3 * block]);
+    }
+
+    #[tokio::test]
+    async fn a_broken_off_piece_resumes_from_the_missing_blocks() {
+        let data = pattern(64 * 1024);
+        let torrent = torrent("http://tracker/announce", &data, 64 * 1024);
+        let cache = BlockCache::default();
+
+        // the first peer sends one block and hangs up
+        let (local, mut remote) = tokio::io::duplex(256 * 1024);
+        let piece = data.clone();
+        tokio::spawn(async move {
+            let first = read_request(&mut remote).await;
+            for _ in 0..3 {
+                read_request(&mut remote).await;
+            }
+            send_block(&mut remote, &piece, first).await;
+        });
+        let mut stream = Stream::new(local).with_block_cache(cache.clone());
+        let result = stream
+            .get_piece_data(0, &torrent, DEFAULT_PIECE_TIMEOUT)
+            .await;
+        assert!(result.is_err());
+        assert_eq!(cache.blocks(0).keys().collect::<Vec<_>>(), [&0]);
+
+        // the next one only gets asked for the rest
+        let (local, mut remote) = tokio::io::duplex(256 * 1024);
+        let piece = data.clone();
+        let peer = tokio::spawn(async move {
+            let mut offsets = Vec::new();
+            for _ in 0..3 {
+                let request = read_request(&mut remote).await;
+                send_block(&mut remote, &piece, request).await;
+                offsets.push(request.1);
+            }
+            offsets
+        });
+        let mut stream = Stream::new(local).with_block_cache(cache.clone());
+        let received = stream
+            .get_piece_data(0, &torrent, DEFAULT_PIECE_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(received, data);
+        let block = 16 * 1024;
+        assert_eq!(peer.await.unwrap(), [block, 2 * block, 3 * block]);
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn pieces_past_32_bit_offsets_are_refused_up_front() {
+        let piece_length = 6 * 1024 * 1024 * 1024_i64;
+        let info = dict(vec![
+            ("length", BencodeValue::Int(piece_length)),
+            ("name", bytes("huge")),
+            ("piece length", BencodeValue::Int(piece_length)),
+            ("pieces", bytes([0; 20])),
+        ]);
+        let file = dict(vec![
+            ("announce", bytes("http://tracker/announce")),
+            ("info", info),
+        ]);
+        let torrent = Torrent::from_bytes(&crate::bencode::encode(&file)).unwrap();
+        let (local, mut remote) = tokio::io::duplex(1024);
+        let mut stream = Stream::new(local);
+        let result = stream
+            .get_piece_data(0, &torrent, DEFAULT_PIECE_TIMEOUT)
+            .await;
+        assert!(
+            format!("{result:?}").contains("too large to request"),
+            "{result:?}"
+        );
+        // and not a single request went out
+        drop(stream);
+        let mut sent = Vec::new();
+        remote.read_to_end(&mut sent).await.unwrap();
+        assert!(sent.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_shared_download_cap_spans_all_streams() {
+        // two 64 KiB pieces at 64 KiB/s: the first one is covered by the full bucket, the second
+        // has to wait for a second's worth of refill
+        let data = pattern(128 * 1024);
+        let torrent = torrent("http://tracker/announce", &data, 64 * 1024);
+        let limiter = RateLimiter::new(64 * 1024);
+        let stream = || {
+            let (local, remote) = tokio::io::duplex(256 * 1024);
+            tokio::spawn(seed(remote, data.clone(), 64 * 1024));
+            Stream::new(local).with_download_limiter(limiter.clone())
+        };
+        let (mut first, mut second) = (stream(), stream());
+        let started = Instant::now();
+        let (a, b) = tokio::join!(
+            first.get_piece_data(0, &torrent, DEFAULT_PIECE_TIMEOUT),
+            second.get_piece_data(1, &torrent, DEFAULT_PIECE_TIMEOUT),
+        );
+        assert_eq!(a.unwrap(), data[..64 * 1024]);
+        assert_eq!(b.unwrap(), data[64 * 1024..]);
+        assert!(started.elapsed() >= Duration::from_millis(950));
+    }
+
+    #[tokio::test]
+    async fn a_shared_upload_cap_spans_all_streams() {
+        // two 64 KiB pieces at 64 KiB/s, like the download cap: the second one waits a second
+        let data = pattern(128 * 1024);
+        let limiter = RateLimiter::new(64 * 1024);
+        let upload = |piece: u32| {
+            let (local, mut remote) = tokio::io::duplex(256 * 1024);
+            tokio::spawn(async move {
+                let mut received = Vec::new();
+                remote.read_to_end(&mut received).await.map(|_| received)
+            });
+            let mut stream = Stream::new(local).with_upload_limiter(limiter.clone());
+            let piece_data = data[piece as usize * 64 * 1024..][..64 * 1024].to_vec();
+            async move {
+                for (block, offset) in piece_data.chunks(16 * 1024).zip((0..).step_by(16 * 1024)) {
+                    stream.send_block(piece, offset, block).await.unwrap();
+                }
+            }
+        };
+        let started = Instant::now();
+        tokio::join!(upload(0), upload(1));
+        assert!(started.elapsed() >= Duration::from_millis(950));
+
+        // and without a cap nothing waits
+        let started = Instant::now();
+        let (local, _remote) = tokio::io::duplex(256 * 1024);
+        let mut stream = Stream::new(local);
+        stream.send_block(0, 0, &data[..128 * 1024]).await.unwrap();
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn get_piece_data_checks_the_hash() {
+        let data = pattern(40_000);
+        let torrent = torrent("http://tracker/announce", &data, 16 * 1024);
+        let (local, remote) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(seed(remote, data.clone(), 16 * 1024));
+        let mut stream = Stream::new(local);
+        let piece = stream
+            .get_piece_data(2, &torrent, DEFAULT_PIECE_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(piece, data[32 * 1024..]);
+
+        let mut corrupt = data.clone();
+        corrupt[20_000] ^= 0xff;
+        let (local, remote) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(seed(remote, corrupt, 16 * 1024));
+        let mut stream = Stream::new(local);
+        let result = stream
+            .get_piece_data(1, &torrent, DEFAULT_PIECE_TIMEOUT)
+            .await;
+        assert!(
+            matches!(result, Err(BtError::HashMismatch { piece: 1 })),
+            "{result:?}"
+        );
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{Context, Result};
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use tokio::sync::{watch, Semaphore};
+use tokio::task::JoinSet;
+use tracing::{info_span, Instrument};
+
+use crate::download::{self, selector::PieceSelector, DownloadOptions};
+use crate::torrent::Torrent;
+
+/// One torrent of a `Session`: where to get it from, in which order and with the options from
+/// `Session::download_options`.
+pub struct Download {
+    pub torrent: Torrent,
+    pub peers: Vec<SocketAddrV4>,
+    pub selector: Box<dyn PieceSelector>,
+    pub options: DownloadOptions,
+}
+
+/// Downloads several torrents at once. They share one budget of `max_peers` connections, the
+/// download rate limiter and cancelling, everything else (state, progress, pausing, summary)
+/// is kept per torrent.
+#[derive(Debug, Clone)]
+pub struct Session {
+    options: DownloadOptions,
+}
+
+impl Session {
+    pub fn new(options: DownloadOptions) -> Self {
+        let connections = Arc::new(Semaphore::new(options.max_peers));
+        Self {
+            options: DownloadOptions {
+                connections: Some(connections),
+                ..options
+            },
+        }
+    }
+
+    /// Options for one more torrent of the session, based on the ones it was created with.
+    pub fn download_options(&self) -> DownloadOptions {
+        DownloadOptions {
+            state: Arc::new(watch::channel(download::ClientState::Connecting).0),
+            paused: Arc::new(watch::channel(false).0),
+            ranking: Arc::new(watch::channel(Vec::new()).0),
+            announced: Arc::new(watch::channel(Vec::new()).0),
+            progress: Arc::new(watch::channel((0, 0)).0),
+            summary: Arc::new(watch::channel(Default::default()).0),
+            ..self.options.clone()
+        }
+    }
+
+    /// Stops every download of the session, see `DownloadHandle::cancel`.
+    pub fn cancel(&self) {
+        self.options.cancel.cancel();
+    }
+
+    /// Runs all `downloads` until each one finished or failed, results come back in the same
+    /// order. One torrent failing doesn't stop the others.
+    pub async fn download_all(&self, downloads: Vec<Download>) -> Result<Vec<Result<Vec<u8>>>> {
+        let mut running = JoinSet::new();
+        for (index, download) in downloads.into_iter().enumerate() {
+            let span = info_span!("torrent", name = download.torrent.info.display_name());
+            running.spawn(
+                async move {
+                    let result = download::download_all(
+                        &download.torrent,
+                        &download.peers,
+                        download.selector,
+                        &download.options,
+                    )
+                    .await;
+                    (index, result)
+                }
+                .instrument(span),
+            );
+        }
+        let mut results: Vec<Option<Result<Vec<u8>>>> = (0..running.len()).map(|_| None).collect();
+        while let Some(joined) = running.join_next().await {
+            let (index, result) = joined.context("CTX: download task panicked")?;
+            results[index] = Some(result);
+        }
+        Ok(results.into_iter().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::selector::Sequential;
+    use crate::testing::{pattern, torrent, Seeder};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn torrents_share_the_connection_cap() {
+        let session = Session::new(DownloadOptions {
+            max_peers: 1,
+            ..Default::default()
+        });
+        let mut downloads = Vec::new();
+        let mut expected = Vec::new();
+        for length in [40 * 16 * 1024, 39 * 16 * 1024 + 5] {
+            let data = pattern(length);
+            let torrent = torrent("http://tracker/announce", &data, 16 * 1024);
+            let seeder = Seeder::start(&torrent, data.clone()).await;
+            downloads.push(Download {
+                torrent,
+                peers: vec![seeder.address],
+                selector: Box::new(Sequential),
+                options: session.download_options(),
+            });
+            expected.push(data);
+        }
+        // nobody seeds this one, it fails without holding up the others
+        downloads.push(Download {
+            torrent: torrent("http://tracker/announce", &pattern(1000), 1000),
+            peers: Vec::new(),
+            selector: Box::new(Sequential),
+            options: session.download_options(),
+        });
+        let handles: Vec<_> = downloads.iter().map(|d| d.options.handle()).collect();
+        let (mut first, mut second) = (
+            downloads[0].options.progress(),
+            downloads[1].options.progress(),
+        );
+        let running = tokio::spawn({
+            let session = session.clone();
+            async move { session.download_all(downloads).await }
+        });
+
+        // whichever torrent got the only connection slot keeps it while paused, the other waits
+        let holder = tokio::select! {
+            _ = first.wait_for(|&(done, _)| done > 0) => 0,
+            _ = second.wait_for(|&(done, _)| done > 0) => 1,
+        };
+        let waiting = if holder == 0 { second } else { first };
+        handles[holder].pause();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(waiting.borrow().0, 0, "both torrents had a connection");
+        handles[holder].resume();
+
+        let results = running.await.unwrap().unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), &expected[0]);
+        assert_eq!(results[1].as_ref().unwrap(), &expected[1]);
+        assert!(results[2].is_err());
+    }
+}
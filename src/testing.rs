@@ -0,0 +1,338 @@
+// helpers shared by the unit tests: torrents built in memory and a scriptable http tracker
+use std::collections::BTreeMap;
+use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpListener;
+
+use crate::bencode::{self, BencodeValue};
+use crate::hash::sha1;
+use crate::peer::{bitfield::BitField, handshake::Handshake, message::MessageType, Stream};
+use crate::torrent::Torrent;
+
+pub(crate) fn dict(entries: Vec<(&str, BencodeValue)>) -> BencodeValue {
+    BencodeValue::Dict(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value))
+            .collect::<BTreeMap<_, _>>(),
+    )
+}
+
+pub(crate) fn bytes(value: impl AsRef<[u8]>) -> BencodeValue {
+    BencodeValue::Bytes(value.as_ref().to_vec())
+}
+
+// deterministic filler that doesn't repeat every 256 bytes
+pub(crate) fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| ((i * 7) ^ (i >> 8)) as u8).collect()
+}
+
+pub(crate) fn piece_hashes(data: &[u8], piece_length: usize) -> Vec<u8> {
+    data.chunks(piece_length).flat_map(sha1).collect()
+}
+
+/// A single-file torrent named `test.bin` holding `data`.
+pub(crate) fn torrent(announce: &str, data: &[u8], piece_length: usize) -> Torrent {
+    let info = dict(vec![
+        ("length", BencodeValue::Int(data.len() as i64)),
+        ("name", bytes("test.bin")),
+        ("piece length", BencodeValue::Int(piece_length as i64)),
+        ("pieces", bytes(piece_hashes(data, piece_length))),
+    ]);
+    let torrent = dict(vec![("announce", bytes(announce)), ("info", info)]);
+    Torrent::from_bytes(&bencode::encode(&torrent)).expect("test torrent must parse")
+}
+
+/// A multi-file torrent named `test` with files of the given `/` separated paths and lengths,
+/// filled with `pattern`.
+pub(crate) fn multi_file_torrent(files: &[(&str, usize)], piece_length: usize) -> Torrent {
+    let total = files.iter().map(|(_, length)| length).sum();
+    let files = files
+        .iter()
+        .map(|(path, length)| {
+            dict(vec![
+                ("length", BencodeValue::Int(*length as i64)),
+                (
+                    "path",
+                    BencodeValue::List(path.split('/').map(bytes).collect()),
+                ),
+            ])
+        })
+        .collect();
+    let info = dict(vec![
+        ("files", BencodeValue::List(files)),
+        ("name", bytes("test")),
+        ("piece length", BencodeValue::Int(piece_length as i64)),
+        ("pieces", bytes(piece_hashes(&pattern(total), piece_length))),
+    ]);
+    let torrent = dict(vec![
+        ("announce", bytes("http://127.0.0.1:1/announce")),
+        ("info", info),
+    ]);
+    Torrent::from_bytes(&bencode::encode(&torrent)).expect("test torrent must parse")
+}
+
+/// Plays a seeder of `data` on the far end of a duplex pipe: every request is answered with the
+/// bytes it asks for and everything else is ignored, until the pipe closes. Returns the ids of
+/// the messages it got, in order.
+pub(crate) async fn seed(mut remote: DuplexStream, data: Vec<u8>, piece_length: usize) -> Vec<u8> {
+    let mut received = Vec::new();
+    loop {
+        let Ok(length) = remote.read_u32().await else {
+            return received;
+        };
+        if length == 0 {
+            continue;
+        }
+        let mut message = vec![0; length as usize];
+        if remote.read_exact(&mut message).await.is_err() {
+            return received;
+        }
+        received.push(message[0]);
+        // request: index, begin, length
+        if message[0] == 6 {
+            let field = |at: usize| u32::from_be_bytes(message[at..at + 4].try_into().unwrap());
+            let (index, begin, length) = (field(1), field(5), field(9));
+            let start = index as usize * piece_length + begin as usize;
+            let block = &data[start..start + length as usize];
+            let mut reply = (9 + block.len() as u32).to_be_bytes().to_vec();
+            reply.push(7);
+            reply.extend(index.to_be_bytes());
+            reply.extend(begin.to_be_bytes());
+            reply.extend(block);
+            if remote.write_all(&reply).await.is_err() {
+                return received;
+            }
+        }
+    }
+}
+
+/// A seeder of `data` on an ephemeral local port, the tcp counterpart of `seed`: accepts the
+/// handshake for its torrent, sends a full bitfield, unchokes whoever is interested and answers
+/// every request.
+pub(crate) struct Seeder {
+    pub address: SocketAddrV4,
+    /// Blocks sent so far, over all connections
+    pub blocks: Arc<AtomicUsize>,
+}
+
+/// Connections accepted by one or more seeders.
+#[derive(Debug, Default)]
+pub(crate) struct Connections {
+    open: AtomicUsize,
+    /// Most connections open at the same time
+    pub peak: AtomicUsize,
+    /// Connections accepted in all
+    pub total: AtomicUsize,
+}
+
+impl Seeder {
+    pub async fn start(torrent: &Torrent, data: Vec<u8>) -> Self {
+        Self::spawn(torrent, data, Arc::default(), Duration::ZERO).await
+    }
+
+    /// Same as `start`, counting connections in `connections`, which may be shared with other
+    /// seeders to count across a swarm.
+    pub async fn start_counting(
+        torrent: &Torrent,
+        data: Vec<u8>,
+        connections: Arc<Connections>,
+    ) -> Self {
+        Self::spawn(torrent, data, connections, Duration::ZERO).await
+    }
+
+    /// Same as `start`, waiting `delay` before sending each block, to play a slow peer.
+    pub async fn start_slow(torrent: &Torrent, data: Vec<u8>, delay: Duration) -> Self {
+        Self::spawn(torrent, data, Arc::default(), delay).await
+    }
+
+    async fn spawn(
+        torrent: &Torrent,
+        data: Vec<u8>,
+        connections: Arc<Connections>,
+        delay: Duration,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = match listener.local_addr().unwrap() {
+            std::net::SocketAddr::V4(address) => address,
+            address => panic!("bound to {address}"),
+        };
+        let (torrent, data) = (torrent.clone(), Arc::new(data));
+        let blocks = Arc::new(AtomicUsize::new(0));
+        let sent = blocks.clone();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let open = connections.open.fetch_add(1, Ordering::SeqCst) + 1;
+                connections.peak.fetch_max(open, Ordering::SeqCst);
+                connections.total.fetch_add(1, Ordering::SeqCst);
+                let (torrent, data, counted, sent) = (
+                    torrent.clone(),
+                    data.clone(),
+                    connections.clone(),
+                    sent.clone(),
+                );
+                tokio::spawn(async move {
+                    let stream = Stream::new(socket);
+                    let _ = serve_blocks(stream, torrent, data, delay, &sent).await;
+                    counted.open.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        Self { address, blocks }
+    }
+}
+
+/// What a `Seeder` does on every connection, also usable on one end of a duplex pipe.
+pub(crate) async fn serve<T: AsyncRead + AsyncWrite + Unpin>(
+    stream: Stream<T>,
+    torrent: Torrent,
+    data: Arc<Vec<u8>>,
+) -> anyhow::Result<()> {
+    serve_blocks(stream, torrent, data, Duration::ZERO, &AtomicUsize::new(0)).await
+}
+
+async fn serve_blocks<T: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: Stream<T>,
+    torrent: Torrent,
+    data: Arc<Vec<u8>>,
+    delay: Duration,
+    sent: &AtomicUsize,
+) -> anyhow::Result<()> {
+    let handshake = Handshake::new(torrent.info.info_hash_bytes());
+    stream.accept_handshake(handshake).await?;
+    answer_requests(stream, torrent, data, delay, sent).await
+}
+
+/// The rest of `serve` once the handshake is done, for peers that have more to say first.
+pub(crate) async fn serve_handshaken<T: AsyncRead + AsyncWrite + Unpin>(
+    stream: Stream<T>,
+    torrent: Torrent,
+    data: Arc<Vec<u8>>,
+) -> anyhow::Result<()> {
+    answer_requests(stream, torrent, data, Duration::ZERO, &AtomicUsize::new(0)).await
+}
+
+async fn answer_requests<T: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: Stream<T>,
+    torrent: Torrent,
+    data: Arc<Vec<u8>>,
+    delay: Duration,
+    sent: &AtomicUsize,
+) -> anyhow::Result<()> {
+    stream
+        .send_bitfield(&BitField::full(torrent.num_pieces()))
+        .await?;
+    let piece_length = torrent.info.piece_length as usize;
+    loop {
+        let (id, payload) = stream.read_message().await?;
+        match MessageType::from_id(id) {
+            Some(MessageType::Interested) => stream.unchoke().await?,
+            Some(MessageType::Request) => {
+                let field = |at: usize| u32::from_be_bytes(payload[at..at + 4].try_into().unwrap());
+                let (index, begin, length) = (field(0), field(4), field(8));
+                let start = index as usize * piece_length + begin as usize;
+                let block = &data[start..start + length as usize];
+                tokio::time::sleep(delay).await;
+                stream.send_block(index, begin, block).await?;
+                sent.fetch_add(1, Ordering::SeqCst);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// What `MockTracker` answers a request with.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Reply {
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+    /// Waits this long before answering, to run into timeouts
+    pub delay: Duration,
+}
+
+impl From<Vec<u8>> for Reply {
+    fn from(body: Vec<u8>) -> Self {
+        Self {
+            body,
+            ..Default::default()
+        }
+    }
+}
+
+type Respond = dyn Fn(usize, &str) -> Reply + Send + Sync;
+
+/// A bare-bones http server on an ephemeral port. Every request is recorded and answered by the
+/// closure it was started with, which gets the number of earlier requests and the request target
+/// (path and query string).
+pub(crate) struct MockTracker {
+    pub address: SocketAddrV4,
+    heads: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockTracker {
+    pub async fn start(respond: impl Fn(usize, &str) -> Reply + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = match listener.local_addr().unwrap() {
+            std::net::SocketAddr::V4(address) => address,
+            address => panic!("bound to {address}"),
+        };
+        let heads = Arc::new(Mutex::new(Vec::new()));
+        let respond: Arc<Respond> = Arc::new(respond);
+        let recorded = heads.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let (respond, recorded) = (respond.clone(), recorded.clone());
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    while !head.ends_with(b"\r\n\r\n") {
+                        head.push(socket.read_u8().await?);
+                    }
+                    let head = String::from_utf8_lossy(&head).into_owned();
+                    let target = head.split(' ').nth(1).unwrap_or_default().to_string();
+                    let count = {
+                        let mut heads = recorded.lock().unwrap();
+                        heads.push(head);
+                        heads.len() - 1
+                    };
+                    let reply = respond(count, &target);
+                    tokio::time::sleep(reply.delay).await;
+                    let mut response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n",
+                        reply.body.len()
+                    );
+                    for (name, value) in &reply.headers {
+                        response.push_str(&format!("{name}: {value}\r\n"));
+                    }
+                    response.push_str("\r\n");
+                    socket.write_all(response.as_bytes()).await?;
+                    socket.write_all(&reply.body).await?;
+                    std::io::Result::Ok(())
+                });
+            }
+        });
+        Self { address, heads }
+    }
+
+    pub fn announce_url(&self) -> String {
+        format!("http://{}/announce", self.address)
+    }
+
+    /// Request targets (path and query string) in the order they came in.
+    pub fn requests(&self) -> Vec<String> {
+        self.heads()
+            .iter()
+            .map(|head| head.split(' ').nth(1).unwrap_or_default().to_string())
+            .collect()
+    }
+
+    /// Whole request heads, request line and headers.
+    pub fn heads(&self) -> Vec<String> {
+        self.heads.lock().unwrap().clone()
+    }
+}
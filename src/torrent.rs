@@ -1,32 +1,157 @@
 use self::hashes::Hashes;
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
+use base32::Alphabet;
 use hex::encode;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use serde_bencode::to_bytes;
-use sha1::{Digest, Sha1};
+use serde_bencode::{from_bytes, to_bytes};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashSet};
 use std::fmt::{Display, Error as FmtError, Formatter};
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use tracing::warn;
 
+use crate::bencode::{self, BencodeValue};
+use crate::hash::sha1;
+use crate::tracker::DEFAULT_USER_AGENT;
+
+pub const DEFAULT_PIECE_LENGTH: u64 = 256 * 1024;
+
+/// The info dict. A parsed one keeps the bytes it was read from, and `to_bytes`, `Torrent::to_bytes`
+/// and the info hash all come from those bytes, not from the fields. After changing a field call
+/// `forget_original`, or they keep describing the torrent as it was.
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Info {
-    pub length: usize,
+    /// File name for single-file torrents, directory name for multi-file ones.
     pub name: String,
+    /// UTF-8 version of `name` that some clients add when `name` is in another encoding.
+    #[serde(
+        rename = "name.utf-8",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub name_utf8: Option<String>,
     #[serde(rename = "piece length")]
-    pub piece_length: usize,
+    pub piece_length: u64,
     /// Each entry of `pieces` is the SHA1 hash of the piece at the corresponding index.
     pub pieces: Hashes, // they get deserialized using the HashesVisitor
+    #[serde(flatten)]
+    pub keys: Keys,
+    /// 2 for v2 and hybrid torrents (BEP 52), missing for plain v1 torrents.
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<i64>,
+    /// 1 for torrents of private trackers (BEP 27), which want peers to come from them only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<i64>,
+    /// Set by some private trackers so their copy of a torrent gets its own info hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Hex MD5 of the file for single-file torrents, some old torrents have it. Multi-file
+    /// torrents keep it in each `File`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5sum: Option<String>,
+    // the info dict as it appeared in the .torrent file. Other clients hash these exact bytes,
+    // and a file that isn't canonically encoded would hash differently once re-encoded
+    #[serde(skip)]
+    raw: Option<Vec<u8>>,
+    // filled in by the first info_hash_bytes call, cleared by forget_original
+    #[serde(skip)]
+    info_hash: OnceLock<[u8; 20]>,
+}
+
+/// A torrent has either a `length` key (single file) or a `files` key (multiple files), never both.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Keys {
+    SingleFile { length: u64 },
+    MultiFile { files: Vec<File> },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct File {
+    pub length: u64,
+    /// Subdirectory names followed by the file name, relative to the torrent's directory.
+    pub path: Vec<String>,
+    /// UTF-8 version of `path`, see `Info::name_utf8`.
+    #[serde(
+        rename = "path.utf-8",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub path_utf8: Option<Vec<String>>,
+    /// Hex MD5 of the file, optional and rarely there.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5sum: Option<String>,
+}
+
+impl File {
+    /// `path.utf-8` if the torrent has it, `path` otherwise. Use this for anything shown or written.
+    pub fn display_path(&self) -> &[String] {
+        self.path_utf8.as_deref().unwrap_or(&self.path)
+    }
 }
 
 impl Info {
-    #[allow(clippy::unnecessary_fallible_conversions)]
+    /// `name.utf-8` if the torrent has it, `name` otherwise. Use this for anything shown or written.
+    pub fn display_name(&self) -> &str {
+        self.name_utf8.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Drops the bytes from the .torrent file and the cached info hash, so that from now on both
+    /// come from the fields. Needed after changing any of them, see `Info`.
+    pub fn forget_original(&mut self) {
+        self.raw = None;
+        self.info_hash = OnceLock::new();
+    }
+
+    /// SHA1 of the bencoded info dict, computed on the first call and cached after that.
     pub fn info_hash_bytes(&self) -> [u8; 20] {
-        let info_encoded = to_bytes(&self).expect("Re-encoding info back to bytes");
-        let mut hasher = <Sha1 as Digest>::new();
-        hasher.update(&info_encoded);
-        // encode(hasher.finalize()) -- this was used when the output of this fn was a String
-        hasher
-            .finalize()
-            .try_into()
-            .expect("Hasher finalize failed")
+        *self.info_hash.get_or_init(|| self.compute_info_hash())
+    }
+
+    /// The bencoded info dict exactly as it gets hashed for the info hash: the original bytes
+    /// when it was parsed from a .torrent file, a fresh encoding otherwise.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        match &self.raw {
+            Some(raw) => Ok(raw.clone()),
+            None => to_bytes(self).context("CTX: encode info dict"),
+        }
+    }
+
+    /// The info dict re-encoded the way BEP 3 requires: dict keys sorted by their raw bytes,
+    /// integers and lengths without leading zeros. Equal to `to_bytes` unless the .torrent file
+    /// was written by a client that got the encoding wrong.
+    pub fn canonical_bytes(&self) -> Result<Vec<u8>> {
+        let (value, _) = bencode::decode(&self.to_bytes()?).context("CTX: decode info dict")?;
+        Ok(bencode::encode(&value))
+    }
+
+    /// SHA1 of `canonical_bytes`, for comparing against clients that hash a re-encoded info dict
+    /// instead of the bytes from the file. Peers and trackers know the torrent by `info_hash_bytes`.
+    pub fn canonical_info_hash_bytes(&self) -> Result<[u8; 20]> {
+        Ok(sha1(&self.canonical_bytes()?))
+    }
+
+    fn compute_info_hash(&self) -> [u8; 20] {
+        let info_encoded = self.to_bytes().expect("Encoding info dict");
+        sha1(&info_encoded)
+    }
+
+    /// SHA256 of the bencoded info dict, which v2 peers use in place of the SHA1 hash.
+    /// None unless the torrent is v2 or hybrid, then it is in both swarms.
+    pub fn info_hash_v2_bytes(&self) -> Option<[u8; 32]> {
+        if self.meta_version != Some(2) {
+            return None;
+        }
+        let info_encoded = self.to_bytes().expect("Encoding info dict");
+        Some(Sha256::digest(info_encoded).into())
     }
 
     pub fn info_hash_str(&self) -> String {
@@ -43,23 +168,428 @@ impl Info {
         }
         encoded
     }
+
+    /// Uppercase RFC 4648 base32 without padding, the other form magnet links use besides hex.
+    pub fn info_hash_base32(&self) -> String {
+        base32::encode(
+            Alphabet::Rfc4648 { padding: false },
+            &self.info_hash_bytes(),
+        )
+    }
+
+    /// Checks a hex or base32 info hash against this torrent's.
+    pub fn matches_info_hash(&self, hash: &str) -> Result<bool> {
+        Ok(parse_info_hash(hash)? == self.info_hash_bytes())
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Parses an info hash given either as 40 hex characters or 32 base32 characters.
+pub fn parse_info_hash(hash: &str) -> Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).context("CTX: decoding hex info hash")?,
+        32 => base32::decode(
+            Alphabet::Rfc4648 { padding: false },
+            &hash.to_ascii_uppercase(),
+        )
+        .ok_or_else(|| anyhow!("Invalid base32 info hash {hash}"))?,
+        n => {
+            return Err(anyhow!(
+                "Info hash must be 40 hex or 32 base32 characters, got {n}"
+            ))
+        }
+    };
+    bytes
+        .try_into()
+        .map_err(|_| anyhow!("Info hash {hash} does not decode to 20 bytes"))
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Torrent {
+    /// Empty for trackerless torrents, which have `nodes` instead.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub announce: String,
+    /// Tiers of backup trackers (BEP 12), when present it takes precedence over `announce`.
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    /// DHT nodes to bootstrap from (BEP 5) as host and port, put there for trackerless torrents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<Vec<(String, u16)>>,
+    // informational keys, kept so writing a torrent back out doesn't drop them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+    /// Unix timestamp
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
     pub info: Info,
 }
 
+impl Torrent {
+    /// Deserializes a torrent and checks that its fields are consistent with each other,
+    /// so a corrupt file fails here instead of after a round-trip to the tracker.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Torrent> {
+        Self::parse(bytes, false)
+    }
+
+    /// Like `from_bytes` but refuses torrents that only go against BEP 3's recommendations,
+    /// which `from_bytes` just warns about, e.g. a piece length that isn't a power of two.
+    pub fn from_bytes_strict(bytes: &[u8]) -> Result<Torrent> {
+        Self::parse(bytes, true)
+    }
+
+    fn parse(bytes: &[u8], strict: bool) -> Result<Torrent> {
+        bencode::check_depth(bytes)?;
+        let mut torrent: Torrent = from_bytes(bytes).context("CTX: torrent file to bytes")?;
+        torrent.info.raw = bencode::dict_value_bytes(bytes, b"info")?.map(<[u8]>::to_vec);
+        torrent.validate(strict)?;
+        Ok(torrent)
+    }
+
+    /// Builds a torrent for a single file or a whole directory, hashing its contents piece by piece.
+    pub fn create(path: &Path, announce: String, piece_length: u64) -> Result<Torrent> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("Cannot name a torrent after {}", path.display()))?
+            .to_string();
+        let mut data = Vec::new();
+        let keys = if path.is_dir() {
+            let mut paths = Vec::new();
+            collect_files(path, &mut paths)?;
+            paths.sort();
+            let mut files = Vec::with_capacity(paths.len());
+            for file_path in paths {
+                let contents = fs::read(&file_path)
+                    .context(format!("CTX: reading {}", file_path.display()))?;
+                // the path inside the torrent is relative to the directory we were given
+                let segments = file_path
+                    .strip_prefix(path)?
+                    .iter()
+                    .map(|segment| {
+                        segment
+                            .to_str()
+                            .map(str::to_string)
+                            .ok_or_else(|| anyhow!("Non UTF-8 path {}", file_path.display()))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                files.push(File {
+                    length: contents.len() as u64,
+                    path: segments,
+                    path_utf8: None,
+                    md5sum: None,
+                });
+                data.extend_from_slice(&contents);
+            }
+            if files.is_empty() {
+                return Err(anyhow!("Directory {} has no files", path.display()));
+            }
+            Keys::MultiFile { files }
+        } else {
+            data = fs::read(path).context(format!("CTX: reading {}", path.display()))?;
+            Keys::SingleFile {
+                length: data.len() as u64,
+            }
+        };
+        if piece_length == 0 {
+            return Err(anyhow!("Piece length must be greater than 0"));
+        }
+        let pieces = data.chunks(piece_length as usize).map(sha1).collect();
+
+        let torrent = Torrent {
+            announce,
+            announce_list: None,
+            nodes: None,
+            comment: None,
+            // no creation date so creating the same torrent twice gives the same file
+            created_by: Some(String::from(DEFAULT_USER_AGENT)),
+            creation_date: None,
+            info: Info {
+                name,
+                name_utf8: None,
+                raw: None,
+                piece_length,
+                pieces: Hashes(pieces),
+                keys,
+                meta_version: None,
+                private: None,
+                source: None,
+                md5sum: None,
+                info_hash: OnceLock::new(),
+            },
+        };
+        torrent.validate(false)?;
+        Ok(torrent)
+    }
+
+    /// Bencodes the torrent. A parsed torrent's info dict is written back exactly as it was read,
+    /// so keys we don't have a field for (e.g. `file tree`) and the info hash survive.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut encoded = to_bytes(self).context("CTX: encoding torrent")?;
+        if let Some(raw) = &self.info.raw {
+            let info = bencode::dict_value_bytes(&encoded, b"info")?
+                .ok_or_else(|| anyhow!("Encoded torrent has no info dict"))?;
+            let start = info.as_ptr() as usize - encoded.as_ptr() as usize;
+            let end = start + info.len();
+            encoded.splice(start..end, raw.iter().copied());
+        }
+        Ok(encoded)
+    }
+
+    /// The v1 (SHA1) info hash and, for hybrid torrents, the v2 (SHA256) one. A hybrid torrent
+    /// announces both so it can talk to peers that only know one of them.
+    pub fn info_hash_v1_and_v2(&self) -> ([u8; 20], Option<[u8; 32]>) {
+        (self.info.info_hash_bytes(), self.info.info_hash_v2_bytes())
+    }
+
+    /// Every tracker to try in order, duplicates removed.
+    pub fn trackers(&self) -> Vec<&str> {
+        self.tiers().into_iter().flatten().collect()
+    }
+
+    /// The `announce-list` tiers (BEP 12), a tracker listed twice only stays in its first tier and
+    /// emptied tiers are dropped. Just `announce` when there is no `announce-list`, nothing for
+    /// trackerless torrents.
+    pub fn tiers(&self) -> Vec<Vec<&str>> {
+        let mut seen = HashSet::new();
+        let tiers: Vec<Vec<&str>> = self
+            .announce_list
+            .iter()
+            .flatten()
+            .map(|tier| {
+                tier.iter()
+                    .map(String::as_str)
+                    .filter(|tracker| seen.insert(*tracker))
+                    .collect::<Vec<_>>()
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect();
+        if tiers.is_empty() && !self.announce.is_empty() {
+            return vec![vec![self.announce.as_str()]];
+        }
+        tiers
+    }
+
+    /// Total number of bytes in the torrent, summed over all files.
+    pub fn total_length(&self) -> u64 {
+        match &self.info.keys {
+            Keys::SingleFile { length } => *length,
+            Keys::MultiFile { files } => files.iter().map(|file| file.length).sum(),
+        }
+    }
+
+    /// Private torrents must not find peers any other way than through their trackers, so no
+    /// peer exchange and no DHT.
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.info.pieces.0.len()
+    }
+
+    /// The SHA1 hash piece `index` must have. Panics if `index` is out of range.
+    pub fn piece_hash(&self, index: usize) -> &[u8; 20] {
+        &self.info.pieces.0[index]
+    }
+
+    /// Whether `data` hashes to piece `index`'s hash, false for pieces out of range.
+    pub fn verify_piece(&self, index: usize, data: &[u8]) -> bool {
+        index < self.num_pieces() && sha1(data) == *self.piece_hash(index)
+    }
+
+    /// Number of bytes in piece `index`, only the last piece can be shorter than `piece_length`.
+    pub fn piece_size(&self, index: u32) -> u64 {
+        let start = index as u64 * self.info.piece_length;
+        self.info
+            .piece_length
+            .min(self.total_length().saturating_sub(start))
+    }
+
+    /// The block requests that fetch piece `index` in order, as (offset, length). Every block is
+    /// `block_size` bytes except the last, which gets whatever is left of the piece. Offsets of
+    /// pieces over 4 GiB don't fit the 32-bit `begin` of a request message, the caller has to
+    /// check before sending them. Panics if `block_size` is 0.
+    pub fn blocks_for_piece(&self, index: u32, block_size: u32) -> Vec<(u64, u32)> {
+        assert!(block_size > 0, "block size must be greater than 0");
+        let piece_size = self.piece_size(index);
+        (0..piece_size)
+            .step_by(block_size as usize)
+            .map(|offset| {
+                let length = (piece_size - offset).min(block_size as u64);
+                (offset, length as u32)
+            })
+            .collect()
+    }
+
+    /// Every file's path relative to the download (just the name for single-file torrents) and
+    /// length, in the order their bytes appear in the torrent.
+    pub fn files(&self) -> Vec<(PathBuf, u64)> {
+        match &self.info.keys {
+            Keys::SingleFile { length } => vec![(PathBuf::from(self.info.display_name()), *length)],
+            Keys::MultiFile { files } => files
+                .iter()
+                .map(|file| (file.display_path().iter().collect(), file.length))
+                .collect(),
+        }
+    }
+
+    /// Maps `len` bytes starting at `global_offset` of the concatenated torrent data onto the
+    /// files they belong to. Each entry is the file's path as in `files`, the offset within that
+    /// file and the number of bytes. Bytes past the end of the torrent are dropped.
+    pub fn locate(&self, global_offset: u64, len: u64) -> Vec<(PathBuf, u64, u64)> {
+        let end = global_offset + len;
+        let mut spans = Vec::new();
+        let mut file_start = 0;
+        for (path, length) in self.files() {
+            let file_end = file_start + length;
+            // empty files never overlap anything, they have no bytes to write
+            let start = global_offset.max(file_start);
+            let stop = end.min(file_end);
+            if start < stop {
+                spans.push((path, start - file_start, stop - start));
+            }
+            if file_end >= end {
+                break;
+            }
+            file_start = file_end;
+        }
+        spans
+    }
+
+    /// The pieces holding bytes of file `index`, the first and last one may be shared with the
+    /// neighbouring files. Empty for empty files.
+    pub fn file_pieces(&self, index: usize) -> Range<u32> {
+        let files = self.files();
+        let start: u64 = files[..index].iter().map(|(_, length)| length).sum();
+        let length = files[index].1;
+        if length == 0 {
+            return 0..0;
+        }
+        let piece_length = self.info.piece_length;
+        (start / piece_length) as u32..(start + length).div_ceil(piece_length) as u32
+    }
+
+    /// Picks files by their index in `files` or by a glob (`*` and `?`) matched against their
+    /// `/` separated path. Every pattern has to match at least one file.
+    pub fn select_files(&self, patterns: &[String]) -> Result<BTreeSet<usize>> {
+        let paths: Vec<String> = self
+            .files()
+            .iter()
+            .map(|(path, _)| {
+                path.iter()
+                    .map(|segment| segment.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/")
+            })
+            .collect();
+        let mut selected = BTreeSet::new();
+        for pattern in patterns {
+            if let Ok(index) = pattern.parse::<usize>() {
+                if index >= paths.len() {
+                    return Err(anyhow!(
+                        "File index {index} is out of range, the torrent has {} files",
+                        paths.len()
+                    ));
+                }
+                selected.insert(index);
+                continue;
+            }
+            let glob = Regex::new(&format!(
+                "^{}$",
+                regex::escape(pattern)
+                    .replace(r"\*", ".*")
+                    .replace(r"\?", ".")
+            ))
+            .context(format!("CTX: invalid file pattern {pattern}"))?;
+            let matches: Vec<usize> = (0..paths.len())
+                .filter(|&index| glob.is_match(&paths[index]))
+                .collect();
+            if matches.is_empty() {
+                return Err(anyhow!("No file matches {pattern}"));
+            }
+            selected.extend(matches);
+        }
+        Ok(selected)
+    }
+
+    fn validate(&self, strict: bool) -> Result<()> {
+        if self.info.piece_length == 0 {
+            return Err(anyhow!("Piece length must be greater than 0"));
+        }
+        // downloads cope with any piece length, the last block of a piece is just shorter
+        if !self.info.piece_length.is_power_of_two() {
+            if strict {
+                return Err(anyhow!(
+                    "Piece length {} is not a power of two",
+                    self.info.piece_length
+                ));
+            }
+            warn!(
+                piece_length = self.info.piece_length,
+                "piece length is not a power of two"
+            );
+        }
+        let length = self.total_length();
+        let expected_pieces = length.div_ceil(self.info.piece_length);
+        if self.num_pieces() as u64 != expected_pieces {
+            return Err(anyhow!(
+                "Torrent has {} piece hashes but a length of {} with piece length {} requires {}",
+                self.num_pieces(),
+                length,
+                self.info.piece_length,
+                expected_pieces
+            ));
+        }
+        // trackerless torrents find peers through the DHT nodes they list instead
+        if self.announce.is_empty() && self.nodes.as_ref().is_some_and(|nodes| !nodes.is_empty()) {
+            return Ok(());
+        }
+        reqwest::Url::parse(&self.announce)
+            .context(format!("CTX: invalid announce URL: {}", self.announce))?;
+        Ok(())
+    }
+}
+
 impl Display for Torrent {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
-        writeln!(f, "Tracker URL: {}", self.announce)?;
-        writeln!(f, "Length: {}", self.info.length)?;
+        match self.announce.as_str() {
+            "" => writeln!(f, "Tracker URL: none")?,
+            announce => writeln!(f, "Tracker URL: {announce}")?,
+        }
+        writeln!(f, "Length: {}", self.total_length())?;
+        match &self.info.keys {
+            Keys::SingleFile { .. } => {}
+            Keys::MultiFile { files } => {
+                writeln!(f, "Files:")?;
+                for file in files {
+                    writeln!(
+                        f,
+                        "{} ({} bytes)",
+                        file.display_path().join("/"),
+                        file.length
+                    )?;
+                }
+            }
+        }
         writeln!(f, "Info Hash: {}", self.info.info_hash_str())?;
         writeln!(f, "Piece Length: {}", self.info.piece_length)?;
         writeln!(f, "Piece Hashes:")?;
         for (index, hash) in self.info.pieces.0.iter().enumerate() {
-            if index < self.info.pieces.0.len() - 1 {
+            // a zero-length torrent has no pieces at all
+            if index + 1 < self.num_pieces() {
                 writeln!(f, "{}", encode(hash))?;
             } else {
                 write!(f, "{}", encode(hash))?;
@@ -69,6 +599,64 @@ impl Display for Torrent {
     }
 }
 
+/// Replaces the trackers of the torrent in `bytes`. Works on the raw bencode instead of `Torrent`
+/// so keys we don't model survive and the info dict, and with it the info hash, stays untouched.
+/// Without `announce_list` an existing one is dropped, clients would keep using it over `announce`.
+pub fn edit_trackers(
+    bytes: &[u8],
+    announce: &str,
+    announce_list: Option<Vec<Vec<String>>>,
+) -> Result<Vec<u8>> {
+    let original = Torrent::from_bytes(bytes)?;
+    let (BencodeValue::Dict(mut dict), _) = bencode::decode(bytes)? else {
+        return Err(anyhow!("Torrent is not a dictionary"));
+    };
+    let urls = std::iter::once(announce)
+        .chain(announce_list.iter().flatten().flatten().map(String::as_str));
+    for url in urls {
+        reqwest::Url::parse(url).context(format!("CTX: invalid announce URL: {url}"))?;
+    }
+    dict.insert(
+        b"announce".to_vec(),
+        BencodeValue::Bytes(announce.as_bytes().to_vec()),
+    );
+    if let Some(tiers) = announce_list {
+        let tiers = tiers
+            .into_iter()
+            .map(|tier| {
+                BencodeValue::List(
+                    tier.into_iter()
+                        .map(|url| BencodeValue::Bytes(url.into_bytes()))
+                        .collect(),
+                )
+            })
+            .collect();
+        dict.insert(b"announce-list".to_vec(), BencodeValue::List(tiers));
+    } else {
+        dict.remove(b"announce-list".as_slice());
+    }
+    let edited = bencode::encode(&BencodeValue::Dict(dict));
+
+    // a torrent that wasn't canonically encoded would re-encode differently
+    if Torrent::from_bytes(&edited)?.info.info_hash_bytes() != original.info.info_hash_bytes() {
+        return Err(anyhow!("Editing would change the info hash"));
+    }
+    Ok(edited)
+}
+
+// walks a directory recursively, we sort afterwards so the piece layout doesn't depend on the fs
+fn collect_files(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir).context(format!("CTX: reading dir {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, paths)?;
+        } else {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
 mod hashes {
     use serde::de::{self, Deserialize, Deserializer, Visitor};
     use serde::ser::{Serialize, Serializer};
@@ -89,7 +677,7 @@ mod hashes {
         where
             E: de::Error,
         {
-            if v.len() % 20 != 0 {
+            if !v.len().is_multiple_of(20) {
                 return Err(E::custom(format!(
                     "Invalid length of the array being deserialized: {}",
                     v.len()
@@ -122,3 +710,642 @@ mod hashes {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, bytes, dict};
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    // piece hashes don't matter for the layout math, so these are all zeros
+    fn layout(files: &[(&str, u64)], piece_length: u64) -> Torrent {
+        let length: u64 = files.iter().map(|(_, length)| length).sum();
+        let pieces = vec![0; length.div_ceil(piece_length) as usize * 20];
+        let files = files
+            .iter()
+            .map(|(name, length)| {
+                dict(vec![
+                    ("length", BencodeValue::Int(*length as i64)),
+                    ("path", BencodeValue::List(vec![bytes(name)])),
+                ])
+            })
+            .collect();
+        let info = dict(vec![
+            ("files", BencodeValue::List(files)),
+            ("name", bytes("big")),
+            ("piece length", BencodeValue::Int(piece_length as i64)),
+            ("pieces", bytes(pieces)),
+        ]);
+        let torrent = dict(vec![
+            ("announce", bytes("http://tracker/announce")),
+            ("info", info),
+        ]);
+        Torrent::from_bytes(&bencode::encode(&torrent)).unwrap()
+    }
+
+    fn with_info(info: BencodeValue) -> Vec<u8> {
+        bencode::encode(&dict(vec![
+            ("announce", bytes("http://tracker/announce")),
+            ("info", info),
+        ]))
+    }
+
+    #[test]
+    fn piece_count_must_match_the_length() {
+        // 40000 bytes in 16 KiB pieces are 3 pieces, not 2
+        let info = dict(vec![
+            ("length", BencodeValue::Int(40000)),
+            ("name", bytes("short.bin")),
+            ("piece length", BencodeValue::Int(1 << 14)),
+            ("pieces", bytes([0; 40])),
+        ]);
+        let e = Torrent::from_bytes(&with_info(info)).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Torrent has 2 piece hashes but a length of 40000 with piece length 16384 requires 3"
+        );
+    }
+
+    #[test]
+    fn zero_piece_length_and_bad_announce_are_rejected() {
+        let info = |piece_length| {
+            dict(vec![
+                ("length", BencodeValue::Int(10)),
+                ("name", bytes("a")),
+                ("piece length", BencodeValue::Int(piece_length)),
+                ("pieces", bytes([0; 20])),
+            ])
+        };
+        assert!(Torrent::from_bytes(&with_info(info(0))).is_err());
+        assert!(Torrent::from_bytes(&with_info(info(16))).is_ok());
+        let torrent = dict(vec![("announce", bytes("not a url")), ("info", info(16))]);
+        assert!(Torrent::from_bytes(&bencode::encode(&torrent)).is_err());
+    }
+
+    #[test]
+    fn lengths_and_piece_counts_of_both_layouts() {
+        let data = testing::pattern(40000);
+        let single = testing::torrent("http://tracker/announce", &data, 1 << 14);
+        assert_eq!(single.total_length(), 40000);
+        assert_eq!(single.num_pieces(), 3);
+
+        let multi = layout(&[("a", 10000), ("b", 22768), ("c", 1)], 1 << 14);
+        assert_eq!(multi.total_length(), 32769);
+        assert_eq!(multi.num_pieces(), 3);
+        let exact = layout(&[("a", 1 << 14), ("b", 1 << 14)], 1 << 14);
+        assert_eq!(exact.num_pieces(), 2);
+    }
+
+    #[test]
+    fn base32_and_hex_name_the_same_info_hash() {
+        let torrent = Torrent::from_bytes(include_bytes!("../sample.torrent")).unwrap();
+        let hex = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+        let base32 = "22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7";
+        assert_eq!(torrent.info.info_hash_str(), hex);
+        assert_eq!(torrent.info.info_hash_base32(), base32);
+
+        assert_eq!(
+            parse_info_hash(hex).unwrap(),
+            parse_info_hash(base32).unwrap()
+        );
+        assert!(torrent
+            .info
+            .matches_info_hash(&base32.to_lowercase())
+            .unwrap());
+        assert!(!torrent.info.matches_info_hash(&"0".repeat(40)).unwrap());
+        assert!(parse_info_hash("d69f91e6").is_err());
+        assert!(parse_info_hash(&"1".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn to_bytes_round_trips_with_the_same_info_hash() {
+        let parsed = Torrent::from_bytes(include_bytes!("../sample.torrent")).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        std::fs::write(&path, testing::pattern(40000)).unwrap();
+        let created =
+            Torrent::create(&path, String::from("http://tracker/announce"), 1 << 14).unwrap();
+        for torrent in [parsed, created] {
+            let again = Torrent::from_bytes(&torrent.to_bytes().unwrap()).unwrap();
+            assert_eq!(again.info.info_hash_bytes(), torrent.info.info_hash_bytes());
+            assert_eq!(again.announce, torrent.announce);
+        }
+    }
+
+    #[test]
+    fn nodes_are_parsed_and_written_back() {
+        let node =
+            |host: &str, port| BencodeValue::List(vec![bytes(host), BencodeValue::Int(port)]);
+        let info = dict(vec![
+            ("length", BencodeValue::Int(100)),
+            ("name", bytes("a")),
+            ("piece length", BencodeValue::Int(64)),
+            ("pieces", bytes([0; 40])),
+        ]);
+        let file = bencode::encode(&dict(vec![
+            ("announce", bytes("http://tracker/announce")),
+            ("info", info),
+            (
+                "nodes",
+                BencodeValue::List(vec![node("router.example", 6881), node("10.0.0.1", 51413)]),
+            ),
+        ]));
+        let torrent = Torrent::from_bytes(&file).unwrap();
+        let nodes = vec![
+            (String::from("router.example"), 6881),
+            (String::from("10.0.0.1"), 51413),
+        ];
+        assert_eq!(torrent.nodes, Some(nodes.clone()));
+        let again = Torrent::from_bytes(&torrent.to_bytes().unwrap()).unwrap();
+        assert_eq!(again.nodes, Some(nodes));
+
+        let without = Torrent::from_bytes(include_bytes!("../sample.torrent")).unwrap();
+        assert_eq!(without.nodes, None);
+    }
+
+    #[test]
+    fn trackerless_torrents_need_nodes() {
+        let info = dict(vec![
+            ("length", BencodeValue::Int(100)),
+            ("name", bytes("a")),
+            ("piece length", BencodeValue::Int(64)),
+            ("pieces", bytes([0; 40])),
+        ]);
+        let node = BencodeValue::List(vec![bytes("router.example"), BencodeValue::Int(6881)]);
+        let file = bencode::encode(&dict(vec![
+            ("info", info.clone()),
+            ("nodes", BencodeValue::List(vec![node])),
+        ]));
+        let torrent = Torrent::from_bytes(&file).unwrap();
+        assert_eq!(torrent.announce, "");
+        assert!(torrent.tiers().is_empty() && torrent.trackers().is_empty());
+        assert!(torrent.to_string().starts_with("Tracker URL: none\n"));
+        // written back without an empty announce
+        assert_eq!(torrent.to_bytes().unwrap(), file);
+
+        // without nodes there is no way to find peers
+        for nodes in [None, Some(BencodeValue::List(Vec::new()))] {
+            let mut keys = vec![("info", info.clone())];
+            keys.extend(nodes.map(|nodes| ("nodes", nodes)));
+            assert!(Torrent::from_bytes(&bencode::encode(&dict(keys))).is_err());
+        }
+    }
+
+    #[test]
+    fn create_from_a_directory_lists_its_files_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("album");
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        // written out of order, the torrent lists them sorted by path
+        let files = [
+            ("d.bin", 300),
+            ("a/z.bin", 5000),
+            ("b.bin", 0),
+            ("a/c.bin", 40000),
+        ];
+        for (name, length) in files {
+            std::fs::write(root.join(name), testing::pattern(length)).unwrap();
+        }
+
+        let torrent =
+            Torrent::create(&root, String::from("http://tracker/announce"), 1 << 14).unwrap();
+        assert_eq!(torrent.info.name, "album");
+        let Keys::MultiFile { files } = &torrent.info.keys else {
+            panic!("expected a multi-file torrent");
+        };
+        let listed: Vec<(Vec<&str>, u64)> = files
+            .iter()
+            .map(|file| (file.path.iter().map(String::as_str).collect(), file.length))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                (vec!["a", "c.bin"], 40000),
+                (vec!["a", "z.bin"], 5000),
+                (vec!["b.bin"], 0),
+                (vec!["d.bin"], 300),
+            ]
+        );
+        let data = [
+            testing::pattern(40000),
+            testing::pattern(5000),
+            testing::pattern(300),
+        ]
+        .concat();
+        assert_eq!(torrent.total_length(), data.len() as u64);
+        let expected: Vec<[u8; 20]> = data.chunks(1 << 14).map(sha1).collect();
+        assert_eq!(torrent.info.pieces.0, expected);
+    }
+
+    #[test]
+    fn creating_twice_gives_the_same_torrent() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("files");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("one"), testing::pattern(20000)).unwrap();
+        std::fs::write(root.join("sub/two"), testing::pattern(7000)).unwrap();
+        let single = dir.path().join("single.bin");
+        std::fs::write(&single, testing::pattern(50000)).unwrap();
+
+        for path in [root, single] {
+            let create =
+                || Torrent::create(&path, String::from("http://tracker/announce"), 1 << 14);
+            let (first, second) = (create().unwrap(), create().unwrap());
+            assert_eq!(first.info.info_hash_bytes(), second.info.info_hash_bytes());
+            assert_eq!(first.to_bytes().unwrap(), second.to_bytes().unwrap());
+        }
+    }
+
+    #[test]
+    fn locate_splits_at_file_boundaries() {
+        let torrent = layout(
+            &[("a", 10), ("b", 5), ("empty", 0), ("c", 7), ("d", 100)],
+            32,
+        );
+        let span = |name: &str, offset, len| (PathBuf::from(name), offset, len);
+        // the first piece covers a, b and c completely and the start of d
+        assert_eq!(
+            torrent.locate(0, 32),
+            vec![
+                span("a", 0, 10),
+                span("b", 0, 5),
+                span("c", 0, 7),
+                span("d", 0, 10)
+            ]
+        );
+        // right at a boundary, nothing of the file before it
+        assert_eq!(torrent.locate(10, 5), vec![span("b", 0, 5)]);
+        assert_eq!(torrent.locate(15, 1), vec![span("c", 0, 1)]);
+        // one byte on each side of a boundary
+        assert_eq!(torrent.locate(9, 2), vec![span("a", 9, 1), span("b", 0, 1)]);
+        assert_eq!(torrent.locate(21, 1), vec![span("c", 6, 1)]);
+        assert_eq!(torrent.locate(22, 1), vec![span("d", 0, 1)]);
+        assert_eq!(torrent.locate(0, 0), vec![]);
+        assert_eq!(torrent.locate(500, 10), vec![]);
+    }
+
+    #[test]
+    fn cached_info_hash_matches_a_fresh_one() {
+        let torrent = Torrent::from_bytes(include_bytes!("../sample.torrent")).unwrap();
+        assert!(torrent.info.info_hash.get().is_none());
+        let first = torrent.info.info_hash_bytes();
+        assert_eq!(torrent.info.info_hash.get(), Some(&first));
+        assert_eq!(torrent.info.info_hash_bytes(), first);
+        assert_eq!(first, torrent.info.compute_info_hash());
+        assert_eq!(first, sha1(&torrent.info.to_bytes().unwrap()));
+    }
+
+    #[test]
+    fn utf8_names_and_paths_are_preferred() {
+        let file = |path: &str, utf8: Option<&str>| {
+            let list = |path: &str| BencodeValue::List(path.split('/').map(bytes).collect());
+            let mut entries = vec![("length", BencodeValue::Int(10)), ("path", list(path))];
+            if let Some(utf8) = utf8 {
+                entries.push(("path.utf-8", list(utf8)));
+            }
+            dict(entries)
+        };
+        let info = dict(vec![
+            (
+                "files",
+                BencodeValue::List(vec![
+                    file("d/f?.txt", Some("d/f\u{e9}.txt")),
+                    file("plain.txt", None),
+                ]),
+            ),
+            ("name", bytes("caf?")),
+            ("name.utf-8", bytes("caf\u{e9}")),
+            ("piece length", BencodeValue::Int(32)),
+            ("pieces", bytes([0; 20])),
+        ]);
+        let torrent = Torrent::from_bytes(&with_info(info)).unwrap();
+        assert_eq!(torrent.info.name, "caf?");
+        assert_eq!(torrent.info.display_name(), "caf\u{e9}");
+        assert_eq!(
+            torrent.files(),
+            vec![
+                (PathBuf::from("d/f\u{e9}.txt"), 10),
+                (PathBuf::from("plain.txt"), 10)
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_are_encoded_in_raw_byte_order() {
+        // uppercase sorts before lowercase, a space before letters and utf8 after ascii
+        let value = dict(vec![
+            ("b", bytes("")),
+            ("\u{e9}", bytes("")),
+            ("B", bytes("")),
+            ("a b", bytes("")),
+            ("ab", bytes("")),
+        ]);
+        assert_eq!(
+            bencode::encode(&value),
+            "d1:B0:3:a b0:2:ab0:1:b0:2:\u{e9}0:e".as_bytes()
+        );
+
+        // every optional key of a multi-file info dict, re-encoded without the original bytes
+        let file = dict(vec![
+            ("length", BencodeValue::Int(10)),
+            ("md5sum", bytes("0123456789abcdef0123456789abcdef")),
+            ("path", BencodeValue::List(vec![bytes("a")])),
+        ]);
+        let info = dict(vec![
+            ("files", BencodeValue::List(vec![file])),
+            ("meta version", BencodeValue::Int(2)),
+            ("name", bytes("d")),
+            ("name.utf-8", bytes("d")),
+            ("piece length", BencodeValue::Int(16)),
+            ("pieces", bytes([0; 20])),
+            ("private", BencodeValue::Int(1)),
+            ("source", bytes("tracker")),
+        ]);
+        let mut torrent = Torrent::from_bytes(&with_info(info.clone())).unwrap();
+        torrent.info.forget_original();
+        assert_eq!(torrent.info.to_bytes().unwrap(), bencode::encode(&info));
+
+        // and a torrent made by a reference client hashes the same whichever bytes we use
+        let sample = Torrent::from_bytes(include_bytes!("../sample.torrent")).unwrap();
+        let expected = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+        assert_eq!(encode(sample.info.info_hash_bytes()), expected);
+        assert_eq!(
+            encode(sample.info.canonical_info_hash_bytes().unwrap()),
+            expected
+        );
+    }
+
+    #[test]
+    fn source_keeps_the_info_hash_stable() {
+        let info = |source: &str| {
+            dict(vec![
+                ("length", BencodeValue::Int(10)),
+                ("name", bytes("a")),
+                ("piece length", BencodeValue::Int(16)),
+                ("pieces", bytes([0; 20])),
+                ("source", bytes(source)),
+                ("x-unknown", bytes("kept")),
+            ])
+        };
+        let mut torrent = Torrent::from_bytes(&with_info(info("tracker"))).unwrap();
+        assert_eq!(torrent.info.source.as_deref(), Some("tracker"));
+        let hash = torrent.info.info_hash_bytes();
+        assert_eq!(hash, sha1(&bencode::encode(&info("tracker"))));
+
+        torrent.comment = Some(String::from("edited"));
+        let again = Torrent::from_bytes(&torrent.to_bytes().unwrap()).unwrap();
+        assert_eq!(again.info.info_hash_bytes(), hash);
+        assert_eq!(again.info.source.as_deref(), Some("tracker"));
+        assert_eq!(again.comment.as_deref(), Some("edited"));
+
+        // the same files under another source are another torrent
+        let other = Torrent::from_bytes(&with_info(info("other"))).unwrap();
+        assert_ne!(other.info.info_hash_bytes(), hash);
+    }
+
+    #[test]
+    fn edited_fields_show_once_the_original_is_forgotten() {
+        let mut torrent = Torrent::from_bytes(include_bytes!("../sample.torrent")).unwrap();
+        let (original, hash) = (torrent.to_bytes().unwrap(), torrent.info.info_hash_bytes());
+        torrent.info.private = Some(1);
+        // still the file's bytes, as documented
+        assert_eq!(torrent.info.info_hash_bytes(), hash);
+
+        torrent.info.forget_original();
+        assert_ne!(torrent.info.info_hash_bytes(), hash);
+        assert_ne!(torrent.to_bytes().unwrap(), original);
+        let again = Torrent::from_bytes(&torrent.to_bytes().unwrap()).unwrap();
+        assert!(again.is_private());
+        assert_eq!(again.info.info_hash_bytes(), torrent.info.info_hash_bytes());
+    }
+
+    // torrents from tests/fixtures and their info hashes, hashed by make_fixtures.py there
+    const FIXTURES: [(&[u8], &str); 4] = [
+        (
+            include_bytes!("../tests/fixtures/multi_file.torrent"),
+            "72a318c1916a05d1c71926172aa1903820cd3825",
+        ),
+        (
+            include_bytes!("../tests/fixtures/announce_list.torrent"),
+            "e2207af5cf636e985d8085ccba62fc276173eed1",
+        ),
+        (
+            include_bytes!("../tests/fixtures/private.torrent"),
+            "b5dd956945ab26b5302a082a91c56baa2b061e4b",
+        ),
+        (
+            include_bytes!("../tests/fixtures/odd_pieces.torrent"),
+            "148fc25be3c1a3be01e57ec4376aa490c6b5f608",
+        ),
+    ];
+
+    #[test]
+    fn fixtures_hash_to_their_known_info_hashes() {
+        for (file, expected) in FIXTURES {
+            let torrent = Torrent::from_bytes(file).unwrap();
+            assert_eq!(torrent.info.info_hash_str(), expected);
+            assert_eq!(
+                encode(torrent.info.canonical_info_hash_bytes().unwrap()),
+                expected
+            );
+            // and re-encoding from the fields gives the same bytes
+            let mut info = torrent.info.clone();
+            info.forget_original();
+            assert_eq!(info.info_hash_str(), expected);
+            assert_eq!(torrent.to_bytes().unwrap(), file);
+        }
+    }
+
+    #[test]
+    fn fixtures_parse_into_their_fields() {
+        let [multi, listed, private, odd] =
+            FIXTURES.map(|(file, _)| Torrent::from_bytes(file).unwrap());
+
+        assert_eq!(multi.info.name, "album");
+        assert_eq!(
+            multi.files(),
+            [
+                (PathBuf::from("a/c.bin"), 40000),
+                (PathBuf::from("a/z.bin"), 5000),
+                (PathBuf::from("b.bin"), 0),
+                (PathBuf::from("d.bin"), 300),
+            ]
+        );
+        assert_eq!(multi.total_length(), 45300);
+        assert_eq!(multi.num_pieces(), 3);
+        assert_eq!(multi.comment.as_deref(), Some("several files"));
+        assert_eq!(multi.creation_date, Some(1700000000));
+
+        assert_eq!(
+            listed.tiers(),
+            [
+                vec![
+                    "http://first.example/announce",
+                    "http://second.example/announce"
+                ],
+                vec!["udp://backup.example:6969"],
+            ]
+        );
+
+        assert!(private.is_private() && !listed.is_private());
+        assert_eq!(private.info.source.as_deref(), Some("PRIV"));
+        assert_eq!(
+            private.announce,
+            "http://private.example/announce?passkey=abc"
+        );
+
+        assert_eq!(odd.info.piece_length, 20000);
+        assert_eq!(odd.num_pieces(), 3);
+        assert_eq!(odd.piece_size(2), 10001);
+        let data = testing::pattern(50001);
+        for (index, piece) in data.chunks(20000).enumerate() {
+            assert!(odd.verify_piece(index, piece));
+        }
+        assert!(Torrent::from_bytes_strict(FIXTURES[3].0).is_err());
+    }
+
+    #[test]
+    fn info_hash_comes_from_the_bytes_in_the_file() {
+        // `name` before `length`, a canonical encoding sorts them the other way around
+        let info = [
+            &b"d4:name5:a.bin6:lengthi10e12:piece lengthi16e6:pieces20:"[..],
+            &[0; 20],
+            b"e",
+        ]
+        .concat();
+        let file = [
+            &b"d8:announce23:http://tracker/announce4:info"[..],
+            &info,
+            b"e",
+        ]
+        .concat();
+        let torrent = Torrent::from_bytes(&file).unwrap();
+        assert_eq!(torrent.info.to_bytes().unwrap(), info);
+        assert_eq!(torrent.info.info_hash_bytes(), sha1(&info));
+        assert_ne!(
+            torrent.info.canonical_info_hash_bytes().unwrap(),
+            torrent.info.info_hash_bytes()
+        );
+        assert_eq!(
+            torrent.info.canonical_bytes().unwrap(),
+            to_bytes(&torrent.info).unwrap()
+        );
+        // and writing the torrent back keeps them
+        assert_eq!(torrent.to_bytes().unwrap(), file);
+    }
+
+    #[test]
+    fn only_hybrid_torrents_have_a_v2_info_hash() {
+        let v1 = Torrent::from_bytes(include_bytes!("../sample.torrent")).unwrap();
+        let (sha1_hash, v2) = v1.info_hash_v1_and_v2();
+        assert_eq!(
+            encode(sha1_hash),
+            "d69f91e6b2ae4c542468d1073a71d4ea13879a7f"
+        );
+        assert_eq!(v2, None);
+
+        let info = dict(vec![
+            ("length", BencodeValue::Int(10)),
+            ("meta version", BencodeValue::Int(2)),
+            ("name", bytes("a")),
+            ("piece length", BencodeValue::Int(16)),
+            ("pieces", bytes([0; 20])),
+        ]);
+        let hybrid = Torrent::from_bytes(&with_info(info.clone())).unwrap();
+        let (sha1_hash, v2) = hybrid.info_hash_v1_and_v2();
+        let encoded = bencode::encode(&info);
+        assert_eq!(sha1_hash, sha1(&encoded));
+        assert_eq!(v2, Some(Sha256::digest(&encoded).into()));
+    }
+
+    #[test]
+    fn pieces_verify_against_their_own_hash_only() {
+        let data = testing::pattern(100);
+        let torrent = testing::torrent("http://t.example/announce", &data, 64);
+        assert_eq!(torrent.piece_hash(1), &sha1(&data[64..]));
+        assert!(torrent.verify_piece(0, &data[..64]));
+        assert!(torrent.verify_piece(1, &data[64..]));
+
+        let mut corrupt = data[..64].to_vec();
+        corrupt[10] ^= 1;
+        assert!(!torrent.verify_piece(0, &corrupt));
+        assert!(!torrent.verify_piece(0, &data[..63]));
+        assert!(!torrent.verify_piece(1, &data[..64]));
+        assert!(!torrent.verify_piece(2, &data[64..]));
+    }
+
+    #[test]
+    fn block_plans_shrink_the_last_block_and_piece() {
+        // a full 32 KiB piece and a last one of 17232 bytes
+        let torrent = layout(&[("a", 50_000)], 32 * 1024);
+        let block = 16 * 1024;
+        assert_eq!(
+            torrent.blocks_for_piece(0, block),
+            [(0, block), (block as u64, block)]
+        );
+        assert_eq!(
+            torrent.blocks_for_piece(1, block),
+            [(0, block), (block as u64, 848)]
+        );
+        assert_eq!(
+            torrent.blocks_for_piece(1, 10_000),
+            [(0, 10_000), (10_000, 7232)]
+        );
+        assert_eq!(torrent.blocks_for_piece(0, 2 * block), [(0, 2 * block)]);
+        assert_eq!(torrent.blocks_for_piece(1, 4 * block), [(0, 17232)]);
+    }
+
+    #[test]
+    fn block_plans_of_pieces_past_4_gib() {
+        let block = 16 * 1024;
+        // a u32 piece size would wrap to 0 here
+        let torrent = layout(&[("a", 4 * GIB)], 4 * GIB);
+        let plan = torrent.blocks_for_piece(0, block);
+        assert_eq!(plan.len(), 262_144);
+        assert_eq!(
+            plan.iter().map(|&(_, length)| length as u64).sum::<u64>(),
+            4 * GIB
+        );
+        assert_eq!(plan.last(), Some(&(4 * GIB - block as u64, block)));
+
+        // and to 2 GiB here, the offsets past 4 GiB are the caller's to refuse
+        let torrent = layout(&[("a", 6 * GIB + 100)], 6 * GIB);
+        let plan = torrent.blocks_for_piece(0, block);
+        assert_eq!(plan.last(), Some(&(6 * GIB - block as u64, block)));
+        assert!(plan.last().unwrap().0 > u32::MAX as u64);
+        assert_eq!(torrent.blocks_for_piece(1, block), [(0, 100)]);
+    }
+
+    #[test]
+    fn display_of_a_zero_length_torrent() {
+        let torrent = layout(&[("empty", 0)], 1 << 14);
+        assert_eq!(torrent.num_pieces(), 0);
+        assert!(torrent.to_string().ends_with("Piece Hashes:\n"));
+    }
+
+    #[test]
+    fn piece_math_past_4_gib() {
+        let piece_length = 16 * 1024 * 1024;
+        let torrent = layout(&[("a", 5 * GIB + 100), ("b", 1000)], piece_length);
+        let last = torrent.num_pieces() as u32 - 1;
+        assert_eq!(last as u64, (5 * GIB + 1100) / piece_length);
+        assert_eq!(torrent.piece_size(last), 1100);
+        assert_eq!(torrent.piece_size(last - 1), piece_length);
+
+        // the last 200 bytes of `a` and the first 400 of `b`
+        let spans = torrent.locate(5 * GIB - 100, 600);
+        assert_eq!(
+            spans,
+            vec![
+                (PathBuf::from("a"), 5 * GIB - 100, 200),
+                (PathBuf::from("b"), 0, 400),
+            ]
+        );
+        // and nothing past the end
+        assert_eq!(
+            torrent.locate(5 * GIB + 1000, 500),
+            vec![(PathBuf::from("b"), 900, 100)]
+        );
+    }
+}
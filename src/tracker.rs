@@ -1,8 +1,21 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use futures::future::join_all;
+use rand::Rng;
+use reqwest::{redirect::Policy, Client};
 use serde::{Deserialize, Serialize};
 use serde_bencode::from_bytes;
+use serde_bytes::{ByteBuf, Bytes};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{debug, info, instrument, warn};
 
 use self::peers::Peers;
+use crate::bencode;
+use crate::error::BtError;
+use crate::peer::handshake::DEFAULT_PEER_ID;
 use crate::torrent::Torrent;
 
 // info_hash: the info hash of the torrent
@@ -28,44 +41,438 @@ pub struct TrackerRequest {
     // pub info_hash: [u8; 20],
     pub peer_id: String,
     pub port: u16,
-    pub uploaded: usize,
-    pub downloaded: usize,
-    pub left: usize,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
     pub compact: u8,
+    /// Left out of regular announces, which are the periodic ones
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<Event>,
+    /// Shared http client, not part of the query string
+    #[serde(skip)]
+    pub client: Client,
+    /// How long a single announce may take before we give up on it
+    #[serde(skip)]
+    pub timeout: Duration,
+    /// How many more times a tracker is tried after the first announce failed
+    #[serde(skip)]
+    pub retries: u32,
+    /// Trackers of the next `announce-list` tier are only asked when the ones before gave fewer
+    /// peers than this
+    #[serde(skip)]
+    pub min_peers: usize,
+    /// The `tracker id` each tracker gave us, by announce url, sent back to it as `trackerid` on
+    /// every later announce. Shared by all clones so re-announces pick it up.
+    #[serde(skip)]
+    pub tracker_ids: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    /// Talk https to trackers listed with http urls, for trackers that only answer over https
+    #[serde(skip)]
+    pub force_https: bool,
+}
+
+/// Tells the tracker about a change in our state instead of it being a periodic announce.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    Started,
+    Completed,
+    Stopped,
+}
+
+pub const DEFAULT_PORT: u16 = 6881;
+pub const DEFAULT_USER_AGENT: &str = "bittorrent-rust/0.1";
+pub const DEFAULT_TRACKER_TIMEOUT: Duration = Duration::from_secs(15);
+pub const DEFAULT_TRACKER_RETRIES: u32 = 2;
+pub const DEFAULT_MIN_PEERS: usize = 30;
+// doubled after every failed attempt on the same tracker
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_REDIRECTS: usize = 5;
+/// Used until a tracker tells us its `interval`.
+pub const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+// first wait after a failed re-announce, doubled for every further failure in a row
+const FAILED_ANNOUNCE_BACKOFF: Duration = Duration::from_secs(15);
+// how far a re-announce may move away from the interval either way, as a fraction of it
+const ANNOUNCE_JITTER: f64 = 0.1;
+
+/// Builds the http client for tracker requests. Build it once and clone it where needed,
+/// clones share the same connection pool.
+pub fn http_client(user_agent: &str, request_timeout: Duration) -> Result<Client> {
+    Client::builder()
+        .user_agent(user_agent)
+        .timeout(request_timeout)
+        .redirect(Policy::limited(MAX_REDIRECTS))
+        // some trackers compress their responses, reqwest undoes it based on Content-Encoding
+        .gzip(true)
+        .deflate(true)
+        .build()
+        .context("CTX: build http client")
+}
+
+// the client used when none is configured, built on first use
+fn default_client() -> Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT
+        .get_or_init(|| {
+            http_client(DEFAULT_USER_AGENT, DEFAULT_TRACKER_TIMEOUT)
+                .expect("Default http client must build")
+        })
+        .clone()
 }
 
 impl TrackerRequest {
-    pub fn default(length: usize) -> Self {
+    pub fn default(length: u64) -> Self {
+        Self::new(String::from(DEFAULT_PEER_ID), DEFAULT_PORT, length)
+    }
+
+    pub fn new(peer_id: String, port: u16, length: u64) -> Self {
         Self {
-            peer_id: String::from("00112233445566778899"),
-            port: 6881,
+            peer_id,
+            port,
             uploaded: 0,
             downloaded: 0,
             left: length,
             compact: 1,
+            event: None,
+            client: default_client(),
+            timeout: DEFAULT_TRACKER_TIMEOUT,
+            retries: DEFAULT_TRACKER_RETRIES,
+            min_peers: DEFAULT_MIN_PEERS,
+            tracker_ids: Arc::default(),
+            force_https: false,
+        }
+    }
+
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub fn with_event(mut self, event: Event) -> Self {
+        self.event = Some(event);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn with_min_peers(mut self, min_peers: usize) -> Self {
+        self.min_peers = min_peers;
+        self
+    }
+
+    pub fn with_force_https(mut self, force_https: bool) -> Self {
+        self.force_https = force_https;
+        self
+    }
+
+    /// `url` as it gets requested, i.e. with https in place of http under `force_https`.
+    pub fn tracker_url(&self, url: &str) -> String {
+        match url.strip_prefix("http://") {
+            Some(rest) if self.force_https => format!("https://{rest}"),
+            _ => url.to_string(),
         }
     }
 
-    pub async fn discover_peers(&self, torrent: &Torrent) -> Result<Peers> {
+    /// The peers `announce` found. Failures are sorted by `BtError::classify`, anything it can't
+    /// place is a `BtError::Tracker`.
+    pub async fn discover_peers(&self, torrent: &Torrent) -> Result<Peers, BtError> {
+        self.announce(torrent)
+            .await
+            .map(|response| response.peers)
+            .map_err(|e| BtError::classify(e, BtError::Tracker))
+    }
+
+    /// Announces to every tracker of the torrent's first tier at once and merges their peers.
+    /// The next tier is only asked while we have fewer than `min_peers` peers. Each tracker is
+    /// retried with backoff, unless it explicitly refused us. Everything but the peers comes
+    /// from the first tracker that answered, in tier order.
+    #[instrument(level = "info", skip_all)]
+    pub async fn announce(&self, torrent: &Torrent) -> Result<TrackerResponse> {
+        let mut errors = Vec::new();
+        let mut merged: Option<TrackerResponse> = None;
+        for tier in torrent.tiers() {
+            let responses = join_all(
+                tier.iter()
+                    .map(|tracker| self.announce_retrying(torrent, tracker)),
+            )
+            .await;
+            for (tracker, response) in tier.iter().zip(responses) {
+                match (response, &mut merged) {
+                    (Ok(response), None) => merged = Some(response),
+                    (Ok(response), Some(merged)) => {
+                        merged.peers.addresses.extend(response.peers.addresses)
+                    }
+                    (Err(e), _) => errors.push((tracker.to_string(), e)),
+                }
+            }
+            if let Some(response) = &mut merged {
+                response.peers.dedup();
+                if response.peers.addresses.len() >= self.min_peers {
+                    break;
+                }
+                debug!(
+                    peers = response.peers.addresses.len(),
+                    "too few peers, trying the next tier"
+                );
+            }
+        }
+        merged.ok_or_else(|| {
+            // the last failure stays in the chain so callers can still tell e.g. a timeout,
+            // the others are only listed before it
+            let Some((tracker, last)) = errors.pop() else {
+                return anyhow!("Torrent has no trackers to announce to");
+            };
+            let listed: String = errors
+                .iter()
+                .map(|(tracker, e)| format!("{tracker}: {e:#}\n"))
+                .collect();
+            last.context(format!("All trackers failed:\n{listed}{tracker}"))
+        })
+    }
+
+    async fn announce_retrying(&self, torrent: &Torrent, tracker: &str) -> Result<TrackerResponse> {
+        let mut attempt = 0;
+        loop {
+            match self.announce_to(torrent, tracker).await {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    warn!(
+                        tracker,
+                        attempt,
+                        error = format!("{e:#}"),
+                        "announce failed"
+                    );
+                    // asking again won't change the tracker's mind
+                    if attempt == self.retries || e.downcast_ref::<TrackerError>().is_some() {
+                        return Err(e);
+                    }
+                }
+            }
+            attempt += 1;
+            tokio::time::sleep(RETRY_BACKOFF * 2u32.pow(attempt - 1)).await;
+        }
+    }
+
+    fn tracker_ids(&self) -> MutexGuard<'_, HashMap<String, Vec<u8>>> {
+        self.tracker_ids.lock().expect("tracker id lock poisoned")
+    }
+
+    /// The full url announcing to `announce` requests, query string included.
+    pub fn announce_url(&self, torrent: &Torrent, announce: &str) -> Result<String> {
         let params =
             serde_urlencoded::to_string(self).context("CTX: url encoding request params")?;
-        let tracker_url = format!(
-            "{}?{}&info_hash={}",
-            torrent.announce,
-            params,
+        let url = self.tracker_url(announce);
+        // the url may already have a query string (e.g. a passkey)
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let mut tracker_url = format!(
+            "{url}{separator}{params}&info_hash={}",
             torrent.info.info_hash_urlencoded()
         );
-        let response = reqwest::get(tracker_url)
+        if let Some(tracker_id) = self.tracker_ids().get(announce) {
+            tracker_url.push_str("&trackerid=");
+            for byte in tracker_id {
+                tracker_url.push_str(&format!("%{byte:02x}"));
+            }
+        }
+        Ok(tracker_url)
+    }
+
+    async fn announce_to(&self, torrent: &Torrent, announce: &str) -> Result<TrackerResponse> {
+        let tracker_url = self.announce_url(torrent, announce)?;
+        debug!(%tracker_url, "announcing to tracker");
+        let response = self
+            .client
+            .get(tracker_url)
+            .timeout(self.timeout)
+            .send()
             .await
             .context("CTX: reqwest::get tracker_url")?;
         let response_bytes = response
             .bytes()
             .await
             .context("CTX: tracker response to bytes")?;
-        let response: TrackerResponse =
+        check_bencoded_dict(&response_bytes)?;
+        let status: TrackerStatus =
+            from_bytes(&response_bytes).context("CTX: byte to tracker status deserialization")?;
+        if let Some(reason) = status.failure_reason {
+            return Err(TrackerError::Failure(reason).into());
+        }
+        let mut response: TrackerResponse =
             from_bytes(&response_bytes).context("CTX: byte to tracker response deserialization")?;
-        Ok(response.peers)
+        if let Some(warning) = &response.warning_message {
+            warn!(%warning, "tracker sent a warning");
+        }
+        if let Some(external_ip) = response.external_ip() {
+            debug!(%external_ip, "tracker sees us as");
+        }
+        if let Some(tracker_id) = &response.tracker_id {
+            self.tracker_ids()
+                .insert(announce.to_string(), tracker_id.to_vec());
+        }
+        response.peers.dedup();
+        info!(
+            peers = response.peers.addresses.len(),
+            "tracker returned peers"
+        );
+        Ok(response)
     }
+
+    /// Asks the tracker how many seeders and leechers the torrent has without announcing ourselves.
+    #[instrument(level = "info", skip_all)]
+    pub async fn scrape(&self, torrent: &Torrent) -> Result<ScrapeStats> {
+        if torrent.announce.is_empty() {
+            return Err(anyhow!("Torrent has no tracker to scrape"));
+        }
+        let url = scrape_url(&self.tracker_url(&torrent.announce))?;
+        // the url may already have a query string (e.g. a passkey)
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let scrape_url = format!(
+            "{url}{separator}info_hash={}",
+            torrent.info.info_hash_urlencoded()
+        );
+        debug!(%scrape_url, "scraping tracker");
+        let response = self
+            .client
+            .get(scrape_url)
+            .timeout(self.timeout)
+            .send()
+            .await
+            .context("CTX: reqwest::get scrape_url")?;
+        let response_bytes = response
+            .bytes()
+            .await
+            .context("CTX: scrape response to bytes")?;
+        check_bencoded_dict(&response_bytes)?;
+        let response: ScrapeResponse =
+            from_bytes(&response_bytes).context("CTX: byte to scrape response deserialization")?;
+        if let Some(reason) = response.failure_reason {
+            return Err(TrackerError::Failure(reason).into());
+        }
+        response
+            .files
+            .get(Bytes::new(&torrent.info.info_hash_bytes()))
+            .copied()
+            .ok_or_else(|| anyhow!("Tracker did not return scrape stats for this torrent"))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TrackerError {
+    /// The tracker refused the request, e.g. because the torrent is not registered with it.
+    #[error("Tracker returned a failure: {0}")]
+    Failure(String),
+}
+
+// If the request failed, the response only has a human readable `failure reason` key.
+// Check it first so users get the reason instead of a deserialization error about missing peers.
+#[derive(Debug, Clone, Deserialize)]
+struct TrackerStatus {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+}
+
+// The scrape response is a dictionary with a `files` key, which maps each requested (raw) info hash
+// to a dictionary with these counts:
+// complete: number of peers with the entire file, i.e. seeders
+// incomplete: number of non-seeder peers, aka "leechers"
+// downloaded: total number of times the tracker registered a completion
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ScrapeStats {
+    pub complete: usize,
+    pub incomplete: usize,
+    pub downloaded: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ScrapeResponse {
+    #[serde(default)]
+    files: HashMap<ByteBuf, ScrapeStats>,
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+}
+
+// tracker responses are always a bencoded dict. Catch the usual impostors (html error pages,
+// compressed bodies without a Content-Encoding header) here so the error says what we got
+// instead of whatever serde_bencode makes of it
+fn check_bencoded_dict(body: &[u8]) -> Result<()> {
+    match body {
+        [b'd', ..] => bencode::check_depth(body),
+        [0x1f, 0x8b, ..] => Err(anyhow!(
+            "Tracker response is gzip compressed but not declared as such"
+        )),
+        [] => Err(anyhow!("Tracker response is empty")),
+        _ => Err(anyhow!(
+            "Tracker response is not bencoded, it starts with {:?}",
+            String::from_utf8_lossy(&body[..body.len().min(40)])
+        )),
+    }
+}
+
+// BEP 48: a tracker supports scrape if the last path segment of its announce url starts with
+// `announce`, the scrape url is then the same url with that `announce` replaced by `scrape`
+fn scrape_url(announce: &str) -> Result<String> {
+    let (prefix, last_segment) = announce
+        .rsplit_once('/')
+        .ok_or_else(|| anyhow!("Invalid announce URL: {announce}"))?;
+    match last_segment.strip_prefix("announce") {
+        Some(rest) => Ok(format!("{prefix}/scrape{rest}")),
+        None => Err(anyhow!("Tracker {announce} does not support scrape")),
+    }
+}
+
+/// Decides when to announce again. Waits the tracker's `interval` give or take 10% so clients
+/// that started together drift apart instead of hitting the tracker at the same moment, and
+/// backs off exponentially while announces keep failing, up to the tracker's `min interval`.
+#[derive(Debug, Clone)]
+pub struct AnnounceSchedule {
+    interval: Duration,
+    min_interval: Option<Duration>,
+    failures: u32,
+}
+
+impl Default for AnnounceSchedule {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_ANNOUNCE_INTERVAL,
+            min_interval: None,
+            failures: 0,
+        }
+    }
+}
+
+impl AnnounceSchedule {
+    /// How long to wait after the tracker answered with `response`.
+    pub fn on_success(&mut self, response: &TrackerResponse) -> Duration {
+        self.failures = 0;
+        if let Some(interval) = response.interval {
+            self.interval = Duration::from_secs(interval);
+        }
+        if let Some(min_interval) = response.min_interval {
+            self.min_interval = Some(Duration::from_secs(min_interval));
+        }
+        jitter(self.interval)
+    }
+
+    /// How long to wait after another failed announce.
+    pub fn on_failure(&mut self) -> Duration {
+        self.failures += 1;
+        // without a min interval the regular one is the most we ever wait
+        let cap = self.min_interval.unwrap_or(self.interval);
+        let backoff =
+            FAILED_ANNOUNCE_BACKOFF.saturating_mul(2u32.saturating_pow(self.failures - 1));
+        jitter(backoff.min(cap))
+    }
+}
+
+fn jitter(delay: Duration) -> Duration {
+    delay.mul_f64(rand::thread_rng().gen_range(1.0 - ANNOUNCE_JITTER..=1.0 + ANNOUNCE_JITTER))
 }
 
 // The tracker's response will be a bencoded dictionary with two keys:
@@ -76,60 +483,673 @@ impl TrackerRequest {
 // peers.
 // A string, which contains list of peers that your client can connect to.
 // Each peer is represented using 6 bytes. The first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number.
+// Trackers may also send a `warning message` next to an otherwise normal response,
+// and an `external ip` (BEP 24) telling us the address they saw the request come from.
 #[derive(Debug, Clone, Deserialize)]
 #[allow(dead_code)]
 pub struct TrackerResponse {
-    // interval: usize,
+    /// Seconds the tracker wants us to wait before announcing again.
+    #[serde(default)]
+    pub interval: Option<u64>,
+    /// Seconds we must wait at least before announcing again, even after a failure.
+    #[serde(rename = "min interval", default)]
+    pub min_interval: Option<u64>,
     pub peers: Peers,
+    #[serde(rename = "warning message", default)]
+    pub warning_message: Option<String>,
+    #[serde(rename = "external ip", default)]
+    external_ip: Option<ByteBuf>,
+    /// Some trackers want this back on every later announce, `TrackerRequest` takes care of that.
+    #[serde(rename = "tracker id", default)]
+    pub tracker_id: Option<ByteBuf>,
+}
+
+impl TrackerResponse {
+    /// Our public address as the tracker saw it, useful to tell if we are behind a NAT.
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        let bytes: &[u8] = self.external_ip.as_ref()?;
+        match bytes.len() {
+            4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+            16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+            _ => None,
+        }
+    }
 }
 
-mod peers {
-    use serde::de::{self, Deserialize, Deserializer, Visitor};
+pub mod peers {
+    use rand::{seq::SliceRandom, Rng};
+    use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
     // use serde::ser::{Serialize, Serializer};
+    use std::collections::HashSet;
     use std::fmt;
     use std::net::{Ipv4Addr, SocketAddrV4};
 
-    #[derive(Debug, Clone)]
+    #[derive(Debug, Clone, Default)]
     pub struct Peers {
         pub addresses: Vec<SocketAddrV4>,
     } // v4 and not v6 because "The first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number"
 
+    impl Peers {
+        /// Removes repeated addresses, keeping the first occurrence of each.
+        pub fn dedup(&mut self) {
+            let mut seen = HashSet::new();
+            self.addresses.retain(|address| seen.insert(*address));
+        }
+
+        /// Randomizes the order so every client doesn't start with the same peer.
+        pub fn shuffle(&mut self) {
+            self.shuffle_with(&mut rand::thread_rng());
+        }
+
+        /// Same as `shuffle`, pass a seeded rng for a reproducible order.
+        pub fn shuffle_with<R: Rng + ?Sized>(&mut self, rng: &mut R) {
+            self.addresses.shuffle(rng);
+        }
+    }
+
     struct PeersVisitor;
 
     impl<'de> Visitor<'de> for PeersVisitor {
         type Value = Peers;
 
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("6 bytes, the first 4 bytes are a peer's IP address and the last 2 are a peer's port number")
+            formatter.write_str("6 bytes per peer, the first 4 bytes are a peer's IP address and the last 2 are a peer's port number, or a list of peer dictionaries or such byte strings")
         }
 
         fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
         where
             E: de::Error,
         {
-            if v.len() % 6 != 0 {
-                return Err(E::custom(format!("length is {}", v.len())));
-            }
+            Ok(Peers {
+                addresses: compact_peers(v)?,
+            })
+        }
 
-            let addresses: Vec<SocketAddrV4> = v
-                .chunks_exact(6)
-                .map(|chunk_6| {
-                    SocketAddrV4::new(
-                        Ipv4Addr::new(chunk_6[0], chunk_6[1], chunk_6[2], chunk_6[3]),
-                        u16::from_be_bytes([chunk_6[4], chunk_6[5]]),
-                    )
-                })
-                .collect();
+        // with compact=0 the tracker sends a list of dictionaries with `peer id`, `ip` and `port`
+        // keys. Some trackers wrap the compact string in a list instead, so take those too
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut addresses = Vec::new();
+            while let Some(peer) = seq.next_element::<ListedPeer>()? {
+                match peer {
+                    ListedPeer::Compact(bytes) => addresses.extend(compact_peers(&bytes)?),
+                    // `ip` may also be a dns name or an ipv6 address, which we can't connect to yet
+                    ListedPeer::Dictionary { ip, port } => {
+                        if let Ok(ip) = ip.parse::<Ipv4Addr>() {
+                            addresses.push(SocketAddrV4::new(ip, port));
+                        }
+                    }
+                }
+            }
             Ok(Peers { addresses })
         }
     }
 
+    fn compact_peers<E: de::Error>(v: &[u8]) -> Result<Vec<SocketAddrV4>, E> {
+        if !v.len().is_multiple_of(6) {
+            return Err(E::custom(format!("length is {}", v.len())));
+        }
+        Ok(v.chunks_exact(6)
+            .map(|chunk_6| {
+                SocketAddrV4::new(
+                    Ipv4Addr::new(chunk_6[0], chunk_6[1], chunk_6[2], chunk_6[3]),
+                    u16::from_be_bytes([chunk_6[4], chunk_6[5]]),
+                )
+            })
+            .collect())
+    }
+
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum ListedPeer {
+        Compact(serde_bytes::ByteBuf),
+        Dictionary { ip: String, port: u16 },
+    }
+
     impl<'de> Deserialize<'de> for Peers {
         fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
         where
             D: Deserializer<'de>,
         {
-            deserializer.deserialize_bytes(PeersVisitor)
+            deserializer.deserialize_any(PeersVisitor)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use rand::{rngs::StdRng, SeedableRng};
+
+        fn peers(ports: impl IntoIterator<Item = u16>) -> Peers {
+            Peers {
+                addresses: ports
+                    .into_iter()
+                    .map(|port| SocketAddrV4::new(Ipv4Addr::LOCALHOST, port))
+                    .collect(),
+            }
+        }
+
+        #[test]
+        fn dedup_keeps_the_first_of_each_address() {
+            let mut list = peers([3, 1, 3, 2, 1]);
+            // same port on another ip is a different peer
+            list.addresses
+                .push(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 3));
+            list.dedup();
+            let mut expected = peers([3, 1, 2]);
+            expected
+                .addresses
+                .push(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 3));
+            assert_eq!(list.addresses, expected.addresses);
+        }
+
+        #[test]
+        fn compact_peers_may_come_wrapped_in_a_list() {
+            let compact = [127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0, 80];
+            let plain = [&b"12:"[..], &compact].concat();
+            let wrapped = [&b"l12:"[..], &compact, b"e"].concat();
+            let parse = |bencoded: &[u8]| serde_bencode::from_bytes::<Peers>(bencoded).unwrap();
+            let expected = [
+                SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6881),
+                SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 80),
+            ];
+            assert_eq!(parse(&plain).addresses, expected);
+            assert_eq!(parse(&wrapped).addresses, expected);
+            // and dictionaries still work, even mixed with a compact string
+            let mixed = [&b"ld2:ip8:10.0.0.24:porti80ee6:"[..], &compact[..6], b"e"].concat();
+            assert_eq!(parse(&mixed).addresses, [expected[1], expected[0]]);
+            assert!(serde_bencode::from_bytes::<Peers>(b"l5:abcdee").is_err());
+        }
+
+        #[test]
+        fn shuffle_with_a_seed_is_reproducible() {
+            let shuffled = |seed| {
+                let mut list = peers(1..=20);
+                list.shuffle_with(&mut StdRng::seed_from_u64(seed));
+                list.addresses
+            };
+            assert_eq!(shuffled(7), shuffled(7));
+            assert_ne!(shuffled(7), peers(1..=20).addresses);
+            let mut sorted = shuffled(7);
+            sorted.sort();
+            assert_eq!(sorted, peers(1..=20).addresses);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::BencodeValue;
+    use crate::testing::{self, bytes, dict, MockTracker};
+    use tokio::io::AsyncReadExt;
+
+    fn request() -> TrackerRequest {
+        TrackerRequest::new(String::from(DEFAULT_PEER_ID), 6881, 1000)
+    }
+
+    #[test]
+    fn announce_url_keeps_an_existing_query_string() {
+        let torrent = testing::torrent("http://t.example/announce", &[1; 100], 64);
+        let url = request()
+            .announce_url(&torrent, "http://t.example/announce?passkey=abc")
+            .unwrap();
+        assert!(
+            url.starts_with("http://t.example/announce?passkey=abc&peer_id="),
+            "{url}"
+        );
+        assert_eq!(url.matches('?').count(), 1);
+        let url = request()
+            .announce_url(&torrent, "http://t.example/announce")
+            .unwrap();
+        assert!(
+            url.starts_with("http://t.example/announce?peer_id="),
+            "{url}"
+        );
+    }
+
+    #[tokio::test]
+    async fn non_compact_peer_lists_are_asked_for_and_parsed() {
+        let tracker = MockTracker::start(|_, _| {
+            let peer = |ip: &str, port| {
+                dict(vec![
+                    ("ip", bytes(ip)),
+                    ("peer id", bytes("-MOCK-00000000000000")),
+                    ("port", BencodeValue::Int(port)),
+                ])
+            };
+            crate::bencode::encode(&dict(vec![
+                ("interval", BencodeValue::Int(60)),
+                (
+                    "peers",
+                    BencodeValue::List(vec![
+                        peer("10.0.0.1", 6881),
+                        // can't connect to these yet, they are skipped
+                        peer("tracker.example", 6881),
+                        peer("::1", 6881),
+                        peer("10.0.0.2", 51413),
+                    ]),
+                ),
+            ]))
+            .into()
+        })
+        .await;
+        let torrent = testing::torrent(&tracker.announce_url(), &[1; 100], 64);
+        let mut request = request().with_retries(0);
+        request.compact = 0;
+        let peers = request.discover_peers(&torrent).await.unwrap();
+        assert_eq!(
+            peers.addresses,
+            [
+                "10.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:51413".parse().unwrap()
+            ]
+        );
+        assert!(tracker.requests()[0].contains("&compact=0&"));
+    }
+
+    #[tokio::test]
+    async fn a_timed_out_announce_is_retried() {
+        let tracker = MockTracker::start(|count, _| testing::Reply {
+            body: crate::bencode::encode(&dict(vec![("peers", bytes([10, 0, 0, 1, 0x1a, 0xe1]))])),
+            // only the first announce hangs
+            delay: Duration::from_secs(if count == 0 { 5 } else { 0 }),
+            ..Default::default()
+        })
+        .await;
+        let torrent = testing::torrent(&tracker.announce_url(), &[1; 100], 64);
+        let peers = request()
+            .with_timeout(Duration::from_millis(200))
+            .with_retries(1)
+            .discover_peers(&torrent)
+            .await
+            .unwrap();
+        assert_eq!(peers.addresses, ["10.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(tracker.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn peers_of_a_tier_are_merged_and_deduped() {
+        let tracker = |peers: &'static [u8]| {
+            MockTracker::start(move |_, _| {
+                crate::bencode::encode(&dict(vec![("peers", bytes(peers))])).into()
+            })
+        };
+        let first = tracker(&[10, 0, 0, 1, 0, 1, 10, 0, 0, 2, 0, 2]).await;
+        let second = tracker(&[10, 0, 0, 2, 0, 2, 10, 0, 0, 3, 0, 3]).await;
+        let backup = tracker(&[10, 0, 0, 4, 0, 4]).await;
+        let mut torrent = testing::torrent(&first.announce_url(), &[1; 100], 64);
+        torrent.announce_list = Some(vec![
+            vec![first.announce_url(), second.announce_url()],
+            vec![backup.announce_url()],
+        ]);
+
+        let peers = request()
+            .with_retries(0)
+            .with_min_peers(3)
+            .discover_peers(&torrent)
+            .await
+            .unwrap();
+        assert_eq!(
+            peers.addresses,
+            [
+                "10.0.0.1:1".parse().unwrap(),
+                "10.0.0.2:2".parse().unwrap(),
+                "10.0.0.3:3".parse().unwrap()
+            ]
+        );
+        assert!(backup.requests().is_empty());
+
+        // not enough, so the next tier is asked too
+        let peers = request()
+            .with_retries(0)
+            .with_min_peers(4)
+            .discover_peers(&torrent)
+            .await
+            .unwrap();
+        assert_eq!(peers.addresses.len(), 4);
+        assert_eq!(peers.addresses[3], "10.0.0.4:4".parse().unwrap());
+        assert_eq!(
+            (
+                first.requests().len(),
+                second.requests().len(),
+                backup.requests().len()
+            ),
+            (2, 2, 1)
+        );
+    }
+
+    // gzip with a single uncompressed deflate block, enough to test decoding without a compressor
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let crc = !data.iter().fold(!0u32, |crc, &byte| {
+            (0..8).fold(crc ^ byte as u32, |crc, _| {
+                (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+            })
+        });
+        let length = data.len() as u16;
+        let mut gzip = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff, 1];
+        gzip.extend(length.to_le_bytes());
+        gzip.extend((!length).to_le_bytes());
+        gzip.extend(data);
+        gzip.extend(crc.to_le_bytes());
+        gzip.extend((data.len() as u32).to_le_bytes());
+        gzip
+    }
+
+    #[tokio::test]
+    async fn gzipped_responses_are_decoded() {
+        let body = crate::bencode::encode(&dict(vec![
+            ("interval", BencodeValue::Int(60)),
+            ("peers", bytes([10, 0, 0, 1, 0x1a, 0xe1])),
+        ]));
+        let compressed = gzip(&body);
+        let tracker = MockTracker::start(move |count, _| testing::Reply {
+            // the second time without saying it is compressed
+            headers: match count {
+                0 => vec![("Content-Encoding", String::from("gzip"))],
+                _ => Vec::new(),
+            },
+            body: compressed.clone(),
+            ..Default::default()
+        })
+        .await;
+        let torrent = testing::torrent(&tracker.announce_url(), &[1; 100], 64);
+        let request = request().with_retries(0);
+        let peers = request.discover_peers(&torrent).await.unwrap();
+        assert_eq!(peers.addresses, ["10.0.0.1:6881".parse().unwrap()]);
+        assert!(tracker.heads()[0]
+            .to_lowercase()
+            .contains("accept-encoding: gzip"));
+
+        let e = request.announce(&torrent).await.unwrap_err();
+        assert!(format!("{e:#}")
+            .ends_with("Tracker response is gzip compressed but not declared as such"));
+    }
+
+    #[tokio::test]
+    async fn configured_user_agent_is_sent() {
+        let tracker = MockTracker::start(|_, _| {
+            crate::bencode::encode(&dict(vec![("peers", bytes(""))])).into()
+        })
+        .await;
+        let torrent = testing::torrent(&tracker.announce_url(), &[1; 100], 64);
+        let client = http_client("private-client/1.0", Duration::from_secs(5)).unwrap();
+        request()
+            .with_client(client)
+            .announce(&torrent)
+            .await
+            .unwrap();
+        let head = tracker.heads()[0].to_ascii_lowercase();
+        assert!(
+            head.contains("\r\nuser-agent: private-client/1.0\r\n"),
+            "{head}"
+        );
+    }
+
+    #[test]
+    fn force_https_only_rewrites_http() {
+        let request = request().with_force_https(true);
+        assert_eq!(
+            request.tracker_url("http://t.example/a"),
+            "https://t.example/a"
+        );
+        assert_eq!(
+            request.tracker_url("udp://t.example:80"),
+            "udp://t.example:80"
+        );
+        assert_eq!(
+            TrackerRequest::default(0).tracker_url("http://t.example/a"),
+            "http://t.example/a"
+        );
+    }
+
+    #[tokio::test]
+    async fn force_https_reaches_every_tracker_of_the_torrent() {
+        // a tls client starts with a handshake record, content type 22, where http starts with GET
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (first_bytes, mut received) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let first = socket.read_u8().await.unwrap();
+                first_bytes.send(first).unwrap();
+            }
+        });
+        let mut torrent = testing::torrent(&format!("http://{address}/a"), &[1; 100], 64);
+        torrent.announce_list = Some(vec![
+            vec![format!("http://{address}/a")],
+            vec![format!("http://{address}/b")],
+        ]);
+
+        let result = request()
+            .with_retries(0)
+            .with_timeout(Duration::from_millis(200))
+            .with_force_https(true)
+            .discover_peers(&torrent)
+            .await;
+        assert!(result.is_err());
+        assert_eq!(received.recv().await, Some(22));
+        assert_eq!(received.recv().await, Some(22));
+        assert!(received.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn failure_reasons_are_tracker_errors() {
+        let tracker = MockTracker::start(|_, _| {
+            crate::bencode::encode(&dict(vec![(
+                "failure reason",
+                bytes("torrent not registered"),
+            )]))
+            .into()
+        })
+        .await;
+        let torrent = testing::torrent(&tracker.announce_url(), &[1; 100], 64);
+        let result = request().discover_peers(&torrent).await;
+        let Err(BtError::Tracker(e)) = result else {
+            panic!("expected a tracker error, got {result:?}");
+        };
+        assert!(
+            matches!(e.downcast_ref(), Some(TrackerError::Failure(reason)) if reason == "torrent not registered"),
+            "{e:#}"
+        );
+        // refusals aren't retried
+        assert_eq!(tracker.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn warnings_come_with_the_peers() {
+        let tracker = MockTracker::start(|_, _| {
+            crate::bencode::encode(&dict(vec![
+                ("interval", BencodeValue::Int(60)),
+                ("peers", bytes([127, 0, 0, 1, 0x1a, 0xe1])),
+                ("warning message", bytes("please upgrade")),
+            ]))
+            .into()
+        })
+        .await;
+        let torrent = testing::torrent(&tracker.announce_url(), &[1; 100], 64);
+        let response = request().announce(&torrent).await.unwrap();
+        assert_eq!(response.warning_message.as_deref(), Some("please upgrade"));
+        assert_eq!(
+            response.peers.addresses,
+            ["127.0.0.1:6881".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn announce_waits_are_jittered_and_back_off() {
+        let within = |wait: Duration, secs: u64| {
+            let secs = Duration::from_secs(secs);
+            assert!(
+                wait >= secs.mul_f64(1.0 - ANNOUNCE_JITTER)
+                    && wait <= secs.mul_f64(1.0 + ANNOUNCE_JITTER),
+                "{wait:?} is not within {ANNOUNCE_JITTER} of {secs:?}"
+            );
+        };
+        let mut schedule = AnnounceSchedule::default();
+        within(schedule.on_failure(), 15);
+        let response = from_bytes::<TrackerResponse>(&crate::bencode::encode(&dict(vec![
+            ("interval", BencodeValue::Int(600)),
+            ("min interval", BencodeValue::Int(100)),
+            ("peers", bytes("")),
+        ])))
+        .unwrap();
+        let waits: Vec<_> = (0..50).map(|_| schedule.on_success(&response)).collect();
+        waits.iter().for_each(|&wait| within(wait, 600));
+        assert!(waits.iter().any(|&wait| wait != waits[0]), "never jittered");
+
+        // doubles on every failure until it hits the min interval
+        let failures: Vec<_> = (0..6).map(|_| schedule.on_failure()).collect();
+        for (&wait, secs) in failures.iter().zip([15, 30, 60, 100, 100, 100]) {
+            within(wait, secs);
         }
+        assert!(failures.windows(2).take(3).all(|pair| pair[0] < pair[1]));
+        // and starts over once an announce goes through
+        schedule.on_success(&response);
+        within(schedule.on_failure(), 15);
+    }
+
+    #[test]
+    fn warning_and_external_ip_are_optional() {
+        let parse = |entries| {
+            let body = crate::bencode::encode(&dict(entries));
+            from_bytes::<TrackerResponse>(&body).unwrap()
+        };
+        let response = parse(vec![
+            ("external ip", bytes([203, 0, 113, 7])),
+            ("peers", bytes("")),
+            ("warning message", bytes("please upgrade")),
+        ]);
+        assert_eq!(response.warning_message.as_deref(), Some("please upgrade"));
+        assert_eq!(response.external_ip(), Some("203.0.113.7".parse().unwrap()));
+        let mut v6 = [0; 16];
+        v6[15] = 1;
+        let response = parse(vec![("external ip", bytes(v6)), ("peers", bytes(""))]);
+        assert_eq!(response.external_ip(), Some("::1".parse().unwrap()));
+
+        let response = parse(vec![("peers", bytes(""))]);
+        assert_eq!(response.warning_message, None);
+        assert_eq!(response.external_ip(), None);
+        // neither 4 nor 16 bytes
+        let response = parse(vec![("external ip", bytes("odd")), ("peers", bytes(""))]);
+        assert_eq!(response.external_ip(), None);
+    }
+
+    #[tokio::test]
+    async fn tracker_id_is_sent_on_the_next_announce() {
+        let tracker = MockTracker::start(|_, _| {
+            crate::bencode::encode(&dict(vec![
+                ("interval", BencodeValue::Int(60)),
+                ("peers", bytes([127, 0, 0, 1, 0x1a, 0xe1])),
+                ("tracker id", bytes("id 1")),
+            ]))
+            .into()
+        })
+        .await;
+        let torrent = testing::torrent(&tracker.announce_url(), &[1; 100], 64);
+        let request = request().with_retries(0);
+        request.announce(&torrent).await.unwrap();
+        // clones share what the tracker told us, which is how re-announces see it
+        request.clone().announce(&torrent).await.unwrap();
+
+        let requests = tracker.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(!requests[0].contains("trackerid"), "{}", requests[0]);
+        assert!(
+            requests[1].ends_with("&trackerid=%69%64%20%31"),
+            "{}",
+            requests[1]
+        );
+    }
+
+    #[test]
+    fn scrape_url_replaces_announce() {
+        assert_eq!(
+            scrape_url("http://t.example/announce").unwrap(),
+            "http://t.example/scrape"
+        );
+        assert_eq!(
+            scrape_url("http://t.example/x/announce.php?passkey=1").unwrap(),
+            "http://t.example/x/scrape.php?passkey=1"
+        );
+        let e = scrape_url("http://t.example/a").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Tracker http://t.example/a does not support scrape"
+        );
+    }
+
+    #[tokio::test]
+    async fn scrape_reads_the_counts_for_our_info_hash() {
+        // the torrent needs the tracker's address and the tracker the torrent's info hash
+        let info_hash: Arc<OnceLock<[u8; 20]>> = Arc::default();
+        let known = info_hash.clone();
+        let tracker = MockTracker::start(move |_, _| {
+            let stats = |complete, incomplete, downloaded| {
+                dict(vec![
+                    ("complete", BencodeValue::Int(complete)),
+                    ("downloaded", BencodeValue::Int(downloaded)),
+                    ("incomplete", BencodeValue::Int(incomplete)),
+                ])
+            };
+            let files = BencodeValue::Dict(
+                [
+                    ([9; 20].to_vec(), stats(1, 1, 1)),
+                    (known.get().unwrap().to_vec(), stats(2, 3, 7)),
+                ]
+                .into(),
+            );
+            crate::bencode::encode(&dict(vec![("files", files)])).into()
+        })
+        .await;
+        let torrent = testing::torrent(&tracker.announce_url(), &[1; 100], 64);
+        info_hash.set(torrent.info.info_hash_bytes()).unwrap();
+
+        let stats = request().scrape(&torrent).await.unwrap();
+        assert_eq!(
+            (stats.complete, stats.incomplete, stats.downloaded),
+            (2, 3, 7)
+        );
+        let requests = tracker.requests();
+        assert!(
+            requests[0].starts_with("/scrape?info_hash=%"),
+            "{}",
+            requests[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn scrape_failures_are_tracker_errors() {
+        let tracker = MockTracker::start(|_, _| {
+            crate::bencode::encode(&dict(vec![("failure reason", bytes("scrape is disabled"))]))
+                .into()
+        })
+        .await;
+        let torrent = testing::torrent(&tracker.announce_url(), &[1; 100], 64);
+        let e = request().scrape(&torrent).await.unwrap_err();
+        assert!(
+            matches!(e.downcast_ref(), Some(TrackerError::Failure(reason)) if reason == "scrape is disabled"),
+            "{e:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn scrape_gives_up_after_the_timeout() {
+        let tracker = MockTracker::start(|_, _| testing::Reply {
+            delay: Duration::from_secs(5),
+            ..Default::default()
+        })
+        .await;
+        let torrent = testing::torrent(&tracker.announce_url(), &[1; 100], 64);
+        let started = std::time::Instant::now();
+        let result = request()
+            .with_timeout(Duration::from_millis(200))
+            .scrape(&torrent)
+            .await;
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
     }
 }
@@ -0,0 +1,693 @@
+// Runs the built binary the way a user or a script would, for the behaviour that only exists in
+// the command line: where torrents are read from, flags and what gets printed.
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::thread::JoinHandle;
+
+use bittorrent_starter_rust::torrent::Torrent;
+
+fn cli(args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_bittorrent-starter-rust"));
+    command.args(args);
+    command
+}
+
+fn run(args: &[&str]) -> Output {
+    cli(args).output().expect("run the cli")
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(
+        output.status.success(),
+        "cli failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout.clone()).expect("utf8 output")
+}
+
+// a .torrent for a small generated file in `dir`
+fn sample_torrent(dir: &Path, announce: &str) -> (PathBuf, Torrent) {
+    let content = dir.join("sample.bin");
+    let data: Vec<u8> = (0..40_000).map(|i| (i * 7 % 251) as u8).collect();
+    fs::write(&content, data).unwrap();
+    let torrent = Torrent::create(&content, announce.to_string(), 16 * 1024).unwrap();
+    let path = dir.join("sample.torrent");
+    fs::write(&path, torrent.to_bytes().unwrap()).unwrap();
+    (path, torrent)
+}
+
+// a torrent from tests/fixtures, see make_fixtures.py there
+fn fixture_path(name: &str) -> String {
+    format!(
+        "{}/tests/fixtures/{name}.torrent",
+        env!("CARGO_MANIFEST_DIR")
+    )
+}
+
+// answers a single http request with `body` and returns the url to fetch it from
+fn serve_once(body: Vec<u8>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/sample.torrent", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        let (mut socket, _) = listener.accept().unwrap();
+        let mut head = Vec::new();
+        let mut byte = [0];
+        while !head.ends_with(b"\r\n\r\n") {
+            socket.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        socket.write_all(response.as_bytes()).unwrap();
+        socket.write_all(&body).unwrap();
+    });
+    url
+}
+
+// a peer that answers one handshake with its own, `reserved` as its reserved bytes. Hands back
+// the handshake it got
+fn handshaking_peer(reserved: [u8; 8]) -> (String, JoinHandle<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let peer = std::thread::spawn(move || {
+        let (mut socket, _) = listener.accept().unwrap();
+        let mut theirs = vec![0; 68];
+        socket.read_exact(&mut theirs).unwrap();
+        let ours = [
+            &theirs[..20],
+            &reserved,
+            &theirs[28..48],
+            b"-PEER--0000000000000",
+        ]
+        .concat();
+        socket.write_all(&ours).unwrap();
+        theirs
+    });
+    (address, peer)
+}
+
+// a peer seeding `data` in `piece_length` pieces to everyone connecting: handshake, a full
+// bitfield, an unchoke once we are interested and every request answered. `data` may differ
+// from what the torrent was made of, to play a peer sending corrupt pieces
+fn seeding_peer(data: Vec<u8>, piece_length: usize) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let data = std::sync::Arc::new(data);
+    std::thread::spawn(move || {
+        for socket in listener.incoming() {
+            let data = data.clone();
+            std::thread::spawn(move || serve_pieces(socket?, &data, piece_length));
+        }
+        std::io::Result::Ok(())
+    });
+    address
+}
+
+fn serve_pieces(
+    mut socket: std::net::TcpStream,
+    data: &[u8],
+    piece_length: usize,
+) -> std::io::Result<()> {
+    let mut theirs = [0; 68];
+    socket.read_exact(&mut theirs)?;
+    socket.write_all(
+        &[
+            &theirs[..20],
+            &[0; 8],
+            &theirs[28..48],
+            b"-PEER--0000000000000",
+        ]
+        .concat(),
+    )?;
+    let pieces = data.len().div_ceil(piece_length);
+    let mut bitfield = vec![0xff; pieces.div_ceil(8)];
+    *bitfield.last_mut().unwrap() <<= bitfield.len() * 8 - pieces;
+    socket.write_all(&(1 + bitfield.len() as u32).to_be_bytes())?;
+    socket.write_all(&[5])?;
+    socket.write_all(&bitfield)?;
+    loop {
+        let mut length = [0; 4];
+        socket.read_exact(&mut length)?;
+        let mut message = vec![0; u32::from_be_bytes(length) as usize];
+        socket.read_exact(&mut message)?;
+        match message.first() {
+            // interested
+            Some(2) => socket.write_all(&[0, 0, 0, 1, 1])?,
+            Some(6) => {
+                let field = |at: usize| u32::from_be_bytes(message[at..at + 4].try_into().unwrap());
+                let (index, begin, length) = (field(1), field(5), field(9));
+                let start = index as usize * piece_length + begin as usize;
+                let block = &data[start..start + length as usize];
+                socket.write_all(&(9 + length).to_be_bytes())?;
+                socket.write_all(&[7])?;
+                socket.write_all(&index.to_be_bytes())?;
+                socket.write_all(&begin.to_be_bytes())?;
+                socket.write_all(block)?;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn port_and_peer_id_flags_reach_tracker_and_peers() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, _) = sample_torrent(dir.path(), "http://127.0.0.1:1/announce");
+    let path = path.to_str().unwrap();
+    let peer_id = "-XY0001-123456789012";
+
+    let url = stdout(&run(&[
+        "announce",
+        "--dry-run",
+        "--port",
+        "7001",
+        &format!("--peer-id={peer_id}"),
+        path,
+    ]));
+    assert!(
+        url.contains(&format!("peer_id={peer_id}&port=7001&")),
+        "{url}"
+    );
+
+    let (address, peer) = handshaking_peer([0; 8]);
+    let output = run(&[
+        "handshake",
+        "--skip-tracker-check",
+        &format!("--peer-id={peer_id}"),
+        path,
+        &address,
+    ]);
+    let peer_line = format!("Peer ID: {}", hex::encode("-PEER--0000000000000"));
+    assert!(stdout(&output).contains(&peer_line));
+    assert_eq!(&peer.join().unwrap()[48..], peer_id.as_bytes());
+
+    let output = run(&["announce", "--dry-run", "--peer-id", "too short", path]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn torrents_are_read_from_stdin_and_urls() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, torrent) = sample_torrent(dir.path(), "http://127.0.0.1:1/announce");
+    let expected = stdout(&run(&["info", path.to_str().unwrap()]));
+    assert!(expected.contains(&format!("Info Hash: {}", torrent.info.info_hash_str())));
+
+    let mut child = cli(&["info", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(&fs::read(&path).unwrap())
+        .unwrap();
+    assert_eq!(stdout(&child.wait_with_output().unwrap()), expected);
+
+    let url = serve_once(fs::read(&path).unwrap());
+    assert_eq!(stdout(&run(&["info", &url])), expected);
+}
+
+#[test]
+fn info_hash_in_both_formats() {
+    let sample = concat!(env!("CARGO_MANIFEST_DIR"), "/sample.torrent");
+    assert_eq!(
+        stdout(&run(&["info-hash", sample])),
+        "d69f91e6b2ae4c542468d1073a71d4ea13879a7f\n"
+    );
+    assert_eq!(
+        stdout(&run(&["info-hash", "--format", "base32", sample])),
+        "22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7\n"
+    );
+    for (fixture, hash) in [
+        ("multi_file", "72a318c1916a05d1c71926172aa1903820cd3825"),
+        ("announce_list", "e2207af5cf636e985d8085ccba62fc276173eed1"),
+        ("private", "b5dd956945ab26b5302a082a91c56baa2b061e4b"),
+        ("odd_pieces", "148fc25be3c1a3be01e57ec4376aa490c6b5f608"),
+    ] {
+        let path = fixture_path(fixture);
+        assert_eq!(stdout(&run(&["info-hash", &path])), format!("{hash}\n"));
+        let output = run(&["info-hash", "--canonical", &path]);
+        assert_eq!(stdout(&output), format!("{hash}\n"));
+    }
+}
+
+#[test]
+fn info_of_the_fixtures() {
+    let info = stdout(&run(&["info", &fixture_path("multi_file")]));
+    assert!(info.contains("Length: 45300\n"), "{info}");
+    assert!(
+        info.contains("Files:\na/c.bin (40000 bytes)\na/z.bin (5000 bytes)\nb.bin (0 bytes)\n"),
+        "{info}"
+    );
+    let info = stdout(&run(&["info", &fixture_path("odd_pieces")]));
+    assert!(info.contains("Piece Length: 20000\n"), "{info}");
+    let output = run(&["--strict", "info", &fixture_path("odd_pieces")]);
+    assert!(!output.status.success());
+
+    let urls = stdout(&run(&[
+        "announce",
+        "--dry-run",
+        &fixture_path("announce_list"),
+    ]));
+    let trackers: Vec<&str> = urls
+        .lines()
+        .map(|url| url.split('?').next().unwrap())
+        .collect();
+    assert_eq!(
+        trackers,
+        [
+            "http://first.example/announce",
+            "http://second.example/announce",
+            "udp://backup.example:6969"
+        ]
+    );
+    // the passkey already in the url is kept
+    let url = stdout(&run(&["announce", "--dry-run", &fixture_path("private")]));
+    assert!(
+        url.starts_with("http://private.example/announce?passkey=abc&peer_id="),
+        "{url}"
+    );
+}
+
+#[test]
+fn edit_changes_the_tracker_but_not_the_info_hash() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, torrent) = sample_torrent(dir.path(), "http://old.example/announce");
+    let edited = dir.path().join("edited.torrent");
+    let (path, edited) = (path.to_str().unwrap(), edited.to_str().unwrap());
+    let new = "http://new.example/announce";
+    stdout(&run(&["edit", "-o", edited, "--announce", new, path]));
+
+    let hash = format!("{}\n", torrent.info.info_hash_str());
+    assert_eq!(stdout(&run(&["info-hash", edited])), hash);
+    assert!(stdout(&run(&["info", edited])).contains(&format!("Tracker URL: {new}")));
+
+    let output = run(&["edit", "-o", edited, "--announce", "not a url", path]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn peers_as_json() {
+    let dir = tempfile::tempdir().unwrap();
+    let body = [
+        &b"d8:intervali60e5:peers12:"[..],
+        &[10, 0, 0, 1, 0x1a, 0xe1, 192, 168, 1, 2, 0, 80],
+        b"e",
+    ]
+    .concat();
+    let (path, _) = sample_torrent(dir.path(), &serve_once(body));
+    let output = run(&["peers", "--json", path.to_str().unwrap()]);
+    assert_eq!(
+        stdout(&output),
+        "[{\"ip\":\"10.0.0.1\",\"port\":6881},{\"ip\":\"192.168.1.2\",\"port\":80}]\n"
+    );
+}
+
+#[test]
+fn check_only_reports_matching_and_corrupt_pieces() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, _) = sample_torrent(dir.path(), "http://127.0.0.1:1/announce");
+    let path = path.to_str().unwrap();
+    let data = fs::read(dir.path().join("sample.bin")).unwrap();
+
+    let good = seeding_peer(data.clone(), 16 * 1024);
+    let output = run(&[
+        "download_piece",
+        "--check-only",
+        "--peer",
+        &good,
+        path,
+        "0",
+        "2",
+    ]);
+    assert_eq!(stdout(&output), "OK piece 0\nOK piece 2\n");
+
+    // the second piece comes back corrupt
+    let mut corrupt = data;
+    corrupt[20_000] ^= 0xff;
+    let bad = seeding_peer(corrupt, 16 * 1024);
+    let output = run(&[
+        "download_piece",
+        "--check-only",
+        "--peer",
+        &bad,
+        path,
+        "0",
+        "1",
+    ]);
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "OK piece 0\nFAIL piece 1\n"
+    );
+}
+
+#[test]
+fn dry_runs_never_reach_the_tracker() {
+    let tracker = TcpListener::bind("127.0.0.1:0").unwrap();
+    tracker.set_nonblocking(true).unwrap();
+    let announce = format!("http://{}/announce", tracker.local_addr().unwrap());
+    let dir = tempfile::tempdir().unwrap();
+    let (path, torrent) = sample_torrent(dir.path(), &announce);
+    let path = path.to_str().unwrap();
+    let output = dir.path().join("out.bin");
+    let output = output.to_str().unwrap();
+
+    let plan = stdout(&run(&["download", "--dry-run", "-o", output, path]));
+    assert!(plan.contains(&format!("Info Hash: {}", torrent.info.info_hash_str())));
+    assert!(plan.contains("Pieces: 3 (40000 bytes)"), "{plan}");
+    assert!(plan.contains(&format!("  {announce}")));
+    let plan = stdout(&run(&["download_piece", "--dry-run", path, "2"]));
+    assert!(plan.contains("Piece: 2 (7232 bytes)"), "{plan}");
+
+    let accepted = tracker.accept();
+    assert_eq!(
+        accepted.map(|_| ()).unwrap_err().kind(),
+        std::io::ErrorKind::WouldBlock
+    );
+    assert!(!Path::new(output).exists());
+}
+
+#[test]
+fn dumped_info_hashes_to_the_info_hash() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, torrent) = sample_torrent(dir.path(), "http://127.0.0.1:1/announce");
+    let dumped = dir.path().join("info.bin");
+    let output = run(&[
+        "dump-info",
+        "-o",
+        dumped.to_str().unwrap(),
+        path.to_str().unwrap(),
+    ]);
+    assert_eq!(stdout(&output), "");
+    let info = fs::read(&dumped).unwrap();
+    assert_eq!(
+        bittorrent_starter_rust::hash::sha1(&info),
+        torrent.info.info_hash_bytes()
+    );
+
+    // without -o the bytes go to stdout
+    let output = run(&["dump-info", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, info);
+}
+
+#[test]
+fn decode_formats_of_a_nested_value() {
+    let nested = "d3:bard1:xli1ei-2eee3:foo4:spame";
+    let decode = |format: &[&str]| stdout(&run(&[&["decode"], format, &[nested]].concat()));
+    let compact = "{\"bar\":{\"x\":[1,-2]},\"foo\":\"spam\"}\n";
+    assert_eq!(decode(&[]), compact);
+    assert_eq!(decode(&["--format", "json"]), compact);
+    assert_eq!(
+        decode(&["--format", "pretty"]),
+        "{\n  \"bar\": {\n    \"x\": [\n      1,\n      -2\n    ]\n  },\n  \"foo\": \"spam\"\n}\n"
+    );
+    assert_eq!(
+        decode(&["--format", "debug"]),
+        "Dict({[98, 97, 114]: Dict({[120]: List([Int(1), Int(-2)])}), \
+         [102, 111, 111]: Bytes([115, 112, 97, 109])})\n"
+    );
+    assert!(!run(&["decode", "--format", "yaml", nested])
+        .status
+        .success());
+}
+
+#[test]
+fn a_selected_peer_is_the_only_one_contacted() {
+    let tracker = TcpListener::bind("127.0.0.1:0").unwrap();
+    tracker.set_nonblocking(true).unwrap();
+    let announce = format!("http://{}/announce", tracker.local_addr().unwrap());
+    let dir = tempfile::tempdir().unwrap();
+    let (path, _) = sample_torrent(dir.path(), &announce);
+    let path = path.to_str().unwrap();
+    let data = fs::read(dir.path().join("sample.bin")).unwrap();
+    let peer = seeding_peer(data.clone(), 16 * 1024);
+
+    let output = dir.path().join("out.bin");
+    stdout(&run(&[
+        "download",
+        "--peer",
+        &peer,
+        "-o",
+        output.to_str().unwrap(),
+        path,
+    ]));
+    assert_eq!(fs::read(&output).unwrap(), data);
+    let piece = dir.path().join("piece.bin");
+    stdout(&run(&[
+        "download_piece",
+        "--peer",
+        &peer,
+        "-o",
+        piece.to_str().unwrap(),
+        path,
+        "1",
+    ]));
+    assert_eq!(fs::read(&piece).unwrap(), data[16 * 1024..32 * 1024]);
+    let accepted = tracker.accept();
+    assert_eq!(
+        accepted.map(|_| ()).unwrap_err().kind(),
+        std::io::ErrorKind::WouldBlock
+    );
+
+    let refusing = {
+        let reserved = TcpListener::bind("127.0.0.1:0").unwrap();
+        reserved.local_addr().unwrap().to_string()
+    };
+    let output = run(&[
+        "download_piece",
+        "--check-only",
+        "--peer",
+        &refusing,
+        path,
+        "0",
+    ]);
+    assert!(!output.status.success());
+    let error = String::from_utf8_lossy(&output.stderr);
+    assert!(error.contains(&refusing), "{error}");
+    assert!(!run(&[
+        "download_piece",
+        "--check-only",
+        "--peer",
+        "not-an-address",
+        path,
+        "0"
+    ])
+    .status
+    .success());
+}
+
+#[test]
+fn skipping_the_tracker_check_skips_the_tracker() {
+    let tracker = TcpListener::bind("127.0.0.1:0").unwrap();
+    tracker.set_nonblocking(true).unwrap();
+    let announce = format!("http://{}/announce", tracker.local_addr().unwrap());
+    let dir = tempfile::tempdir().unwrap();
+    let (path, _) = sample_torrent(dir.path(), &announce);
+    let path = path.to_str().unwrap();
+
+    let (address, peer) = handshaking_peer([0; 8]);
+    let output = run(&["handshake", "--no-peers-check", path, &address]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(stdout(&output).contains("Peer ID: "));
+    peer.join().unwrap();
+    let accepted = tracker.accept();
+    assert_eq!(
+        accepted.map(|_| ()).unwrap_err().kind(),
+        std::io::ErrorKind::WouldBlock
+    );
+
+    // without it the tracker is asked first, here one that isn't there
+    let (unlisted, _) = sample_torrent(dir.path(), "http://127.0.0.1:1/announce");
+    let output = run(&["handshake", unlisted.to_str().unwrap(), &address]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("discover peers"));
+}
+
+#[test]
+fn announce_urls_carry_the_event() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, _) = sample_torrent(dir.path(), "http://127.0.0.1:1/announce");
+    let path = path.to_str().unwrap();
+
+    let url = stdout(&run(&["announce", "--dry-run", path]));
+    assert!(!url.contains("event="), "{url}");
+    assert!(
+        url.contains("&uploaded=0&downloaded=0&left=40000&"),
+        "{url}"
+    );
+    for event in ["started", "stopped", "completed"] {
+        let url = stdout(&run(&[
+            "announce",
+            "--dry-run",
+            "--event",
+            event,
+            "--uploaded",
+            "5",
+            "--left",
+            "7",
+            path,
+        ]));
+        assert!(url.starts_with("http://127.0.0.1:1/announce?"), "{url}");
+        assert!(
+            url.contains(&format!(
+                "&uploaded=5&downloaded=0&left=7&compact=1&event={event}&"
+            )),
+            "{url}"
+        );
+    }
+    let output = run(&["announce", "--dry-run", "--event", "paused", path]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn handshake_reports_the_peer_capabilities() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, _) = sample_torrent(dir.path(), "http://127.0.0.1:1/announce");
+    let path = path.to_str().unwrap();
+
+    let (address, peer) = handshaking_peer([0, 0, 0, 0, 0, 0x10, 0, 0x01]);
+    let report = stdout(&run(&["handshake", "--skip-tracker-check", path, &address]));
+    assert!(
+        report.ends_with("Extension protocol: yes\nDHT: yes\nFast extension: no\n"),
+        "{report}"
+    );
+    // what a download would advertise: fast and, for a public torrent, extensions
+    assert_eq!(peer.join().unwrap()[20..28], [0, 0, 0, 0, 0, 0x10, 0, 0x04]);
+}
+
+#[test]
+fn editing_the_tracker_replaces_an_announce_list() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, torrent) = sample_torrent(dir.path(), "http://old.example/announce");
+    let listed = dir.path().join("listed.torrent");
+    let edited = dir.path().join("edited.torrent");
+    let (path, listed, edited) = (
+        path.to_str().unwrap(),
+        listed.to_str().unwrap(),
+        edited.to_str().unwrap(),
+    );
+    let trackers = |path: &str| -> Vec<String> {
+        stdout(&run(&["announce", "--dry-run", path]))
+            .lines()
+            .map(|url| url.split('?').next().unwrap().to_string())
+            .collect()
+    };
+
+    let output = run(&[
+        "edit",
+        "-o",
+        listed,
+        "--announce",
+        "http://a.example/announce",
+        "--announce-list",
+        "http://a.example/announce,http://b.example/announce",
+        "--announce-list",
+        "http://c.example/announce",
+        path,
+    ]);
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        trackers(listed),
+        [
+            "http://a.example/announce",
+            "http://b.example/announce",
+            "http://c.example/announce"
+        ]
+    );
+
+    // only --announce: the old tiers must not win over it
+    let new = "http://new.example/announce";
+    let output = run(&["edit", "-o", edited, "--announce", new, listed]);
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(trackers(edited), [new]);
+    let hash = format!("{}\n", torrent.info.info_hash_str());
+    assert_eq!(stdout(&run(&["info-hash", edited])), hash);
+}
+
+#[test]
+fn torrent_urls_honor_the_timeout_and_user_agent() {
+    // reads the request and then never answers
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/sample.torrent", listener.local_addr().unwrap());
+    let server = std::thread::spawn(move || {
+        let (mut socket, _) = listener.accept().unwrap();
+        let mut head = Vec::new();
+        let mut byte = [0];
+        while !head.ends_with(b"\r\n\r\n") {
+            socket.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        // hold the connection until the client gives up
+        let _ = socket.read(&mut byte);
+        String::from_utf8(head).unwrap()
+    });
+
+    let started = std::time::Instant::now();
+    let output = run(&["info", "--timeout", "1", "--user-agent", "agent/1.0", &url]);
+    assert!(!output.status.success());
+    assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    let head = server.join().unwrap().to_ascii_lowercase();
+    assert!(head.contains("user-agent: agent/1.0\r\n"), "{head}");
+}
+
+#[test]
+fn torrents_of_a_session_stay_in_their_own_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+    fs::create_dir_all(&first).unwrap();
+    fs::create_dir_all(&second).unwrap();
+    // both named sample.bin, with a different tracker and so a different info hash
+    let (first, _) = sample_torrent(&first, "http://127.0.0.1:1/announce");
+    let (second, _) = sample_torrent(&second, "http://127.0.0.1:2/announce");
+    let output = dir.path().join("out");
+    let (first, second, output) = (
+        first.to_str().unwrap(),
+        second.to_str().unwrap(),
+        output.to_str().unwrap(),
+    );
+
+    let plan = stdout(&run(&[
+        "download",
+        "--dry-run",
+        "-o",
+        output,
+        first,
+        second,
+    ]));
+    let outputs: Vec<&str> = plan
+        .lines()
+        .filter_map(|line| line.strip_prefix("Output: "))
+        .collect();
+    assert_eq!(
+        outputs,
+        [
+            format!("{output}/sample.bin"),
+            format!("{output}/sample.bin (2)")
+        ]
+    );
+
+    // a name that climbs out of the output directory
+    let mut escaping = Torrent::from_bytes(&fs::read(first).unwrap()).unwrap();
+    escaping.info.name = String::from("../x");
+    escaping.info.forget_original();
+    let escaping_path = dir.path().join("escaping.torrent");
+    fs::write(&escaping_path, escaping.to_bytes().unwrap()).unwrap();
+    let escaping_path = escaping_path.to_str().unwrap();
+    for flags in [&["--dry-run"][..], &["--peer", "127.0.0.1:1"]] {
+        let run = run(&[&["download", "-o", output], flags, &[escaping_path, first]].concat());
+        assert!(!run.status.success(), "{flags:?}");
+        let stderr = String::from_utf8_lossy(&run.stderr);
+        assert!(stderr.contains("unsafe path"), "{stderr}");
+    }
+    assert!(!dir.path().join("x").exists());
+}
@@ -0,0 +1,108 @@
+#!/usr/bin/env python3
+"""Writes the fixture torrents next to this script and prints their info hashes.
+
+Bencoding and hashing are done here, independently of the crate, so the tests compare our info
+hashes against another implementation. The content of every file is `pattern` from
+src/testing.rs, so pieces can be checked against data the tests generate themselves.
+"""
+import hashlib
+import os
+
+
+def bencode(value):
+    if isinstance(value, int):
+        return b"i%de" % value
+    if isinstance(value, str):
+        value = value.encode()
+    if isinstance(value, bytes):
+        return b"%d:%s" % (len(value), value)
+    if isinstance(value, list):
+        return b"l" + b"".join(bencode(item) for item in value) + b"e"
+    # BEP 3: keys sorted as raw bytes
+    items = sorted((key.encode(), item) for key, item in value.items())
+    return b"d" + b"".join(bencode(key) + bencode(item) for key, item in items) + b"e"
+
+
+def pattern(length):
+    return bytes(((i * 7) ^ (i >> 8)) & 0xFF for i in range(length))
+
+
+def pieces(data, piece_length):
+    return b"".join(
+        hashlib.sha1(data[start : start + piece_length]).digest()
+        for start in range(0, len(data), piece_length)
+    )
+
+
+def write(name, torrent):
+    encoded = bencode(torrent)
+    with open(os.path.join(os.path.dirname(__file__), name), "wb") as file:
+        file.write(encoded)
+    print(name, hashlib.sha1(bencode(torrent["info"])).hexdigest())
+
+
+files = [(["a", "c.bin"], 40000), (["a", "z.bin"], 5000), (["b.bin"], 0), (["d.bin"], 300)]
+data = b"".join(pattern(length) for _, length in files)
+write(
+    "multi_file.torrent",
+    {
+        "announce": "http://tracker.example/announce",
+        "comment": "several files",
+        "created by": "make_fixtures.py",
+        "creation date": 1700000000,
+        "info": {
+            "files": [{"length": length, "path": path} for path, length in files],
+            "name": "album",
+            "piece length": 16384,
+            "pieces": pieces(data, 16384),
+        },
+    },
+)
+
+data = pattern(40000)
+write(
+    "announce_list.torrent",
+    {
+        "announce": "http://first.example/announce",
+        "announce-list": [
+            ["http://first.example/announce", "http://second.example/announce"],
+            ["udp://backup.example:6969"],
+        ],
+        "info": {
+            "length": len(data),
+            "name": "listed.bin",
+            "piece length": 16384,
+            "pieces": pieces(data, 16384),
+        },
+    },
+)
+
+write(
+    "private.torrent",
+    {
+        "announce": "http://private.example/announce?passkey=abc",
+        "info": {
+            "length": len(data),
+            "name": "private.bin",
+            "piece length": 16384,
+            "pieces": pieces(data, 16384),
+            "private": 1,
+            "source": "PRIV",
+        },
+    },
+)
+
+# pieces of 20000 bytes, which isn't a power of two, and a 10001 byte last piece
+data = pattern(50001)
+write(
+    "odd_pieces.torrent",
+    {
+        "announce": "http://tracker.example/announce",
+        "info": {
+            "length": len(data),
+            "name": "odd.bin",
+            "piece length": 20000,
+            "pieces": pieces(data, 20000),
+        },
+    },
+)
@@ -0,0 +1,147 @@
+// End-to-end check of tracker -> handshake -> pieces -> files. Spins up a bare-bones http tracker
+// and a seeder on ephemeral local ports, downloads a generated torrent from them and compares what
+// lands on disk with the original files.
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use bittorrent_starter_rust::bencode::{self, BencodeValue};
+use bittorrent_starter_rust::download::{self, selector::RarestFirst, DownloadOptions};
+use bittorrent_starter_rust::peer::{
+    bitfield::BitField, handshake::Handshake, message::MessageType, Stream,
+};
+use bittorrent_starter_rust::torrent::Torrent;
+use bittorrent_starter_rust::tracker::TrackerRequest;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const PIECE_LENGTH: usize = 32 * 1024;
+
+#[tokio::test]
+async fn downloads_a_torrent_from_a_local_swarm() -> Result<()> {
+    let source = tempfile::tempdir()?;
+    let content = source.path().join("content");
+    fs::create_dir_all(content.join("sub"))?;
+    // odd sizes so pieces straddle the file boundary and the last piece is short
+    fs::write(content.join("a.bin"), pattern(100_003, 7))?;
+    fs::write(content.join("sub").join("b.bin"), pattern(77_777, 13))?;
+
+    let seeder = TcpListener::bind("127.0.0.1:0").await?;
+    let seeder_addr = match seeder.local_addr()? {
+        std::net::SocketAddr::V4(addr) => addr,
+        addr => return Err(anyhow!("Seeder bound to {addr}, expected IPv4")),
+    };
+    let tracker = TcpListener::bind("127.0.0.1:0").await?;
+    let announce = format!("http://{}/announce", tracker.local_addr()?);
+    let torrent = Torrent::create(&content, announce, PIECE_LENGTH as u64)?;
+    let data = Arc::new(
+        [
+            fs::read(content.join("a.bin"))?,
+            fs::read(content.join("sub").join("b.bin"))?,
+        ]
+        .concat(),
+    );
+
+    tokio::spawn(run_tracker(tracker, seeder_addr));
+    tokio::spawn(run_seeder(seeder, torrent.clone(), data.clone()));
+
+    let peers = TrackerRequest::new(
+        String::from("-LOCAL-0000000000000"),
+        6881,
+        torrent.total_length(),
+    )
+    .discover_peers(&torrent)
+    .await
+    .context("CTX: discover peers")?;
+    assert_eq!(peers.addresses, [seeder_addr]);
+    let file_data = download::download_all(
+        &torrent,
+        &peers.addresses,
+        Box::new(RarestFirst::new(torrent.num_pieces())),
+        &DownloadOptions::default(),
+    )
+    .await?;
+    assert!(
+        file_data == *data,
+        "downloaded bytes differ from the seeded ones"
+    );
+    let output = tempfile::tempdir()?;
+    download::write_output(&torrent, output.path(), &file_data)?;
+
+    for file in ["a.bin", "sub/b.bin"] {
+        let written = fs::read(output.path().join(file)).context(format!("CTX: reading {file}"))?;
+        assert!(
+            written == fs::read(content.join(file))?,
+            "{file} differs from the original"
+        );
+    }
+    Ok(())
+}
+
+// deterministic filler that doesn't repeat every piece
+fn pattern(len: usize, seed: usize) -> Vec<u8> {
+    (0..len).map(|i| ((i * seed) ^ (i >> 8)) as u8).collect()
+}
+
+// answers every request with a compact peer list holding just the seeder
+async fn run_tracker(listener: TcpListener, seeder: SocketAddrV4) -> Result<()> {
+    let mut peers = seeder.ip().octets().to_vec();
+    peers.extend_from_slice(&seeder.port().to_be_bytes());
+    let body = bencode::encode(&BencodeValue::Dict(BTreeMap::from([
+        (b"interval".to_vec(), BencodeValue::Int(60)),
+        (b"peers".to_vec(), BencodeValue::Bytes(peers)),
+    ])));
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+        let body = body.clone();
+        tokio::spawn(async move {
+            // the query string doesn't matter, just wait for the end of the request head
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(socket.read_u8().await?);
+            }
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            socket.write_all(head.as_bytes()).await?;
+            socket.write_all(&body).await?;
+            anyhow::Ok(())
+        });
+    }
+}
+
+// has every piece, unchokes everyone who is interested
+async fn run_seeder(listener: TcpListener, torrent: Torrent, data: Arc<Vec<u8>>) -> Result<()> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        tokio::spawn(serve(Stream::new(socket), torrent.clone(), data.clone()));
+    }
+}
+
+async fn serve(mut stream: Stream, torrent: Torrent, data: Arc<Vec<u8>>) -> Result<()> {
+    let handshake = Handshake::new(torrent.info.info_hash_bytes())
+        .with_peer_id(String::from("-SEED--0000000000000"))?;
+    stream.accept_handshake(handshake).await?;
+    stream
+        .send_bitfield(&BitField::full(torrent.num_pieces()))
+        .await?;
+    loop {
+        let (id, payload) = stream.read_message().await?;
+        match MessageType::from_id(id) {
+            Some(MessageType::Interested) => stream.unchoke().await?,
+            Some(MessageType::Request) => {
+                let field = |at: usize| {
+                    u32::from_be_bytes(payload[at..at + 4].try_into().expect("4 bytes"))
+                };
+                let (piece, offset, length) = (field(0), field(4), field(8));
+                let start = piece as usize * PIECE_LENGTH + offset as usize;
+                let block = &data[start..start + length as usize];
+                stream.send_block(piece, offset, block).await?;
+            }
+            _ => {}
+        }
+    }
+}